@@ -0,0 +1,432 @@
+//! `forgeflare loop`: run the same prompt over and over, each iteration in a
+//! fresh conversation, until a hook signals convergence.
+
+use crate::api::AnthropicClient;
+use crate::cost::CostTracker;
+use crate::fallback::ModelChain;
+use crate::hooks::HookRunner;
+use crate::models::ModelCatalog;
+use crate::output::Output;
+use crate::session::SessionWriter;
+use crate::tools::ToolRegistry;
+use crate::{reap_background_jobs, run_turn, Cli, TurnOutcome, TurnStopReason};
+use chrono::Utc;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::process::Command;
+
+#[derive(clap::Args, Debug)]
+pub struct LoopArgs {
+    /// Prompt sent at the start of every iteration (re-read each time)
+    #[arg(long, default_value = "PROMPT_build.md")]
+    pub prompt_file: PathBuf,
+
+    /// Stop after this many iterations (0 = until converged)
+    #[arg(long, default_value_t = 0)]
+    pub max_iterations: usize,
+
+    /// Commit the working tree after each iteration that changed it
+    #[arg(long, default_value_t = false)]
+    pub git_commit: bool,
+
+    /// Push the current branch after each iteration
+    #[arg(long, default_value_t = false)]
+    pub git_push: bool,
+
+    /// Loop summary, rewritten after every iteration
+    #[arg(long, default_value = ".forgeflare/loop.json")]
+    pub summary_file: PathBuf,
+}
+
+impl LoopArgs {
+    /// These options as command-line arguments, for re-running the loop in a child process.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "--prompt-file".to_string(),
+            self.prompt_file.display().to_string(),
+            "--max-iterations".to_string(),
+            self.max_iterations.to_string(),
+            "--summary-file".to_string(),
+            self.summary_file.display().to_string(),
+        ];
+        if self.git_commit {
+            args.push("--git-commit".to_string());
+        }
+        if self.git_push {
+            args.push("--git-push".to_string());
+        }
+        args
+    }
+}
+
+/// Contents of the summary file.
+#[derive(Debug, Serialize)]
+struct LoopSummary {
+    prompt_file: String,
+    started_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finished_at: Option<String>,
+    /// `running` until the loop ends, then why it ended.
+    status: String,
+    max_iterations: usize,
+    total_tokens: u64,
+    total_cost_usd: f64,
+    iterations: Vec<IterationRecord>,
+}
+
+#[derive(Debug, Serialize)]
+struct IterationRecord {
+    iteration: usize,
+    session_id: String,
+    stop_reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signal: Option<String>,
+    tool_iterations: usize,
+    total_tokens: u64,
+    cost_usd: f64,
+    duration_secs: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pushed: Option<bool>,
+}
+
+/// Why the loop should end after `iteration` (1-based), if it should.
+fn stop_status(
+    reason: TurnStopReason,
+    iteration: usize,
+    max_iterations: usize,
+) -> Option<&'static str> {
+    match reason {
+        TurnStopReason::ConvergenceSignal => Some("converged"),
+        TurnStopReason::BudgetExhausted => Some("budget_exhausted"),
+        // Retries are already spent; a new iteration would hit the same wall.
+        TurnStopReason::ApiError => Some("api_error"),
+        _ if max_iterations > 0 && iteration >= max_iterations => Some("max_iterations"),
+        _ => None,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    args: &LoopArgs,
+    cli: &Cli,
+    client: &AnthropicClient,
+    system_prompt: &str,
+    tools: &ToolRegistry,
+    hooks: &HookRunner,
+    output: &Output,
+    catalog: &ModelCatalog,
+    cost: &mut CostTracker,
+    chain: &mut ModelChain,
+    cwd: &str,
+) {
+    let mut summary = LoopSummary {
+        prompt_file: args.prompt_file.display().to_string(),
+        started_at: Utc::now().to_rfc3339(),
+        finished_at: None,
+        status: "running".to_string(),
+        max_iterations: args.max_iterations,
+        total_tokens: 0,
+        total_cost_usd: 0.0,
+        iterations: Vec::new(),
+    };
+
+    let mut iteration = 0;
+    let status = loop {
+        let prompt = match fs::read_to_string(&args.prompt_file) {
+            Ok(p) if !p.trim().is_empty() => p,
+            Ok(_) => {
+                eprintln!("[loop] {} is empty", args.prompt_file.display());
+                break "prompt_error";
+            }
+            Err(e) => {
+                eprintln!("[loop] Cannot read {}: {e}", args.prompt_file.display());
+                break "prompt_error";
+            }
+        };
+
+        iteration += 1;
+        eprintln!(
+            "[loop] Iteration {iteration}{}",
+            if args.max_iterations > 0 {
+                format!("/{}", args.max_iterations)
+            } else {
+                String::new()
+            }
+        );
+
+        hooks.clear_convergence_state();
+        tools.reset_shell();
+        hooks.set_cwd(cwd);
+        let mut session = SessionWriter::new(cwd, &cli.model);
+        let mut conversation = Vec::new();
+        let started = Instant::now();
+        let turn = run_turn(
+            cli,
+            client,
+            system_prompt,
+            tools,
+            &mut conversation,
+            &mut session,
+            hooks,
+            output,
+            catalog,
+            cost,
+            chain,
+            prompt.trim(),
+        );
+        let outcome: Option<TurnOutcome> = tokio::select! {
+            outcome = turn => Some(outcome),
+            _ = tokio::signal::ctrl_c() => None,
+        };
+        reap_background_jobs(tools);
+        session.write_context(cost.session());
+        let Some(outcome) = outcome else {
+            eprintln!("[loop] Interrupted during iteration {iteration}");
+            break "interrupted";
+        };
+
+        let mut record = IterationRecord {
+            iteration,
+            session_id: session.session_id().to_string(),
+            stop_reason: outcome.reason.as_str().to_string(),
+            signal: hooks.last_signal().map(|(signal, _)| signal),
+            tool_iterations: outcome.tool_iterations,
+            total_tokens: outcome.total_tokens,
+            cost_usd: outcome.cost_usd,
+            duration_secs: started.elapsed().as_secs_f64(),
+            commit: None,
+            pushed: None,
+        };
+
+        if args.git_commit {
+            match commit_iteration(Path::new("."), iteration).await {
+                Ok(Some(sha)) => {
+                    eprintln!("[loop] Committed {}", &sha[..sha.len().min(12)]);
+                    record.commit = Some(sha);
+                }
+                Ok(None) => eprintln!("[loop] No changes to commit"),
+                Err(e) => eprintln!("[loop] git commit failed: {e}"),
+            }
+        }
+        if args.git_push {
+            let pushed = push_branch(Path::new(".")).await;
+            if let Err(e) = &pushed {
+                eprintln!("[loop] git push failed: {e}");
+            }
+            record.pushed = Some(pushed.is_ok());
+        }
+
+        eprintln!(
+            "[loop] Iteration {iteration} ended: {} ({} tool iterations, ${:.4})",
+            record.stop_reason, record.tool_iterations, record.cost_usd
+        );
+        summary.total_tokens += record.total_tokens;
+        summary.total_cost_usd += record.cost_usd;
+        summary.iterations.push(record);
+        if let Err(e) = write_summary(&args.summary_file, &summary) {
+            eprintln!("[loop] Cannot write {}: {e}", args.summary_file.display());
+        }
+
+        if let Some(status) = stop_status(outcome.reason, iteration, args.max_iterations) {
+            break status;
+        }
+    };
+
+    summary.status = status.to_string();
+    summary.finished_at = Some(Utc::now().to_rfc3339());
+    if let Err(e) = write_summary(&args.summary_file, &summary) {
+        eprintln!("[loop] Cannot write {}: {e}", args.summary_file.display());
+    }
+    eprintln!(
+        "[loop] Stopped after {} iterations: {status} (${:.4} total)",
+        summary.iterations.len(),
+        summary.total_cost_usd
+    );
+}
+
+/// Write `summary` to `path` via a temp file + rename, so readers never see a partial file.
+fn write_summary(path: &Path, summary: &LoopSummary) -> std::io::Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let json = serde_json::to_string_pretty(summary).map_err(std::io::Error::other)?;
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, json)?;
+    fs::rename(&tmp, path)
+}
+
+/// Run git in `dir`, returning trimmed stdout or the trimmed stderr on failure.
+async fn git(dir: &Path, args: &[&str]) -> Result<String, String> {
+    let out = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .await
+        .map_err(|e| format!("cannot run git: {e}"))?;
+    if out.status.success() {
+        Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+    } else {
+        Err(String::from_utf8_lossy(&out.stderr).trim().to_string())
+    }
+}
+
+/// Paths never committed by the loop: session transcripts and forgeflare's
+/// own state files, which change every iteration whatever the agent did.
+const COMMIT_PATHSPEC: &[&str] = &["--", ".", ":(exclude).entire", ":(exclude).forgeflare"];
+
+/// Commit everything in the working tree outside `COMMIT_PATHSPEC`'s
+/// exclusions. `Ok(None)` when there was nothing to commit.
+async fn commit_iteration(dir: &Path, iteration: usize) -> Result<Option<String>, String> {
+    let status = [&["status", "--porcelain"], COMMIT_PATHSPEC].concat();
+    if git(dir, &status).await?.is_empty() {
+        return Ok(None);
+    }
+    git(dir, &[&["add", "-A"], COMMIT_PATHSPEC].concat()).await?;
+    git(
+        dir,
+        &[
+            "commit",
+            "-q",
+            "-m",
+            &format!("forgeflare loop iteration {iteration}"),
+        ],
+    )
+    .await?;
+    git(dir, &["rev-parse", "HEAD"]).await.map(Some)
+}
+
+/// Push the current branch to origin, setting the upstream if it has none.
+async fn push_branch(dir: &Path) -> Result<(), String> {
+    let branch = git(dir, &["branch", "--show-current"]).await?;
+    if branch.is_empty() {
+        return Err("detached HEAD".to_string());
+    }
+    if git(dir, &["push", "origin", &branch]).await.is_ok() {
+        return Ok(());
+    }
+    git(dir, &["push", "-u", "origin", &branch])
+        .await
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stop_status_decisions() {
+        assert_eq!(
+            stop_status(TurnStopReason::ConvergenceSignal, 1, 10),
+            Some("converged")
+        );
+        assert_eq!(
+            stop_status(TurnStopReason::BudgetExhausted, 1, 0),
+            Some("budget_exhausted")
+        );
+        assert_eq!(
+            stop_status(TurnStopReason::EndTurn, 3, 3),
+            Some("max_iterations")
+        );
+        assert_eq!(stop_status(TurnStopReason::EndTurn, 3, 4), None);
+        // 0 means unlimited
+        assert_eq!(stop_status(TurnStopReason::ContextOverflow, 1000, 0), None);
+    }
+
+    /// An iteration that ends in `api_error` used to be followed by another
+    /// one. It now ends the loop so `supervise` can restart it.
+    #[test]
+    fn api_error_ends_loop() {
+        assert_eq!(
+            stop_status(TurnStopReason::ApiError, 1, 0),
+            Some("api_error")
+        );
+        assert_eq!(
+            stop_status(TurnStopReason::ApiError, 1000, 0),
+            Some("api_error")
+        );
+    }
+
+    #[test]
+    fn summary_written_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join("loop.json");
+        let summary = LoopSummary {
+            prompt_file: "PROMPT_build.md".to_string(),
+            started_at: "2026-01-01T00:00:00Z".to_string(),
+            finished_at: None,
+            status: "running".to_string(),
+            max_iterations: 5,
+            total_tokens: 1200,
+            total_cost_usd: 0.05,
+            iterations: vec![IterationRecord {
+                iteration: 1,
+                session_id: "s1".to_string(),
+                stop_reason: "convergence_signal".to_string(),
+                signal: Some("converged".to_string()),
+                tool_iterations: 4,
+                total_tokens: 1200,
+                cost_usd: 0.05,
+                duration_secs: 12.5,
+                commit: None,
+                pushed: None,
+            }],
+        };
+        write_summary(&path, &summary).unwrap();
+        assert!(!path.with_extension("json.tmp").exists());
+
+        let v: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(v["status"], "running");
+        assert!(v.get("finished_at").is_none());
+        assert_eq!(v["iterations"][0]["signal"], "converged");
+        assert!(v["iterations"][0].get("commit").is_none());
+    }
+
+    #[tokio::test]
+    async fn commit_iteration_only_when_dirty() {
+        let dir = tempfile::tempdir().unwrap();
+        let d = dir.path();
+        git(d, &["init", "-q"]).await.unwrap();
+        git(d, &["config", "user.email", "loop@example.com"])
+            .await
+            .unwrap();
+        git(d, &["config", "user.name", "loop"]).await.unwrap();
+
+        assert_eq!(commit_iteration(d, 1).await.unwrap(), None);
+
+        fs::write(d.join("a.txt"), "a").unwrap();
+        let sha = commit_iteration(d, 2).await.unwrap().unwrap();
+        assert_eq!(sha.len(), 40);
+        let subject = git(d, &["log", "-1", "--format=%s"]).await.unwrap();
+        assert_eq!(subject, "forgeflare loop iteration 2");
+
+        assert_eq!(commit_iteration(d, 3).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn commit_iteration_skips_transcripts_and_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let d = dir.path();
+        git(d, &["init", "-q"]).await.unwrap();
+        git(d, &["config", "user.email", "loop@example.com"])
+            .await
+            .unwrap();
+        git(d, &["config", "user.name", "loop"]).await.unwrap();
+        fs::create_dir_all(d.join(".entire/metadata/s1")).unwrap();
+        fs::write(d.join(".entire/metadata/s1/full.jsonl"), "{}").unwrap();
+        fs::create_dir_all(d.join(".forgeflare")).unwrap();
+        fs::write(d.join(".forgeflare/loop.json"), "{}").unwrap();
+        fs::write(d.join(".forgeflare/heartbeat.json"), "{}").unwrap();
+
+        assert_eq!(commit_iteration(d, 1).await.unwrap(), None);
+
+        fs::write(d.join("a.txt"), "a").unwrap();
+        commit_iteration(d, 2).await.unwrap().unwrap();
+        let files = git(d, &["ls-files"]).await.unwrap();
+        assert_eq!(files, "a.txt");
+    }
+}
@@ -1,8 +1,26 @@
+use crate::auth::Auth;
+use crate::ratelimit::RateLimitState;
+use crate::sse::SseDecoder;
+use crate::tokens::{request_bytes, TokenEstimator};
 use futures_util::StreamExt;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
 use std::time::Duration;
 
+/// The Messages API accepts at most this many `cache_control` breakpoints per request.
+const MAX_CACHE_BREAKPOINTS: usize = 4;
+
+/// Default gap allowed between SSE chunks. The server sends `ping` events
+/// while the model is thinking, so a live stream never goes quiet this long.
+pub const DEFAULT_STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Whole-request timeout floor; the budget grows with `max_tokens` so long
+/// generations are never cut off by a fixed ceiling.
+const REQUEST_TIMEOUT_BASE_SECS: u64 = 300;
+/// Slowest output rate we still consider healthy, in tokens per second.
+const MIN_OUTPUT_TOKENS_PER_SEC: u64 = 10;
+
 #[derive(Debug, thiserror::Error)]
 pub enum AgentError {
     #[error("API error: {0}")]
@@ -21,8 +39,14 @@ pub enum AgentError {
     #[error("Stream error (transient): {0}")]
     StreamTransient(String),
 
+    #[error("Stream stalled: no data received for {idle_secs}s")]
+    StreamStalled { idle_secs: u64 },
+
     #[error("Stream parse error: {0}")]
     StreamParse(String),
+
+    #[error("Auth error: {0}")]
+    Auth(String),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -38,7 +62,7 @@ pub fn classify_error(e: &AgentError) -> ErrorClass {
             s if s >= 500 => ErrorClass::Transient,
             _ => ErrorClass::Permanent,
         },
-        AgentError::StreamTransient(_) => ErrorClass::Transient,
+        AgentError::StreamTransient(_) | AgentError::StreamStalled { .. } => ErrorClass::Transient,
         AgentError::StreamParse(_) => ErrorClass::Permanent,
         AgentError::Api(e) => {
             if e.is_timeout() || e.is_connect() {
@@ -47,7 +71,7 @@ pub fn classify_error(e: &AgentError) -> ErrorClass {
                 ErrorClass::Permanent
             }
         }
-        AgentError::Json(_) => ErrorClass::Permanent,
+        AgentError::Json(_) | AgentError::Auth(_) => ErrorClass::Permanent,
     }
 }
 
@@ -95,79 +119,187 @@ pub struct Usage {
     pub cache_read_input_tokens: u64,
 }
 
+impl Usage {
+    /// Fraction of the prompt served from cache. `input_tokens` excludes cached
+    /// tokens, so the denominator is the sum of all three input-side fields.
+    pub fn cache_hit_rate(&self) -> f64 {
+        let prompt =
+            self.input_tokens + self.cache_creation_input_tokens + self.cache_read_input_tokens;
+        if prompt == 0 {
+            0.0
+        } else {
+            self.cache_read_input_tokens as f64 / prompt as f64
+        }
+    }
+}
+
 pub struct AnthropicClient {
     client: Client,
     api_url: String,
-    api_key: Option<String>,
+    auth: Auth,
+    rate_limit: Mutex<Option<RateLimitState>>,
+    stream_idle_timeout: Duration,
+    estimator: Mutex<TokenEstimator>,
 }
 
 impl AnthropicClient {
     pub fn new(api_url: &str) -> Self {
         let client = Client::builder()
             .connect_timeout(Duration::from_secs(30))
-            .timeout(Duration::from_secs(300))
             .build()
             .expect("failed to build HTTP client");
 
-        let api_key = std::env::var("ANTHROPIC_API_KEY").ok();
-
         Self {
             client,
             api_url: api_url.trim_end_matches('/').to_string(),
-            api_key,
+            auth: Auth::from_env(),
+            rate_limit: Mutex::new(None),
+            stream_idle_timeout: DEFAULT_STREAM_IDLE_TIMEOUT,
+            estimator: Mutex::new(TokenEstimator::default()),
         }
     }
 
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub fn with_stream_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.stream_idle_timeout = timeout;
+        self
+    }
+
     pub fn api_url(&self) -> &str {
         &self.api_url
     }
 
-    pub fn has_api_key(&self) -> bool {
-        self.api_key.is_some()
+    /// Auth scheme and credential source, safe for verbose output.
+    pub fn auth_description(&self) -> String {
+        self.auth.describe()
     }
 
-    pub async fn send_message(
+    /// Snapshot of the local token estimator, calibrated by every response so far.
+    pub fn estimator(&self) -> TokenEstimator {
+        self.estimator.lock().unwrap().clone()
+    }
+
+    /// Rate-limit budgets from the most recent response that reported them.
+    pub fn rate_limit(&self) -> Option<RateLimitState> {
+        self.rate_limit.lock().unwrap().clone()
+    }
+
+    /// Sleep until the exhausted bucket resets rather than spending a request on a 429.
+    async fn throttle(&self) {
+        let delay = self
+            .rate_limit
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|state| state.throttle_delay(chrono::Utc::now()));
+        if let Some((delay, bucket)) = delay {
+            eprintln!(
+                "[ratelimit] {bucket} budget nearly exhausted, waiting {:.1}s for reset",
+                delay.as_secs_f64()
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// POST `body` once the rate-limit budget allows it. A 401 from a
+    /// refreshable credential is retried once with a fresh one, and the
+    /// response's rate-limit headers are recorded for the next `throttle`.
+    async fn post(
         &self,
-        model: &str,
+        url: &str,
         max_tokens: u32,
+        body: &serde_json::Value,
+    ) -> Result<reqwest::Response, AgentError> {
+        self.throttle().await;
+        let mut resp = self.send(url, max_tokens, body).await?;
+
+        // A rotated credential shows up as a 401; fetch a fresh one and retry once.
+        if resp.status() == reqwest::StatusCode::UNAUTHORIZED && self.auth.can_refresh() {
+            eprintln!("[auth] 401 Unauthorized, refreshing credential via api_key_helper");
+            self.auth.invalidate().await;
+            resp = self.send(url, max_tokens, body).await?;
+        }
+
+        if let Some(state) = RateLimitState::from_headers(resp.headers()) {
+            *self.rate_limit.lock().unwrap() = Some(state);
+        }
+        Ok(resp)
+    }
+
+    async fn send(
+        &self,
+        url: &str,
+        max_tokens: u32,
+        body: &serde_json::Value,
+    ) -> Result<reqwest::Response, AgentError> {
+        let resp = self
+            .client
+            .post(url)
+            .timeout(request_timeout(max_tokens))
+            .header("anthropic-version", "2023-06-01")
+            .headers(self.auth.headers().await?)
+            .json(body)
+            .send()
+            .await?;
+        Ok(resp)
+    }
+
+    /// Pre-flight prompt size from the count-tokens endpoint. No cache
+    /// breakpoints or streaming; only the fields that affect input tokens.
+    pub async fn count_tokens(
+        &self,
+        model: &str,
         system: &str,
         messages: &[Message],
         tools: &[serde_json::Value],
-        stream_callback: &mut dyn FnMut(&str),
-    ) -> Result<(Vec<ContentBlock>, StopReason, Usage), AgentError> {
-        let url = format!("{}/v1/messages", self.api_url);
-
+    ) -> Result<u64, AgentError> {
+        let url = format!("{}/v1/messages/count_tokens", self.api_url);
         let mut body = serde_json::json!({
             "model": model,
-            "max_tokens": max_tokens,
-            "system": [{
-                "type": "text",
-                "text": system,
-                "cache_control": {"type": "ephemeral"}
-            }],
+            "system": system,
             "messages": messages,
-            "stream": true,
         });
-
         if !tools.is_empty() {
-            let mut cached_tools = tools.to_vec();
-            if let Some(last) = cached_tools.last_mut() {
-                last["cache_control"] = serde_json::json!({"type": "ephemeral"});
-            }
-            body["tools"] = serde_json::Value::Array(cached_tools);
+            body["tools"] = serde_json::Value::Array(tools.to_vec());
         }
 
-        let mut req = self
-            .client
-            .post(&url)
-            .header("anthropic-version", "2023-06-01");
-
-        if let Some(ref key) = self.api_key {
-            req = req.header("x-api-key", key);
+        let resp = self.post(&url, 0, &body).await?;
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(AgentError::HttpError {
+                status: status.as_u16(),
+                retry_after: None,
+                body: resp.text().await.unwrap_or_default(),
+            });
         }
+        let parsed: serde_json::Value = resp.json().await?;
+        let tokens = parsed["input_tokens"].as_u64().ok_or_else(|| {
+            AgentError::StreamParse("count_tokens response missing input_tokens".to_string())
+        })?;
+        self.estimator
+            .lock()
+            .unwrap()
+            .calibrate(request_bytes(system, tools, messages), tokens);
+        Ok(tokens)
+    }
 
-        let resp: reqwest::Response = req.json(&body).send().await?;
+    pub async fn send_message(
+        &self,
+        model: &str,
+        max_tokens: u32,
+        system: &str,
+        messages: &[Message],
+        tools: &[serde_json::Value],
+        sink: &mut dyn FnMut(&StreamEvent),
+    ) -> Result<(Vec<ContentBlock>, StopReason, Usage), AgentError> {
+        let url = format!("{}/v1/messages", self.api_url);
+        let body = build_request_body(model, max_tokens, system, messages, tools)?;
 
+        let resp = self.post(&url, max_tokens, &body).await?;
         let status = resp.status();
         if !status.is_success() {
             let retry_after: Option<u64> = resp
@@ -184,18 +316,131 @@ impl AnthropicClient {
         }
 
         let stream = resp.bytes_stream();
-        parse_sse_stream(stream, stream_callback).await
+        let result = parse_sse_stream(stream, self.stream_idle_timeout, sink).await?;
+        self.estimator
+            .lock()
+            .unwrap()
+            .calibrate_from_usage(request_bytes(system, tools, messages), &result.2);
+        Ok(result)
+    }
+}
+
+/// Overall budget for one request: the base plus time to emit `max_tokens`
+/// at the slowest acceptable rate. Stalls are caught sooner by the idle timeout.
+fn request_timeout(max_tokens: u32) -> Duration {
+    Duration::from_secs(
+        REQUEST_TIMEOUT_BASE_SECS + u64::from(max_tokens) / MIN_OUTPUT_TOKENS_PER_SEC,
+    )
+}
+
+/// Build the Messages API request body with prompt-cache breakpoints.
+///
+/// The system prompt and the last tool schema each get one breakpoint. The
+/// remaining budget rolls over the conversation: the most recent user messages
+/// (text or tool_result) are marked so each request reads the previous
+/// request's cached prefix and writes a new one. Breakpoints are derived from
+/// `messages` on every call, so trimming or recovery never leaves stale markers.
+fn build_request_body(
+    model: &str,
+    max_tokens: u32,
+    system: &str,
+    messages: &[Message],
+    tools: &[serde_json::Value],
+) -> Result<serde_json::Value, AgentError> {
+    let mut messages_json = match serde_json::to_value(messages)? {
+        serde_json::Value::Array(items) => items,
+        _ => Vec::new(),
+    };
+    let fixed_breakpoints = 1 + usize::from(!tools.is_empty());
+    apply_message_cache_breakpoints(
+        &mut messages_json,
+        MAX_CACHE_BREAKPOINTS - fixed_breakpoints,
+    );
+
+    let mut body = serde_json::json!({
+        "model": model,
+        "max_tokens": max_tokens,
+        "system": [{
+            "type": "text",
+            "text": system,
+            "cache_control": {"type": "ephemeral"}
+        }],
+        "messages": messages_json,
+        "stream": true,
+    });
+
+    if !tools.is_empty() {
+        let mut cached_tools = tools.to_vec();
+        if let Some(last) = cached_tools.last_mut() {
+            last["cache_control"] = serde_json::json!({"type": "ephemeral"});
+        }
+        body["tools"] = serde_json::Value::Array(cached_tools);
     }
+
+    Ok(body)
+}
+
+/// Mark the last content block of the `max_breakpoints` most recent user
+/// messages with `cache_control`.
+fn apply_message_cache_breakpoints(messages: &mut [serde_json::Value], max_breakpoints: usize) {
+    let mut placed = 0;
+    for msg in messages.iter_mut().rev() {
+        if placed >= max_breakpoints {
+            break;
+        }
+        if msg["role"] != "user" {
+            continue;
+        }
+        if let Some(last) = msg["content"].as_array_mut().and_then(|c| c.last_mut()) {
+            last["cache_control"] = serde_json::json!({"type": "ephemeral"});
+            placed += 1;
+        }
+    }
+}
+
+/// Typed events emitted by `parse_sse_stream` while a response streams in.
+///
+/// Consumers (terminal renderer, JSON event printers, hooks) see tool-use starts,
+/// partial input JSON and usage as they arrive instead of only after the whole
+/// response is assembled.
+#[allow(dead_code)] // Not every consumer reads every field
+#[derive(Debug, Clone)]
+pub enum StreamEvent {
+    /// `message_start`: input-side usage (including cache fields).
+    MessageStart { usage: Usage },
+    /// `content_block_start`: the initial (empty) block for `index`.
+    BlockStart { index: usize, block: ContentBlock },
+    /// `text_delta` for the text block at `index`.
+    TextDelta { index: usize, text: String },
+    /// `input_json_delta` fragment for the tool_use block at `index`.
+    InputJsonDelta { index: usize, partial_json: String },
+    /// `content_block_stop` for `index`.
+    BlockStop { index: usize },
+    /// `message_delta`: stop reason (if recognized) and output token count.
+    MessageDelta {
+        stop_reason: Option<StopReason>,
+        output_tokens: u64,
+    },
+    /// Server-sent `error` event, emitted before the stream is aborted.
+    Error { error_type: String, message: String },
+    /// `ping` keep-alive.
+    Ping,
 }
 
 /// Parse SSE stream into content blocks, stop reason, and usage.
 ///
+/// Framing (line endings, multi-line `data`, UTF-8 across chunk boundaries)
+/// is handled by `SseDecoder`; this function interprets the decoded events.
 /// We collect content_block_start events to initialize blocks, then
 /// content_block_delta events to append text or accumulate tool input JSON,
 /// message_start for input usage, and message_delta for stop_reason + output usage.
+/// Every recognized event is also forwarded to `sink` as a `StreamEvent`.
+/// If no bytes arrive for `idle_timeout` (pings included), the stream is
+/// abandoned with `StreamStalled`.
 async fn parse_sse_stream<S>(
     stream: S,
-    callback: &mut dyn FnMut(&str),
+    idle_timeout: Duration,
+    sink: &mut dyn FnMut(&StreamEvent),
 ) -> Result<(Vec<ContentBlock>, StopReason, Usage), AgentError>
 where
     S: futures_util::Stream<Item = Result<bytes::Bytes, reqwest::Error>> + Unpin,
@@ -203,7 +448,7 @@ where
     let mut content_blocks: Vec<ContentBlock> = Vec::new();
     let mut stop_reason: Option<StopReason> = None;
     let mut usage = Usage::default();
-    let mut buffer = String::new();
+    let mut decoder = SseDecoder::new();
 
     // Track in-progress tool_use input JSON accumulation per block index
     let mut tool_input_bufs: std::collections::HashMap<usize, String> =
@@ -211,148 +456,177 @@ where
 
     futures_util::pin_mut!(stream);
 
-    while let Some(chunk_result) = stream.next().await {
+    loop {
+        let chunk_result = match tokio::time::timeout(idle_timeout, stream.next()).await {
+            Ok(Some(r)) => r,
+            Ok(None) => break,
+            Err(_) => {
+                return Err(AgentError::StreamStalled {
+                    idle_secs: idle_timeout.as_secs(),
+                })
+            }
+        };
         let chunk = chunk_result.map_err(|e| AgentError::StreamTransient(e.to_string()))?;
-        buffer.push_str(&String::from_utf8_lossy(&chunk));
-
-        // Process complete SSE lines
-        while let Some(pos) = buffer.find("\n\n") {
-            {
-                let event_block = &buffer[..pos];
+        for sse in decoder.feed(&chunk) {
+            if sse.data == "[DONE]" {
+                continue;
+            }
 
-                for line in event_block.lines() {
-                    if let Some(data) = line.strip_prefix("data: ") {
-                        if data == "[DONE]" {
-                            continue;
+            let parsed: serde_json::Value = match serde_json::from_str(&sse.data) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+
+            // The JSON `type` is authoritative; fall back to the SSE event name.
+            let event_type = parsed["type"].as_str().unwrap_or(&sse.event);
+
+            match event_type {
+                "message_start" => {
+                    if let Some(u) = parsed.get("message").and_then(|m| m.get("usage")) {
+                        usage.input_tokens = u["input_tokens"].as_u64().unwrap_or(0);
+                        usage.cache_creation_input_tokens =
+                            u["cache_creation_input_tokens"].as_u64().unwrap_or(0);
+                        usage.cache_read_input_tokens =
+                            u["cache_read_input_tokens"].as_u64().unwrap_or(0);
+                    }
+                    sink(&StreamEvent::MessageStart {
+                        usage: usage.clone(),
+                    });
+                }
+                "content_block_start" => {
+                    let cb = &parsed["content_block"];
+                    let index = parsed["index"]
+                        .as_u64()
+                        .map(|i| i as usize)
+                        .unwrap_or(content_blocks.len());
+                    match cb["type"].as_str() {
+                        Some("text") => {
+                            let block = ContentBlock::Text {
+                                text: String::new(),
+                            };
+                            sink(&StreamEvent::BlockStart {
+                                index,
+                                block: block.clone(),
+                            });
+                            content_blocks.push(block);
                         }
-
-                        let parsed: serde_json::Value = match serde_json::from_str(data) {
-                            Ok(v) => v,
-                            Err(_) => continue,
-                        };
-
-                        let event_type = parsed["type"].as_str().unwrap_or("");
-
-                        match event_type {
-                            "message_start" => {
-                                if let Some(u) = parsed.get("message").and_then(|m| m.get("usage"))
+                        Some("tool_use") => {
+                            let idx = content_blocks.len();
+                            let block = ContentBlock::ToolUse {
+                                id: cb["id"].as_str().unwrap_or("").to_string(),
+                                name: cb["name"].as_str().unwrap_or("").to_string(),
+                                input: serde_json::Value::Object(serde_json::Map::new()),
+                            };
+                            sink(&StreamEvent::BlockStart {
+                                index,
+                                block: block.clone(),
+                            });
+                            content_blocks.push(block);
+                            tool_input_bufs.insert(idx, String::new());
+                        }
+                        _ => {}
+                    }
+                }
+                "content_block_delta" => {
+                    let index = parsed["index"].as_u64().unwrap_or(0) as usize;
+                    let delta = &parsed["delta"];
+
+                    match delta["type"].as_str() {
+                        Some("text_delta") => {
+                            if let Some(text) = delta["text"].as_str() {
+                                sink(&StreamEvent::TextDelta {
+                                    index,
+                                    text: text.to_string(),
+                                });
+                                if let Some(ContentBlock::Text { text: ref mut t }) =
+                                    content_blocks.get_mut(index)
                                 {
-                                    usage.input_tokens = u["input_tokens"].as_u64().unwrap_or(0);
-                                    usage.cache_creation_input_tokens =
-                                        u["cache_creation_input_tokens"].as_u64().unwrap_or(0);
-                                    usage.cache_read_input_tokens =
-                                        u["cache_read_input_tokens"].as_u64().unwrap_or(0);
+                                    t.push_str(text);
                                 }
                             }
-                            "content_block_start" => {
-                                let cb = &parsed["content_block"];
-                                match cb["type"].as_str() {
-                                    Some("text") => {
-                                        content_blocks.push(ContentBlock::Text {
-                                            text: String::new(),
-                                        });
-                                    }
-                                    Some("tool_use") => {
-                                        let idx = content_blocks.len();
-                                        content_blocks.push(ContentBlock::ToolUse {
-                                        id: cb["id"].as_str().unwrap_or("").to_string(),
-                                        name: cb["name"].as_str().unwrap_or("").to_string(),
-                                        input: serde_json::Value::Object(serde_json::Map::new()),
-                                    });
-                                        tool_input_bufs.insert(idx, String::new());
-                                    }
-                                    _ => {}
-                                }
-                            }
-                            "content_block_delta" => {
-                                let index = parsed["index"].as_u64().unwrap_or(0) as usize;
-                                let delta = &parsed["delta"];
-
-                                match delta["type"].as_str() {
-                                    Some("text_delta") => {
-                                        if let Some(text) = delta["text"].as_str() {
-                                            callback(text);
-                                            if let Some(ContentBlock::Text { text: ref mut t }) =
-                                                content_blocks.get_mut(index)
-                                            {
-                                                t.push_str(text);
-                                            }
-                                        }
-                                    }
-                                    Some("input_json_delta") => {
-                                        if let Some(partial) = delta["partial_json"].as_str() {
-                                            if let Some(buf) = tool_input_bufs.get_mut(&index) {
-                                                buf.push_str(partial);
-                                            }
-                                        }
-                                    }
-                                    _ => {}
+                        }
+                        Some("input_json_delta") => {
+                            if let Some(partial) = delta["partial_json"].as_str() {
+                                sink(&StreamEvent::InputJsonDelta {
+                                    index,
+                                    partial_json: partial.to_string(),
+                                });
+                                if let Some(buf) = tool_input_bufs.get_mut(&index) {
+                                    buf.push_str(partial);
                                 }
                             }
-                            "content_block_stop" => {
-                                let index = parsed["index"].as_u64().unwrap_or(0) as usize;
-                                // Finalize tool_use input JSON
-                                if let Some(json_str) = tool_input_bufs.remove(&index) {
-                                    if let Some(ContentBlock::ToolUse { ref mut input, .. }) =
-                                        content_blocks.get_mut(index)
-                                    {
-                                        match serde_json::from_str::<serde_json::Value>(&json_str) {
-                                            Ok(v) => *input = v,
-                                            Err(e) => {
-                                                eprintln!(
-                                                    "[error] Failed to parse tool input JSON \
+                        }
+                        _ => {}
+                    }
+                }
+                "content_block_stop" => {
+                    let index = parsed["index"].as_u64().unwrap_or(0) as usize;
+                    // Finalize tool_use input JSON
+                    if let Some(json_str) = tool_input_bufs.remove(&index) {
+                        if let Some(ContentBlock::ToolUse { ref mut input, .. }) =
+                            content_blocks.get_mut(index)
+                        {
+                            match serde_json::from_str::<serde_json::Value>(&json_str) {
+                                Ok(v) => *input = v,
+                                Err(e) => {
+                                    eprintln!(
+                                        "[error] Failed to parse tool input JSON \
                                                      for block {index}: {e}"
-                                                );
-                                                // Set to Null so run_pre_dispatch's null-input
-                                                // check catches it and produces a clean error
-                                                *input = serde_json::Value::Null;
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            "message_delta" => {
-                                if let Some(sr) = parsed["delta"]["stop_reason"].as_str() {
-                                    stop_reason = match sr {
-                                        "end_turn" => Some(StopReason::EndTurn),
-                                        "max_tokens" => Some(StopReason::MaxTokens),
-                                        "tool_use" => Some(StopReason::ToolUse),
-                                        _ => None,
-                                    };
-                                }
-                                if let Some(u) = parsed.get("usage") {
-                                    usage.output_tokens = u["output_tokens"].as_u64().unwrap_or(0);
-                                }
-                            }
-                            "error" => {
-                                let err_type =
-                                    parsed["error"]["type"].as_str().unwrap_or("unknown");
-                                let err_msg = parsed["error"]["message"]
-                                    .as_str()
-                                    .unwrap_or("unknown error");
-                                match err_type {
-                                    "invalid_request_error" => {
-                                        return Err(AgentError::StreamParse(format!(
-                                            "{err_type}: {err_msg}"
-                                        )));
-                                    }
-                                    _ => {
-                                        // Unknown/absent error types default to transient —
-                                        // server-side errors are usually temporary (spec R1).
-                                        return Err(AgentError::StreamTransient(format!(
-                                            "{err_type}: {err_msg}"
-                                        )));
-                                    }
+                                    );
+                                    // Set to Null so run_pre_dispatch's null-input
+                                    // check catches it and produces a clean error
+                                    *input = serde_json::Value::Null;
                                 }
                             }
-                            _ => {}
+                        }
+                    }
+                    sink(&StreamEvent::BlockStop { index });
+                }
+                "message_delta" => {
+                    if let Some(sr) = parsed["delta"]["stop_reason"].as_str() {
+                        stop_reason = match sr {
+                            "end_turn" => Some(StopReason::EndTurn),
+                            "max_tokens" => Some(StopReason::MaxTokens),
+                            "tool_use" => Some(StopReason::ToolUse),
+                            _ => None,
+                        };
+                    }
+                    if let Some(u) = parsed.get("usage") {
+                        usage.output_tokens = u["output_tokens"].as_u64().unwrap_or(0);
+                    }
+                    sink(&StreamEvent::MessageDelta {
+                        stop_reason: stop_reason.clone(),
+                        output_tokens: usage.output_tokens,
+                    });
+                }
+                "ping" => sink(&StreamEvent::Ping),
+                "error" => {
+                    let err_type = parsed["error"]["type"].as_str().unwrap_or("unknown");
+                    let err_msg = parsed["error"]["message"]
+                        .as_str()
+                        .unwrap_or("unknown error");
+                    sink(&StreamEvent::Error {
+                        error_type: err_type.to_string(),
+                        message: err_msg.to_string(),
+                    });
+                    match err_type {
+                        "invalid_request_error" => {
+                            return Err(AgentError::StreamParse(format!("{err_type}: {err_msg}")));
+                        }
+                        _ => {
+                            // Unknown/absent error types default to transient —
+                            // server-side errors are usually temporary (spec R1).
+                            return Err(AgentError::StreamTransient(format!(
+                                "{err_type}: {err_msg}"
+                            )));
                         }
                     }
                 }
+                _ => {}
             }
-            buffer.drain(..pos + 2);
         }
     }
+    decoder.finish();
 
     let stop = stop_reason.ok_or_else(|| {
         AgentError::StreamTransient(
@@ -366,6 +640,77 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::auth::AuthConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const TEST_IDLE: Duration = Duration::from_secs(5);
+
+    /// Answer one request per connection with the next canned response,
+    /// returning each request's `x-api-key`.
+    async fn serve(listener: tokio::net::TcpListener, responses: Vec<&'static str>) -> Vec<String> {
+        let mut keys = Vec::new();
+        for response in responses {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = conn.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length = text
+                        .lines()
+                        .find_map(|l| l.strip_prefix("content-length: "))
+                        .map_or(0, |v| v.trim().parse::<usize>().unwrap());
+                    if request.len() >= end + 4 + length || n == 0 {
+                        break;
+                    }
+                }
+            }
+            let text = String::from_utf8_lossy(&request);
+            keys.extend(
+                text.lines()
+                    .find_map(|l| l.strip_prefix("x-api-key: "))
+                    .map(str::to_string),
+            );
+            conn.write_all(response.as_bytes()).await.unwrap();
+            conn.shutdown().await.unwrap();
+        }
+        keys
+    }
+
+    #[tokio::test]
+    async fn count_tokens_refreshes_credential_and_records_rate_limits() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve(
+            listener,
+            vec![
+                "HTTP/1.1 401 Unauthorized\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+                 anthropic-ratelimit-requests-limit: 50\r\n\
+                 anthropic-ratelimit-requests-remaining: 49\r\n\
+                 content-length: 19\r\nconnection: close\r\n\r\n{\"input_tokens\":42}",
+            ],
+        ));
+        let dir = tempfile::tempdir().unwrap();
+        let counter = dir.path().join("count");
+        let client = AnthropicClient::new(&url).with_auth(Auth::from_config(&AuthConfig {
+            api_key_helper: Some(format!(
+                "echo x >> {p}; printf 'key-%s' $(wc -l < {p})",
+                p = counter.display()
+            )),
+            ..AuthConfig::default()
+        }));
+
+        let tokens = client
+            .count_tokens("claude-test", "sys", &[], &[])
+            .await
+            .unwrap();
+        assert_eq!(tokens, 42);
+        assert_eq!(server.await.unwrap(), ["key-1", "key-2"]);
+        assert!(client.rate_limit().is_some());
+    }
 
     #[test]
     fn stop_reason_serialization() {
@@ -439,8 +784,10 @@ mod tests {
         ))]);
 
         let mut streamed = String::new();
-        let (blocks, stop, _usage) = parse_sse_stream(stream, &mut |text| {
-            streamed.push_str(text);
+        let (blocks, stop, _usage) = parse_sse_stream(stream, TEST_IDLE, &mut |event| {
+            if let StreamEvent::TextDelta { text, .. } = event {
+                streamed.push_str(text);
+            }
         })
         .await
         .unwrap();
@@ -472,7 +819,9 @@ mod tests {
         let stream =
             futures_util::stream::iter(vec![Ok::<_, reqwest::Error>(bytes::Bytes::from(sse_data))]);
 
-        let (blocks, stop, _usage) = parse_sse_stream(stream, &mut |_| {}).await.unwrap();
+        let (blocks, stop, _usage) = parse_sse_stream(stream, TEST_IDLE, &mut |_| {})
+            .await
+            .unwrap();
 
         assert_eq!(stop, StopReason::ToolUse);
         assert_eq!(blocks.len(), 1);
@@ -486,6 +835,154 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn parse_sse_emits_typed_events_in_order() {
+        let sse_data = concat!(
+            "event: message_start\n",
+            "data: {\"type\":\"message_start\",\"message\":{\"id\":\"msg_1\",\"usage\":{\"input_tokens\":42}}}\n\n",
+            "event: content_block_start\n",
+            "data: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"tool_use\",\"id\":\"tu_1\",\"name\":\"Bash\",\"input\":{}}}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\"{\\\"command\\\": \\\"ls\\\"}\"}}\n\n",
+            "event: content_block_stop\n",
+            "data: {\"type\":\"content_block_stop\",\"index\":0}\n\n",
+            "event: message_delta\n",
+            "data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"tool_use\"},\"usage\":{\"output_tokens\":12}}\n\n",
+        );
+
+        let stream =
+            futures_util::stream::iter(vec![Ok::<_, reqwest::Error>(bytes::Bytes::from(sse_data))]);
+
+        let mut events: Vec<StreamEvent> = Vec::new();
+        parse_sse_stream(stream, TEST_IDLE, &mut |event| events.push(event.clone()))
+            .await
+            .unwrap();
+
+        assert_eq!(events.len(), 5);
+        assert!(matches!(
+            &events[0],
+            StreamEvent::MessageStart { usage } if usage.input_tokens == 42
+        ));
+        // Tool start is visible before any input JSON arrives
+        assert!(matches!(
+            &events[1],
+            StreamEvent::BlockStart { index: 0, block: ContentBlock::ToolUse { name, .. } } if name == "Bash"
+        ));
+        assert!(matches!(
+            &events[2],
+            StreamEvent::InputJsonDelta { index: 0, partial_json } if partial_json.contains("command")
+        ));
+        assert!(matches!(&events[3], StreamEvent::BlockStop { index: 0 }));
+        assert!(matches!(
+            &events[4],
+            StreamEvent::MessageDelta {
+                stop_reason: Some(StopReason::ToolUse),
+                output_tokens: 12
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn parse_sse_error_event_emitted_before_abort() {
+        let sse_data = concat!(
+            "event: error\n",
+            "data: {\"type\":\"error\",\"error\":{\"type\":\"overloaded_error\",\"message\":\"Overloaded\"}}\n\n",
+        );
+
+        let stream =
+            futures_util::stream::iter(vec![Ok::<_, reqwest::Error>(bytes::Bytes::from(sse_data))]);
+
+        let mut seen_error = None;
+        let result = parse_sse_stream(stream, TEST_IDLE, &mut |event| {
+            if let StreamEvent::Error { error_type, .. } = event {
+                seen_error = Some(error_type.clone());
+            }
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(seen_error.as_deref(), Some("overloaded_error"));
+    }
+
+    #[tokio::test]
+    async fn parse_sse_crlf_proxy_with_split_multibyte_text() {
+        let sse = concat!(
+            "event: content_block_start\r\n",
+            "data: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}\r\n\r\n",
+            "event: content_block_delta\r\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"caf\u{e9} \u{1f680}\"}}\r\n\r\n",
+            "event: message_delta\r\n",
+            "data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"end_turn\"}}\r\n\r\n",
+        )
+        .as_bytes();
+        // Three-byte chunks split both the CRLF pairs and the multi-byte characters.
+        let chunks: Vec<Result<bytes::Bytes, reqwest::Error>> = sse
+            .chunks(3)
+            .map(|c| Ok(bytes::Bytes::copy_from_slice(c)))
+            .collect();
+
+        let (blocks, stop, _) =
+            parse_sse_stream(futures_util::stream::iter(chunks), TEST_IDLE, &mut |_| {})
+                .await
+                .unwrap();
+        assert_eq!(stop, StopReason::EndTurn);
+        match &blocks[0] {
+            ContentBlock::Text { text } => assert_eq!(text, "caf\u{e9} \u{1f680}"),
+            other => panic!("expected text block, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn parse_sse_stall_after_partial_response() {
+        let sse_data = concat!(
+            "event: message_start\n",
+            "data: {\"type\":\"message_start\",\"message\":{\"id\":\"msg_1\"}}\n\n",
+        );
+        let stream =
+            futures_util::stream::iter(vec![Ok::<_, reqwest::Error>(bytes::Bytes::from(sse_data))])
+                .chain(futures_util::stream::pending());
+
+        let err = parse_sse_stream(stream, Duration::from_millis(50), &mut |_| {})
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AgentError::StreamStalled { .. }));
+        assert_eq!(classify_error(&err), ErrorClass::Transient);
+    }
+
+    #[tokio::test]
+    async fn parse_sse_pings_keep_stream_alive() {
+        let events = [
+            "event: message_start\ndata: {\"type\":\"message_start\",\"message\":{}}\n\n",
+            "event: ping\ndata: {\"type\": \"ping\"}\n\n",
+            "event: ping\ndata: {\"type\": \"ping\"}\n\n",
+            "event: message_delta\ndata: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"end_turn\"}}\n\n",
+        ];
+        // Each chunk arrives 30ms after the last: longer in total than the
+        // 50ms idle timeout, but no single gap exceeds it.
+        let stream = Box::pin(futures_util::stream::iter(events).then(|e| async move {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            Ok::<_, reqwest::Error>(bytes::Bytes::from(e))
+        }));
+
+        let mut pings = 0;
+        let (_, stop, _) = parse_sse_stream(stream, Duration::from_millis(50), &mut |event| {
+            if matches!(event, StreamEvent::Ping) {
+                pings += 1;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(stop, StopReason::EndTurn);
+        assert_eq!(pings, 2);
+    }
+
+    #[test]
+    fn request_timeout_scales_with_max_tokens() {
+        assert_eq!(request_timeout(0), Duration::from_secs(300));
+        assert_eq!(request_timeout(16384), Duration::from_secs(300 + 1638));
+        assert!(request_timeout(64000) > request_timeout(16384));
+    }
+
     #[tokio::test]
     async fn parse_sse_error_event_transient() {
         let sse_data = concat!(
@@ -496,7 +993,9 @@ mod tests {
         let stream =
             futures_util::stream::iter(vec![Ok::<_, reqwest::Error>(bytes::Bytes::from(sse_data))]);
 
-        let err = parse_sse_stream(stream, &mut |_| {}).await.unwrap_err();
+        let err = parse_sse_stream(stream, TEST_IDLE, &mut |_| {})
+            .await
+            .unwrap_err();
         assert!(matches!(err, AgentError::StreamTransient(_)));
     }
 
@@ -510,7 +1009,9 @@ mod tests {
         let stream =
             futures_util::stream::iter(vec![Ok::<_, reqwest::Error>(bytes::Bytes::from(sse_data))]);
 
-        let err = parse_sse_stream(stream, &mut |_| {}).await.unwrap_err();
+        let err = parse_sse_stream(stream, TEST_IDLE, &mut |_| {})
+            .await
+            .unwrap_err();
         assert!(matches!(err, AgentError::StreamParse(_)));
     }
 
@@ -526,7 +1027,9 @@ mod tests {
         let stream =
             futures_util::stream::iter(vec![Ok::<_, reqwest::Error>(bytes::Bytes::from(sse_data))]);
 
-        let err = parse_sse_stream(stream, &mut |_| {}).await.unwrap_err();
+        let err = parse_sse_stream(stream, TEST_IDLE, &mut |_| {})
+            .await
+            .unwrap_err();
         assert!(
             matches!(err, AgentError::StreamTransient(_)),
             "unknown error type should be transient, got: {err}"
@@ -546,7 +1049,9 @@ mod tests {
         let stream =
             futures_util::stream::iter(vec![Ok::<_, reqwest::Error>(bytes::Bytes::from(sse_data))]);
 
-        let err = parse_sse_stream(stream, &mut |_| {}).await.unwrap_err();
+        let err = parse_sse_stream(stream, TEST_IDLE, &mut |_| {})
+            .await
+            .unwrap_err();
         assert!(
             matches!(err, AgentError::StreamTransient(_)),
             "absent error.type should be transient, got: {err}"
@@ -572,7 +1077,9 @@ mod tests {
         let stream =
             futures_util::stream::iter(vec![Ok::<_, reqwest::Error>(bytes::Bytes::from(sse_data))]);
 
-        let err = parse_sse_stream(stream, &mut |_| {}).await.unwrap_err();
+        let err = parse_sse_stream(stream, TEST_IDLE, &mut |_| {})
+            .await
+            .unwrap_err();
         assert!(matches!(err, AgentError::StreamTransient(_)));
     }
 
@@ -697,7 +1204,9 @@ mod tests {
         let stream =
             futures_util::stream::iter(vec![Ok::<_, reqwest::Error>(bytes::Bytes::from(sse_data))]);
 
-        let (_blocks, _stop, usage) = parse_sse_stream(stream, &mut |_| {}).await.unwrap();
+        let (_blocks, _stop, usage) = parse_sse_stream(stream, TEST_IDLE, &mut |_| {})
+            .await
+            .unwrap();
 
         assert_eq!(usage.input_tokens, 1500);
         assert_eq!(usage.output_tokens, 350);
@@ -760,7 +1269,7 @@ mod tests {
 
     #[test]
     fn cache_control_with_single_tool() {
-        let tools = vec![serde_json::json!({"name": "Read", "description": "Read files"})];
+        let tools = [serde_json::json!({"name": "Read", "description": "Read files"})];
 
         let mut cached_tools = tools.to_vec();
         if let Some(last) = cached_tools.last_mut() {
@@ -787,6 +1296,130 @@ mod tests {
         assert!(body.get("tools").is_none());
     }
 
+    fn text_msg(role: &str, text: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: vec![ContentBlock::Text {
+                text: text.to_string(),
+            }],
+        }
+    }
+
+    fn count_breakpoints(body: &serde_json::Value) -> usize {
+        body.to_string().matches("cache_control").count()
+    }
+
+    fn message_has_breakpoint(msg: &serde_json::Value) -> bool {
+        msg["content"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|b| b.get("cache_control").is_some())
+    }
+
+    #[test]
+    fn rolling_breakpoints_on_most_recent_user_messages() {
+        let tools = vec![serde_json::json!({"name": "Read", "description": "Read files"})];
+        let messages = vec![
+            text_msg("user", "first"),
+            text_msg("assistant", "a1"),
+            Message {
+                role: "user".to_string(),
+                content: vec![ContentBlock::ToolResult {
+                    tool_use_id: "tu_1".to_string(),
+                    content: "output".to_string(),
+                    is_error: None,
+                }],
+            },
+            text_msg("assistant", "a2"),
+            text_msg("user", "latest"),
+        ];
+
+        let body = build_request_body("m", 1024, "sys", &messages, &tools).unwrap();
+        let msgs = body["messages"].as_array().unwrap();
+
+        // System + last tool + two rolling message breakpoints = API limit
+        assert_eq!(count_breakpoints(&body), MAX_CACHE_BREAKPOINTS);
+        assert!(!message_has_breakpoint(&msgs[0]));
+        assert!(!message_has_breakpoint(&msgs[1]));
+        assert!(message_has_breakpoint(&msgs[2]), "tool_result is cacheable");
+        assert!(!message_has_breakpoint(&msgs[3]), "assistant never marked");
+        assert!(message_has_breakpoint(&msgs[4]));
+        assert_eq!(msgs[2]["content"][0]["cache_control"]["type"], "ephemeral");
+    }
+
+    #[test]
+    fn rolling_breakpoints_use_tool_budget_when_no_tools() {
+        let messages = vec![
+            text_msg("user", "u1"),
+            text_msg("assistant", "a1"),
+            text_msg("user", "u2"),
+            text_msg("assistant", "a2"),
+            text_msg("user", "u3"),
+            text_msg("assistant", "a3"),
+            text_msg("user", "u4"),
+        ];
+        let body = build_request_body("m", 1024, "sys", &messages, &[]).unwrap();
+        let msgs = body["messages"].as_array().unwrap();
+
+        assert_eq!(count_breakpoints(&body), MAX_CACHE_BREAKPOINTS);
+        assert!(!message_has_breakpoint(&msgs[0]));
+        assert!(message_has_breakpoint(&msgs[2]));
+        assert!(message_has_breakpoint(&msgs[4]));
+        assert!(message_has_breakpoint(&msgs[6]));
+    }
+
+    #[test]
+    fn rolling_breakpoints_replaced_after_prefix_change() {
+        let tools = vec![serde_json::json!({"name": "Read", "description": "Read files"})];
+        let mut messages = vec![
+            text_msg("user", "u1"),
+            text_msg("assistant", "a1"),
+            text_msg("user", "u2"),
+            text_msg("assistant", "a2"),
+            text_msg("user", "u3"),
+        ];
+        let before = build_request_body("m", 1024, "sys", &messages, &tools).unwrap();
+        assert_eq!(count_breakpoints(&before), MAX_CACHE_BREAKPOINTS);
+
+        // Simulate trim (front removed) and recovery (trailing user popped)
+        messages.drain(1..3);
+        messages.pop();
+        messages.push(text_msg("assistant", "a3"));
+        messages.push(text_msg("user", "u4"));
+
+        let after = build_request_body("m", 1024, "sys", &messages, &tools).unwrap();
+        let msgs = after["messages"].as_array().unwrap();
+        assert_eq!(count_breakpoints(&after), MAX_CACHE_BREAKPOINTS);
+        // Remaining: u1, a2, a3, u4 — markers follow the new user positions
+        assert!(message_has_breakpoint(&msgs[0]));
+        assert!(!message_has_breakpoint(&msgs[1]));
+        assert!(!message_has_breakpoint(&msgs[2]));
+        assert!(message_has_breakpoint(&msgs[3]));
+    }
+
+    #[test]
+    fn request_body_never_exceeds_breakpoint_limit() {
+        let tools = vec![serde_json::json!({"name": "Read", "description": "Read files"})];
+        let messages: Vec<Message> = (0..40)
+            .map(|i| text_msg(if i % 2 == 0 { "user" } else { "assistant" }, "x"))
+            .collect();
+        let body = build_request_body("m", 1024, "sys", &messages, &tools).unwrap();
+        assert!(count_breakpoints(&body) <= MAX_CACHE_BREAKPOINTS);
+    }
+
+    #[test]
+    fn cache_hit_rate_from_usage_fields() {
+        let usage = Usage {
+            input_tokens: 100,
+            output_tokens: 50,
+            cache_creation_input_tokens: 100,
+            cache_read_input_tokens: 800,
+        };
+        assert!((usage.cache_hit_rate() - 0.8).abs() < f64::EPSILON);
+        assert_eq!(Usage::default().cache_hit_rate(), 0.0);
+    }
+
     #[tokio::test]
     async fn parse_sse_malformed_tool_input_sets_null() {
         // Simulate truncated/malformed tool input JSON: partial JSON that
@@ -806,7 +1439,9 @@ mod tests {
         let stream =
             futures_util::stream::iter(vec![Ok::<_, reqwest::Error>(bytes::Bytes::from(sse_data))]);
 
-        let (blocks, _stop, _usage) = parse_sse_stream(stream, &mut |_| {}).await.unwrap();
+        let (blocks, _stop, _usage) = parse_sse_stream(stream, TEST_IDLE, &mut |_| {})
+            .await
+            .unwrap();
 
         assert_eq!(blocks.len(), 1);
         if let ContentBlock::ToolUse { id, input, .. } = &blocks[0] {
@@ -841,7 +1476,9 @@ mod tests {
         let stream =
             futures_util::stream::iter(vec![Ok::<_, reqwest::Error>(bytes::Bytes::from(sse_data))]);
 
-        let (blocks, stop, _usage) = parse_sse_stream(stream, &mut |_| {}).await.unwrap();
+        let (blocks, stop, _usage) = parse_sse_stream(stream, TEST_IDLE, &mut |_| {})
+            .await
+            .unwrap();
         assert_eq!(stop, StopReason::EndTurn);
         assert_eq!(blocks.len(), 1);
         if let ContentBlock::Text { text } = &blocks[0] {
@@ -0,0 +1,321 @@
+use crate::api::AgentError;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const HELPER_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_HELPER_TTL_SECS: u64 = 3600;
+
+/// How the credential is attached to each request.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum AuthScheme {
+    /// `x-api-key: <credential>` (direct Anthropic API).
+    #[serde(rename = "x-api-key")]
+    ApiKey,
+    /// `Authorization: Bearer <credential>` (gateways, OAuth tokens).
+    #[serde(rename = "bearer")]
+    Bearer,
+    /// No credential header; an upstream proxy authenticates.
+    #[serde(rename = "none")]
+    None,
+}
+
+/// `[auth]` section of `.forgeflare/config.toml`.
+#[derive(Debug, Default, Deserialize)]
+pub struct AuthConfig {
+    pub scheme: Option<AuthScheme>,
+    /// Shell command whose trimmed stdout is the credential.
+    pub api_key_helper: Option<String>,
+    /// Re-run the helper after this many seconds (default 3600).
+    pub api_key_helper_ttl_secs: Option<u64>,
+    /// Extra headers sent on every request (e.g. `anthropic-beta`, routing headers).
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+enum CredentialSource {
+    Static(Option<String>),
+    Helper {
+        command: String,
+        ttl: Duration,
+        cached: Mutex<Option<(String, Instant)>>,
+    },
+}
+
+/// Request authentication: scheme, credential source, and static headers.
+pub struct Auth {
+    scheme: AuthScheme,
+    source: CredentialSource,
+    headers: HeaderMap,
+}
+
+impl Auth {
+    /// `ANTHROPIC_API_KEY` as `x-api-key`, else `ANTHROPIC_AUTH_TOKEN` as a
+    /// bearer token, else no credential (OAuth proxy mode).
+    pub fn from_env() -> Self {
+        let (scheme, credential) = match (
+            std::env::var("ANTHROPIC_API_KEY").ok(),
+            std::env::var("ANTHROPIC_AUTH_TOKEN").ok(),
+        ) {
+            (Some(key), _) => (AuthScheme::ApiKey, Some(key)),
+            (None, Some(token)) => (AuthScheme::Bearer, Some(token)),
+            (None, None) => (AuthScheme::None, None),
+        };
+        Self {
+            scheme,
+            source: CredentialSource::Static(credential),
+            headers: HeaderMap::new(),
+        }
+    }
+
+    /// Layer config over the environment. A helper replaces the env credential
+    /// and defaults the scheme to `x-api-key` unless one is configured.
+    pub fn from_config(config: &AuthConfig) -> Self {
+        let mut auth = Self::from_env();
+
+        if let Some(ref command) = config.api_key_helper {
+            auth.source = CredentialSource::Helper {
+                command: command.clone(),
+                ttl: Duration::from_secs(
+                    config
+                        .api_key_helper_ttl_secs
+                        .unwrap_or(DEFAULT_HELPER_TTL_SECS),
+                ),
+                cached: Mutex::new(None),
+            };
+            if auth.scheme == AuthScheme::None {
+                auth.scheme = AuthScheme::ApiKey;
+            }
+        }
+        if let Some(scheme) = config.scheme {
+            auth.scheme = scheme;
+        }
+
+        for (name, value) in &config.headers {
+            match (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                (Ok(n), Ok(mut v)) => {
+                    v.set_sensitive(true);
+                    auth.headers.insert(n, v);
+                }
+                _ => eprintln!("[auth] Ignoring invalid header {name:?}"),
+            }
+        }
+
+        auth
+    }
+
+    /// Safe to print: names the scheme and source, never the credential or header values.
+    pub fn describe(&self) -> String {
+        let source = match &self.source {
+            CredentialSource::Static(Some(_)) => "from environment".to_string(),
+            CredentialSource::Static(None) => "no credential".to_string(),
+            CredentialSource::Helper { ttl, .. } => {
+                format!("from api_key_helper, refreshed every {}s", ttl.as_secs())
+            }
+        };
+        let scheme = match self.scheme {
+            AuthScheme::ApiKey => "x-api-key",
+            AuthScheme::Bearer => "bearer",
+            AuthScheme::None => "none (OAuth proxy mode)",
+        };
+        let mut desc = format!("{scheme} ({source})");
+        if !self.headers.is_empty() {
+            let mut names: Vec<&str> = self.headers.keys().map(|k| k.as_str()).collect();
+            names.sort_unstable();
+            desc.push_str(&format!(", extra headers: {}", names.join(", ")));
+        }
+        desc
+    }
+
+    /// True when a 401 may be fixed by fetching a fresh credential.
+    pub fn can_refresh(&self) -> bool {
+        matches!(self.source, CredentialSource::Helper { .. })
+    }
+
+    /// Drop the cached helper credential so the next request re-runs the helper.
+    pub async fn invalidate(&self) {
+        if let CredentialSource::Helper { cached, .. } = &self.source {
+            *cached.lock().await = None;
+        }
+    }
+
+    /// Static headers plus the credential header for the current credential.
+    pub async fn headers(&self) -> Result<HeaderMap, AgentError> {
+        let mut headers = self.headers.clone();
+        if self.scheme == AuthScheme::None {
+            return Ok(headers);
+        }
+        let Some(credential) = self.credential().await? else {
+            return Ok(headers);
+        };
+        let (name, value) = match self.scheme {
+            AuthScheme::ApiKey => (HeaderName::from_static("x-api-key"), credential),
+            AuthScheme::Bearer => (AUTHORIZATION, format!("Bearer {credential}")),
+            AuthScheme::None => unreachable!(),
+        };
+        let mut value = HeaderValue::from_str(&value).map_err(|_| {
+            AgentError::Auth("credential contains invalid header characters".into())
+        })?;
+        value.set_sensitive(true);
+        headers.insert(name, value);
+        Ok(headers)
+    }
+
+    async fn credential(&self) -> Result<Option<String>, AgentError> {
+        match &self.source {
+            CredentialSource::Static(c) => Ok(c.clone()),
+            CredentialSource::Helper {
+                command,
+                ttl,
+                cached,
+            } => {
+                let mut guard = cached.lock().await;
+                if let Some((ref value, fetched_at)) = *guard {
+                    if fetched_at.elapsed() < *ttl {
+                        return Ok(Some(value.clone()));
+                    }
+                }
+                let value = run_helper(command).await?;
+                *guard = Some((value.clone(), Instant::now()));
+                Ok(Some(value))
+            }
+        }
+    }
+}
+
+/// Run the helper via `bash -c` and return its trimmed stdout. Error messages
+/// carry the exit status only; helper output may contain the secret.
+async fn run_helper(command: &str) -> Result<String, AgentError> {
+    let child = tokio::process::Command::new("bash")
+        .arg("-c")
+        .arg(command)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .output();
+
+    let output = tokio::time::timeout(HELPER_TIMEOUT, child)
+        .await
+        .map_err(|_| {
+            AgentError::Auth(format!(
+                "api_key_helper timed out after {}s",
+                HELPER_TIMEOUT.as_secs()
+            ))
+        })?
+        .map_err(|e| AgentError::Auth(format!("api_key_helper failed to start: {e}")))?;
+
+    if !output.status.success() {
+        return Err(AgentError::Auth(format!(
+            "api_key_helper exited with {}",
+            output.status
+        )));
+    }
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if value.is_empty() {
+        return Err(AgentError::Auth(
+            "api_key_helper produced no output".to_string(),
+        ));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn helper_config(command: &str) -> AuthConfig {
+        AuthConfig {
+            api_key_helper: Some(command.to_string()),
+            ..AuthConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn bearer_scheme_from_helper() {
+        let auth = Auth::from_config(&AuthConfig {
+            scheme: Some(AuthScheme::Bearer),
+            ..helper_config("echo '  tok-123  '")
+        });
+        let headers = auth.headers().await.unwrap();
+        assert_eq!(headers[AUTHORIZATION], "Bearer tok-123");
+        assert!(headers.get("x-api-key").is_none());
+    }
+
+    #[tokio::test]
+    async fn helper_cached_until_invalidated() {
+        let dir = tempfile::tempdir().unwrap();
+        let counter = dir.path().join("count");
+        let cmd = format!(
+            "echo x >> {p}; printf 'key-%s' $(wc -l < {p})",
+            p = counter.display()
+        );
+        let auth = Auth::from_config(&helper_config(&cmd));
+        assert!(auth.can_refresh());
+
+        let first = auth.headers().await.unwrap();
+        let second = auth.headers().await.unwrap();
+        assert_eq!(first["x-api-key"], "key-1");
+        assert_eq!(second["x-api-key"], "key-1");
+
+        auth.invalidate().await;
+        let third = auth.headers().await.unwrap();
+        assert_eq!(third["x-api-key"], "key-2");
+    }
+
+    #[tokio::test]
+    async fn helper_ttl_expiry_refetches() {
+        let dir = tempfile::tempdir().unwrap();
+        let counter = dir.path().join("count");
+        let cmd = format!(
+            "echo x >> {p}; printf 'key-%s' $(wc -l < {p})",
+            p = counter.display()
+        );
+        let auth = Auth::from_config(&AuthConfig {
+            api_key_helper_ttl_secs: Some(0),
+            ..helper_config(&cmd)
+        });
+        auth.headers().await.unwrap();
+        let again = auth.headers().await.unwrap();
+        assert_eq!(again["x-api-key"], "key-2");
+    }
+
+    #[tokio::test]
+    async fn helper_failure_is_auth_error_without_output() {
+        let auth = Auth::from_config(&helper_config("echo secret-ish; exit 3"));
+        let err = auth.headers().await.unwrap_err();
+        let msg = err.to_string();
+        assert!(matches!(err, AgentError::Auth(_)));
+        assert!(msg.contains("exit status: 3"), "{msg}");
+        assert!(!msg.contains("secret-ish"));
+    }
+
+    #[tokio::test]
+    async fn static_headers_applied_and_described_without_values() {
+        let mut headers = HashMap::new();
+        headers.insert(
+            "anthropic-beta".to_string(),
+            "prompt-caching-2024-07-31".to_string(),
+        );
+        headers.insert("x-team".to_string(), "platform".to_string());
+        headers.insert("bad header".to_string(), "v".to_string());
+        let auth = Auth::from_config(&AuthConfig {
+            scheme: Some(AuthScheme::None),
+            headers,
+            ..AuthConfig::default()
+        });
+
+        let applied = auth.headers().await.unwrap();
+        assert_eq!(applied["anthropic-beta"], "prompt-caching-2024-07-31");
+        assert_eq!(applied.len(), 2);
+
+        let desc = auth.describe();
+        assert!(desc.contains("anthropic-beta, x-team"), "{desc}");
+        assert!(!desc.contains("platform"));
+    }
+}
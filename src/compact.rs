@@ -0,0 +1,291 @@
+//! Summary compaction: replace the oldest exchanges with a model-written
+//! summary instead of dropping them.
+
+use crate::api::{AgentError, AnthropicClient, ContentBlock, Message, StreamEvent, Usage};
+use crate::tokens::TokenEstimator;
+
+/// Output budget for the summary request. Also reserved out of the trim
+/// target so the summary itself fits once spliced in.
+pub const SUMMARY_MAX_TOKENS: u32 = 4096;
+/// Tool inputs and results longer than this are cut in the summarizer transcript.
+const TOOL_TEXT_LIMIT: usize = 2000;
+pub const SUMMARY_HEADER: &str = "[Summary of earlier conversation]";
+const SUMMARY_ACK: &str =
+    "Earlier exchanges were compacted. Send the summary and I will continue from it.";
+
+const SUMMARY_SYSTEM_PROMPT: &str = "You compress the history of a coding-agent session. \
+Write a concise summary the agent can continue from. Cover: the user's goal and constraints, \
+files read or changed and what changed, commands run and their outcomes, approaches that failed \
+and why, decisions made, and work still outstanding. Prefer concrete names (paths, functions, \
+errors) over prose. Do not invent details.";
+
+/// Exclusive end of the range `1..end` to summarize so the remaining
+/// messages fit `budget_tokens`. `messages[end]` is always an assistant
+/// message: the kept tail then starts a fresh exchange, so no tool_result
+/// is left without its tool_use. When no boundary gets under budget the
+/// latest one is used; `None` if there is nothing to compact.
+pub fn compaction_end(
+    messages: &[Message],
+    estimator: &TokenEstimator,
+    budget_tokens: u64,
+) -> Option<usize> {
+    let sizes: Vec<u64> = messages
+        .iter()
+        .map(|m| estimator.message_tokens(m))
+        .collect();
+    let total: u64 = sizes.iter().sum();
+    if total <= budget_tokens {
+        return None;
+    }
+
+    let mut removed = 0;
+    let mut best = None;
+    for end in 2..messages.len() {
+        removed += sizes[end - 1];
+        if messages[end].role != "assistant" {
+            continue;
+        }
+        best = Some(end);
+        if total - removed <= budget_tokens {
+            break;
+        }
+    }
+    best
+}
+
+/// Replace `messages[1..end]` with a synthetic exchange carrying `summary`.
+pub fn apply_summary(messages: &mut Vec<Message>, end: usize, summary: &str) {
+    let exchange = [
+        Message {
+            role: "assistant".to_string(),
+            content: vec![ContentBlock::Text {
+                text: SUMMARY_ACK.to_string(),
+            }],
+        },
+        Message {
+            role: "user".to_string(),
+            content: vec![ContentBlock::Text {
+                text: format!("{SUMMARY_HEADER}\n\n{summary}"),
+            }],
+        },
+    ];
+    messages.splice(1..end, exchange);
+}
+
+/// Plain-text rendering of messages for the summarizer, with long tool
+/// inputs and results cut down.
+pub fn render_transcript(messages: &[Message]) -> String {
+    let mut out = String::new();
+    for msg in messages {
+        let speaker = if msg.role == "assistant" {
+            "Assistant"
+        } else {
+            "User"
+        };
+        for block in &msg.content {
+            match block {
+                ContentBlock::Text { text } => {
+                    out.push_str(&format!("{speaker}: {text}\n\n"));
+                }
+                ContentBlock::ToolUse { name, input, .. } => {
+                    let input = input.to_string();
+                    out.push_str(&format!(
+                        "{speaker} called {name}: {}\n\n",
+                        clip(&input, TOOL_TEXT_LIMIT)
+                    ));
+                }
+                ContentBlock::ToolResult {
+                    content, is_error, ..
+                } => {
+                    let label = if *is_error == Some(true) {
+                        "Tool error"
+                    } else {
+                        "Tool result"
+                    };
+                    out.push_str(&format!("{label}: {}\n\n", clip(content, TOOL_TEXT_LIMIT)));
+                }
+            }
+        }
+    }
+    out
+}
+
+fn clip(text: &str, limit: usize) -> String {
+    if text.len() <= limit {
+        return text.to_string();
+    }
+    let end = text.floor_char_boundary(limit);
+    format!("{}... ({} bytes total)", &text[..end], text.len())
+}
+
+/// Ask `model` to summarize `messages`. `instructions` (from `/compact <text>`)
+/// is appended to the request. `sink` sees the summary's stream events.
+/// Returns the summary text and the call's usage.
+pub async fn summarize(
+    client: &AnthropicClient,
+    model: &str,
+    max_tokens: u32,
+    messages: &[Message],
+    instructions: Option<&str>,
+    sink: &mut dyn FnMut(&StreamEvent),
+) -> Result<(String, Usage), AgentError> {
+    let mut prompt = format!(
+        "Summarize this earlier part of the session.\n\n<transcript>\n{}</transcript>",
+        render_transcript(messages)
+    );
+    if let Some(extra) = instructions {
+        prompt.push_str(&format!("\n\nAdditional instructions: {extra}"));
+    }
+    let request = [Message {
+        role: "user".to_string(),
+        content: vec![ContentBlock::Text { text: prompt }],
+    }];
+
+    let (blocks, _, usage) = client
+        .send_message(
+            model,
+            max_tokens,
+            SUMMARY_SYSTEM_PROMPT,
+            &request,
+            &[],
+            sink,
+        )
+        .await?;
+
+    let summary = blocks
+        .iter()
+        .filter_map(|b| match b {
+            ContentBlock::Text { text } => Some(text.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string();
+    Ok((summary, usage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(role: &str, text: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: vec![ContentBlock::Text {
+                text: text.to_string(),
+            }],
+        }
+    }
+
+    fn tool_use(id: &str) -> Message {
+        Message {
+            role: "assistant".to_string(),
+            content: vec![ContentBlock::ToolUse {
+                id: id.to_string(),
+                name: "Read".to_string(),
+                input: serde_json::json!({"file_path": "src/main.rs"}),
+            }],
+        }
+    }
+
+    fn tool_result(id: &str, size: usize) -> Message {
+        Message {
+            role: "user".to_string(),
+            content: vec![ContentBlock::ToolResult {
+                tool_use_id: id.to_string(),
+                content: "x".repeat(size),
+                is_error: None,
+            }],
+        }
+    }
+
+    /// Every tool_result must answer a tool_use in the message just before it.
+    fn pairing_valid(messages: &[Message]) -> bool {
+        messages.iter().enumerate().all(|(i, m)| {
+            m.content.iter().all(|b| match b {
+                ContentBlock::ToolResult { tool_use_id, .. } => {
+                    i > 0
+                        && messages[i - 1].content.iter().any(
+                            |p| matches!(p, ContentBlock::ToolUse { id, .. } if id == tool_use_id),
+                        )
+                }
+                _ => true,
+            })
+        })
+    }
+
+    fn tool_session(pairs: usize) -> Vec<Message> {
+        let mut msgs = vec![text("user", "fix the bug")];
+        for i in 0..pairs {
+            let id = format!("t{i}");
+            msgs.push(tool_use(&id));
+            msgs.push(tool_result(&id, 36_000));
+        }
+        msgs
+    }
+
+    #[test]
+    fn under_budget_is_not_compacted() {
+        let msgs = tool_session(2);
+        assert_eq!(
+            compaction_end(&msgs, &TokenEstimator::default(), 1_000_000),
+            None
+        );
+    }
+
+    #[test]
+    fn end_lands_on_assistant_and_fits_budget() {
+        let est = TokenEstimator::default();
+        let mut msgs = tool_session(10);
+        let end = compaction_end(&msgs, &est, 40_000).unwrap();
+        assert_eq!(msgs[end].role, "assistant");
+
+        apply_summary(&mut msgs, end, "did things");
+        assert!(pairing_valid(&msgs));
+        let roles: Vec<&str> = msgs.iter().map(|m| m.role.as_str()).collect();
+        assert!(roles.windows(2).all(|w| w[0] != w[1]), "{roles:?}");
+        let size: u64 = msgs.iter().map(|m| est.message_tokens(m)).sum();
+        assert!(size <= 40_000, "size {size}");
+    }
+
+    #[test]
+    fn zero_budget_compacts_up_to_last_assistant() {
+        let mut msgs = tool_session(3);
+        msgs.push(text("assistant", "done"));
+        let end = compaction_end(&msgs, &TokenEstimator::default(), 0).unwrap();
+        assert_eq!(end, msgs.len() - 1);
+    }
+
+    #[test]
+    fn nothing_to_compact_without_assistant_boundary() {
+        let msgs = vec![text("user", &"a".repeat(10_000)), tool_use("t0")];
+        assert_eq!(compaction_end(&msgs, &TokenEstimator::default(), 0), None);
+    }
+
+    #[test]
+    fn apply_summary_keeps_first_message_and_tail() {
+        let mut msgs = tool_session(3);
+        let tail = msgs[5].clone();
+        apply_summary(&mut msgs, 5, "summary text");
+        assert_eq!(msgs.len(), 5);
+        assert!(
+            matches!(&msgs[0].content[0], ContentBlock::Text { text } if text == "fix the bug")
+        );
+        assert!(matches!(&msgs[2].content[0], ContentBlock::Text { text }
+            if text.starts_with(SUMMARY_HEADER) && text.ends_with("summary text")));
+        assert_eq!(
+            serde_json::to_string(&msgs[3]).unwrap(),
+            serde_json::to_string(&tail).unwrap()
+        );
+    }
+
+    #[test]
+    fn transcript_clips_long_tool_results() {
+        let msgs = [tool_use("t0"), tool_result("t0", 10_000)];
+        let transcript = render_transcript(&msgs);
+        assert!(transcript.contains("Assistant called Read"));
+        assert!(transcript.contains("(10000 bytes total)"));
+        assert!(transcript.len() < 3_000);
+    }
+}
@@ -0,0 +1,242 @@
+use crate::auth::AuthConfig;
+use crate::cost::ModelPricing;
+use crate::models::ModelOverride;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Project-level settings from `.forgeflare/config.toml`.
+///
+/// ```toml
+/// [models."qwen2.5-coder"]
+/// context_window = 32768
+/// max_output_tokens = 8192
+///
+/// [pricing."claude-sonnet-4"]
+/// input = 3.0
+/// output = 15.0
+/// cache_write = 3.75
+/// cache_read = 0.30
+///
+/// [auth]
+/// scheme = "bearer"
+/// api_key_helper = "vault read -field=token secret/anthropic"
+///
+/// [auth.headers]
+/// anthropic-beta = "context-1m-2025-08-07"
+///
+/// [tools]
+/// disabled = ["Bash"]
+/// diff_context = 5
+///
+/// [sandbox]
+/// enabled = true
+/// writable = ["/tmp", "/home/me/.cargo/registry"]
+/// allowed_ports = [443]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    /// Per-model limit overrides (context window, max output, thinking,
+    /// pricing), keyed by model-name prefix.
+    #[serde(default)]
+    pub models: HashMap<String, ModelOverride>,
+
+    /// Per-model rate overrides in USD per million tokens, keyed by model-name prefix.
+    #[serde(default)]
+    pub pricing: HashMap<String, ModelPricing>,
+
+    /// Credential scheme, key helper, and extra request headers.
+    #[serde(default)]
+    pub auth: AuthConfig,
+
+    /// Which tools are offered to the model.
+    #[serde(default)]
+    pub tools: ToolsConfig,
+
+    /// Filesystem and network confinement for Bash commands; Edit follows
+    /// the same write rules.
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ToolsConfig {
+    /// Tool names to leave out of the registry.
+    pub disabled: Vec<String>,
+    /// Unchanged lines shown around each change in Edit's diffs.
+    pub diff_context: usize,
+}
+
+impl Default for ToolsConfig {
+    fn default() -> Self {
+        Self {
+            disabled: Vec::new(),
+            diff_context: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SandboxConfig {
+    /// Run Bash under Landlock. Bash fails closed if the kernel can't enforce it.
+    pub enabled: bool,
+    /// Writable directories besides the workspace; relative paths are
+    /// resolved against the workspace. Defaults to the system temp directory.
+    pub writable: Vec<PathBuf>,
+    /// Leave networking unrestricted. When false and `allowed_ports` is
+    /// empty, commands get their own network namespace with only loopback.
+    pub network: bool,
+    /// When `network` is false, keep the host network but allow TCP
+    /// connects only to these ports. UDP, DNS included, is not filtered.
+    pub allowed_ports: Vec<u16>,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            writable: vec![std::env::temp_dir()],
+            network: false,
+            allowed_ports: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Missing file yields defaults; a parse error is reported and also yields defaults.
+    pub fn load(config_path: &str) -> Self {
+        match fs::read_to_string(config_path) {
+            Ok(content) => match toml::from_str::<Config>(&content) {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("[config] Failed to parse {config_path}: {e}");
+                    Config::default()
+                }
+            },
+            Err(_) => Config::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthScheme;
+
+    #[test]
+    fn load_missing_file_returns_default() {
+        let config = Config::load("/nonexistent/config.toml");
+        assert!(config.pricing.is_empty());
+    }
+
+    #[test]
+    fn load_disabled_tools() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "[tools]\ndisabled = [\"Bash\", \"Edit\"]\n").unwrap();
+        let config = Config::load(path.to_str().unwrap());
+        assert_eq!(config.tools.disabled, ["Bash", "Edit"]);
+        assert_eq!(config.tools.diff_context, 3);
+    }
+
+    #[test]
+    fn load_sandbox() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "[sandbox]\nenabled = true\nallowed_ports = [443]\n").unwrap();
+        let config = Config::load(path.to_str().unwrap());
+        assert!(config.sandbox.enabled);
+        assert!(!config.sandbox.network);
+        assert_eq!(config.sandbox.allowed_ports, [443]);
+        assert_eq!(config.sandbox.writable, [std::env::temp_dir()]);
+
+        assert!(!Config::default().sandbox.enabled);
+    }
+
+    #[test]
+    fn load_pricing_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            r#"
+[pricing."claude-sonnet-4"]
+input = 2.0
+output = 10.0
+cache_write = 2.5
+cache_read = 0.2
+"#,
+        )
+        .unwrap();
+
+        let config = Config::load(path.to_str().unwrap());
+        let p = config.pricing["claude-sonnet-4"];
+        assert_eq!(p.input, 2.0);
+        assert_eq!(p.cache_read, 0.2);
+    }
+
+    #[test]
+    fn load_model_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            r#"
+[models."claude-sonnet-4"]
+context_window = 1000000
+
+[models."qwen"]
+context_window = 32768
+max_output_tokens = 4096
+supports_thinking = false
+pricing = { input = 0.0, output = 0.0, cache_write = 0.0, cache_read = 0.0 }
+"#,
+        )
+        .unwrap();
+
+        let config = Config::load(path.to_str().unwrap());
+        let sonnet = &config.models["claude-sonnet-4"];
+        assert_eq!(sonnet.context_window, Some(1_000_000));
+        assert_eq!(sonnet.max_output_tokens, None);
+        let qwen = &config.models["qwen"];
+        assert_eq!(qwen.max_output_tokens, Some(4096));
+        assert_eq!(qwen.pricing.unwrap().output, 0.0);
+    }
+
+    #[test]
+    fn load_auth_section() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            r#"
+[auth]
+scheme = "bearer"
+api_key_helper = "cat ~/.token"
+api_key_helper_ttl_secs = 600
+
+[auth.headers]
+x-team = "platform"
+"#,
+        )
+        .unwrap();
+
+        let config = Config::load(path.to_str().unwrap());
+        assert_eq!(config.auth.scheme, Some(AuthScheme::Bearer));
+        assert_eq!(config.auth.api_key_helper.as_deref(), Some("cat ~/.token"));
+        assert_eq!(config.auth.api_key_helper_ttl_secs, Some(600));
+        assert_eq!(config.auth.headers["x-team"], "platform");
+    }
+
+    #[test]
+    fn load_invalid_toml_returns_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "[pricing.\"x\"]\ninput = \"cheap\"\n").unwrap();
+        let config = Config::load(path.to_str().unwrap());
+        assert!(config.pricing.is_empty());
+    }
+}
@@ -0,0 +1,197 @@
+use crate::api::Usage;
+use crate::models::ModelCatalog;
+use serde::Deserialize;
+
+/// USD per million tokens for each of the four `Usage` fields.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct ModelPricing {
+    pub input: f64,
+    pub output: f64,
+    pub cache_write: f64,
+    pub cache_read: f64,
+}
+
+impl ModelPricing {
+    pub(crate) const fn new(input: f64, output: f64, cache_write: f64, cache_read: f64) -> Self {
+        Self {
+            input,
+            output,
+            cache_write,
+            cache_read,
+        }
+    }
+
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        (usage.input_tokens as f64 * self.input
+            + usage.output_tokens as f64 * self.output
+            + usage.cache_creation_input_tokens as f64 * self.cache_write
+            + usage.cache_read_input_tokens as f64 * self.cache_read)
+            / 1_000_000.0
+    }
+}
+
+/// Running token and cost totals across one or more API responses.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsageTotals {
+    pub requests: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cache_creation_input_tokens: u64,
+    pub cache_read_input_tokens: u64,
+    pub cost_usd: f64,
+}
+
+impl UsageTotals {
+    pub fn add(&mut self, usage: &Usage, cost_usd: f64) {
+        self.requests += 1;
+        self.input_tokens += usage.input_tokens;
+        self.output_tokens += usage.output_tokens;
+        self.cache_creation_input_tokens += usage.cache_creation_input_tokens;
+        self.cache_read_input_tokens += usage.cache_read_input_tokens;
+        self.cost_usd += cost_usd;
+    }
+
+    /// All four usage fields — every token the API processed or produced.
+    pub fn total_tokens(&self) -> u64 {
+        self.input_tokens
+            + self.output_tokens
+            + self.cache_creation_input_tokens
+            + self.cache_read_input_tokens
+    }
+}
+
+/// Session-wide cost accounting. Cost is resolved per response from the model
+/// that served it, so a mid-session model change is priced correctly.
+pub struct CostTracker {
+    catalog: ModelCatalog,
+    session: UsageTotals,
+}
+
+impl CostTracker {
+    pub fn new(catalog: ModelCatalog) -> Self {
+        Self {
+            catalog,
+            session: UsageTotals::default(),
+        }
+    }
+
+    pub fn has_pricing(&self, model: &str) -> bool {
+        self.catalog.lookup(model).pricing.is_some()
+    }
+
+    /// Record one response against the session totals and return its cost.
+    /// Models without pricing cost 0.
+    pub fn record(&mut self, model: &str, usage: &Usage) -> f64 {
+        let cost = self
+            .catalog
+            .lookup(model)
+            .pricing
+            .map(|p| p.cost(usage))
+            .unwrap_or(0.0);
+        self.session.add(usage, cost);
+        cost
+    }
+
+    pub fn session(&self) -> &UsageTotals {
+        &self.session
+    }
+}
+
+/// Session-level limits from `--max-cost-usd` and `--max-session-tokens`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Budget {
+    pub max_cost_usd: Option<f64>,
+    pub max_session_tokens: Option<u64>,
+}
+
+impl Budget {
+    /// Returns a description of the first exceeded limit, if any.
+    pub fn exhausted(&self, totals: &UsageTotals) -> Option<String> {
+        if let Some(max) = self.max_cost_usd {
+            if totals.cost_usd >= max {
+                return Some(format!(
+                    "session cost ${:.4} reached --max-cost-usd ${max:.4}",
+                    totals.cost_usd
+                ));
+            }
+        }
+        if let Some(max) = self.max_session_tokens {
+            let used = totals.total_tokens();
+            if used >= max {
+                return Some(format!(
+                    "session tokens {used} reached --max-session-tokens {max}"
+                ));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(input: u64, output: u64, write: u64, read: u64) -> Usage {
+        Usage {
+            input_tokens: input,
+            output_tokens: output,
+            cache_creation_input_tokens: write,
+            cache_read_input_tokens: read,
+        }
+    }
+
+    #[test]
+    fn cost_uses_all_four_fields() {
+        let p = ModelPricing::new(3.0, 15.0, 3.75, 0.30);
+        let cost = p.cost(&usage(1_000_000, 1_000_000, 1_000_000, 1_000_000));
+        assert!((cost - (3.0 + 15.0 + 3.75 + 0.30)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn tracker_accumulates_session_totals() {
+        let mut tracker = CostTracker::new(ModelCatalog::default());
+        let c1 = tracker.record("claude-sonnet-4-5", &usage(1000, 500, 0, 2000));
+        let c2 = tracker.record("claude-sonnet-4-5", &usage(100, 50, 0, 0));
+        let s = tracker.session();
+        assert_eq!(s.requests, 2);
+        assert_eq!(s.total_tokens(), 3650);
+        assert!((s.cost_usd - (c1 + c2)).abs() < 1e-12);
+        assert!(c1 > 0.0);
+    }
+
+    #[test]
+    fn unknown_model_costs_nothing() {
+        let mut tracker = CostTracker::new(ModelCatalog::default());
+        assert!(!tracker.has_pricing("local-model"));
+        assert_eq!(tracker.record("local-model", &usage(1000, 1000, 0, 0)), 0.0);
+        assert_eq!(tracker.session().total_tokens(), 2000);
+    }
+
+    #[test]
+    fn budget_exhaustion() {
+        let mut totals = UsageTotals::default();
+        totals.add(&usage(600, 400, 0, 0), 0.5);
+
+        assert!(Budget::default().exhausted(&totals).is_none());
+
+        let cost_budget = Budget {
+            max_cost_usd: Some(0.5),
+            max_session_tokens: None,
+        };
+        assert!(cost_budget
+            .exhausted(&totals)
+            .unwrap()
+            .contains("--max-cost-usd"));
+
+        let token_budget = Budget {
+            max_cost_usd: None,
+            max_session_tokens: Some(1001),
+        };
+        assert!(token_budget.exhausted(&totals).is_none());
+        totals.add(&usage(0, 0, 0, 1), 0.0);
+        assert!(token_budget
+            .exhausted(&totals)
+            .unwrap()
+            .contains("--max-session-tokens"));
+    }
+}
@@ -0,0 +1,132 @@
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// A model change made by the retry loop. Recorded in the session JSONL and
+/// passed to Stop hooks.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ModelSwitch {
+    pub from: String,
+    pub to: String,
+    pub reason: String,
+}
+
+/// Primary model followed by `--fallback-model` entries, in order.
+///
+/// Transient failures advance down the chain; after `cooldown` on a fallback
+/// the next request goes back to the primary.
+pub struct ModelChain {
+    models: Vec<String>,
+    active: usize,
+    switched_at: Option<Instant>,
+    cooldown: Duration,
+}
+
+impl ModelChain {
+    pub fn new(primary: &str, fallbacks: &[String], cooldown: Duration) -> Self {
+        let mut models = vec![primary.to_string()];
+        models.extend(fallbacks.iter().filter(|m| *m != primary).cloned());
+        Self {
+            models,
+            active: 0,
+            switched_at: None,
+            cooldown,
+        }
+    }
+
+    pub fn current(&self) -> &str {
+        &self.models[self.active]
+    }
+
+    /// Move to the next model in the chain. Returns `None` at the end of the chain.
+    pub fn advance(&mut self, reason: &str, now: Instant) -> Option<ModelSwitch> {
+        if self.active + 1 >= self.models.len() {
+            return None;
+        }
+        let from = self.current().to_string();
+        self.active += 1;
+        self.switched_at = Some(now);
+        Some(ModelSwitch {
+            from,
+            to: self.current().to_string(),
+            reason: reason.to_string(),
+        })
+    }
+
+    /// Return to the primary once the cool-down since the last switch has elapsed.
+    pub fn restore_if_cooled(&mut self, now: Instant) -> Option<ModelSwitch> {
+        let switched_at = self.switched_at?;
+        if self.active == 0 || now.duration_since(switched_at) < self.cooldown {
+            return None;
+        }
+        let from = self.current().to_string();
+        self.active = 0;
+        self.switched_at = None;
+        Some(ModelSwitch {
+            from,
+            to: self.current().to_string(),
+            reason: format!("cool-down of {}s elapsed", self.cooldown.as_secs()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain() -> ModelChain {
+        ModelChain::new(
+            "claude-opus-4-6",
+            &[
+                "claude-sonnet-4-5".to_string(),
+                "claude-haiku-4-5".to_string(),
+            ],
+            Duration::from_secs(300),
+        )
+    }
+
+    #[test]
+    fn advances_through_chain_then_stops() {
+        let mut c = chain();
+        let now = Instant::now();
+        assert_eq!(c.current(), "claude-opus-4-6");
+
+        let s = c.advance("HTTP 529", now).unwrap();
+        assert_eq!(s.from, "claude-opus-4-6");
+        assert_eq!(s.to, "claude-sonnet-4-5");
+        assert_eq!(s.reason, "HTTP 529");
+
+        c.advance("HTTP 529", now).unwrap();
+        assert_eq!(c.current(), "claude-haiku-4-5");
+        assert!(c.advance("HTTP 529", now).is_none());
+        assert_eq!(c.current(), "claude-haiku-4-5");
+    }
+
+    #[test]
+    fn restores_primary_after_cooldown() {
+        let mut c = chain();
+        let start = Instant::now();
+        assert!(c.restore_if_cooled(start).is_none());
+
+        c.advance("overloaded", start);
+        assert!(c
+            .restore_if_cooled(start + Duration::from_secs(299))
+            .is_none());
+
+        let s = c
+            .restore_if_cooled(start + Duration::from_secs(300))
+            .unwrap();
+        assert_eq!(s.from, "claude-sonnet-4-5");
+        assert_eq!(s.to, "claude-opus-4-6");
+        assert_eq!(c.current(), "claude-opus-4-6");
+        assert!(c
+            .restore_if_cooled(start + Duration::from_secs(900))
+            .is_none());
+    }
+
+    #[test]
+    fn no_fallbacks_and_duplicate_primary_ignored() {
+        let mut c = ModelChain::new("m", &["m".to_string()], Duration::ZERO);
+        assert!(c.advance("HTTP 529", Instant::now()).is_none());
+        assert_eq!(c.current(), "m");
+    }
+}
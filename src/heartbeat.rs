@@ -0,0 +1,163 @@
+//! Liveness file for `forgeflare supervise`. `run_turn` rewrites it before
+//! every API request and tool dispatch, and every few seconds while a
+//! response or compaction summary streams in; the supervisor treats a file
+//! that stops changing as a hung child.
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Minimum gap between beats written from stream events.
+const STREAM_BEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Beat {
+    pub pid: u32,
+    pub session_id: String,
+    /// `api_call`, `streaming`, `compaction` or `tool`.
+    pub phase: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool: Option<String>,
+    pub tool_iterations: usize,
+    pub at: String,
+}
+
+/// Record activity in `path` (temp file + rename). No-op when no heartbeat
+/// file is configured; write failures are logged and otherwise ignored.
+pub fn beat(
+    path: Option<&Path>,
+    session_id: &str,
+    phase: &str,
+    tool: Option<&str>,
+    tool_iterations: usize,
+) {
+    let Some(path) = path else {
+        return;
+    };
+    let beat = Beat {
+        pid: std::process::id(),
+        session_id: session_id.to_string(),
+        phase: phase.to_string(),
+        tool: tool.map(str::to_string),
+        tool_iterations,
+        at: Utc::now().to_rfc3339(),
+    };
+    if let Err(e) = write(path, &beat) {
+        eprintln!("[warn] Cannot write heartbeat {}: {e}", path.display());
+    }
+}
+
+/// Beats for a streaming request, written at most once per
+/// `STREAM_BEAT_INTERVAL` however fast events arrive.
+pub struct StreamBeat<'a> {
+    path: Option<&'a Path>,
+    session_id: &'a str,
+    phase: &'a str,
+    tool_iterations: usize,
+    last: Instant,
+}
+
+impl<'a> StreamBeat<'a> {
+    /// Starts the interval now; callers beat once before sending the request.
+    pub fn new(
+        path: Option<&'a Path>,
+        session_id: &'a str,
+        phase: &'a str,
+        tool_iterations: usize,
+    ) -> Self {
+        Self {
+            path,
+            session_id,
+            phase,
+            tool_iterations,
+            last: Instant::now(),
+        }
+    }
+
+    /// Call on every stream event.
+    pub fn tick(&mut self) {
+        if self.due(Instant::now()) {
+            beat(
+                self.path,
+                self.session_id,
+                self.phase,
+                None,
+                self.tool_iterations,
+            );
+        }
+    }
+
+    fn due(&mut self, now: Instant) -> bool {
+        if self.path.is_none() || now.duration_since(self.last) < STREAM_BEAT_INTERVAL {
+            return false;
+        }
+        self.last = now;
+        true
+    }
+}
+
+fn write(path: &Path, beat: &Beat) -> std::io::Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let json = serde_json::to_string(beat).map_err(std::io::Error::other)?;
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, json)?;
+    fs::rename(&tmp, path)
+}
+
+pub fn read(path: &Path) -> Option<Beat> {
+    serde_json::from_str(&fs::read_to_string(path).ok()?).ok()
+}
+
+/// Time since `path` was last written, or `None` if it doesn't exist.
+pub fn age(path: &Path) -> Option<Duration> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(
+        SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn beat_roundtrip_and_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".forgeflare").join("heartbeat.json");
+        assert!(age(&path).is_none());
+
+        beat(Some(&path), "s1", "tool", Some("Bash"), 3);
+        let b = read(&path).unwrap();
+        assert_eq!(b.pid, std::process::id());
+        assert_eq!(b.phase, "tool");
+        assert_eq!(b.tool.as_deref(), Some("Bash"));
+        assert_eq!(b.tool_iterations, 3);
+        assert!(age(&path).unwrap() < Duration::from_secs(60));
+        assert!(!path.with_extension("json.tmp").exists());
+    }
+
+    #[test]
+    fn stream_beats_are_throttled() {
+        let path = Path::new("/unused");
+        let mut stream = StreamBeat::new(Some(path), "s1", "streaming", 0);
+        let start = stream.last;
+        assert!(!stream.due(start + Duration::from_secs(1)));
+        assert!(stream.due(start + STREAM_BEAT_INTERVAL));
+        assert!(!stream.due(start + STREAM_BEAT_INTERVAL + Duration::from_secs(1)));
+        assert!(stream.due(start + STREAM_BEAT_INTERVAL * 2));
+
+        let mut disabled = StreamBeat::new(None, "s1", "streaming", 0);
+        assert!(!disabled.due(start + STREAM_BEAT_INTERVAL * 2));
+    }
+
+    #[test]
+    fn no_path_is_noop() {
+        beat(None, "s1", "api_call", None, 0);
+    }
+}
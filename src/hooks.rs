@@ -1,8 +1,12 @@
+use crate::fallback::ModelSwitch;
+use crate::ratelimit::RateLimitState;
+use crate::tools::{EditDiff, JobSummary};
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use tokio::io::AsyncWriteExt;
 
 const DEFAULT_TIMEOUT_MS: u64 = 5000;
@@ -36,6 +40,36 @@ pub enum PostToolResult {
     Signal { signal: String, reason: String },
 }
 
+/// End-of-turn state handed to Stop hooks and recorded as the convergence `final` entry.
+pub struct StopContext<'a> {
+    pub reason: &'a str,
+    pub tool_iterations: usize,
+    pub total_tokens: u64,
+    pub cost_usd: f64,
+    pub session_cost_usd: f64,
+    pub rate_limit: Option<&'a RateLimitState>,
+    pub model: &'a str,
+    pub model_switches: &'a [ModelSwitch],
+    /// Background Bash jobs still running when the turn ended.
+    pub background_jobs: &'a [JobSummary],
+}
+
+impl<'a> StopContext<'a> {
+    pub fn new(reason: &'a str, tool_iterations: usize, total_tokens: u64) -> Self {
+        Self {
+            reason,
+            tool_iterations,
+            total_tokens,
+            cost_usd: 0.0,
+            session_cost_usd: 0.0,
+            rate_limit: None,
+            model: "",
+            model_switches: &[],
+            background_jobs: &[],
+        }
+    }
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 struct ConvergenceState {
     #[serde(default)]
@@ -56,6 +90,10 @@ struct FinalState {
     reason: String,
     tool_iterations: usize,
     total_tokens: u64,
+    #[serde(default)]
+    cost_usd: f64,
+    #[serde(default)]
+    session_cost_usd: f64,
     timestamp: String,
 }
 
@@ -74,7 +112,9 @@ struct PostOutput {
 
 pub struct HookRunner {
     hooks: Vec<HookConfig>,
-    cwd: String,
+    /// Reported to hooks as `cwd`: the Bash shell's working directory, which
+    /// relative tool paths resolve against.
+    cwd: Mutex<String>,
     convergence_dir: PathBuf,
     convergence_path: PathBuf,
     convergence_tmp: PathBuf,
@@ -100,13 +140,22 @@ impl HookRunner {
 
         Self {
             hooks,
-            cwd: cwd.to_string(),
+            cwd: Mutex::new(cwd.to_string()),
             convergence_dir,
             convergence_path,
             convergence_tmp,
         }
     }
 
+    fn cwd(&self) -> String {
+        self.cwd.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Update the working directory reported to hooks.
+    pub fn set_cwd(&self, cwd: &str) {
+        *self.cwd.lock().unwrap_or_else(|e| e.into_inner()) = cwd.to_string();
+    }
+
     pub fn clear_convergence_state(&self) {
         match fs::remove_file(&self.convergence_path) {
             Ok(()) => {}
@@ -120,6 +169,18 @@ impl HookRunner {
         }
     }
 
+    /// The most recent convergence signal recorded since the last clear, as
+    /// `(signal, reason)`.
+    pub fn last_signal(&self) -> Option<(String, String)> {
+        let content = fs::read_to_string(&self.convergence_path).ok()?;
+        let state = serde_json::from_str::<ConvergenceState>(&content).ok()?;
+        state
+            .observations
+            .into_iter()
+            .next_back()
+            .map(|obs| (obs.signal, obs.reason))
+    }
+
     pub async fn run_pre_tool_use(
         &self,
         tool: &str,
@@ -149,7 +210,7 @@ impl HookRunner {
                 "tool": tool,
                 "input": input,
                 "tool_iterations": tool_iterations,
-                "cwd": self.cwd,
+                "cwd": self.cwd(),
             });
 
             let timeout = hook.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS);
@@ -224,7 +285,7 @@ impl HookRunner {
                 "input": input,
                 "blocked": blocked,
                 "tool_iterations": tool_iterations,
-                "cwd": self.cwd,
+                "cwd": self.cwd(),
             });
 
             if blocked {
@@ -262,6 +323,7 @@ impl HookRunner {
         result: &str,
         is_error: bool,
         tool_iterations: usize,
+        diff: Option<&EditDiff>,
     ) -> PostToolResult {
         let matching_hooks: Vec<&HookConfig> = self
             .hooks
@@ -280,15 +342,18 @@ impl HookRunner {
         let mut observations: Vec<Observation> = Vec::new();
 
         for hook in &matching_hooks {
-            let hook_input = serde_json::json!({
+            let mut hook_input = serde_json::json!({
                 "event": "PostToolUse",
                 "tool": tool,
                 "input": input,
                 "result": truncated_result,
                 "is_error": is_error,
                 "tool_iterations": tool_iterations,
-                "cwd": self.cwd,
+                "cwd": self.cwd(),
             });
+            if let Some(diff) = diff {
+                hook_input["diff"] = serde_json::json!(diff);
+            }
 
             let timeout = hook.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS);
 
@@ -339,17 +404,23 @@ impl HookRunner {
         first_signal.unwrap_or(PostToolResult::Continue)
     }
 
-    pub async fn run_stop(&self, reason: &str, tool_iterations: usize, total_tokens: u64) {
+    pub async fn run_stop(&self, ctx: &StopContext<'_>) {
         let matching_hooks: Vec<&HookConfig> =
             self.hooks.iter().filter(|h| h.event == "Stop").collect();
 
         for hook in &matching_hooks {
             let hook_input = serde_json::json!({
                 "event": "Stop",
-                "reason": reason,
-                "tool_iterations": tool_iterations,
-                "total_tokens": total_tokens,
-                "cwd": self.cwd,
+                "reason": ctx.reason,
+                "tool_iterations": ctx.tool_iterations,
+                "total_tokens": ctx.total_tokens,
+                "cost_usd": ctx.cost_usd,
+                "session_cost_usd": ctx.session_cost_usd,
+                "rate_limit": ctx.rate_limit.map(RateLimitState::to_json),
+                "model": ctx.model,
+                "model_switches": ctx.model_switches,
+                "background_jobs": ctx.background_jobs,
+                "cwd": self.cwd(),
             });
 
             let timeout = hook.timeout_ms.unwrap_or(DEFAULT_STOP_TIMEOUT_MS);
@@ -376,9 +447,7 @@ impl HookRunner {
 
         // Write final state to convergence.json
         if let Err(e) = write_final_state(
-            reason,
-            tool_iterations,
-            total_tokens,
+            ctx,
             &self.convergence_dir,
             &self.convergence_path,
             &self.convergence_tmp,
@@ -387,6 +456,33 @@ impl HookRunner {
         }
     }
 
+    /// Fires after a compaction summary is written, before it replaces the
+    /// old messages. Fail-open and observe-only.
+    pub async fn run_pre_compact(&self, trigger: &str, messages_compacted: usize, summary: &str) {
+        let matching_hooks: Vec<&HookConfig> = self
+            .hooks
+            .iter()
+            .filter(|h| h.event == "PreCompact")
+            .collect();
+
+        for hook in &matching_hooks {
+            let hook_input = serde_json::json!({
+                "event": "PreCompact",
+                "trigger": trigger,
+                "messages_compacted": messages_compacted,
+                "summary": summary,
+                "cwd": self.cwd(),
+            });
+
+            let timeout = hook.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS);
+
+            // Fail-open
+            if let Err(e) = run_hook_subprocess(&hook.command, &hook_input, timeout).await {
+                eprintln!("[hooks] PreCompact hook {} failed: {e}", hook.command);
+            }
+        }
+    }
+
     pub fn has_hooks(&self) -> bool {
         !self.hooks.is_empty()
     }
@@ -507,9 +603,7 @@ fn write_observations(
 }
 
 fn write_final_state(
-    reason: &str,
-    tool_iterations: usize,
-    total_tokens: u64,
+    ctx: &StopContext<'_>,
     dir: &Path,
     path: &Path,
     tmp: &Path,
@@ -522,9 +616,11 @@ fn write_final_state(
     };
 
     state.final_state = Some(FinalState {
-        reason: reason.to_string(),
-        tool_iterations,
-        total_tokens,
+        reason: ctx.reason.to_string(),
+        tool_iterations: ctx.tool_iterations,
+        total_tokens: ctx.total_tokens,
+        cost_usd: ctx.cost_usd,
+        session_cost_usd: ctx.session_cost_usd,
         timestamp: Utc::now().to_rfc3339(),
     });
 
@@ -922,6 +1018,47 @@ timeout_ms = 5000
         assert!(parsed.get("blocked_by").is_none());
     }
 
+    #[tokio::test]
+    async fn set_cwd_changes_reported_cwd() {
+        let dir = tempfile::tempdir().unwrap();
+        let observe_log = dir.path().join("observe.log");
+        let observe_script = dir.path().join("observe.sh");
+        fs::write(
+            &observe_script,
+            format!(
+                "#!/bin/bash\ncat > {}\necho '{{}}'\n",
+                observe_log.display()
+            ),
+        )
+        .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&observe_script, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let config_path = dir.path().join("hooks.toml");
+        fs::write(
+            &config_path,
+            format!(
+                "[[hooks]]\nevent = \"PreToolUse\"\nphase = \"observe\"\ncommand = \"{}\"\n",
+                observe_script.display()
+            ),
+        )
+        .unwrap();
+
+        let runner = HookRunner::load(config_path.to_str().unwrap(), dir.path().to_str().unwrap());
+        runner.set_cwd("/somewhere/else");
+        runner
+            .run_pre_tool_use("Bash", &serde_json::json!({"command": "ls"}), 1)
+            .await;
+
+        let parsed: Value =
+            serde_json::from_str(&fs::read_to_string(&observe_log).unwrap()).unwrap();
+        assert_eq!(parsed["cwd"], "/somewhere/else");
+        // Convergence state stays in the project directory
+        assert_eq!(runner.convergence_dir, dir.path().join(".forgeflare"));
+    }
+
     #[tokio::test]
     async fn observe_failure_does_not_affect_outcome() {
         let dir = tempfile::tempdir().unwrap();
@@ -988,6 +1125,7 @@ timeout_ms = 5000
                 "ok",
                 false,
                 5,
+                None,
             )
             .await;
 
@@ -1036,7 +1174,7 @@ timeout_ms = 5000
 
         let runner = HookRunner::load(config_path.to_str().unwrap(), dir.path().to_str().unwrap());
         let result = runner
-            .run_post_tool_use("Bash", &serde_json::json!({}), "ok", false, 0)
+            .run_post_tool_use("Bash", &serde_json::json!({}), "ok", false, 0, None)
             .await;
 
         assert_eq!(result, PostToolResult::Continue);
@@ -1066,12 +1204,59 @@ timeout_ms = 5000
 
         let runner = HookRunner::load(config_path.to_str().unwrap(), dir.path().to_str().unwrap());
         let result = runner
-            .run_post_tool_use("Bash", &serde_json::json!({}), "ok", false, 0)
+            .run_post_tool_use("Bash", &serde_json::json!({}), "ok", false, 0, None)
             .await;
 
         assert_eq!(result, PostToolResult::Continue);
     }
 
+    #[tokio::test]
+    async fn post_tool_use_receives_edit_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        let post_log = dir.path().join("post.log");
+        let hook_script = dir.path().join("post.sh");
+        fs::write(
+            &hook_script,
+            format!(
+                "#!/bin/bash\ncat > {}\necho '{{\"action\":\"continue\"}}'\n",
+                post_log.display()
+            ),
+        )
+        .unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&hook_script, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let config_path = dir.path().join("hooks.toml");
+        fs::write(
+            &config_path,
+            format!(
+                "[[hooks]]\nevent = \"PostToolUse\"\ncommand = \"{}\"\n",
+                hook_script.display()
+            ),
+        )
+        .unwrap();
+
+        let runner = HookRunner::load(config_path.to_str().unwrap(), dir.path().to_str().unwrap());
+        let diff = EditDiff::new("src/lib.rs", "a\nb\n", "a\nc\n", 3);
+        runner
+            .run_post_tool_use("Edit", &serde_json::json!({}), "ok", false, 1, Some(&diff))
+            .await;
+
+        let parsed: Value = serde_json::from_str(&fs::read_to_string(&post_log).unwrap()).unwrap();
+        assert_eq!(parsed["diff"]["path"], "src/lib.rs");
+        assert_eq!(parsed["diff"]["additions"], 1);
+        assert_eq!(parsed["diff"]["deletions"], 1);
+        assert_eq!(parsed["diff"]["hunks"][0]["header"], "@@ -1,2 +1,2 @@");
+        assert_eq!(
+            parsed["diff"]["hunks"][0]["lines"],
+            serde_json::json!([" a", "-b", "+c"])
+        );
+    }
+
     #[tokio::test]
     async fn stop_hook_fires() {
         let dir = tempfile::tempdir().unwrap();
@@ -1103,7 +1288,20 @@ timeout_ms = 5000
         .unwrap();
 
         let runner = HookRunner::load(config_path.to_str().unwrap(), dir.path().to_str().unwrap());
-        runner.run_stop("end_turn", 7, 45000).await;
+        let switches = [ModelSwitch {
+            from: "claude-opus-4-6".to_string(),
+            to: "claude-sonnet-4-5".to_string(),
+            reason: "HTTP 529".to_string(),
+        }];
+        runner
+            .run_stop(&StopContext {
+                cost_usd: 0.12,
+                session_cost_usd: 0.5,
+                model: "claude-sonnet-4-5",
+                model_switches: &switches,
+                ..StopContext::new("end_turn", 7, 45000)
+            })
+            .await;
 
         // Check hook received correct input
         let logged = fs::read_to_string(&stop_log).unwrap();
@@ -1112,6 +1310,12 @@ timeout_ms = 5000
         assert_eq!(parsed["reason"], "end_turn");
         assert_eq!(parsed["tool_iterations"], 7);
         assert_eq!(parsed["total_tokens"], 45000);
+        assert_eq!(parsed["cost_usd"], 0.12);
+        assert_eq!(parsed["session_cost_usd"], 0.5);
+        assert!(parsed["rate_limit"].is_null());
+        assert_eq!(parsed["model"], "claude-sonnet-4-5");
+        assert_eq!(parsed["model_switches"][0]["from"], "claude-opus-4-6");
+        assert_eq!(parsed["model_switches"][0]["reason"], "HTTP 529");
 
         // Check convergence final state (absolute path)
         let conv_path = dir.path().join(".forgeflare/convergence.json");
@@ -1122,6 +1326,47 @@ timeout_ms = 5000
         assert_eq!(final_state.reason, "end_turn");
         assert_eq!(final_state.tool_iterations, 7);
         assert_eq!(final_state.total_tokens, 45000);
+        assert_eq!(final_state.cost_usd, 0.12);
+        assert_eq!(final_state.session_cost_usd, 0.5);
+    }
+
+    #[tokio::test]
+    async fn pre_compact_hook_receives_summary() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("compact.log");
+        let hook_script = dir.path().join("compact.sh");
+        fs::write(
+            &hook_script,
+            format!("#!/bin/bash\ncat > {}\n", log.display()),
+        )
+        .unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&hook_script, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let config_path = dir.path().join("hooks.toml");
+        fs::write(
+            &config_path,
+            format!(
+                "[[hooks]]\nevent = \"PreCompact\"\ncommand = \"{}\"\n",
+                hook_script.display()
+            ),
+        )
+        .unwrap();
+
+        let runner = HookRunner::load(config_path.to_str().unwrap(), dir.path().to_str().unwrap());
+        runner
+            .run_pre_compact("manual", 12, "Edited src/lib.rs")
+            .await;
+
+        let parsed: Value = serde_json::from_str(&fs::read_to_string(&log).unwrap()).unwrap();
+        assert_eq!(parsed["event"], "PreCompact");
+        assert_eq!(parsed["trigger"], "manual");
+        assert_eq!(parsed["messages_compacted"], 12);
+        assert_eq!(parsed["summary"], "Edited src/lib.rs");
     }
 
     #[tokio::test]
@@ -1148,7 +1393,9 @@ timeout_ms = 5000
 
         let runner = HookRunner::load(config_path.to_str().unwrap(), dir.path().to_str().unwrap());
         // Should not panic
-        runner.run_stop("api_error", 3, 10000).await;
+        runner
+            .run_stop(&StopContext::new("api_error", 3, 10000))
+            .await;
     }
 
     #[tokio::test]
@@ -1183,7 +1430,9 @@ timeout_ms = 5000
 
         let runner = HookRunner::load(config_path.to_str().unwrap(), dir.path().to_str().unwrap());
         // Should not panic — unrecognized action is logged and ignored
-        runner.run_stop("end_turn", 5, 20000).await;
+        runner
+            .run_stop(&StopContext::new("end_turn", 5, 20000))
+            .await;
 
         // Convergence final state should still be written despite unrecognized action
         let conv_path = dir.path().join(".forgeflare/convergence.json");
@@ -1240,12 +1489,12 @@ timeout_ms = 5000
         assert_eq!(pre, PreToolResult::Allow);
 
         let post = runner
-            .run_post_tool_use("Bash", &serde_json::json!({}), "ok", false, 0)
+            .run_post_tool_use("Bash", &serde_json::json!({}), "ok", false, 0, None)
             .await;
         assert_eq!(post, PostToolResult::Continue);
 
         // Stop should also be a no-op (doesn't panic)
-        runner.run_stop("end_turn", 0, 0).await;
+        runner.run_stop(&StopContext::new("end_turn", 0, 0)).await;
     }
 
     #[test]
@@ -1271,6 +1520,40 @@ timeout_ms = 5000
         runner.clear_convergence_state();
     }
 
+    #[test]
+    fn last_signal_reads_latest_observation() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = HookRunner::load("/nonexistent", dir.path().to_str().unwrap());
+        assert!(runner.last_signal().is_none());
+
+        let observations = vec![
+            Observation {
+                signal: "progress".to_string(),
+                reason: "tests compile".to_string(),
+                tool_iterations: 2,
+            },
+            Observation {
+                signal: "converged".to_string(),
+                reason: "all tests pass".to_string(),
+                tool_iterations: 4,
+            },
+        ];
+        write_observations(
+            &observations,
+            &runner.convergence_dir,
+            &runner.convergence_path,
+            &runner.convergence_tmp,
+        )
+        .unwrap();
+        assert_eq!(
+            runner.last_signal(),
+            Some(("converged".to_string(), "all tests pass".to_string()))
+        );
+
+        runner.clear_convergence_state();
+        assert!(runner.last_signal().is_none());
+    }
+
     #[test]
     fn convergence_atomic_write() {
         let dir = tempfile::tempdir().unwrap();
@@ -1331,9 +1614,7 @@ timeout_ms = 5000
         let conv_tmp = ff_dir.join("convergence.json.tmp");
 
         write_final_state(
-            "convergence_signal",
-            22,
-            45000,
+            &StopContext::new("convergence_signal", 22, 45000),
             &ff_dir,
             &conv_path,
             &conv_tmp,
@@ -1400,7 +1681,7 @@ timeout_ms = 5000
 
         let runner = HookRunner::load(config_path.to_str().unwrap(), dir.path().to_str().unwrap());
         let result = runner
-            .run_post_tool_use("Bash", &serde_json::json!({}), "ok", false, 10)
+            .run_post_tool_use("Bash", &serde_json::json!({}), "ok", false, 10, None)
             .await;
 
         // First signal wins for return value
@@ -1482,7 +1763,7 @@ timeout_ms = 5000
         for reason in &reasons {
             let dir = tempfile::tempdir().unwrap();
             let runner = HookRunner::load("/nonexistent", dir.path().to_str().unwrap());
-            runner.run_stop(reason, 0, 0).await;
+            runner.run_stop(&StopContext::new(reason, 0, 0)).await;
 
             let conv_path = dir.path().join(".forgeflare/convergence.json");
             let conv = fs::read_to_string(&conv_path).unwrap();
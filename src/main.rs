@@ -1,24 +1,57 @@
+mod agent_loop;
 mod api;
+mod auth;
+mod compact;
+mod config;
+mod cost;
+mod fallback;
+mod heartbeat;
 mod hooks;
+mod mcp;
+mod models;
+mod output;
+mod ratelimit;
 mod session;
+mod sse;
+mod supervise;
+mod tokens;
 mod tools;
 
+use agent_loop::LoopArgs;
 use api::{
     classify_error, AgentError, AnthropicClient, ContentBlock, ErrorClass, Message, StopReason,
+    StreamEvent,
 };
-use clap::Parser;
-use hooks::{HookRunner, PostToolResult, PreToolResult};
+use auth::Auth;
+use clap::{Parser, Subcommand, ValueEnum};
+use compact::SUMMARY_MAX_TOKENS;
+use config::Config;
+use cost::{Budget, CostTracker};
+use fallback::{ModelChain, ModelSwitch};
+use hooks::{HookRunner, PostToolResult, PreToolResult, StopContext};
+use models::ModelCatalog;
+use output::{Event, Output, OutputFormat, TurnResult};
 use session::SessionWriter;
 use std::io::{self, BufRead, Read as _, Write};
-use tools::{all_tool_schemas, dispatch_tool, tool_effect, ToolEffect};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use supervise::SuperviseArgs;
+use tokens::TokenEstimator;
+use tools::{EditDiff, Tool, ToolEffect, ToolRegistry};
 
 const MAX_TOOL_ITERATIONS: usize = 50;
 const MAX_RETRIES: usize = 4;
 const BACKOFF_SCHEDULE: [u64; 4] = [2, 4, 8, 16];
 const RETRY_AFTER_CAP: u64 = 60;
-const CONTEXT_BUDGET_BYTES: usize = 720_000;
-const MODEL_CONTEXT_TOKENS: u64 = 200_000;
-const TRIM_THRESHOLD: u64 = MODEL_CONTEXT_TOKENS * 60 / 100; // 120K tokens
+/// Percent of the model's context window at which trimming starts.
+const TRIM_THRESHOLD_PERCENT: u64 = 60;
+/// Percent of the context window that trimming cuts the prompt down to.
+const TRIM_TARGET_PERCENT: u64 = 50;
+/// Trailing messages (three tool iterations) whose tool results are never elided.
+const ELIDE_KEEP_RECENT_MESSAGES: usize = 6;
+/// Tool results smaller than this are left alone; the stub would save little.
+const ELIDE_MIN_BYTES: usize = 1024;
 const MAX_CONSECUTIVE_BLOCKS: usize = 3;
 const MAX_TOTAL_BLOCKS: usize = 10;
 const PROJECT_INSTRUCTIONS_MAX_BYTES: usize = 32_768;
@@ -86,9 +119,9 @@ struct Cli {
     #[arg(long, default_value = "claude-opus-4-6")]
     model: String,
 
-    /// Maximum tokens in response
-    #[arg(long, default_value_t = 16384)]
-    max_tokens: u32,
+    /// Maximum tokens in response [default: 16384, capped at the model's output limit]
+    #[arg(long)]
+    max_tokens: Option<u32>,
 
     /// API base URL (without /v1/messages path)
     #[arg(
@@ -97,13 +130,95 @@ struct Cli {
         default_value = "https://anthropic-oauth-proxy.tailfb3ea.ts.net"
     )]
     api_url: String,
+
+    /// Size each request with the count-tokens endpoint before sending it
+    #[arg(long, default_value_t = false)]
+    count_tokens: bool,
+
+    /// Abort and retry a response stream after this many seconds without data
+    #[arg(long, default_value_t = 90, value_parser = clap::value_parser!(u64).range(1..))]
+    stream_idle_timeout_secs: u64,
+
+    /// Model to fall back to on repeated transient failures (repeatable, tried in order)
+    #[arg(long = "fallback-model")]
+    fallback_models: Vec<String>,
+
+    /// Consecutive transient failures before switching to the next fallback model
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..))]
+    fallback_after: u64,
+
+    /// Seconds on a fallback model before returning to the primary
+    #[arg(long, default_value_t = 300)]
+    fallback_cooldown_secs: u64,
+
+    /// Stop the turn once session cost reaches this many USD
+    #[arg(long)]
+    max_cost_usd: Option<f64>,
+
+    /// Stop the turn once session tokens (all usage fields) reach this count
+    #[arg(long)]
+    max_session_tokens: Option<u64>,
+
+    /// How to shrink the conversation when it nears the context window
+    #[arg(long, value_enum, default_value_t = ContextStrategy::Trim)]
+    context_strategy: ContextStrategy,
+
+    /// Model that writes compaction summaries (defaults to the active model)
+    #[arg(long)]
+    compact_model: Option<String>,
+
+    /// Resume the session with this ID from .entire/metadata
+    #[arg(long, value_name = "SESSION_ID", conflicts_with = "continue_session")]
+    resume: Option<String>,
+
+    /// Resume the most recent session
+    #[arg(long = "continue", default_value_t = false)]
+    continue_session: bool,
+
+    /// What to write to stdout: streamed text, one JSON result, or NDJSON events
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output_format: OutputFormat,
+
+    /// Rewrite this file before every API request and tool dispatch (watched by supervise)
+    #[arg(long, value_name = "PATH")]
+    heartbeat_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a prompt file repeatedly, each iteration in a fresh conversation,
+    /// until a hook signals convergence
+    Loop(LoopArgs),
+    /// Run the loop as a child process, restarting it when it hangs or crashes
+    Supervise(SuperviseArgs),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum ContextStrategy {
+    /// Drop the oldest exchanges
+    Trim,
+    /// Replace the oldest exchanges with a model-written summary
+    Compact,
 }
 
-fn build_system_prompt() -> String {
+impl Cli {
+    fn budget(&self) -> Budget {
+        Budget {
+            max_cost_usd: self.max_cost_usd,
+            max_session_tokens: self.max_session_tokens,
+        }
+    }
+}
+
+fn build_system_prompt(tools: &ToolRegistry) -> String {
     let cwd = std::env::current_dir()
         .map(|p| p.display().to_string())
         .unwrap_or_else(|_| ".".to_string());
     let platform = std::env::consts::OS;
+    let tool_list = tools.prompt_list();
 
     format!(
         "You are a coding assistant with access to tools for reading, searching, editing files, \
@@ -112,11 +227,7 @@ fn build_system_prompt() -> String {
          - Working directory: {cwd}\n\
          - Platform: {platform}\n\n\
          Available tools (use PascalCase names exactly):\n\
-         - Read: Read file contents (max 1MB)\n\
-         - Glob: List files matching a pattern (max 1000 entries)\n\
-         - Bash: Execute shell commands (120s timeout)\n\
-         - Edit: Edit files with exact text replacement (max 100KB, use replace_all for bulk)\n\
-         - Grep: Search file contents with ripgrep (max 50 matches)\n\n\
+         {tool_list}\n\n\
          Guidelines:\n\
          - Read files before editing them\n\
          - Use Grep to find code before making changes\n\
@@ -125,56 +236,309 @@ fn build_system_prompt() -> String {
     )
 }
 
-/// Trim conversation at exchange boundaries to fit within context budget.
-/// Preserves the first user message and trims from the front, keeping
-/// tool_use/tool_result pairs together.
-fn trim_conversation(messages: &mut Vec<Message>) {
-    let size: usize = messages
-        .iter()
-        .map(|m| serde_json::to_string(m).unwrap_or_default().len())
-        .sum();
+/// Trim conversation at exchange boundaries until its estimated size fits
+/// `budget_tokens`. Preserves the first user message and trims from the
+/// front, keeping tool_use/tool_result pairs together.
+fn trim_conversation(messages: &mut Vec<Message>, estimator: &TokenEstimator, budget_tokens: u64) {
+    let size = messages_tokens(messages, estimator);
 
-    if size <= CONTEXT_BUDGET_BYTES || messages.len() <= 2 {
+    if size <= budget_tokens || messages.len() <= 2 {
         return;
     }
 
     // Keep first message, trim from front of the rest
     let first = messages.remove(0);
+    let first_size = estimator.message_tokens(&first);
+    let mut rest_size = size - first_size;
     while messages.len() > 1 {
-        let new_size: usize = std::iter::once(&first)
-            .chain(messages.iter())
-            .map(|m| serde_json::to_string(m).unwrap_or_default().len())
-            .sum();
-        if new_size <= CONTEXT_BUDGET_BYTES {
+        if first_size + rest_size <= budget_tokens {
             break;
         }
         // Remove pairs to maintain alternation
-        messages.remove(0);
+        rest_size -= estimator.message_tokens(&messages.remove(0));
         if !messages.is_empty() && messages[0].role == "assistant" {
-            messages.remove(0);
+            rest_size -= estimator.message_tokens(&messages.remove(0));
         }
     }
     messages.insert(0, first);
 }
 
-/// Gate trim_conversation on actual token usage from the API.
-/// - last_input_tokens == 0: no data yet, run byte-based trim (safety net)
-/// - last_input_tokens > 0 && < TRIM_THRESHOLD: skip trim (context is safe)
-/// - last_input_tokens >= TRIM_THRESHOLD: run byte-based trim
-fn trim_if_needed(messages: &mut Vec<Message>, last_input_tokens: u64) {
-    if last_input_tokens == 0 || last_input_tokens >= TRIM_THRESHOLD {
-        trim_conversation(messages);
+/// Replace old tool_result bodies with short stubs until the messages fit
+/// `budget_tokens`. Oldest messages go first, largest result first within a
+/// message; the last `ELIDE_KEEP_RECENT_MESSAGES` are untouched. The
+/// tool_use blocks stay, so the model keeps its reasoning trail and can
+/// re-run a tool if it needs the output again. Returns true if anything
+/// was elided.
+fn elide_tool_results(
+    messages: &mut [Message],
+    estimator: &TokenEstimator,
+    budget_tokens: u64,
+) -> bool {
+    let mut size = messages_tokens(messages, estimator);
+    if size <= budget_tokens {
+        return false;
+    }
+
+    let protected_from = messages.len().saturating_sub(ELIDE_KEEP_RECENT_MESSAGES);
+    let mut candidates: Vec<(usize, usize, usize)> = Vec::new(); // (message, block, bytes)
+    for (i, msg) in messages[..protected_from].iter().enumerate() {
+        for (j, block) in msg.content.iter().enumerate() {
+            if let ContentBlock::ToolResult { content, .. } = block {
+                if content.len() >= ELIDE_MIN_BYTES && !content.starts_with("[elided: ") {
+                    candidates.push((i, j, content.len()));
+                }
+            }
+        }
+    }
+    candidates.sort_by_key(|&(i, _, bytes)| (i, std::cmp::Reverse(bytes)));
+
+    let mut elided = false;
+    for (i, j, bytes) in candidates {
+        if size <= budget_tokens {
+            break;
+        }
+        let stub = {
+            let ContentBlock::ToolResult { tool_use_id, .. } = &messages[i].content[j] else {
+                continue;
+            };
+            let source = i
+                .checked_sub(1)
+                .and_then(|p| describe_tool_use(&messages[p], tool_use_id))
+                .unwrap_or_else(|| "tool result".to_string());
+            format!(
+                "[elided: {source}, {}, re-read if needed]",
+                format_bytes(bytes)
+            )
+        };
+        let before = estimator.message_tokens(&messages[i]);
+        if let ContentBlock::ToolResult { content, .. } = &mut messages[i].content[j] {
+            *content = stub;
+        }
+        size = size - before + estimator.message_tokens(&messages[i]);
+        elided = true;
+    }
+    elided
+}
+
+/// "Read src/main.rs", "Bash cargo test", ... for the tool_use `id` in `message`.
+fn describe_tool_use(message: &Message, id: &str) -> Option<String> {
+    message.content.iter().find_map(|b| match b {
+        ContentBlock::ToolUse {
+            id: use_id,
+            name,
+            input,
+        } if use_id == id => {
+            let target = ["file_path", "path", "command", "pattern"]
+                .iter()
+                .find_map(|k| input.get(*k).and_then(|v| v.as_str()));
+            Some(match target {
+                Some(t) => {
+                    let t = t.lines().next().unwrap_or("");
+                    let end = t.floor_char_boundary(60);
+                    let ellipsis = if end < t.len() { "..." } else { "" };
+                    format!("{name} {}{ellipsis}", &t[..end])
+                }
+                None => name.clone(),
+            })
+        }
+        _ => None,
+    })
+}
+
+fn format_bytes(bytes: usize) -> String {
+    if bytes < 1024 {
+        format!("{bytes}B")
+    } else {
+        format!("{}KB", bytes.div_ceil(1024))
     }
 }
 
+fn messages_tokens(messages: &[Message], estimator: &TokenEstimator) -> u64 {
+    messages.iter().map(|m| estimator.message_tokens(m)).sum()
+}
+
+fn trim_threshold(context_window: u64) -> u64 {
+    context_window * TRIM_THRESHOLD_PERCENT / 100
+}
+
+fn trim_target(context_window: u64) -> u64 {
+    context_window * TRIM_TARGET_PERCENT / 100
+}
+
+/// Gate trim_conversation on the pre-flight prompt size.
+///
+/// Below the trim threshold nothing is touched, so the prompt cache stays warm.
+/// At or above it, old tool results are elided first, and whole exchanges
+/// are dropped only if that doesn't bring the messages down to the trim
+/// target less the system/tools overhead (whatever part of `prompt_tokens`
+/// the messages don't account for). Returns true if anything changed.
+fn trim_if_needed(
+    messages: &mut Vec<Message>,
+    prompt_tokens: u64,
+    estimator: &TokenEstimator,
+    context_window: u64,
+) -> bool {
+    if prompt_tokens < trim_threshold(context_window) {
+        return false;
+    }
+    let before = messages.len();
+    let budget = message_budget(messages, prompt_tokens, estimator, context_window);
+    let elided = elide_tool_results(messages, estimator, budget);
+    trim_conversation(messages, estimator, budget);
+    elided || messages.len() < before
+}
+
+/// Token budget for the messages alone: the trim target less the
+/// system/tools overhead (whatever part of `prompt_tokens` the messages
+/// don't account for).
+fn message_budget(
+    messages: &[Message],
+    prompt_tokens: u64,
+    estimator: &TokenEstimator,
+    context_window: u64,
+) -> u64 {
+    let overhead = prompt_tokens.saturating_sub(messages_tokens(messages, estimator));
+    trim_target(context_window).saturating_sub(overhead)
+}
+
+/// Summarize the oldest messages so the rest fit `budget_tokens` (0 compacts
+/// everything up to the latest exchange) and splice the summary in. Returns
+/// false, leaving the conversation untouched, when there is nothing to
+/// compact or the summary call fails.
+#[allow(clippy::too_many_arguments)]
+async fn compact_conversation(
+    cli: &Cli,
+    client: &AnthropicClient,
+    catalog: &ModelCatalog,
+    conversation: &mut Vec<Message>,
+    session: &mut SessionWriter,
+    hooks: &HookRunner,
+    cost: &mut CostTracker,
+    active_model: &str,
+    budget_tokens: u64,
+    trigger: &str,
+    instructions: Option<&str>,
+    tool_iterations: usize,
+) -> bool {
+    let estimator = client.estimator();
+    let Some(end) = compact::compaction_end(conversation, &estimator, budget_tokens) else {
+        return false;
+    };
+    let model = cli.compact_model.as_deref().unwrap_or(active_model);
+    eprintln!("[compact] Summarizing {} messages with {model}", end - 1);
+
+    let max_tokens = catalog.max_tokens_for(model, Some(SUMMARY_MAX_TOKENS));
+    let heartbeat_file = cli.heartbeat_file.as_deref();
+    heartbeat::beat(
+        heartbeat_file,
+        session.session_id(),
+        "compaction",
+        None,
+        tool_iterations,
+    );
+    let mut stream_beat = heartbeat::StreamBeat::new(
+        heartbeat_file,
+        session.session_id(),
+        "compaction",
+        tool_iterations,
+    );
+    let summary = compact::summarize(
+        client,
+        model,
+        max_tokens,
+        &conversation[1..end],
+        instructions,
+        &mut |_| stream_beat.tick(),
+    )
+    .await;
+    heartbeat::beat(
+        heartbeat_file,
+        session.session_id(),
+        "compaction",
+        None,
+        tool_iterations,
+    );
+    let summary = match summary {
+        Ok((summary, usage)) => {
+            cost.record(model, &usage);
+            summary
+        }
+        Err(e) => {
+            eprintln!("[compact] Summary request failed: {e}");
+            return false;
+        }
+    };
+    if summary.is_empty() {
+        eprintln!("[compact] Model returned an empty summary");
+        return false;
+    }
+
+    hooks.run_pre_compact(trigger, end - 1, &summary).await;
+    session.append_compaction(end - 1, conversation.len() - end, &summary);
+    compact::apply_summary(conversation, end, &summary);
+    true
+}
+
+/// Output budget for the next request. Continuations after a truncation get
+/// the model's full output limit unless `--max-tokens` pinned it.
+fn request_max_tokens(
+    catalog: &ModelCatalog,
+    model: &str,
+    requested: Option<u32>,
+    continuation_count: usize,
+) -> u32 {
+    if continuation_count > 0 && requested.is_none() {
+        catalog.lookup(model).max_output_tokens
+    } else {
+        catalog.max_tokens_for(model, requested)
+    }
+}
+
+/// Prompt size for the next request: the count-tokens endpoint when
+/// `--count-tokens` is set, otherwise (or if that call fails) the local estimate.
+async fn preflight_prompt_tokens(
+    cli: &Cli,
+    client: &AnthropicClient,
+    model: &str,
+    system_prompt: &str,
+    tools: &[serde_json::Value],
+    conversation: &[Message],
+) -> u64 {
+    if cli.count_tokens {
+        match client
+            .count_tokens(model, system_prompt, conversation, tools)
+            .await
+        {
+            Ok(tokens) => {
+                if cli.verbose {
+                    eprintln!("[verbose] Pre-flight: {tokens} prompt tokens (count_tokens)");
+                }
+                return tokens;
+            }
+            Err(e) => eprintln!("[warn] count_tokens failed, using local estimate: {e}"),
+        }
+    }
+    let estimator = client.estimator();
+    let tokens = estimator.request_tokens(system_prompt, tools, conversation);
+    if cli.verbose {
+        eprintln!(
+            "[verbose] Pre-flight: ~{tokens} prompt tokens (estimate at {:.2} bytes/token)",
+            estimator.bytes_per_token()
+        );
+    }
+    tokens
+}
+
 /// Recover conversation alternation after API errors.
 /// Pops trailing User message and any orphaned tool_use to maintain
-/// the user/assistant alternation invariant.
-fn recover_conversation(messages: &mut Vec<Message>) {
+/// the user/assistant alternation invariant. Returns how many messages were
+/// popped, for `SessionWriter::rollback`.
+fn recover_conversation(messages: &mut Vec<Message>) -> usize {
+    let before = messages.len();
     // Guard: never empty the conversation entirely — at minimum keep the
     // first user message so the next API call has something to send.
     if messages.len() <= 1 {
-        return;
+        return 0;
     }
     // Pop trailing user message if present (but keep at least 1 message)
     if messages.len() > 1 {
@@ -206,6 +570,62 @@ fn recover_conversation(messages: &mut Vec<Message>) {
             }
         }
     }
+    before - messages.len()
+}
+
+/// Make a conversation read back from a transcript safe to send: cut it at
+/// the first tool_use/tool_result mismatch (a run that died mid-dispatch),
+/// then apply the same trailing cleanup as `recover_conversation`.
+fn repair_resumed_conversation(messages: &mut Vec<Message>) {
+    if let Some(bad) = first_unpaired_message(messages) {
+        messages.truncate(bad.max(1));
+    }
+    recover_conversation(messages);
+}
+
+/// Index of the first message that breaks tool_use/tool_result pairing: an
+/// assistant tool_use not answered in the next message, or a tool_result
+/// that answers no tool_use in the previous one.
+fn first_unpaired_message(messages: &[Message]) -> Option<usize> {
+    fn tool_use_ids(m: &Message) -> Vec<&str> {
+        m.content
+            .iter()
+            .filter_map(|b| match b {
+                ContentBlock::ToolUse { id, .. } => Some(id.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+    fn tool_result_ids(m: &Message) -> Vec<&str> {
+        m.content
+            .iter()
+            .filter_map(|b| match b {
+                ContentBlock::ToolResult { tool_use_id, .. } => Some(tool_use_id.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    for (i, msg) in messages.iter().enumerate() {
+        let results = tool_result_ids(msg);
+        if !results.is_empty() {
+            let answered = i
+                .checked_sub(1)
+                .map(|p| tool_use_ids(&messages[p]))
+                .unwrap_or_default();
+            if results.iter().any(|r| !answered.contains(r)) {
+                return Some(i);
+            }
+        }
+        let uses = tool_use_ids(msg);
+        if !uses.is_empty() {
+            let replies = messages.get(i + 1).map(tool_result_ids).unwrap_or_default();
+            if uses.iter().any(|u| !replies.contains(u)) {
+                return Some(i);
+            }
+        }
+    }
+    None
 }
 
 fn use_color() -> bool {
@@ -254,6 +674,8 @@ enum TurnStopReason {
     BlockLimitConsecutive,
     BlockLimitTotal,
     ConvergenceSignal,
+    BudgetExhausted,
+    ContextOverflow,
 }
 
 impl TurnStopReason {
@@ -266,10 +688,21 @@ impl TurnStopReason {
             TurnStopReason::BlockLimitConsecutive => "block_limit_consecutive",
             TurnStopReason::BlockLimitTotal => "block_limit_total",
             TurnStopReason::ConvergenceSignal => "convergence_signal",
+            TurnStopReason::BudgetExhausted => "budget_exhausted",
+            TurnStopReason::ContextOverflow => "context_overflow",
         }
     }
 }
 
+/// How a turn ended, for callers that run turns unattended.
+#[derive(Debug, Clone, Copy)]
+struct TurnOutcome {
+    reason: TurnStopReason,
+    tool_iterations: usize,
+    total_tokens: u64,
+    cost_usd: f64,
+}
+
 /// Unified pre-dispatch protocol for both parallel and sequential paths.
 /// Checks null-input, runs pre-hook, manages block counting and threshold checks.
 /// Block counts are mutated in place so callers cannot forget to apply them.
@@ -323,6 +756,8 @@ async fn run_pre_dispatch(
 /// Unified post-dispatch protocol for both parallel and sequential paths.
 /// Formats and logs the result, then runs post-hooks. Returns true if a
 /// convergence signal was received (caller should set signal_break).
+/// An Edit's diff is shown in full and passed to the hooks as `diff`.
+#[allow(clippy::too_many_arguments)]
 async fn run_post_dispatch(
     hooks: &HookRunner,
     name: &str,
@@ -331,21 +766,47 @@ async fn run_post_dispatch(
     is_error: bool,
     iterations: usize,
     verbose: bool,
+    diff: Option<&EditDiff>,
 ) -> bool {
     let display = format_tool_result_display(content, is_error, verbose);
     eprintln!("{display}");
+    if let Some(diff) = diff {
+        eprintln!("{}", diff.render(use_color()));
+    }
 
     let post_result = hooks
-        .run_post_tool_use(name, input, content, is_error, iterations)
+        .run_post_tool_use(name, input, content, is_error, iterations, diff)
         .await;
     matches!(post_result, PostToolResult::Signal { .. })
 }
 
+/// Renderer for streamed response events. Text goes to stdout (in the
+/// `--output-format` encoding) as it arrives; tool calls are announced on
+/// stderr the moment the model starts them.
+fn render_stream_event(event: &StreamEvent, output: &Output, verbose: bool) {
+    match event {
+        StreamEvent::TextDelta { text, .. } => output.emit(Event::TextDelta(text)),
+        StreamEvent::BlockStart {
+            block: ContentBlock::ToolUse { name, .. },
+            ..
+        } => {
+            eprintln!("\n[tool] {name}");
+        }
+        StreamEvent::Error {
+            error_type,
+            message,
+        } if verbose => {
+            eprintln!("\n[verbose] Stream error event: {error_type}: {message}");
+        }
+        _ => {}
+    }
+}
+
+/// The tool name is already announced when its block starts streaming, so
+/// dispatch only adds the (truncated) input in verbose mode.
 fn log_tool_dispatch(name: &str, input: &serde_json::Value, verbose: bool) {
     if verbose {
-        eprintln!("\n[tool] {name}({})", truncate_json(input, 100));
-    } else {
-        eprintln!("\n[tool] {name}");
+        eprintln!("[tool] {name}({})", truncate_json(input, 100));
     }
 }
 
@@ -357,10 +818,18 @@ fn threshold_stop_reason(consecutive_block_count: usize) -> TurnStopReason {
     }
 }
 
-/// Dispatch a tool and wrap the result as a ContentBlock::ToolResult.
-/// Used by the parallel path inside spawn_blocking.
-fn dispatch_to_tool_result(id: String, name: String, input: serde_json::Value) -> ContentBlock {
-    let result = dispatch_tool(&name, &input, &mut |_: &str| {});
+/// Run a tool and wrap the result as a ContentBlock::ToolResult.
+/// Used by the parallel path, which spawns one task per tool.
+async fn dispatch_to_tool_result(
+    tool: Option<Arc<dyn Tool>>,
+    id: String,
+    name: String,
+    input: serde_json::Value,
+) -> ContentBlock {
+    let result = match tool {
+        Some(tool) => tool.execute(&input, &mut |_: &str| {}).await,
+        None => Err(format!("Unknown tool: {name}")),
+    };
     let (content, is_error) = match result {
         Ok(output) => (output, false),
         Err(err) => (err, true),
@@ -412,9 +881,46 @@ fn format_tool_result_display(result: &str, is_error: bool, verbose: bool) -> St
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
-    let client = AnthropicClient::new(&cli.api_url);
-    let mut system_prompt = build_system_prompt();
-    let tools = all_tool_schemas();
+    if let Some(Command::Supervise(args)) = &cli.command {
+        if !supervise::run(args, cli.heartbeat_file.as_deref()).await {
+            std::process::exit(1);
+        }
+        return;
+    }
+    let config = Config::load(".forgeflare/config.toml");
+    let catalog = ModelCatalog::new(config.models, config.pricing);
+    if let Some(requested) = cli.max_tokens {
+        let limit = catalog.lookup(&cli.model).max_output_tokens;
+        if requested > limit {
+            eprintln!(
+                "[warn] --max-tokens {requested} exceeds {}'s output limit; using {limit}",
+                cli.model
+            );
+        }
+    }
+    let client = AnthropicClient::new(&cli.api_url)
+        .with_auth(Auth::from_config(&config.auth))
+        .with_stream_idle_timeout(Duration::from_secs(cli.stream_idle_timeout_secs));
+    let workspace = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+    let sandbox = tools::Sandbox::from_config(&config.sandbox, &workspace).map(Arc::new);
+    let mut tools =
+        ToolRegistry::builtin_with_sandbox(sandbox).with_diff_context(config.tools.diff_context);
+    // Held for the life of the process; dropping a server handle stops the server.
+    let _mcp_servers = mcp::start_all(
+        &mcp::load_config(".forgeflare/mcp.toml"),
+        &mut tools,
+        cli.verbose,
+    )
+    .await;
+    for name in &config.tools.disabled {
+        if !tools.remove(name) {
+            eprintln!("[config] Unknown tool in tools.disabled: {name}");
+        }
+    }
+    // The loop handles Ctrl-C itself and reaps jobs after the iteration
+    let in_loop = matches!(cli.command, Some(Command::Loop(_)));
+    reap_background_jobs_on_signal(tools.background_job_killer(), !in_loop);
+    let mut system_prompt = build_system_prompt(&tools);
 
     // Load project instructions (CLAUDE.md or AGENTS.md)
     match load_project_instructions() {
@@ -449,26 +955,112 @@ async fn main() {
     if cli.verbose {
         eprintln!("[verbose] API URL: {}", client.api_url());
         eprintln!("[verbose] Model: {}", cli.model);
-        eprintln!("[verbose] Max tokens: {}", cli.max_tokens);
+        if !cli.fallback_models.is_empty() {
+            eprintln!(
+                "[verbose] Fallback models: {} (after {} transient failures, {}s cool-down)",
+                cli.fallback_models.join(", "),
+                cli.fallback_after,
+                cli.fallback_cooldown_secs
+            );
+        }
+        let info = catalog.lookup(&cli.model);
         eprintln!(
-            "[verbose] API key: {}",
-            if client.has_api_key() {
-                "present"
+            "[verbose] Model limits: {} context, {} max output, thinking {}",
+            info.context_window,
+            info.max_output_tokens,
+            if info.supports_thinking {
+                "supported"
             } else {
-                "none (OAuth proxy mode)"
+                "not supported"
             }
         );
+        eprintln!(
+            "[verbose] Max tokens: {}",
+            catalog.max_tokens_for(&cli.model, cli.max_tokens)
+        );
+        eprintln!("[verbose] Auth: {}", client.auth_description());
+        eprintln!("[verbose] Tools: {}", tools.names().join(", "));
     }
 
-    let mut conversation: Vec<Message> = Vec::new();
-
     let cwd = std::env::current_dir()
         .map(|p| p.display().to_string())
         .unwrap_or_else(|_| ".".to_string());
-    let mut session = SessionWriter::new(&cwd, &cli.model);
     let hooks = HookRunner::load(".forgeflare/hooks.toml", &cwd);
+    let output = Output::new(cli.output_format);
     hooks.clear_convergence_state();
+    let mut cost = CostTracker::new(catalog.clone());
+    let mut chain = ModelChain::new(
+        &cli.model,
+        &cli.fallback_models,
+        Duration::from_secs(cli.fallback_cooldown_secs),
+    );
+    if !cost.has_pricing(&cli.model) {
+        eprintln!(
+            "[warn] No pricing known for model {}; cost will be reported as $0",
+            cli.model
+        );
+    }
 
+    if let Some(Command::Loop(args)) = &cli.command {
+        if cli.resume.is_some() || cli.continue_session {
+            eprintln!("[error] --resume and --continue cannot be used with loop");
+            std::process::exit(1);
+        }
+        agent_loop::run(
+            args,
+            &cli,
+            &client,
+            &system_prompt,
+            &tools,
+            &hooks,
+            &output,
+            &catalog,
+            &mut cost,
+            &mut chain,
+            &cwd,
+        )
+        .await;
+        return;
+    }
+
+    let resume_id = if cli.continue_session {
+        match SessionWriter::latest_session_id() {
+            Some(id) => Some(id),
+            None => {
+                eprintln!("[error] --continue: no previous session found in .entire/metadata");
+                std::process::exit(1);
+            }
+        }
+    } else {
+        cli.resume.clone()
+    };
+    let (mut session, mut conversation) = match resume_id {
+        Some(id) => match SessionWriter::resume(&cwd, &cli.model, &id) {
+            Ok((mut session, mut conversation)) => {
+                let restored = conversation.len();
+                repair_resumed_conversation(&mut conversation);
+                session.rollback(restored - conversation.len());
+                eprintln!(
+                    "[session] Resumed {id}: {} messages{}",
+                    conversation.len(),
+                    if conversation.len() < restored {
+                        format!(
+                            " ({} unpaired trailing messages dropped)",
+                            restored - conversation.len()
+                        )
+                    } else {
+                        String::new()
+                    }
+                );
+                (session, conversation)
+            }
+            Err(e) => {
+                eprintln!("[error] Cannot resume session: {e}");
+                std::process::exit(1);
+            }
+        },
+        None => (SessionWriter::new(&cwd, &cli.model), Vec::new()),
+    };
     if cli.verbose {
         eprintln!("[verbose] Session ID: {}", session.session_id());
         if hooks.has_hooks() {
@@ -498,6 +1090,10 @@ async fn main() {
             &mut conversation,
             &mut session,
             &hooks,
+            &output,
+            &catalog,
+            &mut cost,
+            &mut chain,
             &input,
         )
         .await;
@@ -528,6 +1124,33 @@ async fn main() {
             if input == "exit" || input == "quit" {
                 break;
             }
+            if let Some(rest) = input.strip_prefix("/compact") {
+                if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+                    let instructions = rest.trim();
+                    let active_model = chain.current().to_string();
+                    let compacted = compact_conversation(
+                        &cli,
+                        &client,
+                        &catalog,
+                        &mut conversation,
+                        &mut session,
+                        &hooks,
+                        &mut cost,
+                        &active_model,
+                        0,
+                        "manual",
+                        (!instructions.is_empty()).then_some(instructions),
+                        0,
+                    )
+                    .await;
+                    if compacted {
+                        eprintln!("[compact] Conversation compacted");
+                    } else {
+                        eprintln!("[compact] Nothing compacted");
+                    }
+                    continue;
+                }
+            }
 
             run_turn(
                 &cli,
@@ -537,13 +1160,52 @@ async fn main() {
                 &mut conversation,
                 &mut session,
                 &hooks,
+                &output,
+                &catalog,
+                &mut cost,
+                &mut chain,
                 &input,
             )
             .await;
         }
     }
 
-    session.write_context();
+    reap_background_jobs(&tools);
+    session.write_context(cost.session());
+}
+
+/// Kill background Bash jobs left running when a session ends.
+fn reap_background_jobs(tools: &ToolRegistry) {
+    let killed = tools.kill_background_jobs();
+    if killed > 0 {
+        eprintln!("[bash] Killed {killed} background job(s) still running at session end");
+    }
+}
+
+/// Kill background jobs and the Bash shell, then exit, on SIGTERM and (if
+/// `ctrl_c`) SIGINT. Both run in their own process groups, so these signals
+/// don't reach them, and exiting on a signal skips `reap_background_jobs`
+/// and every Drop.
+fn reap_background_jobs_on_signal(kill_jobs: impl Fn() -> usize + Send + 'static, ctrl_c: bool) {
+    let mut terminate =
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("[warn] Cannot handle SIGTERM: {e}");
+                return;
+            }
+        };
+    tokio::spawn(async move {
+        let code = tokio::select! {
+            _ = terminate.recv() => 143,
+            _ = tokio::signal::ctrl_c(), if ctrl_c => 130,
+        };
+        let killed = kill_jobs();
+        if killed > 0 {
+            eprintln!("[bash] Killed {killed} background job(s) on exit");
+        }
+        std::process::exit(code);
+    });
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -551,12 +1213,16 @@ async fn run_turn(
     cli: &Cli,
     client: &AnthropicClient,
     system_prompt: &str,
-    tools: &[serde_json::Value],
+    tools: &ToolRegistry,
     conversation: &mut Vec<Message>,
     session: &mut SessionWriter,
     hooks: &HookRunner,
+    output: &Output,
+    catalog: &ModelCatalog,
+    cost: &mut CostTracker,
+    chain: &mut ModelChain,
     input: &str,
-) {
+) -> TurnOutcome {
     let user_msg = Message {
         role: "user".to_string(),
         content: vec![ContentBlock::Text {
@@ -569,35 +1235,135 @@ async fn run_turn(
 
     let mut tool_iterations: usize = 0;
     let mut continuation_count: usize = 0;
-    let mut last_input_tokens: u64 = 0;
     let mut consecutive_block_count: usize = 0;
     let mut total_block_count: usize = 0;
     let mut total_tokens: u64 = 0;
+    let mut turn_cost_usd: f64 = 0.0;
+    let mut model_switches: Vec<ModelSwitch> = Vec::new();
+    let mut result_text = String::new();
     let mut turn_stop_reason = TurnStopReason::EndTurn;
+    let budget = cli.budget();
+    let schemas = tools.schemas();
     loop {
-        trim_if_needed(conversation, last_input_tokens);
+        if let Some(exceeded) = budget.exhausted(cost.session()) {
+            eprintln!("[warn] Budget exhausted: {exceeded}");
+            session.rollback(recover_conversation(conversation));
+            turn_stop_reason = TurnStopReason::BudgetExhausted;
+            break;
+        }
 
         if tool_iterations >= MAX_TOOL_ITERATIONS {
             eprintln!("[warn] Tool iteration limit ({MAX_TOOL_ITERATIONS}) reached");
-            recover_conversation(conversation);
+            session.rollback(recover_conversation(conversation));
             turn_stop_reason = TurnStopReason::IterationLimit;
             break;
         }
 
-        // Retry loop: attempt 0 = initial call, 1..=MAX_RETRIES = retries
+        if let Some(switch) = chain.restore_if_cooled(Instant::now()) {
+            eprintln!("[fallback] Returning to {} ({})", switch.to, switch.reason);
+            session.append_model_switch(&switch);
+            model_switches.push(switch);
+        }
+
+        let mut prompt_tokens = preflight_prompt_tokens(
+            cli,
+            client,
+            chain.current(),
+            system_prompt,
+            schemas,
+            conversation,
+        )
+        .await;
+        let model_info = catalog.lookup(chain.current());
+        let estimator = client.estimator();
+        let mut shrunk = false;
+        if cli.context_strategy == ContextStrategy::Compact
+            && prompt_tokens >= trim_threshold(model_info.context_window)
+        {
+            let budget = message_budget(
+                conversation,
+                prompt_tokens,
+                &estimator,
+                model_info.context_window,
+            );
+            shrunk = elide_tool_results(conversation, &estimator, budget);
+            if messages_tokens(conversation, &estimator) > budget {
+                let active_model = chain.current().to_string();
+                shrunk |= compact_conversation(
+                    cli,
+                    client,
+                    catalog,
+                    conversation,
+                    session,
+                    hooks,
+                    cost,
+                    &active_model,
+                    budget.saturating_sub(SUMMARY_MAX_TOKENS as u64),
+                    "auto",
+                    None,
+                    tool_iterations,
+                )
+                .await;
+            }
+            if shrunk {
+                prompt_tokens = estimator.request_tokens(system_prompt, schemas, conversation);
+            }
+        }
+        if trim_if_needed(
+            conversation,
+            prompt_tokens,
+            &estimator,
+            model_info.context_window,
+        ) {
+            shrunk = true;
+        }
+        if shrunk {
+            prompt_tokens = estimator.request_tokens(system_prompt, schemas, conversation);
+            if cli.verbose {
+                eprintln!("[verbose] Shrunk conversation to ~{prompt_tokens} prompt tokens");
+            }
+        }
+        if prompt_tokens >= model_info.context_window {
+            eprintln!(
+                "[error] Request is ~{prompt_tokens} tokens, over the {}-token \
+                 context window even after trimming; not sending",
+                model_info.context_window
+            );
+            session.rollback(recover_conversation(conversation));
+            turn_stop_reason = TurnStopReason::ContextOverflow;
+            break;
+        }
+
+        // Retry loop: attempt 0 = initial call, 1..=MAX_RETRIES = retries.
+        // A fallback switch restarts the count for the new model.
         let mut api_result = None;
-        #[allow(clippy::needless_range_loop)]
-        for attempt in 0..=MAX_RETRIES {
+        let mut attempt: usize = 0;
+        loop {
+            let max_tokens =
+                request_max_tokens(catalog, chain.current(), cli.max_tokens, continuation_count);
+            heartbeat::beat(
+                cli.heartbeat_file.as_deref(),
+                session.session_id(),
+                "api_call",
+                None,
+                tool_iterations,
+            );
+            let mut stream_beat = heartbeat::StreamBeat::new(
+                cli.heartbeat_file.as_deref(),
+                session.session_id(),
+                "streaming",
+                tool_iterations,
+            );
             let result = client
                 .send_message(
-                    &cli.model,
-                    cli.max_tokens,
+                    chain.current(),
+                    max_tokens,
                     system_prompt,
                     conversation,
-                    tools,
-                    &mut |text| {
-                        print!("{text}");
-                        io::stdout().flush().ok();
+                    schemas,
+                    &mut |event| {
+                        stream_beat.tick();
+                        render_stream_event(event, output, cli.verbose);
                     },
                 )
                 .await;
@@ -610,13 +1376,26 @@ async fn run_turn(
                 Err(e) => {
                     eprintln!("\n[error] API call failed: {e}");
                     if classify_error(&e) == ErrorClass::Permanent {
-                        recover_conversation(conversation);
+                        session.rollback(recover_conversation(conversation));
                         turn_stop_reason = TurnStopReason::ApiError;
                         break;
                     }
+                    if (attempt + 1) as u64 >= cli.fallback_after {
+                        let reason = fallback_reason(&e, attempt + 1);
+                        if let Some(switch) = chain.advance(&reason, Instant::now()) {
+                            eprintln!(
+                                "[fallback] Switching from {} to {}: {}",
+                                switch.from, switch.to, switch.reason
+                            );
+                            session.append_model_switch(&switch);
+                            model_switches.push(switch);
+                            attempt = 0;
+                            continue;
+                        }
+                    }
                     if attempt >= MAX_RETRIES {
                         eprintln!("[retry] Max retries ({MAX_RETRIES}) exhausted");
-                        recover_conversation(conversation);
+                        session.rollback(recover_conversation(conversation));
                         turn_stop_reason = TurnStopReason::ApiError;
                         break;
                     }
@@ -631,7 +1410,10 @@ async fn run_turn(
                     } else {
                         BACKOFF_SCHEDULE[attempt]
                     };
-                    if matches!(e, AgentError::StreamTransient(_)) {
+                    if matches!(
+                        e,
+                        AgentError::StreamTransient(_) | AgentError::StreamStalled { .. }
+                    ) {
                         eprintln!("[retry] Retrying from beginning of response...");
                     }
                     eprintln!(
@@ -640,6 +1422,7 @@ async fn run_turn(
                         e
                     );
                     tokio::time::sleep(std::time::Duration::from_secs(delay)).await;
+                    attempt += 1;
                 }
             }
         }
@@ -649,15 +1432,32 @@ async fn run_turn(
             None => break,
         };
 
-        last_input_tokens = usage.input_tokens;
-        total_tokens += usage.input_tokens + usage.output_tokens;
+        total_tokens += usage.input_tokens
+            + usage.output_tokens
+            + usage.cache_creation_input_tokens
+            + usage.cache_read_input_tokens;
+        let response_cost = cost.record(chain.current(), &usage);
+        turn_cost_usd += response_cost;
+        output.emit(Event::Usage {
+            model: chain.current(),
+            usage: &usage,
+            cost_usd: response_cost,
+        });
         if cli.verbose {
             eprintln!(
-                "[verbose] Cache: {} read, {} created, {} total input",
+                "[verbose] Cost: ${turn_cost_usd:.4} this turn, ${:.4} session",
+                cost.session().cost_usd
+            );
+            eprintln!(
+                "[verbose] Cache: {} read, {} created, {} total input ({:.0}% hit)",
                 usage.cache_read_input_tokens,
                 usage.cache_creation_input_tokens,
-                usage.input_tokens
+                usage.input_tokens,
+                usage.cache_hit_rate() * 100.0
             );
+            if let Some(rl) = client.rate_limit() {
+                eprintln!("[verbose] Rate limit: {}", rl.summary());
+            }
         }
 
         let blocks = if stop_reason == StopReason::MaxTokens {
@@ -672,17 +1472,21 @@ async fn run_turn(
         };
         conversation.push(assistant_msg.clone());
         session.append_assistant_turn(&assistant_msg, &usage);
+        let text = message_text(&assistant_msg);
+        if !text.is_empty() {
+            result_text = text;
+        }
 
         // EndTurn — normal completion
         if stop_reason == StopReason::EndTurn {
-            println!();
+            output.emit(Event::MessageEnd);
             turn_stop_reason = TurnStopReason::EndTurn;
             break;
         }
 
         // MaxTokens — decide: continue, dispatch tools, or break
         if stop_reason == StopReason::MaxTokens {
-            println!();
+            output.emit(Event::MessageEnd);
 
             match classify_max_tokens(&blocks, continuation_count) {
                 MaxTokensAction::BreakEmpty => {
@@ -725,10 +1529,13 @@ async fn run_turn(
                 _ => None,
             })
             .collect();
+        for (id, name, input) in &tool_uses {
+            output.emit(Event::ToolUse { id, name, input });
+        }
         let all_pure = !tool_uses.is_empty()
             && tool_uses
                 .iter()
-                .all(|(_, name, _)| tool_effect(name) == ToolEffect::Pure);
+                .all(|(_, name, _)| tools.effect(name) == ToolEffect::Pure);
 
         let mut signal_break = false;
         let mut threshold_tripped = false;
@@ -754,21 +1561,29 @@ async fn run_turn(
                 {
                     PreDispatchResult::Allow => {
                         log_tool_dispatch(name, input, cli.verbose);
+                        heartbeat::beat(
+                            cli.heartbeat_file.as_deref(),
+                            session.session_id(),
+                            "tool",
+                            Some(name),
+                            tool_iterations,
+                        );
                         let id = id.clone();
                         let name = name.clone();
                         let input = input.clone();
-                        let handle = tokio::task::spawn_blocking(move || {
-                            dispatch_to_tool_result(id, name, input)
-                        });
+                        let tool = tools.get(&name).cloned();
+                        let handle = tokio::spawn(dispatch_to_tool_result(tool, id, name, input));
                         spawn_futures.push((i, handle));
                     }
                     PreDispatchResult::Blocked(cb) => {
+                        emit_block(output, name, &cb);
                         slots[i] = Some(cb);
                         blocked_flags[i] = true;
                     }
                     PreDispatchResult::ThresholdTripped => {
                         threshold_tripped = true;
                         threshold_reason = threshold_stop_reason(consecutive_block_count);
+                        emit_block_limit(output, name, threshold_reason);
                         break;
                     }
                 }
@@ -797,9 +1612,11 @@ async fn run_turn(
                             is_err,
                             tool_iterations,
                             cli.verbose,
+                            None,
                         )
                         .await
                         {
+                            emit_signal(output, name);
                             signal_break = true;
                         }
                     }
@@ -824,27 +1641,41 @@ async fn run_turn(
                 {
                     PreDispatchResult::Allow => {}
                     PreDispatchResult::Blocked(cb) => {
+                        emit_block(output, name, &cb);
                         tool_results.push(cb);
                         continue;
                     }
                     PreDispatchResult::ThresholdTripped => {
                         threshold_tripped = true;
                         threshold_reason = threshold_stop_reason(consecutive_block_count);
+                        emit_block_limit(output, name, threshold_reason);
                         break;
                     }
                 }
 
                 log_tool_dispatch(name, input, cli.verbose);
+                heartbeat::beat(
+                    cli.heartbeat_file.as_deref(),
+                    session.session_id(),
+                    "tool",
+                    Some(name),
+                    tool_iterations,
+                );
 
-                let result = dispatch_tool(name, input, &mut |text| {
-                    if cli.verbose {
-                        eprint!("{text}");
-                    }
-                });
-                let (content, is_error) = match result {
+                let outcome = tools
+                    .dispatch_with_details(name, input, &mut |text| {
+                        if cli.verbose {
+                            eprint!("{text}");
+                        }
+                    })
+                    .await;
+                let (content, is_error) = match outcome.result {
                     Ok(output) => (output, false),
                     Err(err) => (err, true),
                 };
+                if let Some(dir) = tools.shell_cwd() {
+                    hooks.set_cwd(&dir.to_string_lossy());
+                }
 
                 if run_post_dispatch(
                     hooks,
@@ -854,9 +1685,11 @@ async fn run_turn(
                     is_error,
                     tool_iterations,
                     cli.verbose,
+                    outcome.diff.as_ref(),
                 )
                 .await
                 {
+                    emit_signal(output, name);
                     signal_break = true;
                 }
 
@@ -873,12 +1706,32 @@ async fn run_turn(
         // Block threshold takes precedence over signal_break
         if threshold_tripped {
             conversation.pop();
+            session.rollback(1);
             turn_stop_reason = threshold_reason;
             break;
         }
         if tool_results.is_empty() {
             break;
         }
+        for block in &tool_results {
+            if let ContentBlock::ToolResult {
+                tool_use_id,
+                content,
+                is_error,
+            } = block
+            {
+                let name = tool_uses
+                    .iter()
+                    .find(|(id, _, _)| id == tool_use_id)
+                    .map_or("", |(_, name, _)| name.as_str());
+                output.emit(Event::ToolResult {
+                    id: tool_use_id,
+                    name,
+                    content,
+                    is_error: is_error.unwrap_or(false),
+                });
+            }
+        }
         let tool_msg = Message {
             role: "user".to_string(),
             content: tool_results,
@@ -892,9 +1745,79 @@ async fn run_turn(
         }
     }
 
+    let rate_limit = client.rate_limit();
+    let background_jobs = tools.background_jobs();
     hooks
-        .run_stop(turn_stop_reason.as_str(), tool_iterations, total_tokens)
+        .run_stop(&StopContext {
+            cost_usd: turn_cost_usd,
+            session_cost_usd: cost.session().cost_usd,
+            rate_limit: rate_limit.as_ref(),
+            model: chain.current(),
+            model_switches: &model_switches,
+            background_jobs: &background_jobs,
+            ..StopContext::new(turn_stop_reason.as_str(), tool_iterations, total_tokens)
+        })
         .await;
+
+    output.emit(Event::Result(TurnResult {
+        result: &result_text,
+        session_id: session.session_id(),
+        stop_reason: turn_stop_reason.as_str(),
+        model: chain.current(),
+        tool_iterations,
+        total_tokens,
+        cost_usd: turn_cost_usd,
+        session_cost_usd: cost.session().cost_usd,
+    }));
+
+    TurnOutcome {
+        reason: turn_stop_reason,
+        tool_iterations,
+        total_tokens,
+        cost_usd: turn_cost_usd,
+    }
+}
+
+/// Text blocks of `message`, joined.
+fn message_text(message: &Message) -> String {
+    message
+        .content
+        .iter()
+        .filter_map(|b| match b {
+            ContentBlock::Text { text } => Some(text.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("")
+}
+
+fn emit_block(output: &Output, tool: &str, blocked: &ContentBlock) {
+    if let ContentBlock::ToolResult { content, .. } = blocked {
+        output.emit(Event::Hook {
+            event: "PreToolUse",
+            tool,
+            decision: "block",
+            reason: Some(content),
+        });
+    }
+}
+
+fn emit_block_limit(output: &Output, tool: &str, reason: TurnStopReason) {
+    output.emit(Event::Hook {
+        event: "PreToolUse",
+        tool,
+        decision: "block_limit",
+        reason: Some(reason.as_str()),
+    });
+}
+
+fn emit_signal(output: &Output, tool: &str) {
+    output.emit(Event::Hook {
+        event: "PostToolUse",
+        tool,
+        decision: "signal",
+        reason: None,
+    });
 }
 
 fn truncate_json(value: &serde_json::Value, max_len: usize) -> String {
@@ -920,6 +1843,16 @@ enum MaxTokensAction {
     BreakCapReached,
 }
 
+/// Short reason for a fallback switch. HTTP bodies are left out; they are
+/// already printed by the retry loop and can be large.
+fn fallback_reason(e: &AgentError, failures: usize) -> String {
+    let last = match e {
+        AgentError::HttpError { status, .. } => format!("HTTP {status}"),
+        other => other.to_string(),
+    };
+    format!("{failures} transient failure(s), last: {last}")
+}
+
 fn classify_max_tokens(blocks: &[ContentBlock], continuation_count: usize) -> MaxTokensAction {
     // Check for empty response (only the "[Response truncated]" placeholder)
     let is_empty = blocks.len() == 1
@@ -950,9 +1883,12 @@ fn atty_check() -> bool {
 mod tests {
     use super::*;
 
+    /// Context window of the built-in Claude models.
+    const CONTEXT: u64 = 200_000;
+
     #[test]
     fn system_prompt_contains_environment_info() {
-        let prompt = build_system_prompt();
+        let prompt = build_system_prompt(&ToolRegistry::builtin());
         assert!(prompt.contains("Working directory:"));
         assert!(prompt.contains("Platform:"));
         // Check PascalCase tool names
@@ -980,7 +1916,7 @@ mod tests {
             },
         ];
         let original_len = msgs.len();
-        trim_conversation(&mut msgs);
+        trim_conversation(&mut msgs, &TokenEstimator::default(), trim_target(CONTEXT));
         assert_eq!(msgs.len(), original_len);
     }
 
@@ -1152,6 +2088,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn fallback_reason_omits_http_body() {
+        let e = AgentError::HttpError {
+            status: 529,
+            retry_after: None,
+            body: "{\"type\":\"error\",\"error\":{\"type\":\"overloaded_error\"}}".to_string(),
+        };
+        assert_eq!(
+            fallback_reason(&e, 2),
+            "2 transient failure(s), last: HTTP 529"
+        );
+        let e = AgentError::StreamTransient("connection reset".to_string());
+        assert!(fallback_reason(&e, 3).ends_with("Stream error (transient): connection reset"));
+    }
+
     #[test]
     fn max_tokens_cap_enforcement() {
         let blocks = vec![ContentBlock::Text {
@@ -1203,15 +2154,29 @@ mod tests {
 
     #[test]
     fn trim_threshold_is_60_percent() {
-        assert_eq!(MODEL_CONTEXT_TOKENS, 200_000);
-        assert_eq!(TRIM_THRESHOLD, 120_000);
+        assert_eq!(trim_threshold(CONTEXT), 120_000);
+        assert_eq!(trim_target(CONTEXT), 100_000);
+        assert_eq!(trim_threshold(1_000_000), 600_000);
+    }
+
+    #[test]
+    fn request_max_tokens_escalates_continuations_only_when_unpinned() {
+        let catalog = ModelCatalog::default();
+        let model = "claude-opus-4-6";
+        assert_eq!(request_max_tokens(&catalog, model, None, 0), 16_384);
+        assert_eq!(request_max_tokens(&catalog, model, None, 1), 64_000);
+        assert_eq!(request_max_tokens(&catalog, model, Some(8_000), 1), 8_000);
+        assert_eq!(
+            request_max_tokens(&catalog, "claude-3-haiku", None, 0),
+            4_096
+        );
     }
 
     #[test]
-    fn trim_if_needed_zero_tokens_runs_trim() {
-        // First call (no data yet) — trim should run.
-        // Build a conversation that exceeds byte budget to verify trim actually fires.
-        let big_text = "x".repeat(CONTEXT_BUDGET_BYTES + 1000);
+    fn trim_if_needed_local_estimate_runs_trim() {
+        // No usage observed yet — the default-calibrated estimate alone must
+        // be enough to detect an oversized conversation.
+        let big_text = "x".repeat(800_000);
         let mut msgs = vec![
             Message {
                 role: "user".to_string(),
@@ -1239,7 +2204,10 @@ mod tests {
             },
         ];
         let original_len = msgs.len();
-        trim_if_needed(&mut msgs, 0);
+        let est = TokenEstimator::default();
+        let prompt_tokens = est.request_tokens("", &[], &msgs);
+        assert!(prompt_tokens >= trim_threshold(CONTEXT));
+        assert!(trim_if_needed(&mut msgs, prompt_tokens, &est, CONTEXT));
         // Conversation was over budget, trim should have removed messages
         assert!(
             msgs.len() < original_len,
@@ -1250,7 +2218,7 @@ mod tests {
     #[test]
     fn trim_if_needed_under_threshold_skips_trim() {
         // Usage is under 120K — trim should NOT run, even if byte budget exceeded.
-        let big_text = "x".repeat(CONTEXT_BUDGET_BYTES + 1000);
+        let big_text = "x".repeat(800_000);
         let mut msgs = vec![
             Message {
                 role: "user".to_string(),
@@ -1276,15 +2244,15 @@ mod tests {
             },
         ];
         let original_len = msgs.len();
-        trim_if_needed(&mut msgs, 50_000); // Well under 120K
-                                           // Trim should have been skipped entirely
+        trim_if_needed(&mut msgs, 50_000, &TokenEstimator::default(), CONTEXT); // Well under 120K
+                                                                                // Trim should have been skipped entirely
         assert_eq!(msgs.len(), original_len, "trim should not have run");
     }
 
     #[test]
     fn trim_if_needed_at_threshold_runs_trim() {
         // Usage exactly at threshold — trim should run.
-        let big_text = "x".repeat(CONTEXT_BUDGET_BYTES + 1000);
+        let big_text = "x".repeat(800_000);
         let mut msgs = vec![
             Message {
                 role: "user".to_string(),
@@ -1310,7 +2278,12 @@ mod tests {
             },
         ];
         let original_len = msgs.len();
-        trim_if_needed(&mut msgs, TRIM_THRESHOLD);
+        trim_if_needed(
+            &mut msgs,
+            trim_threshold(CONTEXT),
+            &TokenEstimator::default(),
+            CONTEXT,
+        );
         assert!(
             msgs.len() < original_len,
             "trim should have reduced message count"
@@ -1320,7 +2293,7 @@ mod tests {
     #[test]
     fn trim_if_needed_above_threshold_runs_trim() {
         // Usage above threshold — trim should run.
-        let big_text = "x".repeat(CONTEXT_BUDGET_BYTES + 1000);
+        let big_text = "x".repeat(800_000);
         let mut msgs = vec![
             Message {
                 role: "user".to_string(),
@@ -1346,7 +2319,7 @@ mod tests {
             },
         ];
         let original_len = msgs.len();
-        trim_if_needed(&mut msgs, 180_000);
+        trim_if_needed(&mut msgs, 180_000, &TokenEstimator::default(), CONTEXT);
         assert!(
             msgs.len() < original_len,
             "trim should have reduced message count"
@@ -1354,42 +2327,173 @@ mod tests {
     }
 
     #[test]
-    fn last_input_tokens_resets_per_turn() {
-        // last_input_tokens is a local variable in run_turn, so each call starts at 0.
-        // This test verifies the constant relationship — run_turn creates fresh state.
-        // The variable is initialized to 0 at the top of run_turn, verified by code inspection.
-        // We test the gate behavior: 0 always runs trim (first-call safety net).
+    fn trim_if_needed_small_conversation_unchanged() {
         let mut msgs = vec![Message {
             role: "user".to_string(),
             content: vec![ContentBlock::Text {
                 text: "hello".to_string(),
             }],
         }];
-        // Small conversation under budget — trim is a no-op, but should be called
-        trim_if_needed(&mut msgs, 0);
+        let est = TokenEstimator::default();
+        let prompt_tokens = est.request_tokens("", &[], &msgs);
+        assert!(!trim_if_needed(&mut msgs, prompt_tokens, &est, CONTEXT));
         assert_eq!(msgs.len(), 1, "small conversation unchanged by trim");
     }
 
+    #[test]
+    fn trim_if_needed_reserves_system_and_tools_overhead() {
+        // Messages alone fit the trim target, but the counted prompt is over
+        // threshold because of a large system prompt — messages must shrink
+        // enough to leave room for it.
+        let est = TokenEstimator::default();
+        let chunk = "x".repeat(36_000); // ~10K tokens per message
+        let mut msgs: Vec<Message> = (0..9)
+            .map(|i| Message {
+                role: if i % 2 == 0 { "user" } else { "assistant" }.to_string(),
+                content: vec![ContentBlock::Text {
+                    text: chunk.clone(),
+                }],
+            })
+            .collect();
+        let message_tokens: u64 = msgs.iter().map(|m| est.message_tokens(m)).sum();
+        assert!(message_tokens < trim_target(CONTEXT));
+
+        let prompt_tokens = message_tokens + 60_000;
+        assert!(trim_if_needed(&mut msgs, prompt_tokens, &est, CONTEXT));
+        let remaining: u64 = msgs.iter().map(|m| est.message_tokens(m)).sum();
+        assert!(
+            remaining + 60_000 <= trim_target(CONTEXT),
+            "remaining {remaining}"
+        );
+        assert_eq!(msgs[0].role, "user");
+    }
+
+    /// User prompt followed by `n` Read iterations of `bytes` each.
+    fn read_session(n: usize, bytes: usize) -> Vec<Message> {
+        let mut msgs = vec![Message {
+            role: "user".to_string(),
+            content: vec![ContentBlock::Text {
+                text: "refactor".to_string(),
+            }],
+        }];
+        for i in 0..n {
+            msgs.push(Message {
+                role: "assistant".to_string(),
+                content: vec![ContentBlock::ToolUse {
+                    id: format!("r{i}"),
+                    name: "Read".to_string(),
+                    input: serde_json::json!({"file_path": format!("src/file{i}.rs")}),
+                }],
+            });
+            msgs.push(Message {
+                role: "user".to_string(),
+                content: vec![ContentBlock::ToolResult {
+                    tool_use_id: format!("r{i}"),
+                    content: "x".repeat(bytes),
+                    is_error: None,
+                }],
+            });
+        }
+        msgs
+    }
+
+    fn result_content(msg: &Message) -> &str {
+        match &msg.content[0] {
+            ContentBlock::ToolResult { content, .. } => content,
+            _ => panic!("not a tool result"),
+        }
+    }
+
+    #[test]
+    fn elide_replaces_oldest_results_with_stubs() {
+        let est = TokenEstimator::default();
+        let mut msgs = read_session(10, 86_016); // ~24K tokens each
+        let budget = 150_000;
+        assert!(elide_tool_results(&mut msgs, &est, budget));
+
+        assert_eq!(msgs.len(), 21, "no messages dropped");
+        assert_eq!(
+            result_content(&msgs[2]),
+            "[elided: Read src/file0.rs, 84KB, re-read if needed]"
+        );
+        assert!(messages_tokens(&msgs, &est) <= budget);
+        // Stopped as soon as the budget was met: newer results survive.
+        assert_eq!(result_content(&msgs[14]).len(), 86_016);
+    }
+
+    #[test]
+    fn elide_never_touches_recent_iterations() {
+        let est = TokenEstimator::default();
+        let mut msgs = read_session(4, 100_000);
+        elide_tool_results(&mut msgs, &est, 0);
+        let protected = msgs.len() - ELIDE_KEEP_RECENT_MESSAGES;
+        for (i, msg) in msgs
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i % 2 == 0 && *i > 0)
+        {
+            let elided = result_content(msg).starts_with("[elided: ");
+            assert_eq!(elided, i < protected, "message {i}");
+        }
+    }
+
+    #[test]
+    fn elide_under_budget_is_noop() {
+        let mut msgs = read_session(2, 4_000);
+        assert!(!elide_tool_results(
+            &mut msgs,
+            &TokenEstimator::default(),
+            CONTEXT
+        ));
+        assert_eq!(result_content(&msgs[2]).len(), 4_000);
+    }
+
+    #[test]
+    fn trim_if_needed_prefers_elision_over_dropping() {
+        let est = TokenEstimator::default();
+        let mut msgs = read_session(12, 40_000);
+        let prompt_tokens = est.request_tokens("", &[], &msgs);
+        assert!(trim_if_needed(&mut msgs, prompt_tokens, &est, CONTEXT));
+        assert_eq!(msgs.len(), 25, "elision alone was enough");
+        assert!(messages_tokens(&msgs, &est) <= trim_target(CONTEXT));
+    }
+
+    #[test]
+    fn describe_tool_use_clips_long_commands() {
+        let msg = Message {
+            role: "assistant".to_string(),
+            content: vec![ContentBlock::ToolUse {
+                id: "b1".to_string(),
+                name: "Bash".to_string(),
+                input: serde_json::json!({"command": format!("echo {}\nls", "a".repeat(100))}),
+            }],
+        };
+        let desc = describe_tool_use(&msg, "b1").unwrap();
+        assert!(desc.starts_with("Bash echo aaa"));
+        assert!(desc.ends_with("..."));
+        assert_eq!(describe_tool_use(&msg, "missing"), None);
+    }
+
     // --- Tool parallelism tests ---
 
     #[test]
     fn batch_classification_all_pure() {
         // A batch of only Read/Glob/Grep tools should classify as all-pure
-        let tool_uses = vec![
+        let tool_uses = [
             ("id1", "Read", serde_json::json!({"file_path": "/tmp/a"})),
             ("id2", "Glob", serde_json::json!({"pattern": "*.rs"})),
             ("id3", "Grep", serde_json::json!({"pattern": "foo"})),
         ];
         let all_pure = tool_uses
             .iter()
-            .all(|(_, name, _)| tool_effect(name) == ToolEffect::Pure);
+            .all(|(_, name, _)| ToolRegistry::builtin().effect(name) == ToolEffect::Pure);
         assert!(all_pure, "all Read/Glob/Grep should be pure");
     }
 
     #[test]
     fn batch_classification_mixed_is_sequential() {
         // A batch with Read + Edit should NOT classify as all-pure
-        let tool_uses = vec![
+        let tool_uses = [
             ("id1", "Read", serde_json::json!({"file_path": "/tmp/a"})),
             (
                 "id2",
@@ -1399,17 +2503,17 @@ mod tests {
         ];
         let all_pure = tool_uses
             .iter()
-            .all(|(_, name, _)| tool_effect(name) == ToolEffect::Pure);
+            .all(|(_, name, _)| ToolRegistry::builtin().effect(name) == ToolEffect::Pure);
         assert!(!all_pure, "mixed batch with Edit should not be all-pure");
     }
 
     #[test]
     fn batch_classification_single_pure() {
         // Degenerate case: batch of 1 pure tool works correctly
-        let tool_uses = vec![("id1", "Read", serde_json::json!({"file_path": "/tmp/a"}))];
+        let tool_uses = [("id1", "Read", serde_json::json!({"file_path": "/tmp/a"}))];
         let all_pure = tool_uses
             .iter()
-            .all(|(_, name, _)| tool_effect(name) == ToolEffect::Pure);
+            .all(|(_, name, _)| ToolRegistry::builtin().effect(name) == ToolEffect::Pure);
         assert!(all_pure, "single Read tool should be all-pure");
     }
 
@@ -1434,15 +2538,15 @@ mod tests {
             })
             .collect();
 
-        // Parallel: use join_all with spawn_blocking
+        // Parallel: one task per Read, joined with join_all
+        let registry = ToolRegistry::builtin();
         let start = std::time::Instant::now();
         let handles: Vec<_> = files
             .iter()
             .map(|f| {
-                let f = f.clone();
-                tokio::task::spawn_blocking(move || {
-                    dispatch_tool("Read", &serde_json::json!({"file_path": f}), &mut |_| {})
-                })
+                let registry = registry.clone();
+                let input = serde_json::json!({"file_path": f});
+                tokio::spawn(async move { registry.dispatch("Read", &input, &mut |_| {}).await })
             })
             .collect();
         let results = futures_util::future::join_all(handles).await;
@@ -1456,7 +2560,9 @@ mod tests {
         // Sequential
         let start = std::time::Instant::now();
         for f in &files {
-            let r = dispatch_tool("Read", &serde_json::json!({"file_path": f}), &mut |_| {});
+            let r = registry
+                .dispatch("Read", &serde_json::json!({"file_path": f}), &mut |_| {})
+                .await;
             assert!(r.is_ok());
         }
         let sequential_time = start.elapsed();
@@ -1478,7 +2584,7 @@ mod tests {
         let _ = std::fs::create_dir_all(&dir);
         std::fs::write(dir.join("exists.txt"), "hello").unwrap();
 
-        let inputs = vec![
+        let inputs = [
             ("id1", dir.join("exists.txt").to_str().unwrap().to_string()),
             (
                 "id2",
@@ -1490,21 +2596,12 @@ mod tests {
         let handles: Vec<_> = inputs
             .iter()
             .map(|(id, f)| {
-                let id = id.to_string();
-                let f = f.clone();
-                tokio::task::spawn_blocking(move || {
-                    let result =
-                        dispatch_tool("Read", &serde_json::json!({"file_path": f}), &mut |_| {});
-                    let (content, is_error) = match result {
-                        Ok(output) => (output, false),
-                        Err(err) => (err, true),
-                    };
-                    ContentBlock::ToolResult {
-                        tool_use_id: id,
-                        content,
-                        is_error: if is_error { Some(true) } else { None },
-                    }
-                })
+                tokio::spawn(dispatch_to_tool_result(
+                    ToolRegistry::builtin().get("Read").cloned(),
+                    id.to_string(),
+                    "Read".to_string(),
+                    serde_json::json!({"file_path": f}),
+                ))
             })
             .collect();
 
@@ -1561,10 +2658,10 @@ mod tests {
             .iter()
             .map(|(id, f)| {
                 let id = id.clone();
-                let f = f.clone();
-                tokio::task::spawn_blocking(move || {
-                    let result =
-                        dispatch_tool("Read", &serde_json::json!({"file_path": f}), &mut |_| {});
+                let registry = ToolRegistry::builtin();
+                let input = serde_json::json!({"file_path": f});
+                tokio::spawn(async move {
+                    let result = registry.dispatch("Read", &input, &mut |_| {}).await;
                     (id, result)
                 })
             })
@@ -1713,10 +2810,11 @@ mod tests {
     #[test]
     fn tool_schemas_have_no_cache_control() {
         // cache_control is added at send time in send_message(), not in tool schemas.
-        // This ensures all_tool_schemas() returns clean schemas.
-        let schemas = all_tool_schemas();
+        // This ensures the registry returns clean schemas.
+        let registry = ToolRegistry::builtin();
+        let schemas = registry.schemas();
         assert!(!schemas.is_empty(), "should have tool schemas");
-        for schema in &schemas {
+        for schema in schemas {
             assert!(
                 schema.get("cache_control").is_none(),
                 "tool schema should not contain cache_control: {}",
@@ -1915,9 +3013,13 @@ mod tests {
     }
 
     #[test]
-    fn build_system_prompt_signature_unchanged() {
-        // build_system_prompt() returns String, takes no args — this compiles iff true.
-        let _: String = build_system_prompt();
+    fn system_prompt_lists_registered_tools_only() {
+        let mut tools = ToolRegistry::builtin();
+        tools.remove("Bash");
+        let prompt = build_system_prompt(&tools);
+        assert!(prompt
+            .contains("- Read: Read file contents with line numbers (paged by offset/limit)\n"));
+        assert!(!prompt.contains("- Bash:"));
     }
 
     // --- Pre-dispatch tests ---
@@ -2034,6 +3136,101 @@ mod tests {
         }
     }
 
+    #[test]
+    fn repair_resumed_drops_interrupted_tool_dispatch() {
+        // Crashed after the assistant asked for a tool but before the result was written.
+        let mut msgs = vec![
+            Message {
+                role: "user".to_string(),
+                content: vec![ContentBlock::Text {
+                    text: "first".to_string(),
+                }],
+            },
+            Message {
+                role: "assistant".to_string(),
+                content: vec![ContentBlock::Text {
+                    text: "ok".to_string(),
+                }],
+            },
+            Message {
+                role: "user".to_string(),
+                content: vec![ContentBlock::Text {
+                    text: "read it".to_string(),
+                }],
+            },
+            Message {
+                role: "assistant".to_string(),
+                content: vec![
+                    ContentBlock::Text {
+                        text: "Reading".to_string(),
+                    },
+                    ContentBlock::ToolUse {
+                        id: "tu_1".to_string(),
+                        name: "Read".to_string(),
+                        input: serde_json::json!({"file_path": "/tmp/x"}),
+                    },
+                ],
+            },
+        ];
+        assert_eq!(first_unpaired_message(&msgs), Some(3));
+        repair_resumed_conversation(&mut msgs);
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[1].role, "assistant");
+    }
+
+    #[test]
+    fn repair_resumed_keeps_valid_pairs() {
+        let mut msgs = vec![
+            Message {
+                role: "user".to_string(),
+                content: vec![ContentBlock::Text {
+                    text: "first".to_string(),
+                }],
+            },
+            Message {
+                role: "assistant".to_string(),
+                content: vec![ContentBlock::ToolUse {
+                    id: "tu_1".to_string(),
+                    name: "Read".to_string(),
+                    input: serde_json::json!({"file_path": "/tmp/x"}),
+                }],
+            },
+            Message {
+                role: "user".to_string(),
+                content: vec![ContentBlock::ToolResult {
+                    tool_use_id: "tu_1".to_string(),
+                    content: "data".to_string(),
+                    is_error: None,
+                }],
+            },
+            Message {
+                role: "assistant".to_string(),
+                content: vec![ContentBlock::Text {
+                    text: "done".to_string(),
+                }],
+            },
+        ];
+        assert_eq!(first_unpaired_message(&msgs), None);
+        repair_resumed_conversation(&mut msgs);
+        assert_eq!(msgs.len(), 4);
+
+        // A tool_result answering nothing is cut along with everything after it.
+        msgs.insert(
+            1,
+            Message {
+                role: "user".to_string(),
+                content: vec![ContentBlock::ToolResult {
+                    tool_use_id: "stray".to_string(),
+                    content: "?".to_string(),
+                    is_error: None,
+                }],
+            },
+        );
+        assert_eq!(first_unpaired_message(&msgs), Some(1));
+        repair_resumed_conversation(&mut msgs);
+        assert_eq!(msgs.len(), 1);
+    }
+
     #[test]
     fn recover_conversation_empty_vec_is_noop() {
         let mut msgs: Vec<Message> = vec![];
@@ -2090,9 +3287,8 @@ mod tests {
                     let id = id.clone();
                     let name = name.clone();
                     let input = input.clone();
-                    let handle = tokio::task::spawn_blocking(move || {
-                        dispatch_to_tool_result(id, name, input)
-                    });
+                    let tool = ToolRegistry::builtin().get(&name).cloned();
+                    let handle = tokio::spawn(dispatch_to_tool_result(tool, id, name, input));
                     spawn_futures.push((i, handle));
                 }
                 PreDispatchResult::Blocked(cb) => {
@@ -2144,7 +3340,8 @@ mod tests {
             }) = slots[i]
             {
                 let is_err = is_error.unwrap_or(false);
-                let _ = run_post_dispatch(&hooks, name, input, content, is_err, 0, false).await;
+                let _ =
+                    run_post_dispatch(&hooks, name, input, content, is_err, 0, false, None).await;
                 post_dispatch_count += 1;
             }
         }
@@ -2245,9 +3442,8 @@ mod tests {
                     let id = id.clone();
                     let name = name.clone();
                     let input = input.clone();
-                    let handle = tokio::task::spawn_blocking(move || {
-                        dispatch_to_tool_result(id, name, input)
-                    });
+                    let tool = ToolRegistry::builtin().get(&name).cloned();
+                    let handle = tokio::spawn(dispatch_to_tool_result(tool, id, name, input));
                     spawn_futures.push((i, handle));
                 }
                 PreDispatchResult::Blocked(cb) => {
@@ -2384,9 +3580,8 @@ mod tests {
                     let id = id.clone();
                     let name = name.clone();
                     let input = input.clone();
-                    let handle = tokio::task::spawn_blocking(move || {
-                        dispatch_to_tool_result(id, name, input)
-                    });
+                    let tool = ToolRegistry::builtin().get(&name).cloned();
+                    let handle = tokio::spawn(dispatch_to_tool_result(tool, id, name, input));
                     spawn_futures.push((i, handle));
                 }
                 PreDispatchResult::Blocked(cb) => {
@@ -2431,7 +3626,8 @@ mod tests {
             }) = slots[i]
             {
                 let is_err = is_error.unwrap_or(false);
-                let _ = run_post_dispatch(&hooks, name, input, content, is_err, 0, false).await;
+                let _ =
+                    run_post_dispatch(&hooks, name, input, content, is_err, 0, false, None).await;
                 post_dispatch_count += 1;
             }
         }
@@ -2497,7 +3693,7 @@ mod tests {
                 text: first_text.clone(),
             }],
         }];
-        // Stuff enough messages to exceed CONTEXT_BUDGET_BYTES
+        // Stuff enough messages to exceed the trim target (~555K estimated tokens)
         let filler = "x".repeat(100_000);
         for i in 0..20 {
             msgs.push(Message {
@@ -2508,7 +3704,7 @@ mod tests {
             });
         }
         let original_len = msgs.len();
-        trim_conversation(&mut msgs);
+        trim_conversation(&mut msgs, &TokenEstimator::default(), trim_target(CONTEXT));
         assert!(msgs.len() < original_len, "should have trimmed something");
         // The critical assertion: first message content is byte-identical.
         if let ContentBlock::Text { text } = &msgs[0].content[0] {
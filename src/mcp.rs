@@ -0,0 +1,546 @@
+//! Client for stdio MCP servers configured in `.forgeflare/mcp.toml`. Each
+//! server's tools are registered as `mcp__<server>__<tool>` and dispatched
+//! through the normal hook path like the built-ins.
+
+use crate::tools::{StreamCallback, Tool, ToolEffect, ToolRegistry, ToolResult};
+use futures_util::future::BoxFuture;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Mutex;
+
+const PROTOCOL_VERSION: &str = "2025-06-18";
+const DEFAULT_TIMEOUT_MS: u64 = 60_000;
+/// Tool names the API accepts: `^[a-zA-Z0-9_-]{1,128}$`.
+const MAX_TOOL_NAME: usize = 128;
+/// Longest tool description used as the system-prompt summary.
+const SUMMARY_LIMIT: usize = 100;
+
+#[derive(Debug, Default, Deserialize)]
+struct McpFile {
+    #[serde(default)]
+    servers: Vec<ServerConfig>,
+}
+
+/// One `[[servers]]` entry in `.forgeflare/mcp.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerConfig {
+    pub name: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Per-request timeout, including the initialize handshake.
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum McpError {
+    #[error("cannot start server: {0}")]
+    Spawn(std::io::Error),
+    #[error("i/o error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("timed out after {0}ms")]
+    Timeout(u64),
+    #[error("server closed the connection")]
+    Closed,
+    #[error("invalid response: {0}")]
+    Protocol(String),
+    #[error("error {code}: {message}")]
+    Rpc { code: i64, message: String },
+}
+
+/// Parse `.forgeflare/mcp.toml`. Missing file yields no servers; a parse
+/// error is reported and also yields none.
+pub fn load_config(path: &str) -> Vec<ServerConfig> {
+    match fs::read_to_string(path) {
+        Ok(content) => match toml::from_str::<McpFile>(&content) {
+            Ok(file) => file.servers,
+            Err(e) => {
+                eprintln!("[mcp] Failed to parse {path}: {e}");
+                Vec::new()
+            }
+        },
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Start every configured server and register its tools. A server that fails
+/// to start or list tools is skipped with a warning. The returned handles own
+/// the server processes; dropping them stops the servers.
+pub async fn start_all(
+    servers: &[ServerConfig],
+    registry: &mut ToolRegistry,
+    verbose: bool,
+) -> Vec<Arc<McpServer>> {
+    let mut started = Vec::new();
+    for config in servers {
+        let server = match McpServer::start(config, verbose).await {
+            Ok(server) => Arc::new(server),
+            Err(e) => {
+                eprintln!("[mcp] {}: {e}", config.name);
+                continue;
+            }
+        };
+        match server.list_tools().await {
+            Ok(tools) => {
+                if verbose {
+                    eprintln!(
+                        "[verbose] MCP server {}: {} tools",
+                        config.name,
+                        tools.len()
+                    );
+                }
+                for def in tools {
+                    registry.register(Arc::new(McpTool::new(Arc::clone(&server), def)));
+                }
+                started.push(server);
+            }
+            Err(e) => eprintln!("[mcp] {}: tools/list failed: {e}", config.name),
+        }
+    }
+    started
+}
+
+struct Connection {
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    next_id: u64,
+}
+
+/// A running stdio MCP server. Requests are serialized over one connection.
+pub struct McpServer {
+    name: String,
+    timeout_ms: u64,
+    conn: Mutex<Connection>,
+    _child: Child,
+}
+
+/// A tool advertised by `tools/list`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ToolDef {
+    name: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default = "empty_schema")]
+    input_schema: Value,
+    #[serde(default)]
+    annotations: ToolAnnotations,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ToolAnnotations {
+    #[serde(default)]
+    read_only_hint: bool,
+}
+
+fn empty_schema() -> Value {
+    json!({"type": "object", "properties": {}})
+}
+
+impl McpServer {
+    /// Spawn the server and complete the initialize handshake.
+    async fn start(config: &ServerConfig, verbose: bool) -> Result<Self, McpError> {
+        let mut child = Command::new(&config.command)
+            .args(&config.args)
+            .envs(&config.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(if verbose {
+                Stdio::inherit()
+            } else {
+                Stdio::null()
+            })
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(McpError::Spawn)?;
+        let stdin = child.stdin.take().ok_or(McpError::Closed)?;
+        let stdout = child.stdout.take().ok_or(McpError::Closed)?;
+
+        let server = Self {
+            name: config.name.clone(),
+            timeout_ms: config.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS),
+            conn: Mutex::new(Connection {
+                stdin,
+                stdout: BufReader::new(stdout),
+                next_id: 1,
+            }),
+            _child: child,
+        };
+
+        server
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": {"name": "forgeflare", "version": env!("CARGO_PKG_VERSION")},
+                }),
+            )
+            .await?;
+        server.notify("notifications/initialized").await?;
+        Ok(server)
+    }
+
+    /// All tools, following `nextCursor` pagination.
+    async fn list_tools(&self) -> Result<Vec<ToolDef>, McpError> {
+        let mut tools = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(c) => json!({"cursor": c}),
+                None => json!({}),
+            };
+            let result = self.request("tools/list", params).await?;
+            let page: Vec<ToolDef> = serde_json::from_value(result["tools"].clone())
+                .map_err(|e| McpError::Protocol(format!("tools/list: {e}")))?;
+            tools.extend(page);
+            cursor = result["nextCursor"].as_str().map(str::to_string);
+            if cursor.is_none() {
+                return Ok(tools);
+            }
+        }
+    }
+
+    async fn call_tool(&self, name: &str, arguments: &Value) -> ToolResult {
+        let result = self
+            .request("tools/call", json!({"name": name, "arguments": arguments}))
+            .await
+            .map_err(|e| format!("MCP server {}: {e}", self.name))?;
+        let text = render_content(&result);
+        if result["isError"].as_bool() == Some(true) {
+            Err(text)
+        } else {
+            Ok(text)
+        }
+    }
+
+    async fn notify(&self, method: &str) -> Result<(), McpError> {
+        let mut conn = self.conn.lock().await;
+        let msg = json!({"jsonrpc": "2.0", "method": method});
+        write_message(&mut conn.stdin, &msg).await
+    }
+
+    /// Send a request and wait for its response, answering any server-initiated
+    /// requests and skipping notifications that arrive in between.
+    async fn request(&self, method: &str, params: Value) -> Result<Value, McpError> {
+        let mut conn = self.conn.lock().await;
+        let id = conn.next_id;
+        conn.next_id += 1;
+        let msg = json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params});
+
+        let exchange = async {
+            write_message(&mut conn.stdin, &msg).await?;
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if conn.stdout.read_line(&mut line).await? == 0 {
+                    return Err(McpError::Closed);
+                }
+                let Ok(reply) = serde_json::from_str::<Value>(line.trim()) else {
+                    continue; // stray non-JSON output
+                };
+                if reply.get("method").is_some() {
+                    if let Some(server_id) = reply.get("id") {
+                        let answer = server_request_reply(server_id, &reply["method"]);
+                        write_message(&mut conn.stdin, &answer).await?;
+                    }
+                    continue;
+                }
+                if reply["id"].as_u64() != Some(id) {
+                    continue;
+                }
+                if let Some(err) = reply.get("error") {
+                    return Err(McpError::Rpc {
+                        code: err["code"].as_i64().unwrap_or(0),
+                        message: err["message"].as_str().unwrap_or("").to_string(),
+                    });
+                }
+                return Ok(reply.get("result").cloned().unwrap_or(Value::Null));
+            }
+        };
+        tokio::time::timeout(Duration::from_millis(self.timeout_ms), exchange)
+            .await
+            .map_err(|_| McpError::Timeout(self.timeout_ms))?
+    }
+}
+
+/// Reply to a request the server sent us: `ping` succeeds, anything else
+/// (sampling, roots, elicitation) is not supported.
+fn server_request_reply(id: &Value, method: &Value) -> Value {
+    if method == "ping" {
+        json!({"jsonrpc": "2.0", "id": id, "result": {}})
+    } else {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {"code": -32601, "message": format!("method not supported: {method}")},
+        })
+    }
+}
+
+async fn write_message(stdin: &mut ChildStdin, msg: &Value) -> Result<(), McpError> {
+    let mut line = msg.to_string();
+    line.push('\n');
+    stdin.write_all(line.as_bytes()).await?;
+    stdin.flush().await?;
+    Ok(())
+}
+
+/// Flatten a `tools/call` result's content blocks to text.
+fn render_content(result: &Value) -> String {
+    let Some(blocks) = result["content"].as_array() else {
+        return String::new();
+    };
+    blocks
+        .iter()
+        .map(|b| match b["type"].as_str() {
+            Some("text") => b["text"].as_str().unwrap_or("").to_string(),
+            Some("resource") => b["resource"]["text"]
+                .as_str()
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("[resource: {}]", b["resource"]["uri"])),
+            Some("resource_link") => format!("[resource: {}]", b["uri"]),
+            Some(other) => format!("[{other} content omitted]"),
+            None => String::new(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// `mcp__<server>__<tool>`, with characters the API rejects replaced by `_`.
+fn qualified_name(server: &str, tool: &str) -> String {
+    let name: String = format!("mcp__{server}__{tool}")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    name.chars().take(MAX_TOOL_NAME).collect()
+}
+
+/// First line of `description`, shortened for the system-prompt tool list.
+fn summarize(description: &str) -> String {
+    let first = description.lines().next().unwrap_or("").trim();
+    if first.is_empty() {
+        return "MCP tool".to_string();
+    }
+    let end = first.floor_char_boundary(SUMMARY_LIMIT);
+    if end < first.len() {
+        format!("{}...", &first[..end])
+    } else {
+        first.to_string()
+    }
+}
+
+/// A tool on an MCP server, exposed to the model under its qualified name.
+struct McpTool {
+    server: Arc<McpServer>,
+    name: String,
+    remote_name: String,
+    description: String,
+    summary: String,
+    input_schema: Value,
+    effect: ToolEffect,
+}
+
+impl McpTool {
+    fn new(server: Arc<McpServer>, def: ToolDef) -> Self {
+        Self {
+            name: qualified_name(&server.name, &def.name),
+            summary: summarize(&def.description),
+            description: def.description,
+            input_schema: def.input_schema,
+            // Only tools that promise not to modify anything may run concurrently.
+            effect: if def.annotations.read_only_hint {
+                ToolEffect::Pure
+            } else {
+                ToolEffect::Mutating
+            },
+            remote_name: def.name,
+            server,
+        }
+    }
+}
+
+impl Tool for McpTool {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn input_schema(&self) -> Value {
+        self.input_schema.clone()
+    }
+
+    fn prompt_summary(&self) -> &str {
+        &self.summary
+    }
+
+    fn effect(&self) -> ToolEffect {
+        self.effect
+    }
+
+    fn execute<'a>(&'a self, input: &'a Value, _: StreamCallback<'a>) -> BoxFuture<'a, ToolResult> {
+        Box::pin(self.server.call_tool(&self.remote_name, input))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimal stdio MCP server: `echo` (read-only) returns its `text`
+    /// argument, `fail` returns an error result. Sends a notification and a
+    /// ping before answering tools/call to exercise the client's skipping.
+    const FIXTURE: &str = r#"#!/bin/bash
+while IFS= read -r line; do
+  id=$(printf '%s' "$line" | sed -n 's/.*"id":\([0-9]*\).*/\1/p')
+  case "$line" in
+    *'"method":"initialize"'*)
+      printf '{"jsonrpc":"2.0","id":%s,"result":{"protocolVersion":"2025-06-18","capabilities":{"tools":{}},"serverInfo":{"name":"fixture","version":"1"}}}\n' "$id" ;;
+    *'"method":"notifications/initialized"'*) ;;
+    *'"method":"tools/list"'*)
+      printf '{"jsonrpc":"2.0","id":%s,"result":{"tools":[{"name":"echo","description":"Echo text back\\nSecond line","inputSchema":{"type":"object","properties":{"text":{"type":"string"}}},"annotations":{"readOnlyHint":true}},{"name":"fail","description":"Always fails","inputSchema":{"type":"object"}}]}}\n' "$id" ;;
+    *'"method":"tools/call"'*'"name":"echo"'*)
+      text=$(printf '%s' "$line" | sed -n 's/.*"text":"\([^"]*\)".*/\1/p')
+      printf '{"jsonrpc":"2.0","method":"notifications/message","params":{}}\n'
+      printf '{"jsonrpc":"2.0","id":"srv-1","method":"ping"}\n'
+      IFS= read -r pong
+      printf '{"jsonrpc":"2.0","id":%s,"result":{"content":[{"type":"text","text":"echo: %s"}]}}\n' "$id" "$text" ;;
+    *'"method":"tools/call"'*)
+      printf '{"jsonrpc":"2.0","id":%s,"result":{"content":[{"type":"text","text":"boom"}],"isError":true}}\n' "$id" ;;
+    *'"id":'*)
+      printf '{"jsonrpc":"2.0","id":%s,"error":{"code":-32601,"message":"unknown method"}}\n' "$id" ;;
+  esac
+done
+"#;
+
+    fn fixture_config(dir: &std::path::Path) -> ServerConfig {
+        let script = dir.join("server.sh");
+        fs::write(&script, FIXTURE).unwrap();
+        ServerConfig {
+            name: "fixture".to_string(),
+            command: "bash".to_string(),
+            args: vec![script.display().to_string()],
+            env: HashMap::new(),
+            timeout_ms: Some(5_000),
+        }
+    }
+
+    #[test]
+    fn load_config_reads_servers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mcp.toml");
+        fs::write(
+            &path,
+            r#"
+[[servers]]
+name = "tickets"
+command = "tickets-mcp"
+args = ["--stdio"]
+env = { TICKETS_URL = "https://tickets.example.com" }
+timeout_ms = 10000
+"#,
+        )
+        .unwrap();
+        let servers = load_config(path.to_str().unwrap());
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].name, "tickets");
+        assert_eq!(servers[0].args, ["--stdio"]);
+        assert_eq!(servers[0].env["TICKETS_URL"], "https://tickets.example.com");
+        assert!(load_config("/nonexistent/mcp.toml").is_empty());
+    }
+
+    #[test]
+    fn qualified_names_are_api_safe() {
+        assert_eq!(qualified_name("db", "schema"), "mcp__db__schema");
+        assert_eq!(
+            qualified_name("my server", "get.ticket/v2"),
+            "mcp__my_server__get_ticket_v2"
+        );
+        assert_eq!(qualified_name("s", &"x".repeat(200)).len(), MAX_TOOL_NAME);
+    }
+
+    #[test]
+    fn content_blocks_flatten_to_text() {
+        let result = json!({"content": [
+            {"type": "text", "text": "a"},
+            {"type": "image", "data": "...", "mimeType": "image/png"},
+            {"type": "resource", "resource": {"uri": "file:///x", "text": "body"}},
+        ]});
+        assert_eq!(render_content(&result), "a\n[image content omitted]\nbody");
+        assert_eq!(summarize("Echo text back\nmore"), "Echo text back");
+    }
+
+    #[tokio::test]
+    async fn fixture_server_tools_register_and_dispatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut registry = ToolRegistry::builtin();
+        let servers = start_all(&[fixture_config(dir.path())], &mut registry, false).await;
+        assert_eq!(servers.len(), 1);
+
+        assert!(registry.get("mcp__fixture__echo").is_some());
+        assert_eq!(registry.effect("mcp__fixture__echo"), ToolEffect::Pure);
+        assert_eq!(registry.effect("mcp__fixture__fail"), ToolEffect::Mutating);
+        assert!(registry
+            .prompt_list()
+            .contains("- mcp__fixture__echo: Echo text back"));
+
+        let out = registry
+            .dispatch("mcp__fixture__echo", &json!({"text": "hi"}), &mut |_| {})
+            .await;
+        assert_eq!(out.unwrap(), "echo: hi");
+
+        let err = registry
+            .dispatch("mcp__fixture__fail", &json!({}), &mut |_| {})
+            .await;
+        assert_eq!(err.unwrap_err(), "boom");
+    }
+
+    #[tokio::test]
+    async fn missing_server_is_skipped() {
+        let mut registry = ToolRegistry::builtin();
+        let config = ServerConfig {
+            name: "missing".to_string(),
+            command: "/nonexistent/mcp-server".to_string(),
+            args: Vec::new(),
+            env: HashMap::new(),
+            timeout_ms: Some(1_000),
+        };
+        let servers = start_all(&[config], &mut registry, false).await;
+        assert!(servers.is_empty());
+        assert_eq!(registry.schemas().len(), 7);
+    }
+
+    #[tokio::test]
+    async fn rpc_error_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = McpServer::start(&fixture_config(dir.path()), false)
+            .await
+            .unwrap();
+        match server.request("resources/list", json!({})).await {
+            Err(McpError::Rpc { code, message }) => {
+                assert_eq!(code, -32601);
+                assert_eq!(message, "unknown method");
+            }
+            other => panic!("expected rpc error, got {other:?}"),
+        }
+    }
+}
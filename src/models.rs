@@ -0,0 +1,324 @@
+use crate::cost::ModelPricing;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Requested output size when `--max-tokens` is not given, capped by the model's limit.
+pub const DEFAULT_MAX_TOKENS: u32 = 16_384;
+
+/// Limits and pricing for one model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelInfo {
+    pub context_window: u64,
+    pub max_output_tokens: u32,
+    pub supports_thinking: bool,
+    /// `None` for models we have no rates for; they are costed at $0.
+    pub pricing: Option<ModelPricing>,
+}
+
+impl ModelInfo {
+    const fn claude(
+        context_window: u64,
+        max_output_tokens: u32,
+        supports_thinking: bool,
+        pricing: ModelPricing,
+    ) -> Self {
+        Self {
+            context_window,
+            max_output_tokens,
+            supports_thinking,
+            pricing: Some(pricing),
+        }
+    }
+}
+
+/// Used for models not in the built-in table and not configured.
+const UNKNOWN_MODEL: ModelInfo = ModelInfo {
+    context_window: 200_000,
+    max_output_tokens: DEFAULT_MAX_TOKENS,
+    supports_thinking: false,
+    pricing: None,
+};
+
+/// Built-in catalog, matched by model-name prefix. More specific prefixes
+/// come first so `claude-opus-4-6` does not fall through to `claude-opus-4`.
+const BUILTIN_MODELS: &[(&str, ModelInfo)] = &[
+    (
+        "claude-opus-4-6",
+        ModelInfo::claude(
+            200_000,
+            64_000,
+            true,
+            ModelPricing::new(5.0, 25.0, 6.25, 0.50),
+        ),
+    ),
+    (
+        "claude-opus-4-5",
+        ModelInfo::claude(
+            200_000,
+            64_000,
+            true,
+            ModelPricing::new(5.0, 25.0, 6.25, 0.50),
+        ),
+    ),
+    (
+        "claude-opus-4",
+        ModelInfo::claude(
+            200_000,
+            32_000,
+            true,
+            ModelPricing::new(15.0, 75.0, 18.75, 1.50),
+        ),
+    ),
+    (
+        "claude-sonnet-4",
+        ModelInfo::claude(
+            200_000,
+            64_000,
+            true,
+            ModelPricing::new(3.0, 15.0, 3.75, 0.30),
+        ),
+    ),
+    (
+        "claude-haiku-4",
+        ModelInfo::claude(
+            200_000,
+            64_000,
+            true,
+            ModelPricing::new(1.0, 5.0, 1.25, 0.10),
+        ),
+    ),
+    (
+        "claude-3-7-sonnet",
+        ModelInfo::claude(
+            200_000,
+            64_000,
+            true,
+            ModelPricing::new(3.0, 15.0, 3.75, 0.30),
+        ),
+    ),
+    (
+        "claude-3-5-sonnet",
+        ModelInfo::claude(
+            200_000,
+            8_192,
+            false,
+            ModelPricing::new(3.0, 15.0, 3.75, 0.30),
+        ),
+    ),
+    (
+        "claude-3-5-haiku",
+        ModelInfo::claude(
+            200_000,
+            8_192,
+            false,
+            ModelPricing::new(0.80, 4.0, 1.0, 0.08),
+        ),
+    ),
+    (
+        "claude-3-haiku",
+        ModelInfo::claude(
+            200_000,
+            4_096,
+            false,
+            ModelPricing::new(0.25, 1.25, 0.30, 0.03),
+        ),
+    ),
+];
+
+/// `[models."<prefix>"]` entry in `.forgeflare/config.toml`. Unset fields
+/// keep the built-in (or unknown-model) value.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ModelOverride {
+    pub context_window: Option<u64>,
+    pub max_output_tokens: Option<u32>,
+    pub supports_thinking: Option<bool>,
+    pub pricing: Option<ModelPricing>,
+}
+
+/// Built-in model table plus config overrides.
+#[derive(Debug, Clone, Default)]
+pub struct ModelCatalog {
+    overrides: HashMap<String, ModelOverride>,
+}
+
+impl ModelCatalog {
+    /// `pricing` is the older `[pricing."<prefix>"]` table; it fills in
+    /// pricing for prefixes whose `[models]` entry doesn't set its own.
+    pub fn new(
+        mut overrides: HashMap<String, ModelOverride>,
+        pricing: HashMap<String, ModelPricing>,
+    ) -> Self {
+        for (prefix, p) in pricing {
+            let entry = overrides.entry(prefix).or_default();
+            entry.pricing.get_or_insert(p);
+        }
+        Self { overrides }
+    }
+
+    /// Resolve `model`: the longest matching built-in prefix (or the unknown-model
+    /// defaults), then every matching override on top, shortest prefix first,
+    /// so a longer prefix wins only for the fields it sets.
+    pub fn lookup(&self, model: &str) -> ModelInfo {
+        let mut info = BUILTIN_MODELS
+            .iter()
+            .find(|(prefix, _)| model.starts_with(prefix))
+            .map(|(_, info)| *info)
+            .unwrap_or(UNKNOWN_MODEL);
+
+        let mut matching: Vec<_> = self
+            .overrides
+            .iter()
+            .filter(|(prefix, _)| model.starts_with(prefix.as_str()))
+            .collect();
+        matching.sort_by_key(|(prefix, _)| prefix.len());
+        for (_, o) in matching {
+            if let Some(v) = o.context_window {
+                info.context_window = v;
+            }
+            if let Some(v) = o.max_output_tokens {
+                info.max_output_tokens = v;
+            }
+            if let Some(v) = o.supports_thinking {
+                info.supports_thinking = v;
+            }
+            if let Some(v) = o.pricing {
+                info.pricing = Some(v);
+            }
+        }
+        info
+    }
+
+    /// Output budget for a request: `requested` (or the default) capped at the
+    /// model's maximum.
+    pub fn max_tokens_for(&self, model: &str, requested: Option<u32>) -> u32 {
+        requested
+            .unwrap_or(DEFAULT_MAX_TOKENS)
+            .min(self.lookup(model).max_output_tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_prefers_most_specific_prefix() {
+        let catalog = ModelCatalog::default();
+        let opus46 = catalog.lookup("claude-opus-4-6");
+        let opus41 = catalog.lookup("claude-opus-4-1-20250805");
+        assert_eq!(opus46.pricing.unwrap().input, 5.0);
+        assert_eq!(opus41.pricing.unwrap().input, 15.0);
+        assert_eq!(opus41.max_output_tokens, 32_000);
+    }
+
+    #[test]
+    fn unknown_model_gets_defaults_without_pricing() {
+        let info = ModelCatalog::default().lookup("llama-3-local");
+        assert_eq!(info, UNKNOWN_MODEL);
+        assert!(info.pricing.is_none());
+    }
+
+    #[test]
+    fn overrides_layer_over_builtin() {
+        let mut overrides = HashMap::new();
+        overrides.insert(
+            "claude-sonnet-4".to_string(),
+            ModelOverride {
+                context_window: Some(1_000_000),
+                ..ModelOverride::default()
+            },
+        );
+        let catalog = ModelCatalog::new(overrides, HashMap::new());
+        let info = catalog.lookup("claude-sonnet-4-5");
+        assert_eq!(info.context_window, 1_000_000);
+        assert_eq!(info.max_output_tokens, 64_000);
+        assert_eq!(info.pricing.unwrap().output, 15.0);
+    }
+
+    #[test]
+    fn local_model_fully_configured() {
+        let mut overrides = HashMap::new();
+        overrides.insert(
+            "qwen".to_string(),
+            ModelOverride {
+                context_window: Some(32_768),
+                max_output_tokens: Some(4_096),
+                supports_thinking: Some(false),
+                pricing: None,
+            },
+        );
+        let catalog = ModelCatalog::new(overrides, HashMap::new());
+        let info = catalog.lookup("qwen2.5-coder-32b");
+        assert_eq!(info.context_window, 32_768);
+        assert_eq!(catalog.max_tokens_for("qwen2.5-coder-32b", None), 4_096);
+        assert_eq!(
+            catalog.max_tokens_for("qwen2.5-coder-32b", Some(100_000)),
+            4_096
+        );
+    }
+
+    #[test]
+    fn legacy_pricing_table_merges() {
+        let mut pricing = HashMap::new();
+        pricing.insert(
+            "claude-opus-4-6".to_string(),
+            ModelPricing::new(1.0, 2.0, 3.0, 4.0),
+        );
+        let catalog = ModelCatalog::new(HashMap::new(), pricing);
+        assert_eq!(
+            catalog.lookup("claude-opus-4-6").pricing.unwrap().output,
+            2.0
+        );
+    }
+
+    #[test]
+    fn matching_overrides_merge_by_field() {
+        let mut overrides = HashMap::new();
+        overrides.insert(
+            "claude-opus-4".to_string(),
+            ModelOverride {
+                context_window: Some(1_000_000),
+                max_output_tokens: Some(20_000),
+                ..ModelOverride::default()
+            },
+        );
+        overrides.insert(
+            "claude-opus-4-6".to_string(),
+            ModelOverride {
+                max_output_tokens: Some(10_000),
+                ..ModelOverride::default()
+            },
+        );
+        let mut pricing = HashMap::new();
+        pricing.insert(
+            "claude-opus".to_string(),
+            ModelPricing::new(1.0, 2.0, 3.0, 4.0),
+        );
+        let catalog = ModelCatalog::new(overrides, pricing);
+
+        // The longer prefix wins where both set a field
+        let info = catalog.lookup("claude-opus-4-6");
+        assert_eq!(info.context_window, 1_000_000);
+        assert_eq!(info.max_output_tokens, 10_000);
+        assert_eq!(info.pricing.unwrap().output, 2.0);
+        assert_eq!(catalog.lookup("claude-opus-4-1").max_output_tokens, 20_000);
+    }
+
+    #[test]
+    fn max_tokens_defaults_and_caps() {
+        let catalog = ModelCatalog::default();
+        assert_eq!(
+            catalog.max_tokens_for("claude-opus-4-6", None),
+            DEFAULT_MAX_TOKENS
+        );
+        assert_eq!(catalog.max_tokens_for("claude-3-5-haiku", None), 8_192);
+        assert_eq!(
+            catalog.max_tokens_for("claude-opus-4-6", Some(200_000)),
+            64_000
+        );
+        assert_eq!(
+            catalog.max_tokens_for("claude-opus-4-6", Some(1_000)),
+            1_000
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//! stdout rendering for `--output-format`. Human-oriented logs stay on stderr
+//! in every format; only the stream written here changes.
+
+use crate::api::Usage;
+use clap::ValueEnum;
+use serde_json::{json, Value};
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// Assistant text as it streams
+    Text,
+    /// One result object per turn
+    Json,
+    /// NDJSON events as they happen, ending with the result object
+    StreamJson,
+}
+
+/// End-of-turn summary, emitted as the `result` object.
+pub struct TurnResult<'a> {
+    pub result: &'a str,
+    pub session_id: &'a str,
+    pub stop_reason: &'a str,
+    pub model: &'a str,
+    pub tool_iterations: usize,
+    pub total_tokens: u64,
+    pub cost_usd: f64,
+    pub session_cost_usd: f64,
+}
+
+pub enum Event<'a> {
+    /// Streamed assistant text.
+    TextDelta(&'a str),
+    /// An assistant message finished streaming.
+    MessageEnd,
+    ToolUse {
+        id: &'a str,
+        name: &'a str,
+        input: &'a Value,
+    },
+    ToolResult {
+        id: &'a str,
+        name: &'a str,
+        content: &'a str,
+        is_error: bool,
+    },
+    /// A hook changed the flow: a guard block, a block limit, or a convergence signal.
+    Hook {
+        event: &'a str,
+        tool: &'a str,
+        decision: &'a str,
+        reason: Option<&'a str>,
+    },
+    Usage {
+        model: &'a str,
+        usage: &'a Usage,
+        cost_usd: f64,
+    },
+    Result(TurnResult<'a>),
+}
+
+pub struct Output {
+    format: OutputFormat,
+}
+
+impl Output {
+    pub fn new(format: OutputFormat) -> Self {
+        Self { format }
+    }
+
+    pub fn emit(&self, event: Event<'_>) {
+        if let Some(text) = self.render(&event) {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(text.as_bytes()).ok();
+            stdout.flush().ok();
+        }
+    }
+
+    /// What `event` writes to stdout in this format, if anything.
+    fn render(&self, event: &Event<'_>) -> Option<String> {
+        match self.format {
+            OutputFormat::Text => match event {
+                Event::TextDelta(text) => Some(text.to_string()),
+                Event::MessageEnd => Some("\n".to_string()),
+                _ => None,
+            },
+            OutputFormat::Json => match event {
+                Event::Result(r) => Some(format!("{}\n", result_json(r))),
+                _ => None,
+            },
+            OutputFormat::StreamJson => event_json(event).map(|v| format!("{v}\n")),
+        }
+    }
+}
+
+fn result_json(r: &TurnResult<'_>) -> Value {
+    json!({
+        "type": "result",
+        "result": r.result,
+        "session_id": r.session_id,
+        "stop_reason": r.stop_reason,
+        "model": r.model,
+        "tool_iterations": r.tool_iterations,
+        "total_tokens": r.total_tokens,
+        "cost_usd": r.cost_usd,
+        "session_cost_usd": r.session_cost_usd,
+    })
+}
+
+fn event_json(event: &Event<'_>) -> Option<Value> {
+    Some(match event {
+        Event::TextDelta(text) => json!({"type": "text_delta", "text": text}),
+        Event::MessageEnd => return None,
+        Event::ToolUse { id, name, input } => {
+            json!({"type": "tool_use", "id": id, "name": name, "input": input})
+        }
+        Event::ToolResult {
+            id,
+            name,
+            content,
+            is_error,
+        } => json!({
+            "type": "tool_result",
+            "id": id,
+            "name": name,
+            "content": content,
+            "is_error": is_error,
+        }),
+        Event::Hook {
+            event,
+            tool,
+            decision,
+            reason,
+        } => json!({
+            "type": "hook",
+            "event": event,
+            "tool": tool,
+            "decision": decision,
+            "reason": reason,
+        }),
+        Event::Usage {
+            model,
+            usage,
+            cost_usd,
+        } => json!({"type": "usage", "model": model, "usage": usage, "cost_usd": cost_usd}),
+        Event::Result(r) => result_json(r),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_result() -> TurnResult<'static> {
+        TurnResult {
+            result: "All tests pass.",
+            session_id: "2026-01-01-abc",
+            stop_reason: "end_turn",
+            model: "claude-opus-4-6",
+            tool_iterations: 3,
+            total_tokens: 1200,
+            cost_usd: 0.01,
+            session_cost_usd: 0.02,
+        }
+    }
+
+    #[test]
+    fn text_format_prints_only_assistant_text() {
+        let out = Output::new(OutputFormat::Text);
+        assert_eq!(out.render(&Event::TextDelta("hi")).as_deref(), Some("hi"));
+        assert_eq!(out.render(&Event::MessageEnd).as_deref(), Some("\n"));
+        assert!(out.render(&Event::Result(sample_result())).is_none());
+    }
+
+    #[test]
+    fn json_format_prints_only_the_result() {
+        let out = Output::new(OutputFormat::Json);
+        assert!(out.render(&Event::TextDelta("hi")).is_none());
+        let line = out.render(&Event::Result(sample_result())).unwrap();
+        let v: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(v["type"], "result");
+        assert_eq!(v["result"], "All tests pass.");
+        assert_eq!(v["session_id"], "2026-01-01-abc");
+        assert_eq!(v["stop_reason"], "end_turn");
+        assert_eq!(v["session_cost_usd"], 0.02);
+    }
+
+    #[test]
+    fn stream_json_emits_one_object_per_line() {
+        let out = Output::new(OutputFormat::StreamJson);
+        let input = json!({"command": "ls"});
+        let usage = Usage {
+            input_tokens: 10,
+            output_tokens: 5,
+            ..Usage::default()
+        };
+        let events = [
+            Event::TextDelta("a\nb"),
+            Event::ToolUse {
+                id: "t1",
+                name: "Bash",
+                input: &input,
+            },
+            Event::Hook {
+                event: "PreToolUse",
+                tool: "Bash",
+                decision: "block",
+                reason: Some("no rm"),
+            },
+            Event::ToolResult {
+                id: "t1",
+                name: "Bash",
+                content: "no rm",
+                is_error: true,
+            },
+            Event::Usage {
+                model: "claude-opus-4-6",
+                usage: &usage,
+                cost_usd: 0.0002,
+            },
+            Event::Result(sample_result()),
+        ];
+        let types: Vec<String> = events
+            .iter()
+            .map(|e| {
+                let line = out.render(e).unwrap();
+                assert_eq!(line.matches('\n').count(), 1, "{line}");
+                let v: Value = serde_json::from_str(&line).unwrap();
+                v["type"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(
+            types,
+            [
+                "text_delta",
+                "tool_use",
+                "hook",
+                "tool_result",
+                "usage",
+                "result"
+            ]
+        );
+        assert!(out.render(&Event::MessageEnd).is_none());
+    }
+}
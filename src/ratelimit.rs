@@ -0,0 +1,253 @@
+use chrono::{DateTime, Utc};
+use reqwest::header::HeaderMap;
+use serde_json::Value;
+use std::time::Duration;
+
+/// Throttle when a bucket drops below this fraction of its limit.
+const LOW_WATER_FRACTION: f64 = 0.05;
+/// Never sleep longer than this on a proactive throttle, even if the reset is further out.
+const MAX_THROTTLE_SECS: u64 = 60;
+/// Upper bound on the per-process jitter added to throttle delays, so processes
+/// sharing one organization budget don't all wake at the same reset instant.
+const MAX_JITTER_MS: u64 = 1000;
+
+/// One `anthropic-ratelimit-<name>-{limit,remaining,reset}` header triple.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bucket {
+    pub limit: u64,
+    pub remaining: u64,
+    pub reset: Option<DateTime<Utc>>,
+}
+
+impl Bucket {
+    fn from_headers(headers: &HeaderMap, name: &str) -> Option<Self> {
+        let get = |suffix: &str| {
+            headers
+                .get(format!("anthropic-ratelimit-{name}-{suffix}"))
+                .and_then(|v| v.to_str().ok())
+        };
+        let limit = get("limit")?.parse::<u64>().ok()?;
+        let remaining = get("remaining")?.parse::<u64>().ok()?;
+        let reset = get("reset")
+            .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+            .map(|dt| dt.with_timezone(&Utc));
+        Some(Self {
+            limit,
+            remaining,
+            reset,
+        })
+    }
+
+    /// Requests run out one at a time, so only throttle when none are left.
+    /// Token buckets drain by whole requests, so keep a margin.
+    fn is_low(&self, name: &str) -> bool {
+        if name == "requests" {
+            self.remaining == 0
+        } else {
+            (self.remaining as f64) < (self.limit as f64) * LOW_WATER_FRACTION
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        serde_json::json!({
+            "limit": self.limit,
+            "remaining": self.remaining,
+            "reset": self.reset.map(|r| r.to_rfc3339()),
+        })
+    }
+}
+
+/// Rate-limit budgets reported by the most recent API response.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimitState {
+    pub requests: Option<Bucket>,
+    pub tokens: Option<Bucket>,
+    pub input_tokens: Option<Bucket>,
+    pub output_tokens: Option<Bucket>,
+}
+
+impl RateLimitState {
+    /// Parse the `anthropic-ratelimit-*` headers. Returns `None` when the
+    /// response carried none (e.g. a proxy that strips them).
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let state = Self {
+            requests: Bucket::from_headers(headers, "requests"),
+            tokens: Bucket::from_headers(headers, "tokens"),
+            input_tokens: Bucket::from_headers(headers, "input-tokens"),
+            output_tokens: Bucket::from_headers(headers, "output-tokens"),
+        };
+        if state == Self::default() {
+            None
+        } else {
+            Some(state)
+        }
+    }
+
+    fn buckets(&self) -> [(&'static str, Option<&Bucket>); 4] {
+        [
+            ("requests", self.requests.as_ref()),
+            ("tokens", self.tokens.as_ref()),
+            ("input_tokens", self.input_tokens.as_ref()),
+            ("output_tokens", self.output_tokens.as_ref()),
+        ]
+    }
+
+    /// How long to wait before the next request, and which bucket forced it.
+    /// Picks the furthest reset among exhausted buckets; a reset already in
+    /// the past means the bucket has refilled.
+    pub fn throttle_delay(&self, now: DateTime<Utc>) -> Option<(Duration, &'static str)> {
+        let (name, wait) = self
+            .buckets()
+            .into_iter()
+            .filter_map(|(name, bucket)| {
+                let bucket = bucket?;
+                if !bucket.is_low(name) {
+                    return None;
+                }
+                let wait = (bucket.reset? - now).to_std().ok()?;
+                Some((name, wait))
+            })
+            .max_by_key(|(_, wait)| *wait)?;
+
+        let capped = wait.min(Duration::from_secs(MAX_THROTTLE_SECS));
+        Some((capped + process_jitter(), name))
+    }
+
+    /// One-line summary for verbose output.
+    pub fn summary(&self) -> String {
+        let parts: Vec<String> = self
+            .buckets()
+            .into_iter()
+            .filter_map(|(name, bucket)| {
+                bucket.map(|b| format!("{name} {}/{}", b.remaining, b.limit))
+            })
+            .collect();
+        parts.join(", ")
+    }
+
+    /// JSON form passed to hooks.
+    pub fn to_json(&self) -> Value {
+        let mut obj = serde_json::Map::new();
+        for (name, bucket) in self.buckets() {
+            if let Some(b) = bucket {
+                obj.insert(name.to_string(), b.to_json());
+            }
+        }
+        Value::Object(obj)
+    }
+}
+
+fn process_jitter() -> Duration {
+    Duration::from_millis(u64::from(std::process::id()) % MAX_JITTER_MS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (k, v) in pairs {
+            map.insert(*k, HeaderValue::from_str(v).unwrap());
+        }
+        map
+    }
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2026-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn no_ratelimit_headers_yields_none() {
+        assert!(RateLimitState::from_headers(&headers(&[("retry-after", "5")])).is_none());
+    }
+
+    #[test]
+    fn parses_all_buckets() {
+        let h = headers(&[
+            ("anthropic-ratelimit-requests-limit", "50"),
+            ("anthropic-ratelimit-requests-remaining", "49"),
+            ("anthropic-ratelimit-requests-reset", "2026-01-01T00:00:01Z"),
+            ("anthropic-ratelimit-input-tokens-limit", "40000"),
+            ("anthropic-ratelimit-input-tokens-remaining", "39000"),
+            (
+                "anthropic-ratelimit-input-tokens-reset",
+                "2026-01-01T00:00:02Z",
+            ),
+            ("anthropic-ratelimit-output-tokens-limit", "8000"),
+            ("anthropic-ratelimit-output-tokens-remaining", "7000"),
+        ]);
+        let state = RateLimitState::from_headers(&h).unwrap();
+        assert_eq!(state.requests.as_ref().unwrap().remaining, 49);
+        assert!(state.tokens.is_none());
+        assert_eq!(state.input_tokens.as_ref().unwrap().limit, 40000);
+        assert!(state.output_tokens.as_ref().unwrap().reset.is_none());
+        assert_eq!(
+            state.summary(),
+            "requests 49/50, input_tokens 39000/40000, output_tokens 7000/8000"
+        );
+        assert_eq!(state.to_json()["requests"]["remaining"], 49);
+        assert_eq!(
+            state.to_json()["requests"]["reset"],
+            "2026-01-01T00:00:01+00:00"
+        );
+    }
+
+    #[test]
+    fn healthy_budget_does_not_throttle() {
+        let h = headers(&[
+            ("anthropic-ratelimit-requests-limit", "50"),
+            ("anthropic-ratelimit-requests-remaining", "1"),
+            ("anthropic-ratelimit-requests-reset", "2026-01-01T00:00:10Z"),
+            ("anthropic-ratelimit-tokens-limit", "100000"),
+            ("anthropic-ratelimit-tokens-remaining", "5000"),
+            ("anthropic-ratelimit-tokens-reset", "2026-01-01T00:00:10Z"),
+        ]);
+        let state = RateLimitState::from_headers(&h).unwrap();
+        assert!(state.throttle_delay(now()).is_none());
+    }
+
+    #[test]
+    fn exhausted_bucket_throttles_until_reset() {
+        let h = headers(&[
+            ("anthropic-ratelimit-requests-limit", "50"),
+            ("anthropic-ratelimit-requests-remaining", "0"),
+            ("anthropic-ratelimit-requests-reset", "2026-01-01T00:00:05Z"),
+            ("anthropic-ratelimit-tokens-limit", "100000"),
+            ("anthropic-ratelimit-tokens-remaining", "100"),
+            ("anthropic-ratelimit-tokens-reset", "2026-01-01T00:00:12Z"),
+        ]);
+        let state = RateLimitState::from_headers(&h).unwrap();
+        let (delay, bucket) = state.throttle_delay(now()).unwrap();
+        assert_eq!(bucket, "tokens");
+        assert!(delay >= Duration::from_secs(12));
+        assert!(delay < Duration::from_secs(12) + Duration::from_millis(MAX_JITTER_MS));
+    }
+
+    #[test]
+    fn throttle_is_capped_and_past_resets_ignored() {
+        let far = headers(&[
+            ("anthropic-ratelimit-requests-limit", "50"),
+            ("anthropic-ratelimit-requests-remaining", "0"),
+            ("anthropic-ratelimit-requests-reset", "2026-01-01T01:00:00Z"),
+        ]);
+        let (delay, _) = RateLimitState::from_headers(&far)
+            .unwrap()
+            .throttle_delay(now())
+            .unwrap();
+        assert!(delay < Duration::from_secs(MAX_THROTTLE_SECS + 1));
+
+        let past = headers(&[
+            ("anthropic-ratelimit-requests-limit", "50"),
+            ("anthropic-ratelimit-requests-remaining", "0"),
+            ("anthropic-ratelimit-requests-reset", "2025-12-31T23:59:59Z"),
+        ]);
+        assert!(RateLimitState::from_headers(&past)
+            .unwrap()
+            .throttle_delay(now())
+            .is_none());
+    }
+}
@@ -1,6 +1,10 @@
 use crate::api::{ContentBlock, Message, Usage};
+use crate::compact;
+use crate::cost::UsageTotals;
+use crate::fallback::ModelSwitch;
 use chrono::Utc;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs::{self, OpenOptions};
 use std::io::Write;
 use std::path::{Path, PathBuf};
@@ -19,43 +23,129 @@ struct JsonlLine<'a> {
     cwd: &'a str,
     version: &'a str,
     message: MessagePayload<'a>,
+    #[serde(flatten)]
+    replay: Replay,
+}
+
+/// What a `system` line does to the conversation when a session is resumed.
+#[derive(Default, Serialize, Deserialize)]
+struct Replay {
+    /// Compaction: messages after the first one and before this line's
+    /// message were replaced by the summary.
+    #[serde(rename = "firstKept", default, skip_serializing_if = "Option::is_none")]
+    first_kept: Option<String>,
+    /// Rollback: this many trailing messages were dropped after a failed request.
+    #[serde(
+        rename = "rolledBack",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    rolled_back: Option<usize>,
 }
 
 #[derive(Serialize)]
 struct MessagePayload<'a> {
     role: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<&'a str>,
     content: &'a [ContentBlock],
     #[serde(skip_serializing_if = "Option::is_none")]
     usage: Option<&'a Usage>,
 }
 
+/// The fields of a `full.jsonl` line needed to rebuild the conversation.
+#[derive(Deserialize)]
+struct TranscriptLine {
+    #[serde(rename = "type")]
+    turn_type: String,
+    uuid: String,
+    #[serde(rename = "parentUuid")]
+    parent_uuid: Option<String>,
+    message: Message,
+    #[serde(flatten)]
+    replay: Replay,
+}
+
 pub struct SessionWriter {
     session_id: String,
     dir: PathBuf,
     cwd: String,
     last_uuid: Option<String>,
+    /// Line uuid behind each live conversation message, oldest first; `None`
+    /// for a compaction summary. Only the tail lines up with the live
+    /// conversation, since trimming drops messages without telling us.
+    live: Vec<Option<String>>,
     prompt_written: bool,
     tool_actions: Vec<(String, String)>,
     model: String,
+    /// Model serving requests right now; differs from `model` after a fallback switch.
+    active_model: String,
     start_time: String,
+    /// Set when this run continued an earlier session: (resume time, messages restored).
+    resumed: Option<(String, usize)>,
 }
 
 impl SessionWriter {
     pub fn new(cwd: &str, model: &str) -> Self {
         let date = Utc::now().format("%Y-%m-%d").to_string();
         let session_id = format!("{}-{}", date, Uuid::new_v4());
-        let dir = Path::new(".entire").join("metadata").join(&session_id);
+        let dir = session_root().join(&session_id);
 
         Self {
             session_id,
             dir,
             cwd: cwd.to_string(),
             last_uuid: None,
+            live: Vec::new(),
             prompt_written: false,
             tool_actions: Vec::new(),
             model: model.to_string(),
+            active_model: model.to_string(),
             start_time: Utc::now().to_rfc3339(),
+            resumed: None,
+        }
+    }
+
+    /// Reopen `.entire/metadata/<session_id>` and rebuild its conversation.
+    /// New lines continue the existing parentUuid chain in the same directory.
+    pub fn resume(
+        cwd: &str,
+        model: &str,
+        session_id: &str,
+    ) -> Result<(Self, Vec<Message>), String> {
+        if session_id.is_empty() || session_id.contains(['/', '\\']) || session_id.starts_with('.')
+        {
+            return Err(format!("invalid session ID: {session_id}"));
+        }
+        Self::resume_dir(cwd, model, session_root().join(session_id))
+    }
+
+    /// Most recently written session under `.entire/metadata`.
+    pub fn latest_session_id() -> Option<String> {
+        latest_session_in(&session_root())
+    }
+
+    fn resume_dir(cwd: &str, model: &str, dir: PathBuf) -> Result<(Self, Vec<Message>), String> {
+        let path = dir.join("full.jsonl");
+        let content = fs::read_to_string(&path)
+            .map_err(|e| format!("cannot read {}: {e}", path.display()))?;
+        let (tip, messages, live) = rebuild_conversation(&content)?;
+
+        let session_id = dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut writer = Self::new(cwd, model);
+        writer.session_id = session_id;
+        writer.prompt_written = dir.join("prompt.txt").exists();
+        writer.dir = dir;
+        writer.last_uuid = tip;
+        writer.live = live;
+        writer.resumed = Some((Utc::now().to_rfc3339(), messages.len()));
+        for message in &messages {
+            writer.collect_tool_actions(message);
         }
+        Ok((writer, messages))
     }
 
     pub fn session_id(&self) -> &str {
@@ -64,12 +154,70 @@ impl SessionWriter {
 
     pub fn append_user_turn(&mut self, message: &Message) {
         self.collect_tool_actions(message);
-        self.append_line("user", message, None);
+        let uuid = self.append_line("user", message, None, Replay::default());
+        self.live.push(uuid);
     }
 
     pub fn append_assistant_turn(&mut self, message: &Message, usage: &Usage) {
         self.collect_tool_actions(message);
-        self.append_line("assistant", message, Some(usage));
+        let uuid = self.append_line("assistant", message, Some(usage), Replay::default());
+        self.live.push(uuid);
+    }
+
+    /// Record a model change as a `system` line; later assistant lines carry the new model.
+    pub fn append_model_switch(&mut self, switch: &ModelSwitch) {
+        let message = Message {
+            role: "system".to_string(),
+            content: vec![ContentBlock::Text {
+                text: format!(
+                    "Model switched from {} to {}: {}",
+                    switch.from, switch.to, switch.reason
+                ),
+            }],
+        };
+        self.active_model = switch.to.clone();
+        self.append_line("system", &message, None, Replay::default());
+    }
+
+    /// Record that `messages_compacted` messages were summarized, keeping
+    /// the last `kept`. Resume splices the summary in before the first kept
+    /// message, found by uuid.
+    pub fn append_compaction(&mut self, messages_compacted: usize, kept: usize, summary: &str) {
+        let at = self.live.len().saturating_sub(kept);
+        let first_kept = self.live.get(at).cloned().flatten();
+        self.live.splice(at..at, [None, None]);
+        let message = Message {
+            role: "system".to_string(),
+            content: vec![ContentBlock::Text {
+                text: format!("Compacted {messages_compacted}{COMPACTION_MARKER}{summary}"),
+            }],
+        };
+        let replay = Replay {
+            first_kept,
+            ..Replay::default()
+        };
+        self.append_line("system", &message, None, replay);
+    }
+
+    /// Record that the last `count` conversation messages were dropped, as
+    /// `recover_conversation` does after a failed request, so resume drops
+    /// them too.
+    pub fn rollback(&mut self, count: usize) {
+        if count == 0 {
+            return;
+        }
+        self.live.truncate(self.live.len().saturating_sub(count));
+        let message = Message {
+            role: "system".to_string(),
+            content: vec![ContentBlock::Text {
+                text: format!("Rolled back {count} messages after a failed request"),
+            }],
+        };
+        let replay = Replay {
+            rolled_back: Some(count),
+            ..Replay::default()
+        };
+        self.append_line("system", &message, None, replay);
     }
 
     pub fn write_prompt(&mut self, prompt: &str) {
@@ -89,7 +237,7 @@ impl SessionWriter {
         }
     }
 
-    pub fn write_context(&self) {
+    pub fn write_context(&self, usage: &UsageTotals) {
         if let Err(e) = self.ensure_dir() {
             eprintln!("[session] Failed to create directory: {e}");
             return;
@@ -103,6 +251,27 @@ impl SessionWriter {
              - CWD: {}\n",
             self.session_id, self.model, self.start_time, self.cwd
         );
+        if let Some((ref at, restored)) = self.resumed {
+            content.push_str(&format!(
+                "- Resumed: {at} ({restored} messages restored from full.jsonl)\n"
+            ));
+        }
+
+        content.push_str(&format!(
+            "\n## Usage\n\n\
+             - Requests: {}\n\
+             - Input tokens: {}\n\
+             - Output tokens: {}\n\
+             - Cache write tokens: {}\n\
+             - Cache read tokens: {}\n\
+             - Cost: ${:.4}\n",
+            usage.requests,
+            usage.input_tokens,
+            usage.output_tokens,
+            usage.cache_creation_input_tokens,
+            usage.cache_read_input_tokens,
+            usage.cost_usd
+        ));
 
         if !self.tool_actions.is_empty() {
             content.push_str("\n## Key Actions\n\n");
@@ -117,10 +286,18 @@ impl SessionWriter {
         }
     }
 
-    fn append_line(&mut self, turn_type: &str, message: &Message, usage: Option<&Usage>) {
+    /// Append one line to the chain and return its uuid, or `None` if the
+    /// session directory can't be created.
+    fn append_line(
+        &mut self,
+        turn_type: &str,
+        message: &Message,
+        usage: Option<&Usage>,
+        replay: Replay,
+    ) -> Option<String> {
         if let Err(e) = self.ensure_dir() {
             eprintln!("[session] Failed to create directory: {e}");
-            return;
+            return None;
         }
 
         let line_uuid = Uuid::new_v4().to_string();
@@ -136,19 +313,21 @@ impl SessionWriter {
             version: env!("CARGO_PKG_VERSION"),
             message: MessagePayload {
                 role: &message.role,
+                model: (turn_type == "assistant").then_some(self.active_model.as_str()),
                 content: &message.content,
                 usage,
             },
+            replay,
         };
 
-        self.last_uuid = Some(line_uuid);
+        self.last_uuid = Some(line_uuid.clone());
 
         let path = self.dir.join("full.jsonl");
         let json = match serde_json::to_string(&line) {
             Ok(j) => j,
             Err(e) => {
                 eprintln!("[session] Failed to serialize JSONL line: {e}");
-                return;
+                return Some(line_uuid);
             }
         };
 
@@ -162,6 +341,7 @@ impl SessionWriter {
                 eprintln!("[session] Failed to open full.jsonl: {e}");
             }
         }
+        Some(line_uuid)
     }
 
     fn ensure_dir(&self) -> std::io::Result<()> {
@@ -178,6 +358,104 @@ impl SessionWriter {
     }
 }
 
+fn session_root() -> PathBuf {
+    Path::new(".entire").join("metadata")
+}
+
+fn latest_session_in(root: &Path) -> Option<String> {
+    fs::read_dir(root)
+        .ok()?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let modified = entry
+                .path()
+                .join("full.jsonl")
+                .metadata()
+                .ok()?
+                .modified()
+                .ok()?;
+            Some((modified, entry.file_name().to_string_lossy().into_owned()))
+        })
+        .max()
+        .map(|(_, id)| id)
+}
+
+/// Separates the message count from the summary in a compaction line.
+const COMPACTION_MARKER: &str = " messages into a summary:\n\n";
+
+/// The summary in a compaction line written by `append_compaction`.
+fn compaction_summary(message: &Message) -> Option<&str> {
+    let Some(ContentBlock::Text { text }) = message.content.first() else {
+        return None;
+    };
+    Some(text.split_once(COMPACTION_MARKER)?.1)
+}
+
+/// Transcript replay: chain tip uuid, messages, and the line uuid behind
+/// each message (`None` for compaction summaries).
+type Rebuilt = (Option<String>, Vec<Message>, Vec<Option<String>>);
+
+/// Rebuild the conversation from `full.jsonl` content by walking the
+/// parentUuid chain back from the last line. `system` lines are part of the
+/// chain but not of the conversation. Replaying them redoes what the live
+/// session did: a compaction replaces everything between the first message
+/// and the first kept one with the summary, and a rollback drops the
+/// trailing messages of a failed request.
+fn rebuild_conversation(content: &str) -> Result<Rebuilt, String> {
+    let mut lines: HashMap<String, TranscriptLine> = HashMap::new();
+    let mut tip = None;
+    for (n, raw) in content.lines().enumerate() {
+        if raw.trim().is_empty() {
+            continue;
+        }
+        let line: TranscriptLine =
+            serde_json::from_str(raw).map_err(|e| format!("full.jsonl line {}: {e}", n + 1))?;
+        tip = Some(line.uuid.clone());
+        lines.insert(line.uuid.clone(), line);
+    }
+
+    let mut chain = Vec::new();
+    let mut cursor = tip.clone();
+    while let Some(uuid) = cursor {
+        let Some(line) = lines.remove(&uuid) else {
+            return Err(format!("full.jsonl references missing parent {uuid}"));
+        };
+        cursor = line.parent_uuid.clone();
+        chain.push(line);
+    }
+    chain.reverse();
+
+    let mut messages = Vec::new();
+    let mut live: Vec<Option<String>> = Vec::new();
+    for line in chain {
+        match line.turn_type.as_str() {
+            "user" | "assistant" => {
+                messages.push(line.message);
+                live.push(Some(line.uuid));
+            }
+            "system" => {
+                if let Some(count) = line.replay.rolled_back {
+                    let keep = messages.len().saturating_sub(count);
+                    messages.truncate(keep);
+                    live.truncate(keep);
+                } else if let Some(first_kept) = &line.replay.first_kept {
+                    let end = live
+                        .iter()
+                        .position(|uuid| uuid.as_ref() == Some(first_kept));
+                    if let (Some(end), Some(summary)) = (end, compaction_summary(&line.message)) {
+                        if end > 1 {
+                            compact::apply_summary(&mut messages, end, summary);
+                            live.splice(1..end, [None, None]);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok((tip, messages, live))
+}
+
 fn extract_first_arg(input: &serde_json::Value) -> String {
     if let Some(obj) = input.as_object() {
         if let Some((_, val)) = obj.iter().next() {
@@ -385,7 +663,9 @@ mod tests {
         };
         writer.append_assistant_turn(&msg, &usage);
 
-        writer.write_context();
+        let mut totals = UsageTotals::default();
+        totals.add(&usage, 0.0123);
+        writer.write_context(&totals);
 
         let ctx_path = writer.dir.join("context.md");
         let content = fs::read_to_string(ctx_path).unwrap();
@@ -393,6 +673,9 @@ mod tests {
         assert!(content.contains("claude-opus-4-6"));
         assert!(content.contains("Key Actions"));
         assert!(content.contains("**Read**: /src/main.rs"));
+        assert!(content.contains("## Usage"));
+        assert!(content.contains("- Output tokens: 50"));
+        assert!(content.contains("- Cost: $0.0123"));
     }
 
     #[test]
@@ -461,4 +744,260 @@ mod tests {
             "user turns should not have usage field"
         );
     }
+
+    #[test]
+    fn model_switch_recorded_and_tags_later_assistant_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = SessionWriter::new(dir.path().to_str().unwrap(), "primary-model");
+        writer.dir = dir.path().join("session-switch");
+
+        writer.append_model_switch(&ModelSwitch {
+            from: "primary-model".to_string(),
+            to: "fallback-model".to_string(),
+            reason: "HTTP 529".to_string(),
+        });
+        let msg = Message {
+            role: "assistant".to_string(),
+            content: vec![ContentBlock::Text {
+                text: "hi".to_string(),
+            }],
+        };
+        writer.append_assistant_turn(&msg, &Usage::default());
+
+        let content = fs::read_to_string(writer.dir.join("full.jsonl")).unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines[0]["type"], "system");
+        assert!(lines[0]["message"].get("model").is_none());
+        assert_eq!(
+            lines[0]["message"]["content"][0]["text"],
+            "Model switched from primary-model to fallback-model: HTTP 529"
+        );
+        assert_eq!(lines[1]["message"]["model"], "fallback-model");
+    }
+
+    #[test]
+    fn compaction_recorded_as_system_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = SessionWriter::new(dir.path().to_str().unwrap(), "claude-opus-4-6");
+        writer.dir = dir.path().join("session-compact");
+
+        writer.append_compaction(8, 2, "Fixed the parser; tests pass.");
+
+        let content = fs::read_to_string(writer.dir.join("full.jsonl")).unwrap();
+        let line: serde_json::Value = serde_json::from_str(content.trim()).unwrap();
+        assert_eq!(line["type"], "system");
+        assert_eq!(
+            line["message"]["content"][0]["text"],
+            "Compacted 8 messages into a summary:\n\nFixed the parser; tests pass."
+        );
+    }
+
+    fn text_message(role: &str, text: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: vec![ContentBlock::Text {
+                text: text.to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn resume_rebuilds_conversation_and_continues_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let session_dir = dir.path().join("2026-01-01-abc");
+        let mut writer = SessionWriter::new("/tmp", "claude-opus-4-6");
+        writer.dir = session_dir.clone();
+        writer.append_user_turn(&text_message("user", "fix it"));
+        writer.append_model_switch(&ModelSwitch {
+            from: "a".to_string(),
+            to: "b".to_string(),
+            reason: "HTTP 529".to_string(),
+        });
+        writer.append_assistant_turn(&text_message("assistant", "done"), &Usage::default());
+
+        let (mut resumed, messages) =
+            SessionWriter::resume_dir("/tmp", "claude-opus-4-6", session_dir.clone()).unwrap();
+        assert_eq!(resumed.session_id(), "2026-01-01-abc");
+        assert_eq!(
+            messages.len(),
+            2,
+            "system line is not a conversation message"
+        );
+        assert_eq!(messages[0].role, "user");
+        assert_eq!(messages[1].role, "assistant");
+
+        resumed.append_user_turn(&text_message("user", "again"));
+        let content = fs::read_to_string(session_dir.join("full.jsonl")).unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[3]["parentUuid"], lines[2]["uuid"]);
+
+        resumed.write_context(&UsageTotals::default());
+        let context = fs::read_to_string(session_dir.join("context.md")).unwrap();
+        assert!(context.contains("- Resumed: "), "{context}");
+        assert!(context.contains("(2 messages restored from full.jsonl)"));
+    }
+
+    #[test]
+    fn resume_after_compaction_restores_summary_not_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let session_dir = dir.path().join("2026-01-01-cmp");
+        let mut writer = SessionWriter::new("/tmp", "claude-opus-4-6");
+        writer.dir = session_dir.clone();
+        let mut live = Vec::new();
+        for (role, text) in [
+            ("user", "one"),
+            ("assistant", "two"),
+            ("user", "three"),
+            ("assistant", "four"),
+            ("user", "five"),
+        ] {
+            let message = text_message(role, text);
+            if role == "user" {
+                writer.append_user_turn(&message);
+            } else {
+                writer.append_assistant_turn(&message, &Usage::default());
+            }
+            live.push(message);
+        }
+        writer.append_compaction(3, 1, "the gist");
+        compact::apply_summary(&mut live, 4, "the gist");
+        let after = text_message("assistant", "six");
+        writer.append_assistant_turn(&after, &Usage::default());
+        live.push(after);
+
+        let (_, messages) =
+            SessionWriter::resume_dir("/tmp", "claude-opus-4-6", session_dir).unwrap();
+        assert_eq!(
+            serde_json::to_value(&messages).unwrap(),
+            serde_json::to_value(&live).unwrap()
+        );
+        let texts = serde_json::to_string(&messages).unwrap();
+        assert!(texts.contains("the gist"));
+        assert!(!texts.contains("\"two\""), "{texts}");
+    }
+
+    #[test]
+    fn compaction_after_trim_and_failed_turn_survives_resume() {
+        fn append(writer: &mut SessionWriter, live: &mut Vec<Message>, role: &str, text: &str) {
+            let message = text_message(role, text);
+            if role == "user" {
+                writer.append_user_turn(&message);
+            } else {
+                writer.append_assistant_turn(&message, &Usage::default());
+            }
+            live.push(message);
+        }
+        fn same(a: &[Message], b: &[Message]) {
+            assert_eq!(
+                serde_json::to_value(a).unwrap(),
+                serde_json::to_value(b).unwrap()
+            );
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let session_dir = dir.path().join("2026-01-01-trm");
+        let mut writer = SessionWriter::new("/tmp", "claude-opus-4-6");
+        writer.dir = session_dir.clone();
+        let mut live = Vec::new();
+        for (role, text) in [
+            ("user", "one"),
+            ("assistant", "two"),
+            ("user", "three"),
+            ("assistant", "four"),
+            ("user", "five"),
+            ("assistant", "six"),
+        ] {
+            append(&mut writer, &mut live, role, text);
+        }
+        // Trimming drops messages from the front without a transcript line
+        live.drain(1..3);
+        // A failed request rolls back the user turn already written
+        append(&mut writer, &mut live, "user", "seven");
+        live.pop();
+        writer.rollback(1);
+        // Summarize "four" and "five", keeping "six"
+        let end = 3;
+        writer.append_compaction(end - 1, live.len() - end, "the gist");
+        compact::apply_summary(&mut live, end, "the gist");
+        append(&mut writer, &mut live, "user", "eight");
+
+        let (mut resumed, messages) =
+            SessionWriter::resume_dir("/tmp", "claude-opus-4-6", session_dir.clone()).unwrap();
+        same(&messages, &live);
+        let texts = serde_json::to_string(&messages).unwrap();
+        assert!(!texts.contains("\"seven\""), "{texts}");
+
+        // The resumed writer picks up where the replay left off
+        let mut live = messages;
+        append(&mut resumed, &mut live, "assistant", "nine");
+        let end = live.len() - 1;
+        resumed.append_compaction(end - 1, 1, "more gist");
+        compact::apply_summary(&mut live, end, "more gist");
+        let (_, messages) =
+            SessionWriter::resume_dir("/tmp", "claude-opus-4-6", session_dir).unwrap();
+        same(&messages, &live);
+    }
+
+    #[test]
+    fn rebuild_follows_parent_chain_not_file_order() {
+        let line = |uuid: &str, parent: Option<&str>, role: &str, text: &str| {
+            serde_json::json!({
+                "type": role,
+                "uuid": uuid,
+                "parentUuid": parent,
+                "message": {"role": role, "content": [{"type": "text", "text": text}]},
+            })
+            .to_string()
+        };
+        // "b-abandoned" branches off "a" but the tip ("c") descends from "b".
+        let content = [
+            line("a", None, "user", "one"),
+            line("b-abandoned", Some("a"), "assistant", "lost"),
+            line("b", Some("a"), "assistant", "two"),
+            line("c", Some("b"), "user", "three"),
+        ]
+        .join("\n");
+        let (tip, messages, _) = rebuild_conversation(&content).unwrap();
+        assert_eq!(tip.as_deref(), Some("c"));
+        let texts: Vec<&str> = messages
+            .iter()
+            .map(|m| match &m.content[0] {
+                ContentBlock::Text { text } => text.as_str(),
+                _ => "",
+            })
+            .collect();
+        assert_eq!(texts, ["one", "two", "three"]);
+
+        assert!(rebuild_conversation(&line("x", Some("gone"), "user", "?")).is_err());
+        assert!(rebuild_conversation("not json").is_err());
+    }
+
+    #[test]
+    fn resume_rejects_path_like_ids_and_missing_sessions() {
+        assert!(SessionWriter::resume("/tmp", "m", "../etc").is_err());
+        assert!(SessionWriter::resume("/tmp", "m", "a/b").is_err());
+        assert!(SessionWriter::resume("/tmp", "m", "no-such-session-id").is_err());
+    }
+
+    #[test]
+    fn latest_session_picks_most_recent_transcript() {
+        let root = tempfile::tempdir().unwrap();
+        assert_eq!(latest_session_in(root.path()), None);
+        for (id, age) in [("old", 100), ("new", 0)] {
+            let dir = root.path().join(id);
+            fs::create_dir_all(&dir).unwrap();
+            let file = fs::File::create(dir.join("full.jsonl")).unwrap();
+            file.set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(age))
+                .unwrap();
+        }
+        fs::create_dir_all(root.path().join("empty")).unwrap();
+        assert_eq!(latest_session_in(root.path()).as_deref(), Some("new"));
+    }
 }
@@ -0,0 +1,299 @@
+//! Incremental decoder for the `text/event-stream` format (WHATWG HTML §9.2).
+//!
+//! Bytes are buffered until a full line is available, so multi-byte UTF-8
+//! sequences and CRLF pairs split across network chunks decode correctly.
+
+/// One dispatched event.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SseEvent {
+    /// The `event:` field, or `"message"` when absent.
+    pub event: String,
+    /// All `data:` lines joined with `\n`.
+    pub data: String,
+    /// Last event ID seen on the stream (persists across events).
+    pub id: Option<String>,
+    /// `retry:` reconnection time in milliseconds, if this event set one.
+    pub retry: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    buf: Vec<u8>,
+    /// Previous chunk ended in `\r`; a leading `\n` in the next chunk belongs to it.
+    after_cr: bool,
+    /// Still at the start of the stream, where a UTF-8 BOM is skipped.
+    at_start: bool,
+    event: String,
+    data: String,
+    has_data: bool,
+    last_id: Option<String>,
+    retry: Option<u64>,
+}
+
+impl SseDecoder {
+    pub fn new() -> Self {
+        Self {
+            at_start: true,
+            ..Self::default()
+        }
+    }
+
+    /// Feed a chunk and return every event completed by it.
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+        let mut bytes = chunk;
+
+        if self.after_cr && !bytes.is_empty() {
+            self.after_cr = false;
+            if let Some(rest) = bytes.strip_prefix(b"\n") {
+                bytes = rest;
+            }
+        }
+
+        self.buf.extend_from_slice(bytes);
+        if self.at_start {
+            if self.buf.len() < 3 && b"\xEF\xBB\xBF".starts_with(&self.buf) {
+                return events;
+            }
+            if self.buf.starts_with(b"\xEF\xBB\xBF") {
+                self.buf.drain(..3);
+            }
+            self.at_start = false;
+        }
+
+        let mut start = 0;
+        let mut i = 0;
+        while i < self.buf.len() {
+            match self.buf[i] {
+                b'\n' => {
+                    let line = self.buf[start..i].to_vec();
+                    self.process_line(&line, &mut events);
+                    start = i + 1;
+                }
+                b'\r' => {
+                    let line = self.buf[start..i].to_vec();
+                    self.process_line(&line, &mut events);
+                    if i + 1 == self.buf.len() {
+                        self.after_cr = true;
+                    } else if self.buf[i + 1] == b'\n' {
+                        i += 1;
+                    }
+                    start = i + 1;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+        self.buf.drain(..start);
+        events
+    }
+
+    /// End of stream. Per spec, a trailing event without a blank line is discarded.
+    pub fn finish(&mut self) {
+        self.buf.clear();
+        self.event.clear();
+        self.data.clear();
+        self.has_data = false;
+    }
+
+    fn process_line(&mut self, line: &[u8], events: &mut Vec<SseEvent>) {
+        if line.is_empty() {
+            self.dispatch(events);
+            return;
+        }
+        if line[0] == b':' {
+            return;
+        }
+
+        let line = String::from_utf8_lossy(line);
+        let (field, value) = match line.find(':') {
+            Some(pos) => {
+                let value = &line[pos + 1..];
+                (&line[..pos], value.strip_prefix(' ').unwrap_or(value))
+            }
+            None => (line.as_ref(), ""),
+        };
+
+        match field {
+            "event" => self.event = value.to_string(),
+            "data" => {
+                if self.has_data {
+                    self.data.push('\n');
+                }
+                self.data.push_str(value);
+                self.has_data = true;
+            }
+            "id" if !value.contains('\0') => self.last_id = Some(value.to_string()),
+            "retry" if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                self.retry = value.parse().ok();
+            }
+            _ => {}
+        }
+    }
+
+    fn dispatch(&mut self, events: &mut Vec<SseEvent>) {
+        let event = std::mem::take(&mut self.event);
+        let retry = self.retry.take();
+        if !self.has_data {
+            return;
+        }
+        self.has_data = false;
+        events.push(SseEvent {
+            event: if event.is_empty() {
+                "message".to_string()
+            } else {
+                event
+            },
+            data: std::mem::take(&mut self.data),
+            id: self.last_id.clone(),
+            retry,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn decode_all(input: &[u8]) -> Vec<SseEvent> {
+        let mut d = SseDecoder::new();
+        let events = d.feed(input);
+        d.finish();
+        events
+    }
+
+    fn ev(event: &str, data: &str, id: Option<&str>) -> SseEvent {
+        SseEvent {
+            event: event.to_string(),
+            data: data.to_string(),
+            id: id.map(str::to_string),
+            retry: None,
+        }
+    }
+
+    #[test]
+    fn named_event_and_default_type() {
+        let events = decode_all(b"event: ping\ndata: {}\n\ndata: x\n\n");
+        assert_eq!(
+            events,
+            vec![ev("ping", "{}", None), ev("message", "x", None)]
+        );
+    }
+
+    #[test]
+    fn multiline_data_joined_with_newline() {
+        let events = decode_all(b"data: first\ndata:second\ndata\n\n");
+        assert_eq!(events[0].data, "first\nsecond\n");
+    }
+
+    #[test]
+    fn crlf_and_bare_cr_line_endings() {
+        let events = decode_all(b"event: a\r\ndata: 1\r\n\r\nevent: b\rdata: 2\r\r");
+        assert_eq!(events, vec![ev("a", "1", None), ev("b", "2", None)]);
+    }
+
+    #[test]
+    fn comments_unknown_fields_and_empty_events_ignored() {
+        let events = decode_all(b": keep-alive\n\nfoo: bar\nevent: x\n\ndata: y\n\n");
+        assert_eq!(events, vec![ev("message", "y", None)]);
+    }
+
+    #[test]
+    fn id_persists_and_retry_parsed() {
+        let events = decode_all(b"id: 7\nretry: 1500\ndata: a\n\ndata: b\n\nid\ndata: c\n\n");
+        assert_eq!(events[0].id.as_deref(), Some("7"));
+        assert_eq!(events[0].retry, Some(1500));
+        assert_eq!(events[1].id.as_deref(), Some("7"));
+        assert_eq!(events[1].retry, None);
+        assert_eq!(events[2].id.as_deref(), Some(""));
+    }
+
+    #[test]
+    fn invalid_retry_and_nul_id_ignored() {
+        let events = decode_all(b"id: a\0b\nretry: 10x\ndata: z\n\n");
+        assert_eq!(events[0].id, None);
+        assert_eq!(events[0].retry, None);
+    }
+
+    #[test]
+    fn leading_bom_stripped_even_when_split() {
+        let mut d = SseDecoder::new();
+        assert!(d.feed(b"\xEF").is_empty());
+        assert!(d.feed(b"\xBB").is_empty());
+        let events = d.feed(b"\xBFdata: ok\n\n");
+        assert_eq!(events, vec![ev("message", "ok", None)]);
+    }
+
+    #[test]
+    fn trailing_event_without_blank_line_discarded() {
+        let mut d = SseDecoder::new();
+        assert!(d.feed(b"data: partial\n").is_empty());
+        d.finish();
+        assert!(d.feed(b"\n").is_empty());
+    }
+
+    #[test]
+    fn multibyte_utf8_split_across_chunks() {
+        let input = "data: héllo 日本 🎉\n\n".as_bytes();
+        let mut d = SseDecoder::new();
+        let mut events = Vec::new();
+        for b in input {
+            events.extend(d.feed(std::slice::from_ref(b)));
+        }
+        assert_eq!(events, vec![ev("message", "héllo 日本 🎉", None)]);
+    }
+
+    fn arb_stream() -> impl Strategy<Value = Vec<u8>> {
+        let field = prop_oneof![
+            "[a-zé日🎉 {}\":]{0,12}".prop_map(|v| format!("data: {v}")),
+            "[a-z_]{1,8}".prop_map(|v| format!("event: {v}")),
+            "[0-9]{1,4}".prop_map(|v| format!("id: {v}")),
+            "[0-9]{1,5}".prop_map(|v| format!("retry: {v}")),
+            "[a-z ]{0,8}".prop_map(|v| format!(":{v}")),
+            Just(String::new()),
+        ];
+        let eol = prop_oneof![Just("\n"), Just("\r\n"), Just("\r")];
+        prop::collection::vec((field, eol), 0..24).prop_map(|lines| {
+            lines
+                .into_iter()
+                .flat_map(|(f, e)| format!("{f}{e}").into_bytes())
+                .collect()
+        })
+    }
+
+    proptest! {
+        /// Splitting the same bytes at arbitrary points never changes the decoded events.
+        #[test]
+        fn chunking_does_not_change_events(
+            input in arb_stream(),
+            cuts in prop::collection::vec(any::<prop::sample::Index>(), 0..8),
+        ) {
+            let expected = decode_all(&input);
+
+            let mut points: Vec<usize> = cuts.iter().map(|c| c.index(input.len() + 1)).collect();
+            points.sort_unstable();
+            let mut d = SseDecoder::new();
+            let mut got = Vec::new();
+            let mut prev = 0;
+            for p in points.into_iter().chain(std::iter::once(input.len())) {
+                got.extend(d.feed(&input[prev..p]));
+                prev = p;
+            }
+            d.finish();
+            prop_assert_eq!(got, expected);
+        }
+
+        /// Byte-at-a-time delivery matches whole-buffer decoding.
+        #[test]
+        fn single_byte_chunks_match(input in arb_stream()) {
+            let expected = decode_all(&input);
+            let mut d = SseDecoder::new();
+            let mut got = Vec::new();
+            for b in &input {
+                got.extend(d.feed(std::slice::from_ref(b)));
+            }
+            prop_assert_eq!(got, expected);
+        }
+    }
+}
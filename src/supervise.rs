@@ -0,0 +1,506 @@
+//! `forgeflare supervise`: run `forgeflare loop` as a child process, restart
+//! it when it hangs or dies, and give up on failures a restart can't fix.
+
+use crate::agent_loop::LoopArgs;
+use crate::heartbeat;
+use chrono::Utc;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+use tokio::process::{Child, Command};
+
+/// Default heartbeat path when `--heartbeat-file` isn't given.
+const DEFAULT_HEARTBEAT_FILE: &str = ".forgeflare/heartbeat.json";
+/// How often the child's heartbeat is checked and the status file refreshed.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a child gets to exit on its own after Ctrl-C before it is killed.
+const INTERRUPT_GRACE: Duration = Duration::from_secs(10);
+
+#[derive(clap::Args, Debug)]
+pub struct SuperviseArgs {
+    /// Give up after this many restarts
+    #[arg(long, default_value_t = 5)]
+    pub max_restarts: u32,
+
+    /// Restart the child when its heartbeat is older than this many seconds
+    #[arg(long, default_value_t = 600, value_parser = clap::value_parser!(u64).range(1..))]
+    pub hang_timeout_secs: u64,
+
+    /// Delay before the first restart, doubled for each one after
+    #[arg(long, default_value_t = 10)]
+    pub backoff_secs: u64,
+
+    /// Upper bound on the restart delay
+    #[arg(long, default_value_t = 300)]
+    pub max_backoff_secs: u64,
+
+    /// Supervisor state, rewritten on every change and poll
+    #[arg(long, default_value = ".forgeflare/supervisor.json")]
+    pub status_file: PathBuf,
+
+    #[command(flatten)]
+    pub loop_args: LoopArgs,
+}
+
+/// Why a child run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ExitReason {
+    Converged,
+    MaxIterations,
+    Interrupted,
+    BudgetExhausted,
+    PromptError,
+    ApiError,
+    /// Heartbeat went stale; the child was killed.
+    Hung,
+    /// Exited without finishing its loop summary (panic, signal, ...).
+    Crashed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Disposition {
+    /// The loop finished; stop supervising.
+    Done,
+    /// A restart would fail the same way.
+    Fatal,
+    Restart,
+}
+
+impl ExitReason {
+    /// Map the loop summary's final `status` to a reason. A missing summary,
+    /// or one still `running`, means the child died mid-loop.
+    fn from_loop_status(status: Option<&str>) -> Self {
+        match status {
+            Some("converged") => ExitReason::Converged,
+            Some("max_iterations") => ExitReason::MaxIterations,
+            Some("interrupted") => ExitReason::Interrupted,
+            Some("budget_exhausted") => ExitReason::BudgetExhausted,
+            Some("prompt_error") => ExitReason::PromptError,
+            Some("api_error") => ExitReason::ApiError,
+            _ => ExitReason::Crashed,
+        }
+    }
+
+    fn disposition(self) -> Disposition {
+        match self {
+            ExitReason::Converged | ExitReason::MaxIterations | ExitReason::Interrupted => {
+                Disposition::Done
+            }
+            ExitReason::BudgetExhausted | ExitReason::PromptError => Disposition::Fatal,
+            ExitReason::ApiError | ExitReason::Hung | ExitReason::Crashed => Disposition::Restart,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum State {
+    Running,
+    Restarting,
+    Converged,
+    Stopped,
+    Failed,
+}
+
+/// Contents of the status file.
+#[derive(Debug, Serialize)]
+struct SupervisorStatus {
+    state: State,
+    started_at: String,
+    updated_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<u32>,
+    restarts: u32,
+    max_restarts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_restart_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    heartbeat: Option<heartbeat::Beat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    heartbeat_age_secs: Option<u64>,
+    runs: Vec<RunRecord>,
+}
+
+#[derive(Debug, Serialize)]
+struct RunRecord {
+    started_at: String,
+    ended_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+    reason: ExitReason,
+    disposition: Disposition,
+    iterations: usize,
+}
+
+/// Restart delay before restart number `restart` (1-based).
+fn backoff(base: Duration, max: Duration, restart: u32) -> Duration {
+    base.saturating_mul(1 << restart.saturating_sub(1).min(16))
+        .min(max)
+}
+
+/// Arguments for the child: the parent's global options, a heartbeat file if
+/// none was given, then `loop` with the loop options.
+fn child_args(global: &[String], heartbeat: Option<&Path>, loop_args: &LoopArgs) -> Vec<String> {
+    let mut args = global.to_vec();
+    if let Some(path) = heartbeat {
+        args.push("--heartbeat-file".to_string());
+        args.push(path.display().to_string());
+    }
+    args.push("loop".to_string());
+    args.extend(loop_args.to_args());
+    args
+}
+
+/// Global options from our own command line: everything before `supervise`.
+fn global_args() -> Vec<String> {
+    std::env::args()
+        .skip(1)
+        .take_while(|a| a != "supervise")
+        .collect()
+}
+
+/// Run the supervisor until the loop finishes or fails for good. Returns
+/// false if it gave up.
+pub async fn run(args: &SuperviseArgs, heartbeat_file: Option<&Path>) -> bool {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            eprintln!("[supervise] Cannot locate forgeflare executable: {e}");
+            return false;
+        }
+    };
+    let heartbeat_path = heartbeat_file
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_HEARTBEAT_FILE));
+    let argv = child_args(
+        &global_args(),
+        heartbeat_file.is_none().then_some(heartbeat_path.as_path()),
+        &args.loop_args,
+    );
+    let hang_timeout = Duration::from_secs(args.hang_timeout_secs);
+
+    let now = Utc::now().to_rfc3339();
+    let mut status = SupervisorStatus {
+        state: State::Running,
+        started_at: now.clone(),
+        updated_at: now,
+        pid: None,
+        restarts: 0,
+        max_restarts: args.max_restarts,
+        next_restart_at: None,
+        heartbeat: None,
+        heartbeat_age_secs: None,
+        runs: Vec::new(),
+    };
+
+    loop {
+        // Stale files from an earlier run would look like a fresh heartbeat or a finished loop.
+        fs::remove_file(&heartbeat_path).ok();
+        fs::remove_file(&args.loop_args.summary_file).ok();
+
+        let run_started = Utc::now().to_rfc3339();
+        let mut child = match Command::new(&exe)
+            .args(&argv)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                eprintln!("[supervise] Cannot start loop: {e}");
+                status.state = State::Failed;
+                write_status(&args.status_file, &mut status);
+                return false;
+            }
+        };
+        status.state = State::Running;
+        status.pid = child.id();
+        status.next_restart_at = None;
+        status.heartbeat = None;
+        status.heartbeat_age_secs = None;
+        eprintln!(
+            "[supervise] Started loop (pid {})",
+            status.pid.unwrap_or_default()
+        );
+        write_status(&args.status_file, &mut status);
+
+        let (exit, hung, interrupted) =
+            watch(&mut child, &heartbeat_path, hang_timeout, args, &mut status).await;
+
+        let summary = read_loop_summary(&args.loop_args.summary_file);
+        let reason = if hung {
+            ExitReason::Hung
+        } else {
+            ExitReason::from_loop_status(summary.as_ref().and_then(|s| s["status"].as_str()))
+        };
+        let disposition = if interrupted {
+            Disposition::Done
+        } else {
+            reason.disposition()
+        };
+        status.pid = None;
+        status.runs.push(RunRecord {
+            started_at: run_started,
+            ended_at: Utc::now().to_rfc3339(),
+            exit_code: exit.and_then(|e| e.code()),
+            reason,
+            disposition,
+            iterations: summary
+                .as_ref()
+                .and_then(|s| s["iterations"].as_array().map(Vec::len))
+                .unwrap_or(0),
+        });
+        eprintln!("[supervise] Loop ended: {reason:?} ({disposition:?})");
+
+        match disposition {
+            Disposition::Done => {
+                status.state = if reason == ExitReason::Converged {
+                    State::Converged
+                } else {
+                    State::Stopped
+                };
+                write_status(&args.status_file, &mut status);
+                return true;
+            }
+            Disposition::Fatal => {
+                status.state = State::Failed;
+                write_status(&args.status_file, &mut status);
+                return false;
+            }
+            Disposition::Restart if status.restarts >= args.max_restarts => {
+                eprintln!(
+                    "[supervise] Max restarts ({}) reached; giving up",
+                    args.max_restarts
+                );
+                status.state = State::Failed;
+                write_status(&args.status_file, &mut status);
+                return false;
+            }
+            Disposition::Restart => {}
+        }
+
+        status.restarts += 1;
+        let delay = backoff(
+            Duration::from_secs(args.backoff_secs),
+            Duration::from_secs(args.max_backoff_secs),
+            status.restarts,
+        );
+        status.state = State::Restarting;
+        status.next_restart_at =
+            Some((Utc::now() + chrono::Duration::from_std(delay).unwrap_or_default()).to_rfc3339());
+        write_status(&args.status_file, &mut status);
+        eprintln!(
+            "[supervise] Restarting in {}s (restart {}/{})",
+            delay.as_secs(),
+            status.restarts,
+            args.max_restarts
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = tokio::signal::ctrl_c() => {
+                status.state = State::Stopped;
+                status.next_restart_at = None;
+                write_status(&args.status_file, &mut status);
+                return true;
+            }
+        }
+    }
+}
+
+/// Wait for the child to exit, killing it if its heartbeat goes stale.
+/// Returns its exit status (if it could be collected), whether it was
+/// killed as hung, and whether the supervisor was interrupted.
+async fn watch(
+    child: &mut Child,
+    heartbeat_path: &Path,
+    hang_timeout: Duration,
+    args: &SuperviseArgs,
+    status: &mut SupervisorStatus,
+) -> (Option<ExitStatus>, bool, bool) {
+    let spawned = Instant::now();
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            exit = child.wait() => return (exit.ok(), false, false),
+            _ = tokio::signal::ctrl_c() => {
+                // The terminal delivers Ctrl-C to the child too; let it write its summary.
+                let exit = match tokio::time::timeout(INTERRUPT_GRACE, child.wait()).await {
+                    Ok(exit) => exit.ok(),
+                    Err(_) => {
+                        child.kill().await.ok();
+                        child.wait().await.ok()
+                    }
+                };
+                return (exit, false, true);
+            }
+            _ = poll.tick() => {
+                let age = heartbeat::age(heartbeat_path).unwrap_or_else(|| spawned.elapsed());
+                status.heartbeat = heartbeat::read(heartbeat_path);
+                status.heartbeat_age_secs = Some(age.as_secs());
+                write_status(&args.status_file, status);
+                if age > hang_timeout {
+                    eprintln!(
+                        "[supervise] No heartbeat for {}s; killing loop",
+                        age.as_secs()
+                    );
+                    child.kill().await.ok();
+                    return (child.wait().await.ok(), true, false);
+                }
+            }
+        }
+    }
+}
+
+fn read_loop_summary(path: &Path) -> Option<serde_json::Value> {
+    serde_json::from_str(&fs::read_to_string(path).ok()?).ok()
+}
+
+/// Stamp and write `status` to `path` (temp file + rename). Failures are logged.
+fn write_status(path: &Path, status: &mut SupervisorStatus) {
+    status.updated_at = Utc::now().to_rfc3339();
+    let result = (|| {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let json = serde_json::to_string_pretty(status).map_err(std::io::Error::other)?;
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, json)?;
+        fs::rename(&tmp, path)
+    })();
+    if let Err(e) = result {
+        eprintln!("[supervise] Cannot write {}: {e}", path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loop_args() -> LoopArgs {
+        LoopArgs {
+            prompt_file: PathBuf::from("PROMPT_build.md"),
+            max_iterations: 10,
+            git_commit: true,
+            git_push: false,
+            summary_file: PathBuf::from(".forgeflare/loop.json"),
+        }
+    }
+
+    #[test]
+    fn loop_status_maps_to_reason_and_disposition() {
+        let cases = [
+            (Some("converged"), ExitReason::Converged, Disposition::Done),
+            (
+                Some("max_iterations"),
+                ExitReason::MaxIterations,
+                Disposition::Done,
+            ),
+            (
+                Some("interrupted"),
+                ExitReason::Interrupted,
+                Disposition::Done,
+            ),
+            (
+                Some("budget_exhausted"),
+                ExitReason::BudgetExhausted,
+                Disposition::Fatal,
+            ),
+            (
+                Some("prompt_error"),
+                ExitReason::PromptError,
+                Disposition::Fatal,
+            ),
+            (
+                Some("api_error"),
+                ExitReason::ApiError,
+                Disposition::Restart,
+            ),
+            (Some("running"), ExitReason::Crashed, Disposition::Restart),
+            (None, ExitReason::Crashed, Disposition::Restart),
+        ];
+        for (status, reason, disposition) in cases {
+            let r = ExitReason::from_loop_status(status);
+            assert_eq!(r, reason, "{status:?}");
+            assert_eq!(r.disposition(), disposition, "{status:?}");
+        }
+        assert_eq!(ExitReason::Hung.disposition(), Disposition::Restart);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let base = Duration::from_secs(10);
+        let max = Duration::from_secs(300);
+        let delays: Vec<u64> = (1..=7).map(|n| backoff(base, max, n).as_secs()).collect();
+        assert_eq!(delays, [10, 20, 40, 80, 160, 300, 300]);
+        assert_eq!(backoff(base, max, 1000).as_secs(), 300);
+    }
+
+    #[test]
+    fn child_args_adds_heartbeat_and_loop_options() {
+        let global = vec!["--model".to_string(), "claude-sonnet-4-5".to_string()];
+        let args = child_args(
+            &global,
+            Some(Path::new(".forgeflare/hb.json")),
+            &loop_args(),
+        );
+        assert_eq!(
+            args,
+            [
+                "--model",
+                "claude-sonnet-4-5",
+                "--heartbeat-file",
+                ".forgeflare/hb.json",
+                "loop",
+                "--prompt-file",
+                "PROMPT_build.md",
+                "--max-iterations",
+                "10",
+                "--summary-file",
+                ".forgeflare/loop.json",
+                "--git-commit",
+            ]
+        );
+
+        let args = child_args(&[], None, &loop_args());
+        assert_eq!(args[0], "loop");
+    }
+
+    #[test]
+    fn status_file_written_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("supervisor.json");
+        let mut status = SupervisorStatus {
+            state: State::Restarting,
+            started_at: "2026-01-01T00:00:00Z".to_string(),
+            updated_at: String::new(),
+            pid: None,
+            restarts: 1,
+            max_restarts: 5,
+            next_restart_at: Some("2026-01-01T00:01:00Z".to_string()),
+            heartbeat: None,
+            heartbeat_age_secs: Some(601),
+            runs: vec![RunRecord {
+                started_at: "2026-01-01T00:00:00Z".to_string(),
+                ended_at: "2026-01-01T00:10:00Z".to_string(),
+                exit_code: None,
+                reason: ExitReason::Hung,
+                disposition: Disposition::Restart,
+                iterations: 2,
+            }],
+        };
+        write_status(&path, &mut status);
+        assert!(!path.with_extension("json.tmp").exists());
+
+        let v: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(v["state"], "restarting");
+        assert_eq!(v["runs"][0]["reason"], "hung");
+        assert_eq!(v["runs"][0]["disposition"], "restart");
+        assert!(v.get("pid").is_none());
+        assert!(!v["updated_at"].as_str().unwrap().is_empty());
+    }
+}
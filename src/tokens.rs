@@ -0,0 +1,154 @@
+use crate::api::{Message, Usage};
+
+/// Starting ratio before any response has been observed. Matches the old
+/// 720KB ≈ 200K-token budget this estimator replaced.
+const DEFAULT_BYTES_PER_TOKEN: f64 = 3.6;
+/// Sane bounds for a calibrated ratio; anything outside is a measurement glitch.
+const MIN_BYTES_PER_TOKEN: f64 = 1.0;
+const MAX_BYTES_PER_TOKEN: f64 = 8.0;
+/// Weight given to each new observation.
+const CALIBRATION_WEIGHT: f64 = 0.5;
+
+/// Local prompt-size estimator: serialized request bytes divided by a
+/// bytes-per-token ratio that is recalibrated against every `Usage` the API
+/// reports, so code-heavy or non-ASCII sessions converge on their real ratio.
+#[derive(Debug, Clone)]
+pub struct TokenEstimator {
+    bytes_per_token: f64,
+}
+
+impl Default for TokenEstimator {
+    fn default() -> Self {
+        Self {
+            bytes_per_token: DEFAULT_BYTES_PER_TOKEN,
+        }
+    }
+}
+
+impl TokenEstimator {
+    pub fn bytes_per_token(&self) -> f64 {
+        self.bytes_per_token
+    }
+
+    pub fn tokens_for_bytes(&self, bytes: usize) -> u64 {
+        (bytes as f64 / self.bytes_per_token).ceil() as u64
+    }
+
+    pub fn message_tokens(&self, message: &Message) -> u64 {
+        self.tokens_for_bytes(message_bytes(message))
+    }
+
+    /// Estimated prompt tokens for a full request.
+    pub fn request_tokens(
+        &self,
+        system: &str,
+        tools: &[serde_json::Value],
+        messages: &[Message],
+    ) -> u64 {
+        self.tokens_for_bytes(request_bytes(system, tools, messages))
+    }
+
+    /// Fold in an observation: a request of `bytes` was measured at `tokens`.
+    pub fn calibrate(&mut self, bytes: usize, tokens: u64) {
+        if bytes == 0 || tokens == 0 {
+            return;
+        }
+        let observed =
+            (bytes as f64 / tokens as f64).clamp(MIN_BYTES_PER_TOKEN, MAX_BYTES_PER_TOKEN);
+        self.bytes_per_token += (observed - self.bytes_per_token) * CALIBRATION_WEIGHT;
+    }
+
+    /// Calibrate from a response's usage. All three input-side fields count:
+    /// cached tokens are still part of the prompt.
+    pub fn calibrate_from_usage(&mut self, bytes: usize, usage: &Usage) {
+        let prompt =
+            usage.input_tokens + usage.cache_creation_input_tokens + usage.cache_read_input_tokens;
+        self.calibrate(bytes, prompt);
+    }
+}
+
+fn message_bytes(message: &Message) -> usize {
+    serde_json::to_string(message).map_or(0, |s| s.len())
+}
+
+/// Serialized size of the parts of a request that count toward input tokens.
+pub fn request_bytes(system: &str, tools: &[serde_json::Value], messages: &[Message]) -> usize {
+    system.len()
+        + tools
+            .iter()
+            .map(|t| serde_json::to_string(t).map_or(0, |s| s.len()))
+            .sum::<usize>()
+        + messages.iter().map(message_bytes).sum::<usize>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ContentBlock;
+
+    fn text_message(text: &str) -> Message {
+        Message {
+            role: "user".to_string(),
+            content: vec![ContentBlock::Text {
+                text: text.to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn default_ratio_estimates() {
+        let est = TokenEstimator::default();
+        assert_eq!(est.tokens_for_bytes(36_000), 10_000);
+        assert_eq!(est.tokens_for_bytes(0), 0);
+    }
+
+    #[test]
+    fn request_tokens_counts_system_tools_and_messages() {
+        let est = TokenEstimator::default();
+        let msgs = [text_message(&"a".repeat(3600))];
+        let tools = [serde_json::json!({"name": "Read"})];
+        let with_everything = est.request_tokens(&"s".repeat(3600), &tools, &msgs);
+        let messages_only = est.request_tokens("", &[], &msgs);
+        assert!(with_everything > messages_only + 1000);
+        assert!(messages_only >= 1000);
+    }
+
+    #[test]
+    fn calibration_converges_toward_observed_ratio() {
+        let mut est = TokenEstimator::default();
+        // Dense content: 2 bytes per token.
+        for _ in 0..10 {
+            est.calibrate(200_000, 100_000);
+        }
+        assert!((est.bytes_per_token() - 2.0).abs() < 0.01);
+        assert!(est.tokens_for_bytes(200_000).abs_diff(100_000) < 200);
+    }
+
+    #[test]
+    fn calibration_ignores_empty_and_clamps_outliers() {
+        let mut est = TokenEstimator::default();
+        est.calibrate(0, 100);
+        est.calibrate(100, 0);
+        assert_eq!(est.bytes_per_token(), DEFAULT_BYTES_PER_TOKEN);
+
+        for _ in 0..20 {
+            est.calibrate(1_000_000, 1);
+        }
+        assert!(est.bytes_per_token() <= MAX_BYTES_PER_TOKEN);
+    }
+
+    #[test]
+    fn calibrate_from_usage_includes_cache_fields() {
+        let mut est = TokenEstimator::default();
+        let usage = Usage {
+            input_tokens: 1_000,
+            output_tokens: 500,
+            cache_creation_input_tokens: 4_000,
+            cache_read_input_tokens: 5_000,
+        };
+        for _ in 0..10 {
+            est.calibrate_from_usage(30_000, &usage);
+        }
+        assert!((est.bytes_per_token() - 3.0).abs() < 0.01);
+    }
+}
@@ -0,0 +1,199 @@
+//! Unified diffs of Edit changes: a compact copy for the tool result, a
+//! full (optionally colored) rendering for stderr, and the structured form
+//! PostToolUse hooks receive as `diff`.
+
+use serde::Serialize;
+use similar::{ChangeTag, TextDiff};
+
+/// Diff lines kept in the tool result; the rest are counted in a note.
+const COMPACT_MAX_LINES: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EditDiff {
+    pub path: String,
+    pub additions: usize,
+    pub deletions: usize,
+    pub hunks: Vec<DiffHunk>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DiffHunk {
+    /// `@@ -old_start,old_len +new_start,new_len @@`
+    pub header: String,
+    /// Lines prefixed with ` `, `-` or `+`, without trailing newlines.
+    pub lines: Vec<String>,
+}
+
+impl EditDiff {
+    /// Diff `old` against `new` by line, keeping `context` unchanged lines
+    /// around each change.
+    pub fn new(path: &str, old: &str, new: &str, context: usize) -> Self {
+        let diff = TextDiff::from_lines(old, new);
+        let mut additions = 0;
+        let mut deletions = 0;
+        let hunks = diff
+            .unified_diff()
+            .context_radius(context)
+            .iter_hunks()
+            .map(|hunk| {
+                let mut lines = Vec::new();
+                for change in hunk.iter_changes() {
+                    let sign = match change.tag() {
+                        ChangeTag::Equal => ' ',
+                        ChangeTag::Delete => {
+                            deletions += 1;
+                            '-'
+                        }
+                        ChangeTag::Insert => {
+                            additions += 1;
+                            '+'
+                        }
+                    };
+                    let text = change.value();
+                    lines.push(format!("{sign}{}", text.strip_suffix('\n').unwrap_or(text)));
+                    if change.missing_newline() {
+                        lines.push("\\ No newline at end of file".to_string());
+                    }
+                }
+                DiffHunk {
+                    header: hunk.header().to_string(),
+                    lines,
+                }
+            })
+            .collect();
+        Self {
+            path: path.to_string(),
+            additions,
+            deletions,
+            hunks,
+        }
+    }
+
+    /// `(+A -D)` counts for result headers.
+    pub fn stat(&self) -> String {
+        format!("(+{} -{})", self.additions, self.deletions)
+    }
+
+    /// Hunks only, cut off after `COMPACT_MAX_LINES` lines.
+    pub fn compact(&self) -> String {
+        let lines: Vec<&str> = self
+            .hunks
+            .iter()
+            .flat_map(|h| {
+                std::iter::once(h.header.as_str()).chain(h.lines.iter().map(|l| l.as_str()))
+            })
+            .collect();
+        let mut out = lines
+            .iter()
+            .take(COMPACT_MAX_LINES)
+            .copied()
+            .collect::<Vec<_>>()
+            .join("\n");
+        if lines.len() > COMPACT_MAX_LINES {
+            out.push_str(&format!(
+                "\n[{} more diff lines not shown]",
+                lines.len() - COMPACT_MAX_LINES
+            ));
+        }
+        out
+    }
+
+    /// Full unified diff with `---`/`+++` file headers, for the terminal.
+    pub fn render(&self, color: bool) -> String {
+        let paint = |code: &str, line: &str| {
+            if color {
+                format!("\x1b[{code}m{line}\x1b[0m")
+            } else {
+                line.to_string()
+            }
+        };
+        // a/ and b/ only make sense in front of a relative path
+        let (old, new) = if self.path.starts_with('/') {
+            ("", "")
+        } else {
+            ("a/", "b/")
+        };
+        let mut out = vec![
+            paint("1", &format!("--- {old}{}", self.path)),
+            paint("1", &format!("+++ {new}{}", self.path)),
+        ];
+        for hunk in &self.hunks {
+            out.push(paint("36", &hunk.header));
+            for line in &hunk.lines {
+                out.push(match line.as_bytes().first() {
+                    Some(b'-') => paint("31", line),
+                    Some(b'+') => paint("32", line),
+                    _ => line.clone(),
+                });
+            }
+        }
+        out.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hunks_with_context() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\n";
+        let new = "a\nb\nc\nD\ne\nf\ng\nh\n";
+        let diff = EditDiff::new("f.txt", old, new, 1);
+        assert_eq!((diff.additions, diff.deletions), (1, 1));
+        assert_eq!(
+            diff.hunks,
+            [DiffHunk {
+                header: "@@ -3,3 +3,3 @@".into(),
+                lines: vec![" c".into(), "-d".into(), "+D".into(), " e".into()],
+            }]
+        );
+        assert_eq!(diff.stat(), "(+1 -1)");
+        assert_eq!(diff.compact(), "@@ -3,3 +3,3 @@\n c\n-d\n+D\n e");
+    }
+
+    #[test]
+    fn separate_hunks_and_missing_newline() {
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9";
+        let new = "one\n2\n3\n4\n5\n6\n7\n8\nnine";
+        let diff = EditDiff::new("f.txt", old, new, 2);
+        assert_eq!(diff.hunks.len(), 2);
+        assert_eq!(
+            diff.hunks[1].lines,
+            [
+                " 7",
+                " 8",
+                "-9",
+                "\\ No newline at end of file",
+                "+nine",
+                "\\ No newline at end of file"
+            ]
+        );
+    }
+
+    #[test]
+    fn compact_truncates_long_diffs() {
+        let new: String = (0..150).map(|i| format!("{i}\n")).collect();
+        let diff = EditDiff::new("f.txt", "", &new, 3);
+        assert_eq!(diff.additions, 150);
+        let compact = diff.compact();
+        assert_eq!(compact.lines().count(), COMPACT_MAX_LINES + 1);
+        assert!(compact.ends_with("\n[51 more diff lines not shown]"));
+    }
+
+    #[test]
+    fn render_colors_only_when_asked() {
+        let diff = EditDiff::new("f.txt", "x\n", "y\n", 3);
+        assert_eq!(
+            diff.render(false),
+            "--- a/f.txt\n+++ b/f.txt\n@@ -1 +1 @@\n-x\n+y"
+        );
+        let absolute = EditDiff::new("/src/f.txt", "x\n", "y\n", 3);
+        assert!(absolute
+            .render(false)
+            .starts_with("--- /src/f.txt\n+++ /src/f.txt\n"));
+        let colored = diff.render(true);
+        assert!(colored.contains("\x1b[31m-x\x1b[0m"));
+        assert!(colored.contains("\x1b[32m+y\x1b[0m"));
+    }
+}
@@ -0,0 +1,543 @@
+//! Background Bash jobs. Each job is its own `bash -c` process group whose
+//! stdout and stderr lines land in a bounded buffer; BashOutput returns what
+//! arrived since the previous poll and KillShell stops the group.
+
+use super::sandbox::Sandbox;
+use super::ToolResult;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Output kept per job; older lines are dropped first.
+pub const JOB_BUFFER_BYTES: usize = 1_048_576;
+
+/// Longest line kept whole; longer output without a newline is split.
+const MAX_LINE_BYTES: usize = JOB_BUFFER_BYTES / 16;
+
+/// Finished jobs kept for BashOutput before the oldest are forgotten.
+const MAX_FINISHED_JOBS: usize = 16;
+
+/// How long KillShell waits after SIGTERM before sending SIGKILL.
+const KILL_GRACE: Duration = Duration::from_secs(2);
+
+/// A job still running, as reported to Stop hooks.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct JobSummary {
+    pub id: String,
+    pub command: String,
+    pub pid: u32,
+    pub running_secs: u64,
+}
+
+#[derive(Default)]
+struct OutputBuffer {
+    lines: VecDeque<String>,
+    bytes: usize,
+    /// Sequence number the next pushed line will get.
+    next_seq: u64,
+    /// First sequence number BashOutput hasn't returned yet.
+    read_seq: u64,
+}
+
+impl OutputBuffer {
+    fn push(&mut self, line: String) {
+        self.bytes += line.len();
+        self.lines.push_back(line);
+        self.next_seq += 1;
+        while self.bytes > JOB_BUFFER_BYTES && self.lines.len() > 1 {
+            let dropped = self.lines.pop_front().unwrap();
+            self.bytes -= dropped.len();
+        }
+    }
+
+    /// Lines added since the last call, plus how many unread lines were
+    /// pushed out of the buffer before they could be returned.
+    fn take_new(&mut self) -> (Vec<String>, u64) {
+        let first_seq = self.next_seq - self.lines.len() as u64;
+        let dropped = first_seq.saturating_sub(self.read_seq);
+        let skip = self.read_seq.saturating_sub(first_seq) as usize;
+        let new = self.lines.iter().skip(skip).cloned().collect();
+        self.read_seq = self.next_seq;
+        (new, dropped)
+    }
+}
+
+enum JobStatus {
+    Running,
+    Exited(ExitStatus),
+    Killed,
+}
+
+struct Job {
+    id: String,
+    command: String,
+    child: Child,
+    started: Instant,
+    status: JobStatus,
+    output: Arc<Mutex<OutputBuffer>>,
+    /// Reader threads that have hit EOF (two when both pipes are drained).
+    readers_done: Arc<AtomicUsize>,
+}
+
+impl Job {
+    fn finished(&self) -> bool {
+        !matches!(self.status, JobStatus::Running)
+    }
+
+    /// Both pipes hit EOF, so every line is in the buffer. A process the job
+    /// left running can hold them open after the leader exits.
+    fn drained(&self) -> bool {
+        self.readers_done.load(Ordering::SeqCst) >= 2
+    }
+
+    /// Whether anything is left in the job's process group, leader or not.
+    fn group_alive(&self) -> bool {
+        // SAFETY: signal 0 only checks that our own child's group exists.
+        unsafe { libc::kill(-(self.child.id() as i32), 0) == 0 }
+    }
+
+    fn refresh(&mut self) {
+        if let (JobStatus::Running, Ok(Some(status))) = (&self.status, self.child.try_wait()) {
+            self.status = JobStatus::Exited(status);
+            // Give the readers a moment to deliver the last lines
+            let deadline = Instant::now() + Duration::from_millis(200);
+            while !self.drained() && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        match &self.status {
+            JobStatus::Running => format!("running for {}s", self.started.elapsed().as_secs()),
+            JobStatus::Exited(status) => match (status.code(), status.signal()) {
+                (Some(code), _) => format!("exited with code {code}"),
+                (None, Some(signal)) => format!("terminated by signal {signal}"),
+                (None, None) => "exited".to_string(),
+            },
+            JobStatus::Killed => "killed".to_string(),
+        }
+    }
+
+    /// SIGKILL whatever is left of the process group after the leader exited.
+    fn kill_leftovers(&self) {
+        if self.group_alive() {
+            // SAFETY: kill(2) with a negative pid signals our own child's group.
+            unsafe {
+                libc::kill(-(self.child.id() as i32), libc::SIGKILL);
+            }
+        }
+    }
+
+    /// SIGTERM the process group, then SIGKILL it if it outlives the grace period.
+    fn kill(&mut self) {
+        let pgid = -(self.child.id() as i32);
+        // SAFETY: kill(2) with a negative pid signals our own child's group.
+        unsafe {
+            libc::kill(pgid, libc::SIGTERM);
+        }
+        let deadline = Instant::now() + KILL_GRACE;
+        while matches!(self.child.try_wait(), Ok(None)) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        // Also reaches grandchildren that ignored SIGTERM
+        unsafe {
+            libc::kill(pgid, libc::SIGKILL);
+        }
+        let _ = self.child.wait();
+        self.status = JobStatus::Killed;
+    }
+}
+
+/// Background jobs started by the Bash tool in this session.
+#[derive(Default)]
+pub struct Jobs {
+    next_id: AtomicUsize,
+    jobs: Mutex<Vec<Job>>,
+    sandbox: Option<Arc<Sandbox>>,
+}
+
+impl Jobs {
+    /// An empty job table whose commands start inside `sandbox`, if any.
+    pub fn new(sandbox: Option<Arc<Sandbox>>) -> Self {
+        Self {
+            next_id: AtomicUsize::new(0),
+            jobs: Mutex::new(Vec::new()),
+            sandbox,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Job>> {
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Start `command` in `cwd` and return its id (`bash_1`, `bash_2`, ...).
+    pub fn spawn(&self, command: &str, cwd: &Path) -> Result<String, String> {
+        let mut cmd = Command::new("bash");
+        cmd.arg("-c")
+            .arg(command)
+            .current_dir(cwd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0);
+        if let Some(sandbox) = &self.sandbox {
+            sandbox.apply(&mut cmd)?;
+        }
+        let mut child = cmd
+            .spawn()
+            .map_err(|e| format!("Failed to spawn bash: {e}"))?;
+        let mut jobs = self.lock();
+        prune_finished(&mut jobs);
+
+        let output = Arc::new(Mutex::new(OutputBuffer::default()));
+        let readers_done = Arc::new(AtomicUsize::new(0));
+        spawn_reader(child.stdout.take().unwrap(), &output, &readers_done);
+        spawn_reader(child.stderr.take().unwrap(), &output, &readers_done);
+
+        let id = format!("bash_{}", self.next_id.fetch_add(1, Ordering::SeqCst) + 1);
+        jobs.push(Job {
+            id: id.clone(),
+            command: command.to_string(),
+            child,
+            started: Instant::now(),
+            status: JobStatus::Running,
+            output,
+            readers_done,
+        });
+        Ok(id)
+    }
+
+    /// Status line plus output since the last poll, keeping only lines that
+    /// match `filter` (the others are still consumed), labeled if the job
+    /// failed with what looks like a sandbox denial. A finished job is removed once this has
+    /// returned its last output, i.e. after both pipes closed.
+    pub fn poll(&self, id: &str, filter: Option<&Regex>) -> ToolResult {
+        let mut jobs = self.lock();
+        let job = find(&mut jobs, id)?;
+        job.refresh();
+        let done = job.finished() && job.drained();
+        let failed = matches!(&job.status, JobStatus::Exited(status) if !status.success());
+        let (lines, dropped) = job
+            .output
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take_new();
+
+        let mut out = format!("[{} {}: {}]\n", job.id, job.describe(), job.command);
+        if job.finished() && !done {
+            out.push_str("[a process it started still holds its output open]\n");
+        }
+        if dropped > 0 {
+            out.push_str(&format!(
+                "[{dropped} earlier lines dropped; buffer holds the last 1MB]\n"
+            ));
+        }
+        let total = lines.len();
+        let mut shown = 0;
+        for line in lines {
+            if filter.is_none_or(|re| re.is_match(line.trim_end_matches('\n'))) {
+                out.push_str(&line);
+                if !line.ends_with('\n') {
+                    out.push('\n');
+                }
+                shown += 1;
+            }
+        }
+        if total == 0 {
+            out.push_str("(no new output)\n");
+        } else if shown < total {
+            out.push_str(&format!("[{} lines did not match filter]\n", total - shown));
+        }
+        if done {
+            jobs.retain(|j| j.id != id);
+        }
+        match &self.sandbox {
+            Some(sandbox) if failed => Ok(sandbox.label_failure(out)),
+            _ => Ok(out),
+        }
+    }
+
+    pub fn kill(&self, id: &str) -> ToolResult {
+        let mut jobs = self.lock();
+        let job = find(&mut jobs, id)?;
+        job.refresh();
+        if !matches!(job.status, JobStatus::Running) {
+            job.kill_leftovers();
+            return Ok(format!("{} already {}", job.id, job.describe()));
+        }
+        job.kill();
+        Ok(format!("Killed {}: {}", job.id, job.command))
+    }
+
+    /// Jobs that haven't exited yet.
+    pub fn running(&self) -> Vec<JobSummary> {
+        let mut jobs = self.lock();
+        jobs.iter_mut()
+            .filter_map(|job| {
+                job.refresh();
+                (!job.finished()).then(|| JobSummary {
+                    id: job.id.clone(),
+                    command: job.command.clone(),
+                    pid: job.child.id(),
+                    running_secs: job.started.elapsed().as_secs(),
+                })
+            })
+            .collect()
+    }
+
+    /// Kill every job's process group, including what a finished job left
+    /// running. Returns how many jobs still had processes.
+    pub fn kill_all(&self) -> usize {
+        let mut jobs = self.lock();
+        let mut killed = 0;
+        for job in jobs.iter_mut() {
+            job.refresh();
+            if matches!(job.status, JobStatus::Running) {
+                job.kill();
+                killed += 1;
+            } else if job.group_alive() {
+                job.kill_leftovers();
+                killed += 1;
+            }
+        }
+        killed
+    }
+}
+
+impl Drop for Jobs {
+    fn drop(&mut self) {
+        self.kill_all();
+    }
+}
+
+/// Forget the oldest finished jobs nobody polled, keeping `MAX_FINISHED_JOBS`.
+fn prune_finished(jobs: &mut Vec<Job>) {
+    for job in jobs.iter_mut() {
+        job.refresh();
+    }
+    let mut excess = jobs
+        .iter()
+        .filter(|j| j.finished())
+        .count()
+        .saturating_sub(MAX_FINISHED_JOBS);
+    jobs.retain(|j| {
+        let drop = excess > 0 && j.finished();
+        excess -= drop as usize;
+        !drop
+    });
+}
+
+fn find<'a>(jobs: &'a mut [Job], id: &str) -> Result<&'a mut Job, String> {
+    jobs.iter_mut()
+        .find(|j| j.id == id)
+        .ok_or_else(|| format!("No background job with id {id}"))
+}
+
+fn spawn_reader(
+    pipe: impl Read + Send + 'static,
+    output: &Arc<Mutex<OutputBuffer>>,
+    done: &Arc<AtomicUsize>,
+) {
+    let output = Arc::clone(output);
+    let done = Arc::clone(done);
+    std::thread::spawn(move || {
+        let mut reader = BufReader::with_capacity(4096, pipe);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            let mut line = reader.by_ref().take(MAX_LINE_BYTES as u64);
+            match line.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => output
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(String::from_utf8_lossy(&buf).into_owned()),
+            }
+        }
+        done.fetch_add(1, Ordering::SeqCst);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Everything BashOutput returns until the job is forgotten.
+    fn wait_for_exit(jobs: &Jobs, id: &str) -> String {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut out = String::new();
+        while Instant::now() < deadline {
+            match jobs.poll(id, None) {
+                Ok(polled) => out.push_str(&polled),
+                Err(_) => break,
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        out
+    }
+
+    #[test]
+    fn buffer_reads_incrementally_and_reports_drops() {
+        let mut buf = OutputBuffer::default();
+        buf.push("a\n".into());
+        assert_eq!(buf.take_new(), (vec!["a\n".to_string()], 0));
+        assert_eq!(buf.take_new(), (vec![], 0));
+
+        let line = "x".repeat(JOB_BUFFER_BYTES / 2);
+        for _ in 0..3 {
+            buf.push(line.clone());
+        }
+        let (new, dropped) = buf.take_new();
+        assert_eq!((new.len(), dropped), (2, 1));
+    }
+
+    #[test]
+    fn job_output_status_and_filter() {
+        let jobs = Jobs::default();
+        let dir = tempfile::tempdir().unwrap();
+        let id = jobs
+            .spawn("echo one; echo two >&2; echo three; exit 4", dir.path())
+            .unwrap();
+        assert_eq!(id, "bash_1");
+        let out = wait_for_exit(&jobs, &id);
+        assert!(
+            out.contains("[bash_1 exited with code 4: echo one;"),
+            "{out}"
+        );
+        for line in ["one\n", "two\n", "three\n"] {
+            assert!(out.contains(line), "{out}");
+        }
+
+        // Its final output has been read, so the job is gone
+        assert!(jobs
+            .poll(&id, None)
+            .unwrap_err()
+            .contains("No background job with id bash_1"));
+
+        let id = jobs
+            .spawn("printf 'keep 1\\ndrop\\nkeep 2'", dir.path())
+            .unwrap();
+        assert_eq!(id, "bash_2");
+        std::thread::sleep(Duration::from_millis(300));
+        let out = jobs.poll(&id, Some(&Regex::new("^keep").unwrap())).unwrap();
+        assert!(out.contains("keep 1\nkeep 2\n"), "{out}");
+        assert!(out.ends_with("[1 lines did not match filter]\n"), "{out}");
+        assert!(jobs.running().is_empty());
+    }
+
+    #[test]
+    fn kill_stops_process_group() {
+        let jobs = Jobs::default();
+        let dir = tempfile::tempdir().unwrap();
+        let id = jobs
+            .spawn("trap '' TERM; sleep 30 & sleep 30", dir.path())
+            .unwrap();
+        let running = jobs.running();
+        assert_eq!(running.len(), 1);
+        assert_eq!(running[0].id, id);
+
+        assert!(jobs.kill(&id).unwrap().starts_with("Killed bash_1"));
+        assert!(jobs.running().is_empty());
+        assert_eq!(jobs.kill(&id).unwrap(), "bash_1 already killed");
+        assert!(jobs
+            .poll("bash_9", None)
+            .unwrap_err()
+            .contains("No background job"));
+    }
+
+    #[test]
+    fn output_after_leader_exits_is_kept() {
+        let jobs = Jobs::default();
+        let dir = tempfile::tempdir().unwrap();
+        let id = jobs
+            .spawn("(sleep 0.5; echo late) & echo early", dir.path())
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(250));
+        let out = jobs.poll(&id, None).unwrap();
+        assert!(out.starts_with("[bash_1 exited with code 0:"), "{out}");
+        assert!(out.contains("still holds its output open"), "{out}");
+        assert!(out.contains("early\n"), "{out}");
+        let out = wait_for_exit(&jobs, &id);
+        assert!(out.contains("late\n"), "{out}");
+    }
+
+    #[test]
+    fn kill_all_reaches_what_finished_jobs_left_running() {
+        let jobs = Jobs::default();
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        jobs.spawn(
+            &format!(
+                "sleep 30 > /dev/null 2>&1 & echo $! > {}",
+                pid_file.display()
+            ),
+            dir.path(),
+        )
+        .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !jobs.running().is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        assert_eq!(jobs.kill_all(), 1);
+        std::thread::sleep(Duration::from_millis(100));
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim()));
+        // Gone, or a zombie waiting for init to reap it
+        assert!(
+            stat.is_err() || stat.unwrap().contains(") Z "),
+            "sleep survived"
+        );
+    }
+
+    #[test]
+    fn kill_all_reaps_running_jobs() {
+        let jobs = Jobs::default();
+        let dir = tempfile::tempdir().unwrap();
+        jobs.spawn("sleep 30", dir.path()).unwrap();
+        jobs.spawn("true", dir.path()).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(jobs.kill_all(), 1);
+        assert!(jobs.running().is_empty());
+    }
+
+    #[test]
+    fn long_lines_are_split() {
+        let jobs = Jobs::default();
+        let dir = tempfile::tempdir().unwrap();
+        let id = jobs
+            .spawn(
+                &format!("head -c {} /dev/zero | tr '\\0' x", MAX_LINE_BYTES * 2 + 5),
+                dir.path(),
+            )
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !jobs.running().is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let out = jobs.poll(&id, None).unwrap();
+        let lens: Vec<usize> = out.lines().skip(1).map(str::len).collect();
+        assert_eq!(lens, [MAX_LINE_BYTES, MAX_LINE_BYTES, 5]);
+    }
+
+    #[test]
+    fn unpolled_finished_jobs_are_pruned() {
+        let jobs = Jobs::default();
+        let dir = tempfile::tempdir().unwrap();
+        for _ in 0..MAX_FINISHED_JOBS + 2 {
+            jobs.spawn("true", dir.path()).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(300));
+        jobs.spawn("true", dir.path()).unwrap();
+        let ids: Vec<String> = jobs.lock().iter().map(|j| j.id.clone()).collect();
+        assert_eq!(ids.len(), MAX_FINISHED_JOBS + 1);
+        assert_eq!(ids[0], "bash_3");
+    }
+}
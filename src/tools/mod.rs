@@ -1,158 +1,836 @@
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use grep_regex::RegexMatcherBuilder;
+use grep_searcher::{BinaryDetection, Searcher, SearcherBuilder, Sink, SinkContext, SinkMatch};
+use ignore::overrides::OverrideBuilder;
+use ignore::types::TypesBuilder;
+use ignore::WalkBuilder;
+use regex::Regex;
 use serde_json::{json, Value};
 use std::collections::HashSet;
 use std::io::{BufRead, BufReader};
-use std::path::Path;
-use std::process::{Command, Stdio};
-use std::sync::{mpsc, OnceLock};
-use std::time::{Duration, Instant};
-
-/// Generate `all_tool_schemas()` from a declarative tool list.
-/// Each entry: name, description, schema (serde_json::Value).
-macro_rules! tools {
-    ( $( $name:expr, $desc:expr, $schema:expr );+ $(;)? ) => {
-        pub fn all_tool_schemas() -> Vec<serde_json::Value> {
-            vec![
-                $(
-                    serde_json::json!({
-                        "name": $name,
-                        "description": $desc,
-                        "input_schema": $schema,
-                    }),
-                )+
-            ]
-        }
-    };
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+mod diff;
+mod jobs;
+mod sandbox;
+mod shell;
+
+pub use diff::EditDiff;
+pub use jobs::JobSummary;
+use jobs::Jobs;
+pub use sandbox::Sandbox;
+use shell::Shell;
+
+/// Result of a tool call: the output, or an error message shown to the model.
+pub type ToolResult = Result<String, String>;
+
+/// A tool result plus structured details for hooks and the terminal.
+#[derive(Debug)]
+pub struct ToolOutcome {
+    pub result: ToolResult,
+    /// The change a successful Edit made.
+    pub diff: Option<EditDiff>,
+}
+
+impl From<ToolResult> for ToolOutcome {
+    fn from(result: ToolResult) -> Self {
+        Self { result, diff: None }
+    }
+}
+
+/// Streaming output callback. Only tools that produce output incrementally call it.
+pub type StreamCallback<'a> = &'a mut (dyn FnMut(&str) + Send);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToolEffect {
+    /// No side effects; may run concurrently with other pure tools.
+    Pure,
+    /// Changes files or runs commands; runs alone, in order.
+    Mutating,
 }
 
-tools! {
-    "Read", "Read a file from disk. Returns file contents as text. Binary files return a placeholder message. Maximum 1MB file size.",
-    json!({
-        "type": "object",
-        "properties": {
-            "file_path": {
-                "type": "string",
-                "description": "Absolute or relative path to the file to read"
+/// A tool the model can call.
+pub trait Tool: Send + Sync {
+    /// PascalCase name the model calls the tool by.
+    fn name(&self) -> &str;
+    /// Description sent in the tool schema.
+    fn description(&self) -> &str;
+    /// JSON Schema for the tool's input.
+    fn input_schema(&self) -> Value;
+    /// One-line summary for the tool list in the system prompt.
+    fn prompt_summary(&self) -> &str;
+    fn effect(&self) -> ToolEffect;
+    fn execute<'a>(
+        &'a self,
+        input: &'a Value,
+        stream_cb: StreamCallback<'a>,
+    ) -> BoxFuture<'a, ToolResult>;
+    /// `execute` plus any structured details; most tools have none.
+    fn run<'a>(
+        &'a self,
+        input: &'a Value,
+        stream_cb: StreamCallback<'a>,
+    ) -> BoxFuture<'a, ToolOutcome> {
+        Box::pin(self.execute(input, stream_cb).map(ToolOutcome::from))
+    }
+}
+
+/// The tools offered to the model, in schema order.
+#[derive(Clone, Default)]
+pub struct ToolRegistry {
+    tools: Vec<Arc<dyn Tool>>,
+    schemas: Vec<Value>,
+    /// The Bash tool's persistent shell, shared so callers can see its cwd.
+    shell: Option<Arc<Shell>>,
+    /// Background jobs shared by Bash, BashOutput and KillShell.
+    jobs: Option<Arc<Jobs>>,
+    /// Confines Bash and the jobs it starts, and the files Edit may write.
+    sandbox: Option<Arc<Sandbox>>,
+}
+
+impl ToolRegistry {
+    /// The built-in tools with an unconfined Bash.
+    #[cfg(test)]
+    pub fn builtin() -> Self {
+        Self::builtin_with_sandbox(None)
+    }
+
+    /// Read, Glob, Bash, Edit, Grep, BashOutput and KillShell. Bash gets a
+    /// fresh shell started in the current directory and an empty job table,
+    /// both confined by `sandbox` when one is given; Edit then only writes
+    /// where a confined command could.
+    pub fn builtin_with_sandbox(sandbox: Option<Arc<Sandbox>>) -> Self {
+        let start_dir = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+        let shell = Arc::new(Shell::new(start_dir, sandbox.clone()));
+        let description = bash_description(sandbox.as_deref());
+        let jobs = Arc::new(Jobs::new(sandbox.clone()));
+        let mut registry = Self {
+            shell: Some(Arc::clone(&shell)),
+            jobs: Some(Arc::clone(&jobs)),
+            sandbox: sandbox.clone(),
+            ..Self::default()
+        };
+        let base = BaseDir(Some(Arc::clone(&shell)));
+        registry.register(Arc::new(ReadTool { base: base.clone() }));
+        registry.register(Arc::new(GlobTool { base: base.clone() }));
+        registry.register(Arc::new(BashTool {
+            shell,
+            jobs: Arc::clone(&jobs),
+            description,
+        }));
+        registry.register(Arc::new(EditTool {
+            context: EDIT_DIFF_CONTEXT,
+            base: base.clone(),
+            sandbox,
+        }));
+        registry.register(Arc::new(GrepTool { base }));
+        registry.register(Arc::new(BashOutputTool {
+            jobs: Arc::clone(&jobs),
+        }));
+        registry.register(Arc::new(KillShellTool { jobs }));
+        registry
+    }
+
+    /// Show `lines` unchanged lines around each change in Edit's diffs.
+    pub fn with_diff_context(mut self, lines: usize) -> Self {
+        if self.get("Edit").is_some() {
+            self.register(Arc::new(EditTool {
+                context: lines,
+                base: BaseDir(self.shell.clone()),
+                sandbox: self.sandbox.clone(),
+            }));
+        }
+        self
+    }
+
+    /// Add `tool`, replacing any tool with the same name in place.
+    pub fn register(&mut self, tool: Arc<dyn Tool>) {
+        match self.tools.iter().position(|t| t.name() == tool.name()) {
+            Some(i) => self.tools[i] = tool,
+            None => self.tools.push(tool),
+        }
+        self.rebuild_schemas();
+    }
+
+    /// Working directory of the Bash shell, after any `cd`s the model ran.
+    pub fn shell_cwd(&self) -> Option<PathBuf> {
+        self.shell.as_ref().map(|s| s.cwd())
+    }
+
+    /// Restart the Bash shell in its starting directory.
+    pub fn reset_shell(&self) {
+        if let Some(shell) = &self.shell {
+            shell.reset();
+        }
+    }
+
+    /// Background jobs that are still running.
+    pub fn background_jobs(&self) -> Vec<JobSummary> {
+        self.jobs.as_ref().map(|j| j.running()).unwrap_or_default()
+    }
+
+    /// Kill all running background jobs. Returns how many there were.
+    pub fn kill_background_jobs(&self) -> usize {
+        self.jobs.as_ref().map_or(0, |j| j.kill_all())
+    }
+
+    /// `kill_background_jobs` as a handle that outlives this borrow, for
+    /// signal handlers running on another task. It also kills the Bash shell,
+    /// which runs in its own process group and so misses the signal too.
+    pub fn background_job_killer(&self) -> impl Fn() -> usize + Send + 'static {
+        let jobs = self.jobs.clone();
+        let shell = self.shell.clone();
+        move || {
+            if let Some(shell) = &shell {
+                shell.kill();
             }
-        },
-        "required": ["file_path"]
-    });
-
-    "Glob", "List files matching a glob pattern. Returns up to 1000 entries in alphabetical order.",
-    json!({
-        "type": "object",
-        "properties": {
-            "pattern": {
-                "type": "string",
-                "description": "Glob pattern (e.g. '**/*.rs', 'src/*.ts')"
+            jobs.as_ref().map_or(0, |j| j.kill_all())
+        }
+    }
+
+    /// Remove the tool called `name`. Returns false if there was none.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.tools.len();
+        self.tools.retain(|t| t.name() != name);
+        self.rebuild_schemas();
+        self.tools.len() < before
+    }
+
+    fn rebuild_schemas(&mut self) {
+        self.schemas = self
+            .tools
+            .iter()
+            .map(|t| {
+                json!({
+                    "name": t.name(),
+                    "description": t.description(),
+                    "input_schema": t.input_schema(),
+                })
+            })
+            .collect();
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn Tool>> {
+        self.tools.iter().find(|t| t.name() == name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.tools.iter().map(|t| t.name()).collect()
+    }
+
+    /// Schemas for the API request's `tools` field.
+    pub fn schemas(&self) -> &[Value] {
+        &self.schemas
+    }
+
+    /// "- Name: summary" lines for the system prompt.
+    pub fn prompt_list(&self) -> String {
+        self.tools
+            .iter()
+            .map(|t| format!("- {}: {}", t.name(), t.prompt_summary()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Unknown tools are treated as mutating so they never run concurrently.
+    pub fn effect(&self, name: &str) -> ToolEffect {
+        self.get(name).map_or(ToolEffect::Mutating, |t| t.effect())
+    }
+
+    /// Run the tool called `name`. Returns Ok(output) or Err(error_message).
+    #[cfg(test)]
+    pub async fn dispatch(
+        &self,
+        name: &str,
+        input: &Value,
+        stream_cb: StreamCallback<'_>,
+    ) -> ToolResult {
+        self.dispatch_with_details(name, input, stream_cb)
+            .await
+            .result
+    }
+
+    /// `dispatch`, keeping the structured details the tool reported.
+    pub async fn dispatch_with_details(
+        &self,
+        name: &str,
+        input: &Value,
+        stream_cb: StreamCallback<'_>,
+    ) -> ToolOutcome {
+        match self.get(name) {
+            Some(tool) => tool.run(input, stream_cb).await,
+            None => Err(format!("Unknown tool: {name}")).into(),
+        }
+    }
+}
+
+/// Run a synchronous tool body on the blocking pool, so pure tools in the
+/// same batch actually overlap.
+fn run_blocking<T: From<ToolResult> + Send + 'static>(
+    input: &Value,
+    exec: impl FnOnce(&Value) -> T + Send + 'static,
+) -> BoxFuture<'static, T> {
+    let input = input.clone();
+    Box::pin(async move {
+        tokio::task::spawn_blocking(move || exec(&input))
+            .await
+            .unwrap_or_else(|e| Err(format!("tool panicked: {e}")).into())
+    })
+}
+
+/// Where relative paths in file tool inputs resolve: the Bash shell's
+/// working directory, so Read and Edit follow the model's `cd`s.
+#[derive(Clone, Default)]
+struct BaseDir(Option<Arc<Shell>>);
+
+impl BaseDir {
+    /// `input` with its `key` path rooted at the shell's directory when that
+    /// isn't the process's. A missing `key` counts as `default`. Results are
+    /// then absolute, so listed paths stay valid wherever the shell goes.
+    fn resolve(&self, input: &Value, key: &str, default: Option<&str>) -> Value {
+        let mut input = input.clone();
+        let Some(shell) = &self.0 else {
+            return input;
+        };
+        let dir = shell.cwd();
+        if std::env::current_dir().is_ok_and(|cwd| cwd == dir) {
+            return input;
+        }
+        let Some(path) = input[key].as_str().or(default) else {
+            return input;
+        };
+        let resolved = match path {
+            "." => dir,
+            path if Path::new(path).is_relative() => dir.join(path),
+            _ => return input,
+        };
+        if let Value::Object(map) = &mut input {
+            map.insert(key.to_string(), json!(resolved.to_string_lossy()));
+        }
+        input
+    }
+}
+
+struct ReadTool {
+    base: BaseDir,
+}
+
+impl Tool for ReadTool {
+    fn name(&self) -> &str {
+        "Read"
+    }
+
+    fn description(&self) -> &str {
+        "Read a file from disk. Output is numbered like `cat -n` (the numbers and tab are not part of the file) under a header giving the total line count. Returns up to 2000 lines starting at `offset`; use `offset` and `limit` to page through longer files. Lines over 2000 characters are truncated. Binary files return a placeholder message."
+    }
+
+    fn input_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "file_path": {
+                    "type": "string",
+                    "description": "Absolute path, or relative to the Bash shell's working directory"
+                },
+                "offset": {
+                    "type": "integer",
+                    "description": "1-based line number to start reading from (default: 1)"
+                },
+                "limit": {
+                    "type": "integer",
+                    "description": "Maximum number of lines to return (default: 2000)"
+                }
             },
-            "path": {
-                "type": "string",
-                "description": "Base directory to search from (default: current directory)"
-            }
-        },
-        "required": ["pattern"]
-    });
-
-    "Bash", "Execute a bash command. Returns stdout and stderr. 120 second timeout. Streaming output.",
-    json!({
-        "type": "object",
-        "properties": {
-            "command": {
-                "type": "string",
-                "description": "The bash command to execute"
+            "required": ["file_path"]
+        })
+    }
+
+    fn prompt_summary(&self) -> &str {
+        "Read file contents with line numbers (paged by offset/limit)"
+    }
+
+    fn effect(&self) -> ToolEffect {
+        ToolEffect::Pure
+    }
+
+    fn execute<'a>(&'a self, input: &'a Value, _: StreamCallback<'a>) -> BoxFuture<'a, ToolResult> {
+        run_blocking(&self.base.resolve(input, "file_path", None), read_exec)
+    }
+}
+
+struct GlobTool {
+    base: BaseDir,
+}
+
+impl Tool for GlobTool {
+    fn name(&self) -> &str {
+        "Glob"
+    }
+
+    fn description(&self) -> &str {
+        "List files matching a glob pattern. Skips .git and anything ignored by .gitignore/.ignore. Brace groups may be nested or repeated (e.g. '{src,tests}/**/*.{rs,toml}'). Returns up to 1000 entries, alphabetically or newest first with sort=\"mtime\", and says when results were truncated."
+    }
+
+    fn input_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "pattern": {
+                    "type": "string",
+                    "description": "Glob pattern (e.g. '**/*.rs', 'src/*.ts')"
+                },
+                "path": {
+                    "type": "string",
+                    "description": "Base directory to search from (default: current directory)"
+                },
+                "exclude": {
+                    "type": "array",
+                    "items": {"type": "string"},
+                    "description": "Glob patterns to skip. Patterns without '/' match a file or directory name at any depth; others match the path relative to the base directory"
+                },
+                "sort": {
+                    "type": "string",
+                    "enum": ["name", "mtime"],
+                    "description": "Result order: name (default) or mtime, newest first"
+                }
             },
-            "description": {
-                "type": "string",
-                "description": "Brief description of what the command does"
+            "required": ["pattern"]
+        })
+    }
+
+    fn prompt_summary(&self) -> &str {
+        "List files matching a pattern, gitignore-aware (max 1000 entries)"
+    }
+
+    fn effect(&self) -> ToolEffect {
+        ToolEffect::Pure
+    }
+
+    fn execute<'a>(&'a self, input: &'a Value, _: StreamCallback<'a>) -> BoxFuture<'a, ToolResult> {
+        run_blocking(&self.base.resolve(input, "path", Some(".")), glob_exec)
+    }
+}
+
+struct BashTool {
+    shell: Arc<Shell>,
+    jobs: Arc<Jobs>,
+    description: String,
+}
+
+const BASH_DESCRIPTION: &str = "Execute a bash command in a persistent shell: the working directory and exported variables carry over between calls. Returns stdout and stderr. 120 second timeout; on timeout the shell is restarted in the same directory and its environment is lost. Set reset=true to start a fresh shell in the project directory. Set run_in_background=true for servers, watchers and long test runs: the command starts in the shell's working directory (without its exported variables) and a job id comes back at once; read its output with BashOutput and stop it with KillShell. Streaming output.";
+
+/// Bash's description, plus the sandbox policy when there is one.
+fn bash_description(sandbox: Option<&Sandbox>) -> String {
+    match sandbox {
+        None => BASH_DESCRIPTION.to_string(),
+        Some(sandbox) => format!(
+            "{BASH_DESCRIPTION} Commands run in a sandbox: {}. A failed command whose output contains an error the sandbox could have caused, such as Permission denied or Network is unreachable, is prefixed with [sandbox]; that label is a guess from the text and the error may have another cause. Edit is held to the same write rules.",
+            sandbox.describe()
+        ),
+    }
+}
+
+impl Tool for BashTool {
+    fn name(&self) -> &str {
+        "Bash"
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn input_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "command": {
+                    "type": "string",
+                    "description": "The bash command to execute"
+                },
+                "description": {
+                    "type": "string",
+                    "description": "Brief description of what the command does"
+                },
+                "reset": {
+                    "type": "boolean",
+                    "description": "Restart the shell in the project directory before running command (command may then be omitted)"
+                },
+                "run_in_background": {
+                    "type": "boolean",
+                    "description": "Run as a background job and return its id immediately (default: false)"
+                }
             }
-        },
-        "required": ["command"]
-    });
-
-    "Edit", "Edit a file by replacing exact text matches. Maximum 100KB file size. Default: single exact match. Set replace_all=true for bulk replacements. Empty old_str on missing file creates the file (with parent directories). Empty old_str on existing file appends content.",
-    json!({
-        "type": "object",
-        "properties": {
-            "file_path": {
-                "type": "string",
-                "description": "Path to the file to edit"
-            },
-            "old_str": {
-                "type": "string",
-                "description": "Exact text to find and replace (empty string = create/append)"
-            },
-            "new_str": {
-                "type": "string",
-                "description": "Replacement text"
-            },
-            "replace_all": {
-                "type": "boolean",
-                "description": "Replace all occurrences instead of requiring a single unique match (default: false)"
+        })
+    }
+
+    fn prompt_summary(&self) -> &str {
+        "Execute shell commands in a persistent shell (120s timeout)"
+    }
+
+    fn effect(&self) -> ToolEffect {
+        ToolEffect::Mutating
+    }
+
+    fn execute<'a>(
+        &'a self,
+        input: &'a Value,
+        stream_cb: StreamCallback<'a>,
+    ) -> BoxFuture<'a, ToolResult> {
+        // Runs on the blocking pool so the turn stays cancellable; output is
+        // relayed to the callback, which borrows from the caller.
+        let shell = Arc::clone(&self.shell);
+        let jobs = Arc::clone(&self.jobs);
+        let input = input.clone();
+        Box::pin(async move {
+            let interrupt = KillOnDrop(Some(&self.shell));
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+            let mut task = tokio::task::spawn_blocking(move || {
+                bash_exec(&shell, &jobs, &input, &mut |chunk| {
+                    let _ = tx.send(chunk.to_string());
+                })
+            });
+            let result = loop {
+                tokio::select! {
+                    biased;
+                    Some(chunk) = rx.recv() => stream_cb(&chunk),
+                    result = &mut task => break result,
+                }
+            };
+            interrupt.disarm();
+            while let Ok(chunk) = rx.try_recv() {
+                stream_cb(&chunk);
             }
-        },
-        "required": ["file_path", "old_str", "new_str"]
-    });
-
-    "Grep", "Search file contents using ripgrep (rg). Returns up to 50 matches. Requires rg to be installed.",
-    json!({
-        "type": "object",
-        "properties": {
-            "pattern": {
-                "type": "string",
-                "description": "Regex pattern to search for"
+            result.unwrap_or_else(|e| Err(format!("tool panicked: {e}")))
+        })
+    }
+}
+
+/// Kills the shell if a Bash call is dropped before it finishes, e.g. when
+/// Ctrl-C cancels the turn, so the command doesn't outlive the interrupt.
+struct KillOnDrop<'a>(Option<&'a Shell>);
+
+impl KillOnDrop<'_> {
+    fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for KillOnDrop<'_> {
+    fn drop(&mut self) {
+        if let Some(shell) = self.0 {
+            shell.kill();
+        }
+    }
+}
+
+struct BashOutputTool {
+    jobs: Arc<Jobs>,
+}
+
+impl Tool for BashOutputTool {
+    fn name(&self) -> &str {
+        "BashOutput"
+    }
+
+    fn description(&self) -> &str {
+        "Read new output from a background Bash job: everything written since the last BashOutput call for that job, after a status line (running, exited with code N, or killed). Stdout and stderr are interleaved. The last 1MB of output is kept per job. Once a poll reports that a job has finished and returns its last output, its id is no longer valid; if something the job started still holds its output open, the status line says so and later polls return the rest. Use filter to return only matching lines; the rest are still marked as read."
+    }
+
+    fn input_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "bash_id": {
+                    "type": "string",
+                    "description": "Job id returned by Bash with run_in_background (e.g. 'bash_1')"
+                },
+                "filter": {
+                    "type": "string",
+                    "description": "Regex; only output lines matching it are returned"
+                }
             },
-            "path": {
-                "type": "string",
-                "description": "File or directory to search in (default: current directory)"
+            "required": ["bash_id"]
+        })
+    }
+
+    fn prompt_summary(&self) -> &str {
+        "Read new output from a background Bash job"
+    }
+
+    fn effect(&self) -> ToolEffect {
+        ToolEffect::Pure
+    }
+
+    fn execute<'a>(&'a self, input: &'a Value, _: StreamCallback<'a>) -> BoxFuture<'a, ToolResult> {
+        let jobs = Arc::clone(&self.jobs);
+        run_blocking(input, move |input| {
+            let id = input["bash_id"]
+                .as_str()
+                .ok_or("Missing required parameter: bash_id")?;
+            let filter = match input["filter"].as_str() {
+                Some(re) => Some(Regex::new(re).map_err(|e| format!("Invalid filter regex: {e}"))?),
+                None => None,
+            };
+            jobs.poll(id, filter.as_ref())
+        })
+    }
+}
+
+struct KillShellTool {
+    jobs: Arc<Jobs>,
+}
+
+impl Tool for KillShellTool {
+    fn name(&self) -> &str {
+        "KillShell"
+    }
+
+    fn description(&self) -> &str {
+        "Stop a background Bash job. Sends SIGTERM to its whole process group, then SIGKILL after 2 seconds."
+    }
+
+    fn input_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "bash_id": {
+                    "type": "string",
+                    "description": "Job id returned by Bash with run_in_background"
+                }
             },
-            "file_type": {
-                "type": "string",
-                "description": "File type filter (e.g. 'rs', 'py', 'js')"
+            "required": ["bash_id"]
+        })
+    }
+
+    fn prompt_summary(&self) -> &str {
+        "Stop a background Bash job"
+    }
+
+    fn effect(&self) -> ToolEffect {
+        ToolEffect::Mutating
+    }
+
+    fn execute<'a>(&'a self, input: &'a Value, _: StreamCallback<'a>) -> BoxFuture<'a, ToolResult> {
+        let jobs = Arc::clone(&self.jobs);
+        run_blocking(input, move |input| {
+            let id = input["bash_id"]
+                .as_str()
+                .ok_or("Missing required parameter: bash_id")?;
+            jobs.kill(id)
+        })
+    }
+}
+
+/// Unchanged lines around each change in Edit's diffs unless configured.
+const EDIT_DIFF_CONTEXT: usize = 3;
+
+struct EditTool {
+    context: usize,
+    base: BaseDir,
+    sandbox: Option<Arc<Sandbox>>,
+}
+
+impl Tool for EditTool {
+    fn name(&self) -> &str {
+        "Edit"
+    }
+
+    fn description(&self) -> &str {
+        "Edit a file by replacing exact text matches. Maximum 100KB file size. Default: single exact match. Set replace_all=true for bulk replacements. Empty old_str on missing file creates the file (with parent directories). Empty old_str on existing file appends content. Returns a unified diff of the change."
+    }
+
+    fn input_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "file_path": {
+                    "type": "string",
+                    "description": "Path to the file to edit"
+                },
+                "old_str": {
+                    "type": "string",
+                    "description": "Exact text to find and replace (empty string = create/append)"
+                },
+                "new_str": {
+                    "type": "string",
+                    "description": "Replacement text"
+                },
+                "replace_all": {
+                    "type": "boolean",
+                    "description": "Replace all occurrences instead of requiring a single unique match (default: false)"
+                }
             },
-            "case_sensitive": {
-                "type": "boolean",
-                "description": "Case-sensitive search (default: true)"
+            "required": ["file_path", "old_str", "new_str"]
+        })
+    }
+
+    fn prompt_summary(&self) -> &str {
+        "Edit files with exact text replacement (max 100KB, use replace_all for bulk)"
+    }
+
+    fn effect(&self) -> ToolEffect {
+        ToolEffect::Mutating
+    }
+
+    fn execute<'a>(
+        &'a self,
+        input: &'a Value,
+        stream_cb: StreamCallback<'a>,
+    ) -> BoxFuture<'a, ToolResult> {
+        Box::pin(self.run(input, stream_cb).map(|outcome| outcome.result))
+    }
+
+    fn run<'a>(&'a self, input: &'a Value, _: StreamCallback<'a>) -> BoxFuture<'a, ToolOutcome> {
+        let context = self.context;
+        let input = self.base.resolve(input, "file_path", None);
+        let sandbox = self.sandbox.clone();
+        run_blocking(&input, move |input| {
+            if let (Some(sandbox), Some(path)) = (&sandbox, input["file_path"].as_str()) {
+                if let Err(e) = sandbox.check_write(Path::new(path)) {
+                    return Err(e).into();
+                }
+            }
+            match edit_exec(input, context) {
+                Ok((summary, diff)) => ToolOutcome {
+                    result: Ok(format!("{summary} {}\n{}", diff.stat(), diff.compact())),
+                    diff: Some(diff),
+                },
+                Err(e) => Err(e).into(),
             }
-        },
-        "required": ["pattern"]
-    });
+        })
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum ToolEffect {
-    Pure,
-    Mutating,
+struct GrepTool {
+    base: BaseDir,
+}
+
+impl Tool for GrepTool {
+    fn name(&self) -> &str {
+        "Grep"
+    }
+
+    fn description(&self) -> &str {
+        "Search file contents with a regex. Respects .gitignore and skips hidden and binary files. output_mode \"content\" (default) shows matching lines as path:line:text, with -A/-B/-C context lines as path-line-text; \"files_with_matches\" lists matching files; \"count\" shows path:count. Results are paged: head_limit entries (default 200) starting after offset."
+    }
+
+    fn input_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "pattern": {
+                    "type": "string",
+                    "description": "Regex pattern to search for"
+                },
+                "path": {
+                    "type": "string",
+                    "description": "File or directory to search in (default: current directory)"
+                },
+                "output_mode": {
+                    "type": "string",
+                    "enum": ["content", "files_with_matches", "count"],
+                    "description": "What to return (default: content)"
+                },
+                "glob": {
+                    "type": "string",
+                    "description": "Only search files matching this glob (e.g. '*.rs', '**/*.{ts,tsx}')"
+                },
+                "exclude": {
+                    "type": "string",
+                    "description": "Skip files matching this glob (e.g. '**/tests/**')"
+                },
+                "file_type": {
+                    "type": "string",
+                    "description": "File type filter (e.g. 'rust', 'py', 'js')"
+                },
+                "case_sensitive": {
+                    "type": "boolean",
+                    "description": "Case-sensitive search (default: true)"
+                },
+                "multiline": {
+                    "type": "boolean",
+                    "description": "Let the pattern span lines; `.` also matches newlines (default: false)"
+                },
+                "-A": {
+                    "type": "integer",
+                    "description": "Lines of context after each match (content mode)"
+                },
+                "-B": {
+                    "type": "integer",
+                    "description": "Lines of context before each match (content mode)"
+                },
+                "-C": {
+                    "type": "integer",
+                    "description": "Lines of context before and after each match (content mode)"
+                },
+                "offset": {
+                    "type": "integer",
+                    "description": "Number of result entries to skip (default: 0)"
+                },
+                "head_limit": {
+                    "type": "integer",
+                    "description": "Maximum number of result entries to return (default: 200)"
+                }
+            },
+            "required": ["pattern"]
+        })
+    }
+
+    fn prompt_summary(&self) -> &str {
+        "Search file contents by regex (content, files_with_matches or count; paged)"
+    }
+
+    fn effect(&self) -> ToolEffect {
+        ToolEffect::Pure
+    }
+
+    fn execute<'a>(&'a self, input: &'a Value, _: StreamCallback<'a>) -> BoxFuture<'a, ToolResult> {
+        run_blocking(&self.base.resolve(input, "path", Some(".")), grep_exec)
+    }
 }
 
-pub fn tool_effect(name: &str) -> ToolEffect {
-    match name {
-        "Read" | "Glob" | "Grep" => ToolEffect::Pure,
-        "Bash" | "Edit" => ToolEffect::Mutating,
-        _ => ToolEffect::Mutating,
+/// Lines returned by Read when no `limit` is given.
+const READ_DEFAULT_LIMIT: usize = 2000;
+/// Longer lines are cut to this many characters.
+const READ_MAX_LINE_CHARS: usize = 2000;
+/// Output budget for one page; a page stops early rather than exceed it.
+const READ_MAX_PAGE_BYTES: usize = 1_048_576;
+
+/// Optional integer parameter of at least `min`; `None` when absent or null.
+fn uint_param(input: &Value, key: &str, min: u64) -> Result<Option<usize>, String> {
+    match input.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(v) => v
+            .as_u64()
+            .filter(|&n| n >= min)
+            .map(|n| Some(n as usize))
+            .ok_or_else(|| match min {
+                0 => format!("{key} must be a non-negative integer"),
+                1 => format!("{key} must be a positive integer"),
+                _ => format!("{key} must be an integer >= {min}"),
+            }),
     }
 }
 
-/// Dispatch a tool call by name. Returns Ok(output) or Err(error_message).
-/// Bash gets a streaming callback; other tools don't need one.
-pub fn dispatch_tool(
-    name: &str,
-    input: &Value,
-    stream_cb: &mut dyn FnMut(&str),
-) -> Result<String, String> {
-    match name {
-        "Read" => read_exec(input),
-        "Glob" => glob_exec(input),
-        "Bash" => bash_exec(input, stream_cb),
-        "Edit" => edit_exec(input),
-        "Grep" => grep_exec(input),
-        _ => Err(format!("Unknown tool: {name}")),
+/// Cut `line` to `READ_MAX_LINE_CHARS`, noting the original length.
+fn truncate_line(line: &str) -> std::borrow::Cow<'_, str> {
+    let char_count = line.chars().count();
+    if char_count <= READ_MAX_LINE_CHARS {
+        return line.into();
     }
+    let cut: String = line.chars().take(READ_MAX_LINE_CHARS).collect();
+    format!("{cut}... [line truncated, {char_count} chars]").into()
 }
 
-fn read_exec(input: &Value) -> Result<String, String> {
+fn read_exec(input: &Value) -> ToolResult {
     let file_path = input["file_path"]
         .as_str()
         .ok_or("Missing required parameter: file_path")?;
+    let offset = uint_param(input, "offset", 1)?.unwrap_or(1);
+    let limit = uint_param(input, "limit", 1)?.unwrap_or(READ_DEFAULT_LIMIT);
 
     let path = Path::new(file_path);
     if !path.exists() {
@@ -161,96 +839,279 @@ fn read_exec(input: &Value) -> Result<String, String> {
 
     let metadata =
         std::fs::metadata(path).map_err(|e| format!("Cannot read file metadata: {e}"))?;
-    if metadata.len() > 1_048_576 {
-        return Err(format!(
-            "File too large: {} bytes (limit: 1MB)",
+    let file = std::fs::File::open(path).map_err(|e| format!("Cannot read file: {e}"))?;
+    let mut reader = BufReader::with_capacity(64 * 1024, file);
+
+    // Check for binary content (NUL bytes in first 8KB)
+    let head = reader
+        .fill_buf()
+        .map_err(|e| format!("Cannot read file: {e}"))?;
+    if head[..head.len().min(8192)].contains(&0) {
+        return Ok(format!(
+            "[Binary file: {file_path}, {} bytes]",
             metadata.len()
         ));
     }
 
-    let content = std::fs::read(path).map_err(|e| format!("Cannot read file: {e}"))?;
+    let mut body = String::new();
+    let mut last_shown = 0;
+    let mut total = 0;
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        let n = reader
+            .read_until(b'\n', &mut buf)
+            .map_err(|e| format!("Cannot read file: {e}"))?;
+        if n == 0 {
+            break;
+        }
+        total += 1;
+        if total < offset || total - offset >= limit || body.len() >= READ_MAX_PAGE_BYTES {
+            continue;
+        }
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.trim_end_matches('\n').trim_end_matches('\r');
+        body.push_str(&format!("{total:>6}\t{}\n", truncate_line(line)));
+        last_shown = total;
+    }
 
-    // Check for binary content (NUL bytes in first 8KB)
-    let check_len = content.len().min(8192);
-    if content[..check_len].contains(&0) {
-        return Ok(format!(
-            "[Binary file: {file_path}, {} bytes]",
-            content.len()
+    if total == 0 {
+        return Ok(format!("[{file_path}: empty file]"));
+    }
+    if offset > total {
+        return Err(format!(
+            "offset {offset} is past the end of {file_path} ({total} lines)"
         ));
     }
 
-    String::from_utf8(content).map_err(|_| format!("File contains invalid UTF-8: {file_path}"))
+    let header = if offset == 1 && last_shown == total {
+        format!("[{file_path}: {total} lines]")
+    } else {
+        let size = if metadata.len() > READ_MAX_PAGE_BYTES as u64 {
+            format!(", {:.1}MB file", metadata.len() as f64 / 1_048_576.0)
+        } else {
+            String::new()
+        };
+        let more = if last_shown < total {
+            format!(" Use offset={} to read more.", last_shown + 1)
+        } else {
+            String::new()
+        };
+        format!("[{file_path}: lines {offset}-{last_shown} of {total}{size}.{more}]")
+    };
+    Ok(format!("{header}\n{body}"))
 }
 
-fn glob_exec(input: &Value) -> Result<String, String> {
+/// Maximum entries Glob returns.
+const GLOB_LIMIT: usize = 1000;
+
+fn glob_exec(input: &Value) -> ToolResult {
     let pattern = input["pattern"]
         .as_str()
         .ok_or("Missing required parameter: pattern")?;
     let base = input["path"].as_str().unwrap_or(".");
-
-    let full_pattern = if pattern.starts_with('/') || pattern.starts_with('.') {
-        pattern.to_string()
-    } else {
-        format!("{base}/{pattern}")
+    let by_mtime = match input["sort"].as_str().unwrap_or("name") {
+        "name" => false,
+        "mtime" => true,
+        other => return Err(format!("Unknown sort: {other} (expected name or mtime)")),
+    };
+    let excludes: Vec<&str> = match &input["exclude"] {
+        Value::Null => Vec::new(),
+        Value::String(s) => vec![s.as_str()],
+        Value::Array(items) => items.iter().filter_map(Value::as_str).collect(),
+        _ => return Err("exclude must be a string or an array of strings".to_string()),
     };
 
-    let expanded = expand_braces(&full_pattern);
+    // Absolute patterns and `..` are joined as-is; `./` is dropped so
+    // results read `src/main.rs`, not `./src/main.rs`.
+    let full_pattern = Path::new(base).join(pattern).to_string_lossy().to_string();
+    let full_pattern = strip_dot_slash(&full_pattern);
+
+    // Expand every pattern up front so one bad alternative fails the whole call
+    let mut includes = globset::GlobSetBuilder::new();
+    let mut roots: Vec<(String, Option<usize>)> = Vec::new();
+    for pat in expand_braces(full_pattern) {
+        includes.add(compile_glob(&pat)?);
+        let root = glob_walk_root(&pat);
+        if !roots.contains(&root) {
+            roots.push(root);
+        }
+    }
+    let includes = includes
+        .build()
+        .map_err(|e| format!("Invalid glob pattern '{pattern}': {e}"))?;
+
+    let mut names = globset::GlobSetBuilder::new();
+    let mut rel_paths = globset::GlobSetBuilder::new();
+    for pat in excludes {
+        for pat in expand_braces(pat) {
+            let target = if pat.contains('/') {
+                &mut rel_paths
+            } else {
+                &mut names
+            };
+            target.add(compile_glob(&pat)?);
+        }
+    }
+    let names = names.build().map_err(|e| e.to_string())?;
+    let rel_paths = Arc::new(rel_paths.build().map_err(|e| e.to_string())?);
+    let names = Arc::new(names);
+    let base_path = Path::new(strip_dot_slash(base)).to_path_buf();
 
     let mut seen = HashSet::new();
-    let mut results = Vec::new();
-    const LIMIT: usize = 1000;
-
-    for pat in &expanded {
-        let paths =
-            glob::glob(pat).map_err(|e| format!("Invalid glob pattern '{}': {}", pat, e))?;
-        for entry in paths {
-            if results.len() >= LIMIT {
-                break;
+    let mut results: Vec<(String, SystemTime)> = Vec::new();
+    for (root, max_depth) in roots {
+        if !Path::new(&root).exists() {
+            continue;
+        }
+        let names = Arc::clone(&names);
+        let rel_paths = Arc::clone(&rel_paths);
+        let base_path = base_path.clone();
+        let walker = WalkBuilder::new(&root)
+            .hidden(false)
+            .require_git(false)
+            .max_depth(max_depth)
+            .filter_entry(move |entry| {
+                if entry.depth() == 0 {
+                    return true;
+                }
+                if entry.file_name() == ".git" || names.is_match(entry.file_name()) {
+                    return false;
+                }
+                let path = Path::new(strip_dot_slash_path(entry.path()));
+                let rel = path.strip_prefix(&base_path).unwrap_or(path);
+                !rel_paths.is_match(rel)
+            })
+            .build();
+        // Skip entries with errors (permission denied, etc.)
+        for entry in walker.flatten() {
+            if entry.depth() == 0 {
+                continue;
             }
-            // Skip entries with errors (permission denied, etc.)
-            let path = match entry {
-                Ok(p) => p,
-                Err(_) => continue,
-            };
-            let path_str = path.to_string_lossy().to_string();
-            if seen.insert(path_str.clone()) {
-                results.push(path_str);
+            let path = strip_dot_slash_path(entry.path());
+            if !includes.is_match(path) || !seen.insert(path.to_string()) {
+                continue;
             }
-        }
-        if results.len() >= LIMIT {
-            break;
+            let mtime = if by_mtime {
+                entry
+                    .metadata()
+                    .ok()
+                    .and_then(|m| m.modified().ok())
+                    .unwrap_or(SystemTime::UNIX_EPOCH)
+            } else {
+                SystemTime::UNIX_EPOCH
+            };
+            results.push((path.to_string(), mtime));
         }
     }
 
     if results.is_empty() {
-        Ok("No files found".to_string())
+        return Ok("No files found".to_string());
+    }
+    if by_mtime {
+        results.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
     } else {
-        Ok(results.join("\n"))
+        results.sort_by(|a, b| a.0.cmp(&b.0));
     }
+    let total = results.len();
+    let mut out = results
+        .into_iter()
+        .take(GLOB_LIMIT)
+        .map(|(path, _)| path)
+        .collect::<Vec<_>>()
+        .join("\n");
+    if total > GLOB_LIMIT {
+        out.push_str(&format!(
+            "\n[Showing {GLOB_LIMIT} of {total} entries. Narrow the pattern or path to see the rest.]"
+        ));
+    }
+    Ok(out)
 }
 
-/// Expand a single top-level brace group in a glob pattern.
-/// `**/*.{rs,toml}` → `["**/*.rs", "**/*.toml"]`
-/// `src/{main,lib}.rs` → `["src/main.rs", "src/lib.rs"]`
-/// No braces or unmatched braces → returns the original pattern unchanged.
-fn expand_braces(pattern: &str) -> Vec<String> {
-    let open = match pattern.find('{') {
-        Some(i) => i,
-        None => return vec![pattern.to_string()],
-    };
-    let close = match pattern[open..].find('}') {
-        Some(i) => open + i,
-        None => return vec![pattern.to_string()],
-    };
+fn strip_dot_slash(path: &str) -> &str {
+    let mut path = path;
+    while let Some(rest) = path.strip_prefix("./") {
+        path = rest.trim_start_matches('/');
+    }
+    path
+}
 
-    let prefix = &pattern[..open];
-    let suffix = &pattern[close + 1..];
-    let alternatives = &pattern[open + 1..close];
+fn strip_dot_slash_path(path: &Path) -> &str {
+    strip_dot_slash(path.to_str().unwrap_or_default())
+}
 
-    alternatives
-        .split(',')
-        .map(|alt| format!("{prefix}{alt}{suffix}"))
-        .collect()
+/// Compile one brace-free glob. `*` and `?` stop at `/`; only `**` crosses
+/// directories. Braces left over from `expand_braces` are unmatched and
+/// taken literally.
+fn compile_glob(pattern: &str) -> Result<globset::Glob, String> {
+    let literal_braces = pattern.replace('{', "[{]").replace('}', "[}]");
+    globset::GlobBuilder::new(&literal_braces)
+        .literal_separator(true)
+        .build()
+        .map_err(|e| format!("Invalid glob pattern '{pattern}': {e}"))
+}
+
+/// Directory to start walking for `pattern` (its leading literal components)
+/// and how deep to go: unlimited if the rest contains `**`.
+fn glob_walk_root(pattern: &str) -> (String, Option<usize>) {
+    let parts: Vec<&str> = pattern.split('/').collect();
+    let is_literal = |p: &str| !p.contains(['*', '?', '[', '{']);
+    let literal = parts[..parts.len() - 1]
+        .iter()
+        .take_while(|p| is_literal(p))
+        .count();
+    let rest = &parts[literal..];
+    let root = match parts[..literal].join("/") {
+        r if r.is_empty() && pattern.starts_with('/') => "/".to_string(),
+        r if r.is_empty() => ".".to_string(),
+        r => r,
+    };
+    let depth = (!rest.contains(&"**")).then_some(rest.len());
+    (root, depth)
+}
+
+/// Expand every brace group in a glob pattern, including repeated and
+/// nested groups.
+/// `**/*.{rs,toml}` → `["**/*.rs", "**/*.toml"]`
+/// `{src,tests}/*.{rs,md}` → `["src/*.rs", "src/*.md", "tests/*.rs", "tests/*.md"]`
+/// `a.{b,c{d,e}}` → `["a.b", "a.cd", "a.ce"]`
+/// No braces or unmatched braces → those characters are left unchanged.
+fn expand_braces(pattern: &str) -> Vec<String> {
+    for (open, _) in pattern.match_indices('{') {
+        let mut depth = 0;
+        let mut splits = Vec::new();
+        let mut close = None;
+        for (i, c) in pattern[open..].char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        close = Some(open + i);
+                        break;
+                    }
+                }
+                ',' if depth == 1 => splits.push(open + i),
+                _ => {}
+            }
+        }
+        let Some(close) = close else {
+            continue;
+        };
+        splits.push(close);
+
+        let prefix = &pattern[..open];
+        let suffix = &pattern[close + 1..];
+        let mut start = open + 1;
+        let mut expanded = Vec::new();
+        for end in splits {
+            let alt = &pattern[start..end];
+            expanded.extend(expand_braces(&format!("{prefix}{alt}{suffix}")));
+            start = end + 1;
+        }
+        return expanded;
+    }
+    vec![pattern.to_string()]
 }
 
 /// Deny-list patterns for bash commands. Whitespace-normalized lowercase matching.
@@ -285,158 +1146,47 @@ fn is_denied_command(cmd: &str) -> bool {
 /// killed to prevent unbounded memory growth from runaway processes.
 const BASH_OUTPUT_LIMIT: usize = 1_048_576;
 
-fn bash_exec(input: &Value, stream_cb: &mut dyn FnMut(&str)) -> Result<String, String> {
-    let command = input["command"]
-        .as_str()
-        .ok_or("Missing required parameter: command")?;
-
-    if is_denied_command(command) {
-        return Err(format!("Command blocked by safety guard: {command}"));
-    }
-
-    let timeout = Duration::from_secs(120);
-    let deadline = Instant::now() + timeout;
-
-    let mut child = Command::new("bash")
-        .arg("-c")
-        .arg(command)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|e| format!("Failed to spawn bash: {e}"))?;
-
-    let stdout = child.stdout.take().unwrap();
-    let stderr = child.stderr.take().unwrap();
-
-    let (tx_out, rx) = mpsc::channel::<String>();
-    let tx_err = tx_out.clone();
-
-    // Stdout reader thread
-    std::thread::spawn(move || {
-        let reader = BufReader::with_capacity(4096, stdout);
-        let mut buf = Vec::new();
-        let mut r = reader;
-        loop {
-            buf.clear();
-            match r.read_until(b'\n', &mut buf) {
-                Ok(0) => break,
-                Ok(_) => {
-                    let s = String::from_utf8_lossy(&buf).to_string();
-                    if tx_out.send(s).is_err() {
-                        break;
-                    }
-                }
-                Err(_) => break,
-            }
-        }
-    });
-
-    // Stderr reader thread
-    std::thread::spawn(move || {
-        let reader = BufReader::with_capacity(4096, stderr);
-        let mut buf = Vec::new();
-        let mut r = reader;
-        loop {
-            buf.clear();
-            match r.read_until(b'\n', &mut buf) {
-                Ok(0) => break,
-                Ok(_) => {
-                    let s = String::from_utf8_lossy(&buf).to_string();
-                    if tx_err.send(s).is_err() {
-                        break;
-                    }
-                }
-                Err(_) => break,
-            }
-        }
-    });
-
-    // Drop our copy of tx so rx closes when threads finish
-    let mut output = String::new();
-    let mut timed_out = false;
-    let mut truncated = false;
-
-    loop {
-        if Instant::now() >= deadline {
-            let _ = child.kill();
-            timed_out = true;
-            break;
-        }
+/// How long a foreground Bash command may run.
+const BASH_TIMEOUT: Duration = Duration::from_secs(120);
 
-        match rx.recv_timeout(Duration::from_millis(50)) {
-            Ok(chunk) => {
-                stream_cb(&chunk);
-                if output.len() + chunk.len() > BASH_OUTPUT_LIMIT {
-                    let remaining = BASH_OUTPUT_LIMIT.saturating_sub(output.len());
-                    output.push_str(&chunk[..chunk.floor_char_boundary(remaining)]);
-                    let _ = child.kill();
-                    truncated = true;
-                    break;
-                }
-                output.push_str(&chunk);
-            }
-            Err(mpsc::RecvTimeoutError::Timeout) => {
-                // Check if process has exited
-                match child.try_wait() {
-                    Ok(Some(_)) => {
-                        // Process finished — drain remaining
-                        while let Ok(chunk) = rx.try_recv() {
-                            stream_cb(&chunk);
-                            if output.len() + chunk.len() <= BASH_OUTPUT_LIMIT {
-                                output.push_str(&chunk);
-                            } else if !truncated {
-                                let remaining = BASH_OUTPUT_LIMIT.saturating_sub(output.len());
-                                output.push_str(&chunk[..chunk.floor_char_boundary(remaining)]);
-                                truncated = true;
-                            }
-                        }
-                        break;
-                    }
-                    Ok(None) => continue,
-                    Err(_) => break,
-                }
-            }
-            Err(mpsc::RecvTimeoutError::Disconnected) => {
-                // Both threads done
-                let _ = child.wait();
-                break;
-            }
+fn bash_exec(
+    shell: &Shell,
+    jobs: &Jobs,
+    input: &Value,
+    stream_cb: StreamCallback<'_>,
+) -> ToolResult {
+    let reset = input["reset"].as_bool().unwrap_or(false);
+    if reset {
+        shell.reset();
+    }
+    let Some(command) = input["command"].as_str() else {
+        if reset {
+            return Ok(format!(
+                "Shell reset. Working directory: {}",
+                shell.cwd().display()
+            ));
         }
-    }
+        return Err("Missing required parameter: command".to_string());
+    };
 
-    if truncated {
-        let _ = child.wait();
-        return Err(format!(
-            "Command output exceeded 1MB limit (truncated):\n{output}"
-        ));
+    if is_denied_command(command) {
+        return Err(format!("Command blocked by safety guard: {command}"));
     }
 
-    if timed_out {
-        if output.is_empty() {
-            return Err(format!("Command timed out after 120s: {command}"));
-        }
-        return Err(format!(
-            "Command timed out after 120s (partial output):\n{output}"
+    if input["run_in_background"].as_bool().unwrap_or(false) {
+        let cwd = shell.cwd();
+        let id = jobs.spawn(command, &cwd)?;
+        return Ok(format!(
+            "Started background job {id} in {}. Read its output with BashOutput (bash_id \"{id}\"); stop it with KillShell.",
+            cwd.display()
         ));
     }
 
-    let status = child
-        .wait()
-        .map_err(|e| format!("Failed to wait for process: {e}"))?;
-
-    if status.success() {
-        Ok(output)
-    } else {
-        let code = status.code().unwrap_or(-1);
-        if output.is_empty() {
-            Err(format!("Command failed with exit code {code}"))
-        } else {
-            Err(format!("Command failed with exit code {code}:\n{output}"))
-        }
-    }
+    shell.run(command, BASH_TIMEOUT, stream_cb)
 }
 
-fn edit_exec(input: &Value) -> Result<String, String> {
+/// Apply an edit. Returns a one-line summary and the diff of the change.
+fn edit_exec(input: &Value, context: usize) -> Result<(String, EditDiff), String> {
     let file_path = input["file_path"]
         .as_str()
         .ok_or("Missing required parameter: file_path")?;
@@ -464,11 +1214,14 @@ fn edit_exec(input: &Value) -> Result<String, String> {
                 ));
             }
             // Append
-            let mut content =
+            let content =
                 std::fs::read_to_string(path).map_err(|e| format!("Cannot read file: {e}"))?;
-            content.push_str(new_str);
-            std::fs::write(path, &content).map_err(|e| format!("Cannot write file: {e}"))?;
-            return Ok(format!("Appended to {file_path}"));
+            let new_content = format!("{content}{new_str}");
+            std::fs::write(path, &new_content).map_err(|e| format!("Cannot write file: {e}"))?;
+            return Ok((
+                format!("Appended to {file_path}"),
+                EditDiff::new(file_path, &content, &new_content, context),
+            ));
         } else {
             if new_str.len() as u64 > EDIT_SIZE_LIMIT {
                 return Err(format!(
@@ -482,7 +1235,10 @@ fn edit_exec(input: &Value) -> Result<String, String> {
                     .map_err(|e| format!("Cannot create directories: {e}"))?;
             }
             std::fs::write(path, new_str).map_err(|e| format!("Cannot create file: {e}"))?;
-            return Ok(format!("Created {file_path}"));
+            return Ok((
+                format!("Created {file_path}"),
+                EditDiff::new(file_path, "", new_str, context),
+            ));
         }
     }
 
@@ -504,7 +1260,10 @@ fn edit_exec(input: &Value) -> Result<String, String> {
         let new_content = content.replace(old_str, new_str);
         let count = content.matches(old_str).count();
         std::fs::write(path, &new_content).map_err(|e| format!("Cannot write file: {e}"))?;
-        return Ok(format!("Replaced {count} occurrences in {file_path}"));
+        return Ok((
+            format!("Replaced {count} occurrences in {file_path}"),
+            EditDiff::new(file_path, &content, &new_content, context),
+        ));
     }
 
     // Single exact match
@@ -521,101 +1280,379 @@ fn edit_exec(input: &Value) -> Result<String, String> {
     let new_content = content.replacen(old_str, new_str, 1);
     std::fs::write(path, &new_content).map_err(|e| format!("Cannot write file: {e}"))?;
 
-    Ok(format!("Edited {file_path}"))
+    Ok((
+        format!("Edited {file_path}"),
+        EditDiff::new(file_path, &content, &new_content, context),
+    ))
 }
 
-fn grep_exec(input: &Value) -> Result<String, String> {
+/// Result entries returned by Grep when no `head_limit` is given.
+const GREP_DEFAULT_HEAD_LIMIT: usize = 200;
+
+#[derive(Clone, Copy, PartialEq)]
+enum GrepMode {
+    Content,
+    FilesWithMatches,
+    Count,
+}
+
+/// Window of result entries selected by `offset`/`head_limit`. Entries
+/// outside it are counted but never formatted. `--` separators are not
+/// entries: they only appear between two entries on the same page.
+struct GrepPage {
+    offset: usize,
+    limit: usize,
+    total: usize,
+    shown: usize,
+    lines: Vec<String>,
+    break_pending: bool,
+}
+
+impl GrepPage {
+    fn push(&mut self, entry: impl FnOnce() -> String) {
+        let separate = std::mem::take(&mut self.break_pending);
+        if self.total >= self.offset && self.shown < self.limit {
+            if separate && self.shown > 0 {
+                self.lines.push("--".to_string());
+            }
+            self.lines.push(entry());
+            self.shown += 1;
+        }
+        self.total += 1;
+    }
+
+    /// Put a `--` before the next entry, if it lands on this page after another.
+    fn context_break(&mut self) {
+        self.break_pending = true;
+    }
+}
+
+/// Collects one file's matches into the page.
+struct GrepSink<'a> {
+    path: &'a str,
+    mode: GrepMode,
+    page: &'a mut GrepPage,
+    matches: usize,
+}
+
+impl GrepSink<'_> {
+    fn push_lines(&mut self, first_line: u64, sep: char, bytes: &[u8]) {
+        let text = String::from_utf8_lossy(bytes);
+        for (i, line) in text.lines().enumerate() {
+            let n = first_line + i as u64;
+            let path = self.path;
+            self.page
+                .push(|| format!("{path}{sep}{n}{sep}{}", truncate_line(line)));
+        }
+    }
+}
+
+impl Sink for GrepSink<'_> {
+    type Error = std::io::Error;
+
+    fn matched(&mut self, _: &Searcher, m: &SinkMatch<'_>) -> Result<bool, Self::Error> {
+        self.matches += 1;
+        match self.mode {
+            GrepMode::Content => {
+                self.push_lines(m.line_number().unwrap_or(0), ':', m.bytes());
+                Ok(true)
+            }
+            // One match is enough to list the file
+            GrepMode::FilesWithMatches => Ok(false),
+            GrepMode::Count => Ok(true),
+        }
+    }
+
+    fn context(&mut self, _: &Searcher, c: &SinkContext<'_>) -> Result<bool, Self::Error> {
+        self.push_lines(c.line_number().unwrap_or(0), '-', c.bytes());
+        Ok(true)
+    }
+
+    fn context_break(&mut self, _: &Searcher) -> Result<bool, Self::Error> {
+        self.page.context_break();
+        Ok(true)
+    }
+}
+
+fn grep_exec(input: &Value) -> ToolResult {
     let pattern = input["pattern"]
         .as_str()
         .ok_or("Missing required parameter: pattern")?;
     let path = input["path"].as_str().unwrap_or(".");
     let file_type = input["file_type"].as_str();
     let case_sensitive = input["case_sensitive"].as_bool().unwrap_or(true);
+    let multiline = input["multiline"].as_bool().unwrap_or(false);
+    let mode = match input["output_mode"].as_str().unwrap_or("content") {
+        "content" => GrepMode::Content,
+        "files_with_matches" => GrepMode::FilesWithMatches,
+        "count" => GrepMode::Count,
+        other => {
+            return Err(format!(
+                "Unknown output_mode: {other} (expected content, files_with_matches or count)"
+            ))
+        }
+    };
+    let context = uint_param(input, "-C", 0)?.unwrap_or(0);
+    let after = uint_param(input, "-A", 0)?.unwrap_or(context);
+    let before = uint_param(input, "-B", 0)?.unwrap_or(context);
+    let mut page = GrepPage {
+        offset: uint_param(input, "offset", 0)?.unwrap_or(0),
+        limit: uint_param(input, "head_limit", 1)?.unwrap_or(GREP_DEFAULT_HEAD_LIMIT),
+        total: 0,
+        shown: 0,
+        lines: Vec::new(),
+        break_pending: false,
+    };
 
-    // Check rg is installed (cached — runs `which` once per process)
-    static RG_AVAILABLE: OnceLock<bool> = OnceLock::new();
-    let rg_ok = *RG_AVAILABLE.get_or_init(|| {
-        Command::new("which")
-            .arg("rg")
-            .output()
-            .map(|o| o.status.success())
-            .unwrap_or(false)
-    });
-    if !rg_ok {
-        return Err(
-            "ripgrep (rg) is not installed. Install it with: brew install ripgrep (macOS) or apt install ripgrep (Linux)"
-                .to_string(),
-        );
-    }
-
-    let mut cmd = Command::new("rg");
-    cmd.arg("--max-count=50")
-        .arg("--line-number")
-        .arg("--no-heading")
-        .arg("--color=never");
-
-    if !case_sensitive {
-        cmd.arg("-i");
+    if !Path::new(path).exists() {
+        return Err(format!("Path not found: {path}"));
+    }
+
+    let matcher = RegexMatcherBuilder::new()
+        .case_insensitive(!case_sensitive)
+        .multi_line(multiline)
+        .dot_matches_new_line(multiline)
+        .build(pattern)
+        .map_err(|e| format!("Invalid regex: {e}"))?;
+
+    let mut searcher = SearcherBuilder::new();
+    searcher
+        .line_number(true)
+        .multi_line(multiline)
+        .binary_detection(BinaryDetection::quit(0));
+    if mode == GrepMode::Content {
+        searcher.before_context(before).after_context(after);
+    }
+    let mut searcher = searcher.build();
+
+    let mut walker = WalkBuilder::new(path);
+    // Honor .gitignore even outside a git checkout, and walk in a fixed
+    // order so paging is stable between calls.
+    walker.require_git(false).sort_by_file_name(|a, b| a.cmp(b));
+
+    let mut overrides = OverrideBuilder::new(path);
+    for (key, negate) in [("glob", false), ("exclude", true)] {
+        if let Some(glob) = input[key].as_str() {
+            let glob = if negate {
+                format!("!{glob}")
+            } else {
+                glob.to_string()
+            };
+            overrides
+                .add(&glob)
+                .map_err(|e| format!("Invalid {key} pattern: {e}"))?;
+        }
     }
+    walker.overrides(
+        overrides
+            .build()
+            .map_err(|e| format!("Invalid glob pattern: {e}"))?,
+    );
 
     if let Some(ft) = file_type {
-        cmd.arg("--type").arg(ft);
+        let mut types = TypesBuilder::new();
+        types.add_defaults();
+        types.select(ft);
+        walker.types(
+            types
+                .build()
+                .map_err(|e| format!("Invalid file_type: {e}"))?,
+        );
     }
 
-    cmd.arg(pattern).arg(path);
-
-    let output = cmd.output().map_err(|e| format!("Failed to run rg: {e}"))?;
-
-    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-
-    if !output.status.success() {
-        let code = output.status.code().unwrap_or(-1);
-        if code == 1 {
-            // rg exit 1 = no matches
-            return Ok("No matches found".to_string());
+    let with_context = mode == GrepMode::Content && (before > 0 || after > 0);
+    for entry in walker.build() {
+        let Ok(entry) = entry else { continue };
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        let shown = entry.path().to_string_lossy();
+        let shown = shown.strip_prefix("./").unwrap_or(&shown);
+        // Context groups from different files are separated like groups within one
+        if with_context {
+            page.context_break();
+        }
+        let mut sink = GrepSink {
+            path: shown,
+            mode,
+            page: &mut page,
+            matches: 0,
+        };
+        // Unreadable files are skipped, as rg does after reporting them
+        if searcher
+            .search_path(&matcher, entry.path(), &mut sink)
+            .is_err()
+        {
+            continue;
         }
-        if !stderr.is_empty() {
-            return Err(stderr.trim().to_string());
+        let matches = sink.matches;
+        if matches == 0 {
+            continue;
+        }
+        match mode {
+            GrepMode::Content => {}
+            GrepMode::FilesWithMatches => page.push(|| shown.to_string()),
+            GrepMode::Count => page.push(|| format!("{shown}:{matches}")),
         }
-        return Err(format!("rg exited with code {code}"));
     }
 
-    let result = stdout.trim().to_string();
-    if result.is_empty() {
-        Ok("No matches found".to_string())
-    } else {
-        Ok(result)
+    if page.total == 0 {
+        return Ok("No matches found".to_string());
     }
+    if page.shown == 0 {
+        return Ok(format!(
+            "No results at offset {} ({} total)",
+            page.offset, page.total
+        ));
+    }
+    let mut out = page.lines.join("\n");
+    let shown_end = page.offset + page.shown;
+    if shown_end < page.total || page.offset > 0 {
+        out.push_str(&format!(
+            "\n[Showing results {}-{shown_end} of {}.",
+            page.offset + 1,
+            page.total
+        ));
+        if shown_end < page.total {
+            out.push_str(&format!(" Use offset={shown_end} to see more."));
+        }
+        out.push(']');
+    }
+    Ok(out)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Run a built-in tool to completion from a synchronous test.
+    fn dispatch_tool(name: &str, input: &Value, stream_cb: StreamCallback<'_>) -> ToolResult {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(ToolRegistry::builtin().dispatch(name, input, stream_cb))
+    }
+
+    /// Run `tool` on `input` with `extra`'s keys merged in, and strip the
+    /// `dir/` prefix from the output so it can be compared literally.
+    fn dispatch_in(tool: &str, dir: &Path, mut input: Value, extra: Value) -> ToolResult {
+        if let Value::Object(extra) = extra {
+            input.as_object_mut().unwrap().extend(extra);
+        }
+        let prefix = format!("{}/", dir.display());
+        dispatch_tool(tool, &input, &mut |_| {})
+            .map(|out| out.replace(&prefix, ""))
+            .map_err(|err| err.replace(&prefix, ""))
+    }
+
+    struct EchoTool;
+
+    impl Tool for EchoTool {
+        fn name(&self) -> &str {
+            "Echo"
+        }
+
+        fn description(&self) -> &str {
+            "Echo the input text."
+        }
+
+        fn input_schema(&self) -> Value {
+            json!({"type": "object", "properties": {"text": {"type": "string"}}})
+        }
+
+        fn prompt_summary(&self) -> &str {
+            "Echo text back"
+        }
+
+        fn effect(&self) -> ToolEffect {
+            ToolEffect::Pure
+        }
+
+        fn execute<'a>(
+            &'a self,
+            input: &'a Value,
+            _: StreamCallback<'a>,
+        ) -> BoxFuture<'a, ToolResult> {
+            Box::pin(async move { Ok(input["text"].as_str().unwrap_or("").to_string()) })
+        }
+    }
+
     #[test]
-    fn schemas_returns_five_pascal_case() {
-        let schemas = all_tool_schemas();
-        assert_eq!(schemas.len(), 5);
+    fn schemas_returns_seven_pascal_case() {
+        let registry = ToolRegistry::builtin();
+        let schemas = registry.schemas();
+        assert_eq!(schemas.len(), 7);
 
         let names: Vec<&str> = schemas
             .iter()
             .map(|s| s["name"].as_str().unwrap())
             .collect();
-        assert_eq!(names, vec!["Read", "Glob", "Bash", "Edit", "Grep"]);
+        assert_eq!(
+            names,
+            vec![
+                "Read",
+                "Glob",
+                "Bash",
+                "Edit",
+                "Grep",
+                "BashOutput",
+                "KillShell"
+            ]
+        );
+        assert_eq!(registry.names(), names);
+        for schema in schemas {
+            assert!(schema["description"].is_string());
+            assert_eq!(schema["input_schema"]["type"], "object");
+        }
     }
 
     #[test]
-    fn dispatch_known_tool_read() {
+    fn prompt_list_follows_registry() {
+        let mut registry = ToolRegistry::builtin();
+        let list = registry.prompt_list();
+        assert!(list
+            .starts_with("- Read: Read file contents with line numbers (paged by offset/limit)\n"));
+        assert_eq!(list.lines().count(), 7);
+
+        assert!(registry.remove("Bash"));
+        assert!(!registry.remove("Bash"));
+        assert!(!registry.prompt_list().contains("- Bash:"));
+        assert_eq!(registry.schemas().len(), 6);
+    }
+
+    #[tokio::test]
+    async fn registered_tool_is_dispatched() {
+        let mut registry = ToolRegistry::builtin();
+        registry.register(Arc::new(EchoTool));
+        assert_eq!(registry.schemas().len(), 8);
+        assert_eq!(registry.schemas()[7]["name"], "Echo");
+        assert!(registry.prompt_list().ends_with("- Echo: Echo text back"));
+        assert_eq!(registry.effect("Echo"), ToolEffect::Pure);
+        let out = registry
+            .dispatch("Echo", &json!({"text": "hi"}), &mut |_| {})
+            .await;
+        assert_eq!(out.unwrap(), "hi");
+
+        // Re-registering a name replaces the tool in place.
+        registry.register(Arc::new(EchoTool));
+        assert_eq!(registry.schemas().len(), 8);
+    }
+
+    #[tokio::test]
+    async fn dispatch_known_tool_read() {
         // Read a file that definitely exists
-        let result = dispatch_tool("Read", &json!({"file_path": "Cargo.toml"}), &mut |_| {});
+        let result = ToolRegistry::builtin()
+            .dispatch("Read", &json!({"file_path": "Cargo.toml"}), &mut |_| {})
+            .await;
         assert!(result.is_ok());
         assert!(result.unwrap().contains("[package]"));
     }
 
-    #[test]
-    fn dispatch_unknown_tool() {
-        let result = dispatch_tool("Unknown", &json!({}), &mut |_| {});
+    #[tokio::test]
+    async fn dispatch_unknown_tool() {
+        let result = ToolRegistry::builtin()
+            .dispatch("Unknown", &json!({}), &mut |_| {})
+            .await;
         assert!(result.is_err());
         assert!(result.unwrap_err().contains("Unknown tool"));
     }
@@ -692,6 +1729,54 @@ mod tests {
         let _ = std::fs::remove_file(&file);
     }
 
+    #[tokio::test]
+    async fn edit_reports_diff_with_its_result() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        std::fs::write(&file, "1\n2\n3\n4\n5\n6\n").unwrap();
+        let registry = ToolRegistry::builtin().with_diff_context(1);
+
+        let outcome = registry
+            .dispatch_with_details(
+                "Edit",
+                &json!({
+                    "file_path": file.to_str().unwrap(),
+                    "old_str": "4\n",
+                    "new_str": "four\nfour and a half\n",
+                }),
+                &mut |_| {},
+            )
+            .await;
+        let path = file.display();
+        assert_eq!(
+            outcome.result.unwrap(),
+            format!("Edited {path} (+2 -1)\n@@ -3,3 +3,4 @@\n 3\n-4\n+four\n+four and a half\n 5")
+        );
+        let diff = outcome.diff.unwrap();
+        assert_eq!(diff.path, path.to_string());
+        assert_eq!((diff.additions, diff.deletions), (2, 1));
+
+        // A failed edit has no diff
+        let outcome = registry
+            .dispatch_with_details(
+                "Edit",
+                &json!({"file_path": file.to_str().unwrap(), "old_str": "x", "new_str": "y"}),
+                &mut |_| {},
+            )
+            .await;
+        assert!(outcome.result.is_err());
+        assert!(outcome.diff.is_none());
+        // Other tools never report one
+        let outcome = registry
+            .dispatch_with_details(
+                "Read",
+                &json!({"file_path": file.to_str().unwrap()}),
+                &mut |_| {},
+            )
+            .await;
+        assert!(outcome.result.is_ok() && outcome.diff.is_none());
+    }
+
     #[test]
     fn edit_create_file() {
         let dir = std::env::temp_dir().join("forgeflare_test_edit");
@@ -938,6 +2023,129 @@ mod tests {
         assert_eq!(result, vec!["**/*.rs", "**/*.toml", "**/*.md"]);
     }
 
+    #[test]
+    fn expand_braces_multiple_groups() {
+        let result = expand_braces("{src,tests}/*.{rs,md}");
+        assert_eq!(
+            result,
+            vec!["src/*.rs", "src/*.md", "tests/*.rs", "tests/*.md"]
+        );
+    }
+
+    #[test]
+    fn expand_braces_nested_groups() {
+        assert_eq!(expand_braces("a.{b,c{d,e}}"), vec!["a.b", "a.cd", "a.ce"]);
+        // Unmatched open brace before a valid group stays literal
+        assert_eq!(expand_braces("x{y{a,b}"), vec!["x{ya", "x{yb"]);
+    }
+
+    fn glob_fixture() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for d in [
+            "src/nested",
+            "target/debug",
+            "node_modules/pkg",
+            ".git",
+            ".github",
+        ] {
+            std::fs::create_dir_all(root.join(d)).unwrap();
+        }
+        std::fs::write(root.join(".gitignore"), "target/\nnode_modules/\n").unwrap();
+        for f in [
+            "src/main.rs",
+            "src/nested/deep.rs",
+            "src/notes.md",
+            "target/debug/build.rs",
+            "node_modules/pkg/index.rs",
+            ".git/config.rs",
+            ".github/ci.yml",
+            "Cargo.toml",
+        ] {
+            std::fs::write(root.join(f), "").unwrap();
+        }
+        dir
+    }
+
+    fn glob_in(dir: &tempfile::TempDir, input: Value) -> String {
+        let path = json!({"path": dir.path().to_str().unwrap()});
+        dispatch_in("Glob", dir.path(), path, input).unwrap()
+    }
+
+    #[test]
+    fn glob_skips_ignored_and_git_dirs() {
+        let dir = glob_fixture();
+        assert_eq!(
+            glob_in(&dir, json!({"pattern": "**/*.rs"})),
+            "src/main.rs\nsrc/nested/deep.rs"
+        );
+        // Hidden directories other than .git are still searched
+        assert_eq!(
+            glob_in(&dir, json!({"pattern": "**/*.yml"})),
+            ".github/ci.yml"
+        );
+    }
+
+    #[test]
+    fn glob_single_star_stays_in_directory() {
+        let dir = glob_fixture();
+        assert_eq!(glob_in(&dir, json!({"pattern": "src/*.rs"})), "src/main.rs");
+        assert_eq!(
+            glob_in(&dir, json!({"pattern": "{src,src/nested}/*.{rs,md}"})),
+            "src/main.rs\nsrc/nested/deep.rs\nsrc/notes.md"
+        );
+    }
+
+    #[test]
+    fn glob_exclude_patterns() {
+        let dir = glob_fixture();
+        assert_eq!(
+            glob_in(&dir, json!({"pattern": "**/*.rs", "exclude": ["nested"]})),
+            "src/main.rs"
+        );
+        assert_eq!(
+            glob_in(
+                &dir,
+                json!({"pattern": "**/*", "exclude": ["src/**", ".github"]})
+            ),
+            ".gitignore\nCargo.toml\nsrc"
+        );
+    }
+
+    #[test]
+    fn glob_sort_by_mtime_newest_first() {
+        let dir = glob_fixture();
+        let old = std::time::SystemTime::now() - Duration::from_secs(3600);
+        for (f, age) in [("src/main.rs", 2), ("src/notes.md", 0), ("Cargo.toml", 1)] {
+            std::fs::File::options()
+                .write(true)
+                .open(dir.path().join(f))
+                .unwrap()
+                .set_modified(old + Duration::from_secs(600 * (2 - age)))
+                .unwrap();
+        }
+        assert_eq!(
+            glob_in(
+                &dir,
+                json!({"pattern": "{*.toml,src/*.{rs,md}}", "sort": "mtime"})
+            ),
+            "src/notes.md\nCargo.toml\nsrc/main.rs"
+        );
+    }
+
+    #[test]
+    fn glob_reports_truncation() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..GLOB_LIMIT + 5 {
+            std::fs::write(dir.path().join(format!("f{i:04}.txt")), "").unwrap();
+        }
+        let out = glob_in(&dir, json!({"pattern": "*.txt"}));
+        assert_eq!(out.lines().count(), GLOB_LIMIT + 1);
+        assert!(out.ends_with(
+            "[Showing 1000 of 1005 entries. Narrow the pattern or path to see the rest.]"
+        ));
+    }
+
     #[test]
     fn glob_brace_expansion_invalid_pattern_fails_entirely() {
         // glob-shell-injection R5: if brace expansion produces an invalid pattern,
@@ -956,6 +2164,10 @@ mod tests {
 
     // --- ToolEffect classification tests ---
 
+    fn tool_effect(name: &str) -> ToolEffect {
+        ToolRegistry::builtin().effect(name)
+    }
+
     #[test]
     fn tool_effect_pure_tools() {
         assert_eq!(tool_effect("Read"), ToolEffect::Pure);
@@ -978,16 +2190,16 @@ mod tests {
 
     #[test]
     fn tool_effect_exhaustive_for_all_tools() {
-        let schemas = all_tool_schemas();
-        for schema in &schemas {
+        let registry = ToolRegistry::builtin();
+        for schema in registry.schemas() {
             let name = schema["name"].as_str().unwrap();
             let effect = tool_effect(name);
             // Every known tool must have an explicit classification (not fall through to unknown)
             match name {
-                "Read" | "Glob" | "Grep" => {
+                "Read" | "Glob" | "Grep" | "BashOutput" => {
                     assert_eq!(effect, ToolEffect::Pure, "{name} should be Pure")
                 }
-                "Bash" | "Edit" => {
+                "Bash" | "Edit" | "KillShell" => {
                     assert_eq!(effect, ToolEffect::Mutating, "{name} should be Mutating")
                 }
                 _ => panic!("New tool {name} needs explicit ToolEffect classification"),
@@ -995,6 +2207,159 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn background_job_round_trip() {
+        let registry = ToolRegistry::builtin();
+        let started = registry
+            .dispatch(
+                "Bash",
+                &json!({"command": "echo ready; sleep 30", "run_in_background": true}),
+                &mut |_| {},
+            )
+            .await
+            .unwrap();
+        assert!(
+            started.starts_with("Started background job bash_1"),
+            "{started}"
+        );
+
+        let jobs = registry.background_jobs();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].command, "echo ready; sleep 30");
+
+        let mut out = String::new();
+        for _ in 0..500 {
+            out = registry
+                .dispatch("BashOutput", &json!({"bash_id": "bash_1"}), &mut |_| {})
+                .await
+                .unwrap();
+            if out.ends_with("ready\n") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(out.starts_with("[bash_1 running for "), "{out}");
+        assert!(out.ends_with("ready\n"), "{out}");
+
+        let err = registry
+            .dispatch(
+                "BashOutput",
+                &json!({"bash_id": "bash_1", "filter": "("}),
+                &mut |_| {},
+            )
+            .await
+            .unwrap_err();
+        assert!(err.contains("Invalid filter regex"));
+
+        let killed = registry
+            .dispatch("KillShell", &json!({"bash_id": "bash_1"}), &mut |_| {})
+            .await
+            .unwrap();
+        assert!(killed.starts_with("Killed bash_1"));
+        assert!(registry.background_jobs().is_empty());
+        assert_eq!(registry.kill_background_jobs(), 0);
+    }
+
+    #[test]
+    fn cancelled_bash_call_kills_the_command() {
+        let registry = ToolRegistry::builtin();
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let command = format!("sleep 30 & echo $! > {}; wait", pid_file.display());
+            let input = json!({ "command": command });
+            let mut ignore = |_: &str| {};
+            let call = registry.dispatch("Bash", &input, &mut ignore);
+            // Dropping the call is what Ctrl-C's select! does to the turn
+            let interrupted = tokio::time::timeout(Duration::from_secs(1), call).await;
+            assert!(interrupted.is_err(), "sleep finished: {interrupted:?}");
+
+            let start = std::time::Instant::now();
+            let out = registry
+                .dispatch("Bash", &json!({"command": "echo next"}), &mut |_| {})
+                .await
+                .unwrap();
+            assert_eq!(out, "next\n");
+            assert!(start.elapsed() < Duration::from_secs(10));
+        });
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim()));
+        // Gone, or a zombie waiting for init to reap it
+        assert!(
+            stat.is_err() || stat.unwrap().contains(") Z "),
+            "sleep survived"
+        );
+    }
+
+    #[test]
+    fn file_tools_resolve_relative_paths_in_shell_cwd() {
+        let registry = ToolRegistry::builtin();
+        let dir = tempfile::tempdir().unwrap();
+        let dir_path = dir.path().canonicalize().unwrap();
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let run =
+            |name: &str, input: Value| rt.block_on(registry.dispatch(name, &input, &mut |_| {}));
+        run(
+            "Bash",
+            json!({"command": format!("cd {}", dir_path.display())}),
+        )
+        .unwrap();
+
+        run(
+            "Edit",
+            json!({"file_path": "main.rs", "old_str": "", "new_str": "fn main() {}\n"}),
+        )
+        .unwrap();
+        let created = dir_path.join("main.rs");
+        assert!(created.exists());
+        assert!(run("Read", json!({"file_path": "main.rs"}))
+            .unwrap()
+            .contains("fn main() {}"));
+        let listed = run("Glob", json!({"pattern": "*.rs"})).unwrap();
+        assert_eq!(listed.trim(), created.display().to_string());
+        let found = run("Grep", json!({"pattern": "fn main", "path": "."})).unwrap();
+        assert!(found.starts_with(&created.display().to_string()), "{found}");
+    }
+
+    #[test]
+    fn background_job_runs_in_shell_cwd() {
+        let registry = ToolRegistry::builtin();
+        let dir = tempfile::tempdir().unwrap();
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let cd = format!("cd {}", dir.path().display());
+            registry
+                .dispatch("Bash", &json!({"command": cd}), &mut |_| {})
+                .await
+                .unwrap();
+            registry
+                .dispatch(
+                    "Bash",
+                    &json!({"command": "pwd > where.txt", "run_in_background": true}),
+                    &mut |_| {},
+                )
+                .await
+                .unwrap();
+        });
+        let file = dir.path().join("where.txt");
+        for _ in 0..500 {
+            if std::fs::read_to_string(&file).is_ok_and(|s| !s.is_empty()) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(std::fs::read_to_string(&file).is_ok());
+    }
+
     // --- bash_exec output cap tests ---
 
     #[test]
@@ -1076,24 +2441,172 @@ mod tests {
         assert!(!file.exists());
     }
 
-    // --- grep rg cache test ---
+    // --- native grep ---
 
     #[test]
-    fn grep_rg_cache_is_consistent() {
-        // Call grep twice — both should succeed (rg is installed on this system)
-        // and the OnceLock should be initialized on first call, reused on second
-        let r1 = dispatch_tool(
+    fn grep_single_file_path() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/main.rs");
+        let out = dispatch_tool(
             "Grep",
-            &json!({"pattern": "fn main", "path": "src/main.rs"}),
+            &json!({"pattern": "fn main", "path": path}),
             &mut |_| {},
+        )
+        .unwrap();
+        assert!(out.starts_with(&format!("{path}:")), "{out}");
+        assert!(out.contains(":fn main()") || out.contains(":async fn main()"));
+    }
+
+    fn grep_fixture() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir_all(root.join("target")).unwrap();
+        std::fs::write(root.join(".gitignore"), "target/\n").unwrap();
+        std::fs::write(
+            root.join("src/a.rs"),
+            "one\nneedle 1\nthree\nfour\nfive\nsix\nneedle 2\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("src/b.txt"), "needle in text\n").unwrap();
+        std::fs::write(root.join("target/out.rs"), "needle built\n").unwrap();
+        dir
+    }
+
+    fn grep_in(dir: &tempfile::TempDir, extra: Value) -> String {
+        let input = json!({"pattern": "needle", "path": dir.path().to_str().unwrap()});
+        dispatch_in("Grep", dir.path(), input, extra).unwrap()
+    }
+
+    #[test]
+    fn grep_output_modes_respect_gitignore() {
+        let dir = grep_fixture();
+        assert_eq!(
+            grep_in(&dir, json!({})),
+            "src/a.rs:2:needle 1\nsrc/a.rs:7:needle 2\nsrc/b.txt:1:needle in text"
+        );
+        assert_eq!(
+            grep_in(&dir, json!({"output_mode": "files_with_matches"})),
+            "src/a.rs\nsrc/b.txt"
+        );
+        assert_eq!(
+            grep_in(&dir, json!({"output_mode": "count"})),
+            "src/a.rs:2\nsrc/b.txt:1"
+        );
+    }
+
+    #[test]
+    fn grep_context_lines_and_separators() {
+        let dir = grep_fixture();
+        assert_eq!(
+            grep_in(&dir, json!({"-C": 1})),
+            "src/a.rs-1-one\nsrc/a.rs:2:needle 1\nsrc/a.rs-3-three\n--\n\
+             src/a.rs-6-six\nsrc/a.rs:7:needle 2\n--\nsrc/b.txt:1:needle in text"
+        );
+        // -A overrides the after half of -C
+        assert_eq!(
+            grep_in(&dir, json!({"-B": 1, "-A": 0, "glob": "*.rs"})),
+            "src/a.rs-1-one\nsrc/a.rs:2:needle 1\n--\nsrc/a.rs-6-six\nsrc/a.rs:7:needle 2"
+        );
+    }
+
+    #[test]
+    fn grep_glob_exclude_and_file_type() {
+        let dir = grep_fixture();
+        assert_eq!(
+            grep_in(
+                &dir,
+                json!({"glob": "*.txt", "output_mode": "files_with_matches"})
+            ),
+            "src/b.txt"
+        );
+        assert_eq!(
+            grep_in(
+                &dir,
+                json!({"exclude": "*.txt", "output_mode": "files_with_matches"})
+            ),
+            "src/a.rs"
+        );
+        assert_eq!(
+            grep_in(
+                &dir,
+                json!({"file_type": "rust", "output_mode": "files_with_matches"})
+            ),
+            "src/a.rs"
+        );
+    }
+
+    #[test]
+    fn grep_offset_and_head_limit_page() {
+        let dir = grep_fixture();
+        let out = grep_in(&dir, json!({"head_limit": 2}));
+        assert_eq!(
+            out,
+            "src/a.rs:2:needle 1\nsrc/a.rs:7:needle 2\n\
+             [Showing results 1-2 of 3. Use offset=2 to see more.]"
+        );
+        let out = grep_in(&dir, json!({"head_limit": 2, "offset": 2}));
+        assert_eq!(
+            out,
+            "src/b.txt:1:needle in text\n[Showing results 3-3 of 3.]"
+        );
+        let out = grep_in(&dir, json!({"offset": 5}));
+        assert_eq!(out, "No results at offset 5 (3 total)");
+    }
+
+    #[test]
+    fn grep_separators_are_not_paged_results() {
+        let dir = grep_fixture();
+        // Six lines with -C 1; the two `--` don't count
+        let out = grep_in(&dir, json!({"-C": 1, "head_limit": 3}));
+        assert_eq!(
+            out,
+            "src/a.rs-1-one\nsrc/a.rs:2:needle 1\nsrc/a.rs-3-three\n\
+             [Showing results 1-3 of 6. Use offset=3 to see more.]"
+        );
+        let out = grep_in(&dir, json!({"-C": 1, "head_limit": 3, "offset": 3}));
+        assert_eq!(
+            out,
+            "src/a.rs-6-six\nsrc/a.rs:7:needle 2\n--\nsrc/b.txt:1:needle in text\n\
+             [Showing results 4-6 of 6.]"
+        );
+    }
+
+    #[test]
+    fn grep_multiline_and_case() {
+        let dir = grep_fixture();
+        let out = grep_in(&dir, json!({"pattern": "one.needle", "multiline": true}));
+        assert_eq!(out, "src/a.rs:1:one\nsrc/a.rs:2:needle 1");
+        assert_eq!(
+            grep_in(&dir, json!({"pattern": "one.needle"})),
+            "No matches found"
         );
-        let r2 = dispatch_tool(
+        assert_eq!(
+            grep_in(
+                &dir,
+                json!({"pattern": "NEEDLE IN", "case_sensitive": false})
+            ),
+            "src/b.txt:1:needle in text"
+        );
+    }
+
+    #[test]
+    fn grep_rejects_bad_input() {
+        let err = dispatch_tool("Grep", &json!({"pattern": "("}), &mut |_| {}).unwrap_err();
+        assert!(err.contains("Invalid regex"), "{err}");
+        let err = dispatch_tool(
             "Grep",
-            &json!({"pattern": "fn main", "path": "src/main.rs"}),
+            &json!({"pattern": "x", "output_mode": "lines"}),
             &mut |_| {},
-        );
-        assert!(r1.is_ok(), "first grep should succeed");
-        assert!(r2.is_ok(), "second grep should succeed");
+        )
+        .unwrap_err();
+        assert!(err.contains("Unknown output_mode"));
+        let err = dispatch_tool(
+            "Grep",
+            &json!({"pattern": "x", "path": "/nonexistent/forgeflare"}),
+            &mut |_| {},
+        )
+        .unwrap_err();
+        assert!(err.contains("Path not found"));
     }
 
     #[test]
@@ -1140,37 +2653,94 @@ mod tests {
         );
     }
 
+    fn read_with(path: &Path, extra: Value) -> ToolResult {
+        let input = json!({"file_path": path.to_str().unwrap()});
+        dispatch_in("Read", path.parent().unwrap(), input, extra)
+    }
+
     #[test]
-    fn read_exactly_1mb_succeeds() {
-        // The guard is `> 1_048_576` (strict greater-than), so exactly 1MB
-        // should be allowed. 1MB + 1 should be rejected.
-        let dir = std::env::temp_dir().join("forgeflare_test_read_boundary");
-        let _ = std::fs::create_dir_all(&dir);
+    fn read_numbers_lines_with_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        std::fs::write(&file, "alpha\r\nbeta\ngamma").unwrap();
 
-        let file_ok = dir.join("exactly_1mb.txt");
-        let data_ok = "a".repeat(1_048_576);
-        std::fs::write(&file_ok, &data_ok).unwrap();
+        let out = read_with(&file, json!({})).unwrap();
+        let mut lines = out.lines();
+        assert!(lines.next().unwrap().ends_with(": 3 lines]"));
+        assert_eq!(lines.next(), Some("     1\talpha"));
+        assert_eq!(lines.next(), Some("     2\tbeta"));
+        assert_eq!(lines.next(), Some("     3\tgamma"));
+        assert_eq!(lines.next(), None);
+    }
 
-        let result = dispatch_tool(
-            "Read",
-            &json!({"file_path": file_ok.to_str().unwrap()}),
-            &mut |_| {},
+    #[test]
+    fn read_offset_and_limit_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("many.txt");
+        let data: String = (1..=10).map(|i| format!("line{i}\n")).collect();
+        std::fs::write(&file, data).unwrap();
+
+        let out = read_with(&file, json!({"offset": 4, "limit": 3})).unwrap();
+        let mut lines = out.lines();
+        let header = lines.next().unwrap();
+        assert!(header.contains("lines 4-6 of 10"), "{header}");
+        assert!(header.contains("offset=7"), "{header}");
+        assert_eq!(
+            lines.collect::<Vec<_>>(),
+            vec!["     4\tline4", "     5\tline5", "     6\tline6"]
         );
-        assert!(result.is_ok(), "exactly 1MB should be allowed");
-        assert_eq!(result.unwrap().len(), 1_048_576);
 
-        let file_over = dir.join("over_1mb.txt");
-        let data_over = "a".repeat(1_048_577);
-        std::fs::write(&file_over, &data_over).unwrap();
+        // Last page has no "read more" hint
+        let out = read_with(&file, json!({"offset": 9})).unwrap();
+        assert!(out.starts_with("[many.txt: lines 9-10 of 10.]"), "{out}");
 
-        let result = dispatch_tool(
-            "Read",
-            &json!({"file_path": file_over.to_str().unwrap()}),
-            &mut |_| {},
-        );
-        assert!(result.is_err(), "1MB + 1 should be rejected");
-        assert!(result.unwrap_err().contains("too large"));
+        let err = read_with(&file, json!({"offset": 11})).unwrap_err();
+        assert!(err.contains("past the end"));
+        let err = read_with(&file, json!({"limit": 0})).unwrap_err();
+        assert!(err.contains("limit must be a positive integer"));
+    }
 
-        let _ = std::fs::remove_dir_all(&dir);
+    #[test]
+    fn read_truncates_long_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("wide.txt");
+        std::fs::write(&file, format!("{}\nshort\n", "é".repeat(2500))).unwrap();
+
+        let out = read_with(&file, json!({})).unwrap();
+        let first = out.lines().nth(1).unwrap();
+        assert!(first.ends_with("... [line truncated, 2500 chars]"));
+        assert_eq!(first.matches('é').count(), READ_MAX_LINE_CHARS);
+        assert_eq!(out.lines().nth(2), Some("     2\tshort"));
+    }
+
+    #[test]
+    fn read_empty_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("empty.txt");
+        std::fs::write(&file, "").unwrap();
+        assert!(read_with(&file, json!({})).unwrap().contains("empty file"));
+    }
+
+    #[test]
+    fn read_oversized_file_returns_first_page() {
+        // Files over 1MB used to be rejected outright; now the first page
+        // comes back with a header pointing at the rest.
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("big.txt");
+        let data: String = (1..=30_000).map(|i| format!("{i:0>60}\n")).collect();
+        assert!(data.len() > READ_MAX_PAGE_BYTES);
+        std::fs::write(&file, &data).unwrap();
+
+        let out = read_with(&file, json!({})).unwrap();
+        let header = out.lines().next().unwrap();
+        assert!(header.contains("lines 1-2000 of 30000"), "{header}");
+        assert!(header.contains("1.7MB file"), "{header}");
+        assert!(header.contains("offset=2001"), "{header}");
+        assert_eq!(out.lines().count(), 2001);
+
+        // A huge limit is still capped by the page byte budget
+        let out = read_with(&file, json!({"limit": 100_000})).unwrap();
+        assert!(out.len() < READ_MAX_PAGE_BYTES + 1024);
+        assert!(out.lines().next().unwrap().contains("to read more"));
     }
 }
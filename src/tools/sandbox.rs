@@ -0,0 +1,629 @@
+//! Landlock confinement for Bash. The ruleset is installed in the child
+//! between fork and exec, so the persistent shell, background jobs and
+//! everything they start can read the whole filesystem but write only under
+//! the workspace and configured directories. The child also gets its own
+//! user and mount namespaces in which the workspace's `.forgeflare` and
+//! `.git` are read-only bind mounts: code forgeflare and git run outside the
+//! sandbox lives there.
+//!
+//! Without network access the child gets an empty network namespace with
+//! only loopback, unless some TCP ports are allowed: then it keeps the host
+//! network and Landlock limits TCP connects to those ports, leaving UDP
+//! (DNS included) unfiltered. Neither mode stops connects to Unix sockets
+//! that have a path, such as `/var/run/docker.sock`.
+
+use crate::config::SandboxConfig;
+use landlock::{
+    path_beneath_rules, Access, AccessFs, AccessNet, CompatLevel, Compatible, NetPort, Ruleset,
+    RulesetAttr, RulesetCreatedAttr, RulesetError, ABI,
+};
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Landlock ABI the ruleset targets: v4 adds TCP bind/connect control.
+const LANDLOCK_ABI: ABI = ABI::V4;
+
+/// Device files every command may write to.
+const WRITABLE_DEVICES: &[&str] = &["/dev/null", "/dev/zero", "/dev/full", "/dev/tty"];
+
+/// Error text sandbox denials tend to produce. Commands report plenty of
+/// these for other reasons too, so a match only suggests the sandbox.
+const VIOLATION_HINTS: &[&str] = &[
+    "Permission denied",
+    "Operation not permitted",
+    "Read-only file system",
+    "Network is unreachable",
+    "Temporary failure in name resolution",
+    "Could not resolve host",
+];
+
+/// Workspace entries mounted read-only: forgeflare's config and hooks, and
+/// git's hooks and config, which run unconfined when forgeflare or the user
+/// next call git. Missing ones are created empty so they can't be planted.
+const PROTECTED: &[&str] = &[".forgeflare", ".git"];
+
+/// Mount flags a bind remount must keep, or the kernel refuses it inside a
+/// user namespace, as `(statvfs flag, mount flag)` pairs.
+const LOCKED_MOUNT_FLAGS: &[(libc::c_ulong, libc::c_ulong)] = &[
+    (libc::ST_NOSUID, libc::MS_NOSUID),
+    (libc::ST_NODEV, libc::MS_NODEV),
+    (libc::ST_NOEXEC, libc::MS_NOEXEC),
+    (libc::ST_NOATIME, libc::MS_NOATIME),
+    (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+    (libc::ST_RELATIME, libc::MS_RELATIME),
+];
+
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    writable: Vec<PathBuf>,
+    protected: Vec<PathBuf>,
+    network: bool,
+    allowed_ports: Vec<u16>,
+}
+
+impl Sandbox {
+    /// `None` unless `[sandbox] enabled = true`.
+    pub fn from_config(config: &SandboxConfig, workspace: &Path) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+        let mut writable = vec![workspace.to_path_buf()];
+        writable.extend(config.writable.iter().map(|p| workspace.join(p)));
+        Some(Self {
+            writable,
+            protected: PROTECTED.iter().map(|p| workspace.join(p)).collect(),
+            network: config.network,
+            allowed_ports: config.allowed_ports.clone(),
+        })
+    }
+
+    /// Arrange for `cmd` to start confined. Fails, rather than running the
+    /// command unconfined, if the kernel can't enforce the whole ruleset.
+    pub fn apply(&self, cmd: &mut Command) -> Result<(), String> {
+        let fd = self.ruleset()?;
+        let namespace = Namespace::prepare(&self.protected, self.isolates_network())?;
+        // SAFETY: the hook only makes syscalls, all async-signal-safe, on
+        // buffers prepared before the fork.
+        unsafe {
+            cmd.pre_exec(move || {
+                namespace.enter()?;
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+                    || libc::syscall(libc::SYS_landlock_restrict_self, fd.as_raw_fd(), 0) != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether commands get a network namespace of their own.
+    fn isolates_network(&self) -> bool {
+        !self.network && self.allowed_ports.is_empty()
+    }
+
+    fn ruleset(&self) -> Result<OwnedFd, String> {
+        self.build_ruleset()
+            .map_err(|e| format!("Sandbox unavailable: {e}"))?
+            .ok_or_else(|| "Sandbox unavailable: Landlock is not supported".to_string())
+    }
+
+    fn build_ruleset(&self) -> Result<Option<OwnedFd>, RulesetError> {
+        let mut ruleset = Ruleset::default()
+            .set_compatibility(CompatLevel::HardRequirement)
+            .handle_access(AccessFs::from_all(LANDLOCK_ABI))?;
+        let filter_ports = !self.network && !self.isolates_network();
+        if filter_ports {
+            ruleset = ruleset.handle_access(AccessNet::from_all(LANDLOCK_ABI))?;
+        }
+        let mut created = ruleset
+            .create()?
+            .add_rules(path_beneath_rules(["/"], AccessFs::from_read(LANDLOCK_ABI)))?
+            .add_rules(path_beneath_rules(
+                self.writable.iter().map(PathBuf::as_path),
+                AccessFs::from_all(LANDLOCK_ABI),
+            ))?
+            .add_rules(path_beneath_rules(
+                WRITABLE_DEVICES,
+                AccessFs::from_all(LANDLOCK_ABI),
+            ))?;
+        if filter_ports {
+            for &port in &self.allowed_ports {
+                created = created.add_rule(NetPort::new(port, AccessNet::ConnectTcp))?;
+            }
+        }
+        Ok(created.into())
+    }
+
+    /// The write and network policy, for the Bash description and labels.
+    pub fn describe(&self) -> String {
+        let writable = self
+            .writable
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let network = match (self.network, self.allowed_ports.as_slice()) {
+            (true, _) => "unrestricted".to_string(),
+            (false, []) => "isolated, loopback only".to_string(),
+            (false, ports) => format!(
+                "TCP connects only to ports {}",
+                ports
+                    .iter()
+                    .map(u16::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        format!(
+            "writes are allowed only under {writable} (not .forgeflare or .git); network: {network}"
+        )
+    }
+
+    /// Prefix a failed command's output with a sandbox notice when it
+    /// contains an error a denial could have caused. Only call this for
+    /// failures: successful output mentioning such errors (a grep for
+    /// "Permission denied") is not a denial. Even so this is a guess from the
+    /// text: it can flag unrelated errors, and misses denials a command
+    /// reports in other words or not at all.
+    pub fn label_failure(&self, output: String) -> String {
+        if !VIOLATION_HINTS.iter().any(|hint| output.contains(hint)) {
+            return output;
+        }
+        format!(
+            "[sandbox] The output shows a permission or network error, which may be \
+             caused by the sandbox: {}.\n{output}",
+            self.describe()
+        )
+    }
+
+    /// Refuse a write the sandbox would deny a confined command. Edit runs
+    /// in-process, outside Landlock and the read-only mounts, so it checks
+    /// this itself.
+    pub fn check_write(&self, path: &Path) -> Result<(), String> {
+        let allowed = resolve_for_write(path).is_some_and(|target| {
+            let under = |roots: &[PathBuf]| {
+                roots
+                    .iter()
+                    .any(|root| target.starts_with(root.canonicalize().unwrap_or(root.clone())))
+            };
+            under(&self.writable) && !under(&self.protected)
+        });
+        if allowed {
+            Ok(())
+        } else {
+            Err(format!(
+                "[sandbox] Cannot write {}: {}",
+                path.display(),
+                self.describe()
+            ))
+        }
+    }
+}
+
+/// Where a write to `path` would land: its nearest existing ancestor with
+/// symlinks resolved, plus the components still to be created. `None` if
+/// those climb out with `..`.
+fn resolve_for_write(path: &Path) -> Option<PathBuf> {
+    let path = std::env::current_dir().ok()?.join(path);
+    let mut existing = path.as_path();
+    let mut missing = Vec::new();
+    let resolved = loop {
+        match existing.canonicalize() {
+            Ok(resolved) => break resolved,
+            Err(_) => {
+                missing.push(existing.file_name()?);
+                existing = existing.parent()?;
+            }
+        }
+    };
+    Some(
+        missing
+            .iter()
+            .rev()
+            .fold(resolved, |dir, name| dir.join(name)),
+    )
+}
+
+/// Everything the child needs to set up its namespaces, formatted before
+/// the fork so the `pre_exec` hook doesn't allocate.
+struct Namespace {
+    uid_map: CString,
+    gid_map: CString,
+    protected: Vec<CString>,
+    isolate_network: bool,
+}
+
+impl Namespace {
+    fn prepare(protected: &[PathBuf], isolate_network: bool) -> Result<Self, String> {
+        for path in protected {
+            if !path.exists() {
+                std::fs::create_dir(path).map_err(|e| {
+                    format!("Sandbox unavailable: cannot create {}: {e}", path.display())
+                })?;
+            }
+        }
+        // SAFETY: geteuid and getegid cannot fail.
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let cstring = |bytes: &[u8]| {
+            CString::new(bytes).map_err(|_| "Sandbox unavailable: path contains NUL".to_string())
+        };
+        Ok(Self {
+            uid_map: cstring(format!("{uid} {uid} 1").as_bytes())?,
+            gid_map: cstring(format!("{gid} {gid} 1").as_bytes())?,
+            protected: protected
+                .iter()
+                .map(|p| cstring(p.as_os_str().as_bytes()))
+                .collect::<Result<_, _>>()?,
+            isolate_network,
+        })
+    }
+
+    /// Unshare user and mount namespaces, keeping our own uid and gid, and
+    /// remount the protected paths read-only; unshare the network too, with
+    /// loopback up, if asked. Runs in the forked child.
+    fn enter(&self) -> io::Result<()> {
+        let mut flags = libc::CLONE_NEWUSER | libc::CLONE_NEWNS;
+        if self.isolate_network {
+            flags |= libc::CLONE_NEWNET;
+        }
+        // SAFETY: plain syscalls on NUL-terminated buffers we own.
+        unsafe {
+            check(libc::unshare(flags))?;
+            write_proc(c"/proc/self/setgroups", c"deny")?;
+            write_proc(c"/proc/self/uid_map", &self.uid_map)?;
+            write_proc(c"/proc/self/gid_map", &self.gid_map)?;
+            // Keep our mounts from propagating back to the parent namespace
+            check(libc::mount(
+                std::ptr::null(),
+                c"/".as_ptr(),
+                std::ptr::null(),
+                libc::MS_REC | libc::MS_PRIVATE,
+                std::ptr::null(),
+            ))?;
+            for path in &self.protected {
+                check(libc::mount(
+                    path.as_ptr(),
+                    path.as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_BIND | libc::MS_REC,
+                    std::ptr::null(),
+                ))?;
+                let mut stat: libc::statvfs = std::mem::zeroed();
+                check(libc::statvfs(path.as_ptr(), &mut stat))?;
+                let mut flags = libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY;
+                for &(st, ms) in LOCKED_MOUNT_FLAGS {
+                    if stat.f_flag & st != 0 {
+                        flags |= ms;
+                    }
+                }
+                if flags & (libc::MS_NOATIME | libc::MS_RELATIME) == 0 {
+                    flags |= libc::MS_STRICTATIME;
+                }
+                check(libc::mount(
+                    std::ptr::null(),
+                    path.as_ptr(),
+                    std::ptr::null(),
+                    flags,
+                    std::ptr::null(),
+                ))?;
+            }
+        }
+        if self.isolate_network {
+            loopback_up()?;
+        }
+        Ok(())
+    }
+}
+
+/// Bring up `lo` so servers and tests inside the sandbox can still talk to
+/// each other over 127.0.0.1.
+fn loopback_up() -> io::Result<()> {
+    // SAFETY: an ioctl round trip on a socket we open and close here.
+    unsafe {
+        let sock = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if sock < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut req: libc::ifreq = std::mem::zeroed();
+        for (dst, &src) in req.ifr_name.iter_mut().zip(b"lo") {
+            *dst = src as libc::c_char;
+        }
+        let mut ret = libc::ioctl(sock, libc::SIOCGIFFLAGS, &mut req);
+        if ret == 0 {
+            req.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+            ret = libc::ioctl(sock, libc::SIOCSIFFLAGS, &req);
+        }
+        let err = io::Error::last_os_error();
+        libc::close(sock);
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(err)
+        }
+    }
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Write `contents` to a /proc file in the single write(2) it requires.
+unsafe fn write_proc(path: &CStr, contents: &CStr) -> io::Result<()> {
+    let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let bytes = contents.to_bytes();
+    let written = libc::write(fd, bytes.as_ptr().cast(), bytes.len());
+    let err = io::Error::last_os_error();
+    libc::close(fd);
+    if written == bytes.len() as isize {
+        Ok(())
+    } else {
+        Err(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::jobs::Jobs;
+    use crate::tools::shell::Shell;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Whether this kernel lets us start a confined process at all.
+    fn sandbox_available() -> bool {
+        let workspace = tempfile::tempdir().unwrap();
+        let sandbox = Sandbox::from_config(
+            &SandboxConfig {
+                enabled: true,
+                ..SandboxConfig::default()
+            },
+            workspace.path(),
+        )
+        .unwrap();
+        let mut cmd = Command::new("true");
+        let status = sandbox
+            .apply(&mut cmd)
+            .and_then(|()| cmd.status().map_err(|e| e.to_string()));
+        match status {
+            Ok(status) if status.success() => true,
+            other => {
+                eprintln!("skipping sandbox test: {other:?}");
+                false
+            }
+        }
+    }
+
+    fn sandboxed_shell(workspace: &Path, config: SandboxConfig) -> Shell {
+        let sandbox = Sandbox::from_config(
+            &SandboxConfig {
+                enabled: true,
+                ..config
+            },
+            workspace,
+        );
+        Shell::new(workspace.to_path_buf(), sandbox.map(Arc::new))
+    }
+
+    fn run(shell: &Shell, command: &str) -> Result<String, String> {
+        shell.run(command, Duration::from_secs(10), &mut |_| {})
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        assert!(Sandbox::from_config(&SandboxConfig::default(), Path::new("/w")).is_none());
+    }
+
+    #[test]
+    fn label_only_permission_errors() {
+        let sandbox = Sandbox {
+            writable: vec![PathBuf::from("/w")],
+            protected: Vec::new(),
+            network: false,
+            allowed_ports: vec![443, 8080],
+        };
+        assert_eq!(sandbox.label_failure("exit code 1".into()), "exit code 1");
+        let labeled = sandbox.label_failure("x: Permission denied".into());
+        assert!(labeled.starts_with("[sandbox] "), "{labeled}");
+        assert!(
+            labeled.contains("may be caused by the sandbox"),
+            "{labeled}"
+        );
+        assert!(labeled.contains("network: TCP connects only to ports 443, 8080."));
+        assert!(labeled.ends_with("\nx: Permission denied"));
+    }
+
+    #[test]
+    fn edit_writes_follow_sandbox_rules() {
+        let workspace = tempfile::tempdir().unwrap();
+        let root = workspace.path().canonicalize().unwrap();
+        std::fs::create_dir(root.join("src")).unwrap();
+        std::os::unix::fs::symlink("/etc", root.join("link")).unwrap();
+        let sandbox = Sandbox::from_config(
+            &SandboxConfig {
+                enabled: true,
+                ..SandboxConfig::default()
+            },
+            &root,
+        )
+        .unwrap();
+
+        sandbox.check_write(&root.join("src/new/lib.rs")).unwrap();
+        sandbox.check_write(&root.join("src/../README.md")).unwrap();
+        for denied in [
+            root.join(".forgeflare/hooks.toml"),
+            root.join(".git/config"),
+            root.join("link/escape.txt"),
+            root.join("new/../../escape.txt"),
+            PathBuf::from("/etc/escape.txt"),
+        ] {
+            let err = sandbox.check_write(&denied).unwrap_err();
+            assert!(err.starts_with("[sandbox] Cannot write "), "{err}");
+        }
+
+        // Edit goes through the same check
+        let tools = super::super::ToolRegistry::builtin_with_sandbox(Some(Arc::new(sandbox)));
+        let hooks = root.join(".forgeflare/hooks.toml");
+        let input = serde_json::json!({
+            "file_path": hooks,
+            "old_str": "",
+            "new_str": "[[hooks]]\n",
+        });
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let err = rt
+            .block_on(tools.dispatch("Edit", &input, &mut |_| {}))
+            .unwrap_err();
+        assert!(err.starts_with("[sandbox] Cannot write "), "{err}");
+        assert!(!hooks.exists());
+    }
+
+    #[test]
+    fn writes_confined_to_workspace() {
+        if !sandbox_available() {
+            return;
+        }
+        let workspace = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let shell = sandboxed_shell(
+            workspace.path(),
+            SandboxConfig {
+                writable: Vec::new(),
+                ..SandboxConfig::default()
+            },
+        );
+
+        run(&shell, "echo ok > inside.txt && cat inside.txt > /dev/null").unwrap();
+        assert!(workspace.path().join("inside.txt").exists());
+
+        let target = outside.path().join("escape.txt");
+        let err = run(&shell, &format!("echo no > {}", target.display())).unwrap_err();
+        assert!(err.starts_with("[sandbox] "), "{err}");
+        assert!(!target.exists());
+        // Reading outside the workspace is still allowed
+        run(&shell, &format!("ls {}", outside.path().display())).unwrap();
+    }
+
+    #[test]
+    fn forgeflare_and_git_are_read_only() {
+        if !sandbox_available() {
+            return;
+        }
+        let workspace = tempfile::tempdir().unwrap();
+        let hooks = workspace.path().join(".forgeflare/hooks.toml");
+        std::fs::create_dir(workspace.path().join(".forgeflare")).unwrap();
+        std::fs::write(&hooks, "").unwrap();
+        let shell = sandboxed_shell(workspace.path(), SandboxConfig::default());
+
+        run(&shell, "echo ok > .gitignore").unwrap();
+        let err = run(&shell, "echo '[[guard]]' >> .forgeflare/hooks.toml").unwrap_err();
+        assert!(err.starts_with("[sandbox] "), "{err}");
+        run(&shell, "mkdir .git/hooks").unwrap_err();
+        run(&shell, "umount .git").unwrap_err();
+        run(&shell, "rm -rf .forgeflare .git").unwrap_err();
+        assert_eq!(std::fs::read_to_string(&hooks).unwrap(), "");
+        // .git didn't exist, so an empty one now holds its place
+        let git = workspace.path().join(".git");
+        assert_eq!(std::fs::read_dir(git).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn labels_failed_commands_and_jobs_only() {
+        if !sandbox_available() {
+            return;
+        }
+        let workspace = tempfile::tempdir().unwrap();
+        let shell = sandboxed_shell(workspace.path(), SandboxConfig::default());
+        let err = run(&shell, "touch .git/x").unwrap_err();
+        assert!(err.starts_with("[sandbox] "), "{err}");
+        assert!(err.ends_with("Read-only file system\n"), "{err}");
+        // Successful output that merely mentions such an error is left alone
+        let out = run(&shell, "echo 'x: Permission denied'").unwrap();
+        assert_eq!(out, "x: Permission denied\n");
+
+        let sandbox = Sandbox::from_config(
+            &SandboxConfig {
+                enabled: true,
+                ..SandboxConfig::default()
+            },
+            workspace.path(),
+        );
+        let jobs = Jobs::new(sandbox.map(Arc::new));
+        let id = jobs.spawn("touch /x", workspace.path()).unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+        let out = jobs.poll(&id, None).unwrap();
+        assert!(out.starts_with("[sandbox] "), "{out}");
+        assert!(out.contains("Permission denied"), "{out}");
+        let id = jobs
+            .spawn("echo 'x: Permission denied'", workspace.path())
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+        let out = jobs.poll(&id, None).unwrap();
+        assert!(out.starts_with("[bash_2 exited with code 0"), "{out}");
+    }
+
+    #[test]
+    fn network_isolated_by_default() {
+        if !sandbox_available() {
+            return;
+        }
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let workspace = tempfile::tempdir().unwrap();
+        let shell = sandboxed_shell(workspace.path(), SandboxConfig::default());
+
+        // The host's loopback is out of reach, and so is UDP
+        run(&shell, &format!("exec 3<>/dev/tcp/127.0.0.1/{port}")).unwrap_err();
+        let err = run(&shell, "echo x > /dev/udp/192.0.2.1/53").unwrap_err();
+        assert!(err.contains("Network is unreachable"), "{err}");
+        // The sandbox's own loopback is up: refused, not unreachable
+        let err = run(&shell, &format!("exec 3<>/dev/tcp/127.0.0.1/{port}")).unwrap_err();
+        assert!(err.contains("Connection refused"), "{err}");
+    }
+
+    #[test]
+    fn allowed_ports_limit_tcp_connects() {
+        if !sandbox_available() {
+            return;
+        }
+        let allowed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let other = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = |l: &std::net::TcpListener| l.local_addr().unwrap().port();
+        let workspace = tempfile::tempdir().unwrap();
+        let shell = sandboxed_shell(
+            workspace.path(),
+            SandboxConfig {
+                allowed_ports: vec![port(&allowed)],
+                ..SandboxConfig::default()
+            },
+        );
+
+        run(
+            &shell,
+            &format!("exec 3<>/dev/tcp/127.0.0.1/{}", port(&allowed)),
+        )
+        .unwrap();
+        let err = run(
+            &shell,
+            &format!("exec 3<>/dev/tcp/127.0.0.1/{}", port(&other)),
+        )
+        .unwrap_err();
+        assert!(err.starts_with("[sandbox] "), "{err}");
+        // Only TCP is filtered in this mode
+        run(&shell, "echo x > /dev/udp/127.0.0.1/9").unwrap();
+    }
+}
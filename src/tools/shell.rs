@@ -0,0 +1,478 @@
+//! Long-lived bash process behind the Bash tool. `cd`, `export` and
+//! `source` carry over between calls. Each command's output ends with a
+//! sentinel line on stdout (exit code and new working directory) and one on
+//! stderr, so the reader knows when both streams are drained.
+
+use super::sandbox::Sandbox;
+use super::{StreamCallback, ToolResult, BASH_OUTPUT_LIMIT};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+enum Msg {
+    Output(String),
+    /// Stdout sentinel: the command finished.
+    Done {
+        status: i32,
+        cwd: String,
+    },
+    /// Stderr sentinel: all of the command's stderr has been read.
+    StderrDone,
+    /// Stdout hit EOF: the shell exited.
+    Closed,
+}
+
+enum Outcome {
+    Finished(i32),
+    TimedOut,
+    Truncated,
+    Exited(Option<i32>),
+}
+
+/// One bash process. Dropping it kills the process group, which takes any
+/// children the command left behind with it.
+struct Session {
+    child: Child,
+    stdin: ChildStdin,
+    rx: mpsc::Receiver<Msg>,
+    marker: String,
+    /// Shared with `Shell::kill`: this session's process group, 0 once dropped.
+    leader: Arc<AtomicI32>,
+}
+
+impl Session {
+    fn spawn(
+        dir: &Path,
+        sandbox: Option<&Sandbox>,
+        leader: Arc<AtomicI32>,
+    ) -> Result<Self, String> {
+        let mut cmd = Command::new("bash");
+        cmd.args(["--noprofile", "--norc"])
+            .current_dir(dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0);
+        if let Some(sandbox) = sandbox {
+            sandbox.apply(&mut cmd)?;
+        }
+        let mut child = cmd
+            .spawn()
+            .map_err(|e| format!("Failed to spawn bash: {e}"))?;
+        let marker = format!("__forgeflare_{}__", uuid::Uuid::new_v4().simple());
+        let (tx, rx) = mpsc::channel();
+        spawn_reader(child.stdout.take().unwrap(), &marker, tx.clone(), true);
+        spawn_reader(child.stderr.take().unwrap(), &marker, tx, false);
+        let stdin = child.stdin.take().unwrap();
+        leader.store(child.id() as i32, Ordering::SeqCst);
+        Ok(Self {
+            child,
+            stdin,
+            rx,
+            marker,
+            leader,
+        })
+    }
+
+    fn is_alive(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+
+    /// Run `command` in the shell, updating `cwd` from the sentinel.
+    fn run(
+        &mut self,
+        command: &str,
+        deadline: Instant,
+        output: &mut String,
+        cwd: &mut PathBuf,
+        stream_cb: StreamCallback<'_>,
+    ) -> Outcome {
+        // The command is read verbatim through a quoted heredoc and eval'd
+        // with stdin from /dev/null, so it can't consume the control pipe.
+        let marker = &self.marker;
+        let script = format!(
+            "IFS= read -r -d '' __ff_cmd <<'{marker}'\n{command}\n{marker}\n\
+             eval \"$__ff_cmd\" < /dev/null\n\
+             printf '{marker} %d %s\\n' \"$?\" \"$PWD\"\n\
+             printf '{marker}\\n' >&2\n"
+        );
+        if self.stdin.write_all(script.as_bytes()).is_err() || self.stdin.flush().is_err() {
+            return Outcome::Exited(self.child.wait().ok().and_then(|s| s.code()));
+        }
+
+        let mut status = None;
+        let mut stderr_done = false;
+        loop {
+            if let (Some(code), true) = (status, stderr_done) {
+                return Outcome::Finished(code);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Outcome::TimedOut;
+            }
+            match self
+                .rx
+                .recv_timeout((deadline - now).min(Duration::from_millis(50)))
+            {
+                Ok(Msg::Output(chunk)) => {
+                    stream_cb(&chunk);
+                    if output.len() + chunk.len() > BASH_OUTPUT_LIMIT {
+                        let remaining = BASH_OUTPUT_LIMIT.saturating_sub(output.len());
+                        output.push_str(&chunk[..chunk.floor_char_boundary(remaining)]);
+                        return Outcome::Truncated;
+                    }
+                    output.push_str(&chunk);
+                }
+                Ok(Msg::Done {
+                    status: code,
+                    cwd: dir,
+                }) => {
+                    status = Some(code);
+                    *cwd = PathBuf::from(dir);
+                }
+                Ok(Msg::StderrDone) => stderr_done = true,
+                Ok(Msg::Closed) | Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Outcome::Exited(self.child.wait().ok().and_then(|s| s.code()));
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    // A background child can hold stdout open after bash exits
+                    if let Ok(Some(exit)) = self.child.try_wait() {
+                        while let Ok(Msg::Output(chunk)) = self.rx.try_recv() {
+                            stream_cb(&chunk);
+                            if output.len() + chunk.len() <= BASH_OUTPUT_LIMIT {
+                                output.push_str(&chunk);
+                            }
+                        }
+                        return Outcome::Exited(exit.code());
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let pid = self.child.id() as i32;
+        let _ = self
+            .leader
+            .compare_exchange(pid, 0, Ordering::SeqCst, Ordering::SeqCst);
+        // SAFETY: kill(2) with a negative pid signals our own child's group.
+        unsafe {
+            libc::kill(-pid, libc::SIGKILL);
+        }
+        let _ = self.child.wait();
+    }
+}
+
+fn spawn_reader(
+    pipe: impl Read + Send + 'static,
+    marker: &str,
+    tx: mpsc::Sender<Msg>,
+    stdout: bool,
+) {
+    let marker = marker.to_string();
+    std::thread::spawn(move || {
+        let mut reader = BufReader::with_capacity(4096, pipe);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => {
+                    if stdout {
+                        let _ = tx.send(Msg::Closed);
+                    }
+                    break;
+                }
+                Ok(_) => {}
+            }
+            let line = String::from_utf8_lossy(&buf);
+            let Some(at) = line.find(&marker) else {
+                if tx.send(Msg::Output(line.into_owned())).is_err() {
+                    break;
+                }
+                continue;
+            };
+            // Output without a trailing newline shares the sentinel's line
+            if at > 0 && tx.send(Msg::Output(line[..at].to_string())).is_err() {
+                break;
+            }
+            let msg = if stdout {
+                let rest = line[at + marker.len()..].trim_start();
+                let rest = rest.strip_suffix('\n').unwrap_or(rest);
+                let (status, cwd) = rest.split_once(' ').unwrap_or((rest, ""));
+                Msg::Done {
+                    status: status.parse().unwrap_or(-1),
+                    cwd: cwd.to_string(),
+                }
+            } else {
+                Msg::StderrDone
+            };
+            if tx.send(msg).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+struct State {
+    session: Option<Session>,
+    cwd: PathBuf,
+}
+
+/// The Bash tool's shell. Started on first use; restarted in the last known
+/// working directory after it exits, times out or floods its output.
+pub struct Shell {
+    start_dir: PathBuf,
+    sandbox: Option<Arc<Sandbox>>,
+    state: Mutex<State>,
+    /// Process group of the running bash, readable without the state lock
+    /// (which a running command holds).
+    leader: Arc<AtomicI32>,
+}
+
+impl Shell {
+    pub fn new(start_dir: PathBuf, sandbox: Option<Arc<Sandbox>>) -> Self {
+        Self {
+            sandbox,
+            state: Mutex::new(State {
+                session: None,
+                cwd: start_dir.clone(),
+            }),
+            start_dir,
+            leader: Arc::new(AtomicI32::new(0)),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The shell's working directory as of its last command.
+    pub fn cwd(&self) -> PathBuf {
+        self.lock().cwd.clone()
+    }
+
+    /// Kill the shell. The next command starts fresh in the starting
+    /// directory, with no `cd`s or exports carried over.
+    pub fn reset(&self) {
+        let mut state = self.lock();
+        state.session = None;
+        state.cwd = self.start_dir.clone();
+    }
+
+    /// Kill the shell and everything in its process group, without waiting
+    /// for the command in progress. The shell runs in its own group, so a
+    /// terminal's Ctrl-C doesn't reach it; this is how an interrupt does. The
+    /// interrupted command fails and the next one starts a fresh shell.
+    pub fn kill(&self) {
+        let pgid = self.leader.load(Ordering::SeqCst);
+        if pgid > 0 {
+            // SAFETY: kill(2) with a negative pid signals our own child's group.
+            unsafe {
+                libc::kill(-pgid, libc::SIGKILL);
+            }
+        }
+    }
+
+    /// Run `command`, flagging a failure that looks like a sandbox denial.
+    pub fn run(
+        &self,
+        command: &str,
+        timeout: Duration,
+        stream_cb: StreamCallback<'_>,
+    ) -> ToolResult {
+        let result = self.run_unlabeled(command, timeout, stream_cb);
+        match &self.sandbox {
+            Some(sandbox) => result.map_err(|e| sandbox.label_failure(e)),
+            None => result,
+        }
+    }
+
+    fn run_unlabeled(
+        &self,
+        command: &str,
+        timeout: Duration,
+        stream_cb: StreamCallback<'_>,
+    ) -> ToolResult {
+        let mut guard = self.lock();
+        let state = &mut *guard;
+        let mut notice = String::new();
+        if state.session.as_mut().is_some_and(|s| !s.is_alive()) {
+            state.session = None;
+            notice = restart_notice("the previous shell exited", &state.cwd);
+        }
+        if state.session.is_none() {
+            if !state.cwd.is_dir() {
+                state.cwd = self.start_dir.clone();
+            }
+            state.session = Some(Session::spawn(
+                &state.cwd,
+                self.sandbox.as_deref(),
+                Arc::clone(&self.leader),
+            )?);
+        }
+        let session = state.session.as_mut().unwrap();
+
+        let mut output = String::new();
+        let outcome = session.run(
+            command,
+            Instant::now() + timeout,
+            &mut output,
+            &mut state.cwd,
+            stream_cb,
+        );
+        output.push_str(&notice);
+
+        let secs = timeout.as_secs();
+        match outcome {
+            Outcome::Finished(0) => Ok(output),
+            Outcome::Finished(code) => Err(if output.is_empty() {
+                format!("Command failed with exit code {code}")
+            } else {
+                format!("Command failed with exit code {code}:\n{output}")
+            }),
+            Outcome::TimedOut => {
+                state.session = None;
+                let notice = restart_notice("it timed out", &state.cwd);
+                if output.is_empty() {
+                    Err(format!(
+                        "Command timed out after {secs}s: {command}{notice}"
+                    ))
+                } else {
+                    Err(format!(
+                        "Command timed out after {secs}s (partial output):\n{output}{notice}"
+                    ))
+                }
+            }
+            Outcome::Truncated => {
+                state.session = None;
+                let notice = restart_notice("its output was cut off", &state.cwd);
+                Err(format!(
+                    "Command output exceeded 1MB limit (truncated):\n{output}{notice}"
+                ))
+            }
+            Outcome::Exited(code) => {
+                state.session = None;
+                let code = code.unwrap_or(-1);
+                output.push_str(&restart_notice(
+                    &format!("the shell exited with code {code}"),
+                    &state.cwd,
+                ));
+                if code == 0 {
+                    Ok(output)
+                } else {
+                    Err(format!("Command failed with exit code {code}:\n{output}"))
+                }
+            }
+        }
+    }
+}
+
+fn restart_notice(why: &str, cwd: &Path) -> String {
+    format!(
+        "\n[Shell restarted because {why}; exported variables were lost. Working directory: {}]",
+        cwd.display()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell() -> (tempfile::TempDir, Shell) {
+        let dir = tempfile::tempdir().unwrap();
+        let shell = Shell::new(dir.path().canonicalize().unwrap(), None);
+        (dir, shell)
+    }
+
+    fn run(shell: &Shell, command: &str) -> ToolResult {
+        shell.run(command, Duration::from_secs(10), &mut |_| {})
+    }
+
+    #[test]
+    fn cwd_and_env_persist() {
+        let (dir, shell) = shell();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        run(&shell, "cd sub && export GREETING=hi").unwrap();
+        assert_eq!(shell.cwd(), dir.path().canonicalize().unwrap().join("sub"));
+        assert_eq!(
+            run(&shell, "echo \"$GREETING\" && pwd").unwrap(),
+            format!("hi\n{}\n", shell.cwd().display())
+        );
+    }
+
+    #[test]
+    fn exit_codes_and_unterminated_output() {
+        let (_dir, shell) = shell();
+        let err = run(&shell, "printf partial; false").unwrap_err();
+        assert_eq!(err, "Command failed with exit code 1:\npartial");
+        assert_eq!(run(&shell, "printf 'no newline'").unwrap(), "no newline");
+        let err = run(
+            &shell,
+            "echo oops >&2; exit_code() { return 7; }; exit_code",
+        )
+        .unwrap_err();
+        assert_eq!(err, "Command failed with exit code 7:\noops\n");
+    }
+
+    #[test]
+    fn quoting_and_stdin_are_isolated() {
+        let (_dir, shell) = shell();
+        // Unbalanced parens and quotes inside a heredoc, plus a reader of stdin
+        let out = run(&shell, "cat <<'EOF'\n) ' \"\nEOF\ncat; echo after").unwrap();
+        assert_eq!(out, ") ' \"\nafter\n");
+        assert_eq!(run(&shell, "echo still alive").unwrap(), "still alive\n");
+    }
+
+    #[test]
+    fn exit_restarts_in_last_cwd() {
+        let (dir, shell) = shell();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        run(&shell, "cd sub; export KEEP=1").unwrap();
+        let err = run(&shell, "exit 3").unwrap_err();
+        assert!(err.starts_with("Command failed with exit code 3"), "{err}");
+        assert!(err.contains("Shell restarted because the shell exited"));
+        let out = run(&shell, "echo \"[$KEEP]\"; pwd").unwrap();
+        assert_eq!(out, format!("[]\n{}\n", shell.cwd().display()));
+        assert!(shell.cwd().ends_with("sub"));
+    }
+
+    #[test]
+    fn timeout_kills_and_restarts() {
+        let (_dir, shell) = shell();
+        run(&shell, "export KEEP=1").unwrap();
+        let start = Instant::now();
+        let err = shell
+            .run(
+                "echo started; sleep 30",
+                Duration::from_millis(500),
+                &mut |_| {},
+            )
+            .unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(
+            err.starts_with("Command timed out after 0s (partial output):\nstarted\n"),
+            "{err}"
+        );
+        assert!(err.contains("because it timed out"));
+        assert_eq!(run(&shell, "echo \"[$KEEP]\"").unwrap(), "[]\n");
+    }
+
+    #[test]
+    fn reset_returns_to_start_dir() {
+        let (dir, shell) = shell();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        run(&shell, "cd sub").unwrap();
+        shell.reset();
+        assert_eq!(shell.cwd(), dir.path().canonicalize().unwrap());
+        assert_eq!(
+            run(&shell, "pwd").unwrap(),
+            format!("{}\n", shell.cwd().display())
+        );
+    }
+}